@@ -0,0 +1,643 @@
+use agsim::agent::HoldingTime;
+use agsim::config::{self, ModelFile};
+use agsim::ctmc::Generator;
+use agsim::dynamic::DynamicState;
+use agsim::matrix::TransitionMatrix;
+use agsim::model::InitialMode;
+use agsim::output::{CsvSink, EventSink, JsonlSink};
+use agsim::reliability::ReliabilityMetrics;
+use agsim::replication::Replications;
+use agsim::simulation::Simulation;
+use agsim::state::{AgentId, FieldName, StateChangeEvent, TimelineEntry};
+use agsim::summary::Summary;
+use agsim::validation;
+use chrono::{DateTime, Duration, Utc};
+use clap::error::ErrorKind;
+use clap::{Args, CommandFactory, Parser, Subcommand, ValueEnum};
+use std::cell::Cell;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::error::Error;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::PathBuf;
+use std::process::ExitCode;
+
+/// Runs agent-based simulations described in TOML, YAML or JSON model files.
+#[derive(Parser)]
+#[command(name = "agsim", version)]
+struct Cli {
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Checks a model file and reports on the structure of its models
+    Validate { file: PathBuf },
+    /// Computes the analytical metrics of the models of a file
+    Analyze {
+        file: PathBuf,
+        /// Only analyze this model
+        #[arg(long)]
+        model: Option<String>,
+        /// Modes counted as up, for reliability metrics
+        #[arg(long, value_delimiter = ',')]
+        up: Vec<String>,
+    },
+    /// Runs a model file, streaming its events, and prints summary statistics
+    Run {
+        #[command(flatten)]
+        run: RunArgs,
+        /// Format of the events
+        #[arg(long, value_enum, default_value_t = Format::Jsonl)]
+        format: Format,
+        /// File to write the events to, instead of stdout
+        #[arg(long, short)]
+        output: Option<PathBuf>,
+        /// Write values as strings, as JSON events were before values were typed (CSV values always are)
+        #[arg(long)]
+        string_values: bool,
+    },
+    /// Runs independent replications and prints confidence intervals of the time-weighted mean of each field
+    Replicate {
+        #[command(flatten)]
+        run: RunArgs,
+        /// Number of replications
+        #[arg(long, short = 'n', default_value_t = 10)]
+        replications: usize,
+        /// Confidence level of the intervals
+        #[arg(long, default_value_t = 0.95)]
+        confidence: f64,
+        /// Number of threads, all cores by default
+        #[arg(long)]
+        threads: Option<usize>,
+    },
+    /// Runs a model file and prints the timeline of one agent
+    Timeline {
+        #[command(flatten)]
+        run: RunArgs,
+        /// Id of the agent
+        #[arg(long)]
+        agent: String,
+    },
+}
+
+#[derive(Args)]
+struct RunArgs {
+    file: PathBuf,
+    /// Duration of the run, i.e. "7d" or "12h", overriding that of the file
+    #[arg(long, value_parser = config::parse_duration)]
+    duration: Option<Duration>,
+    /// Seed of the run, overriding that of the file
+    #[arg(long)]
+    seed: Option<u64>,
+    /// Warm-up period run before the start time, whose events are discarded
+    #[arg(long, value_parser = config::parse_duration)]
+    warm_up: Option<Duration>,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum Format {
+    Jsonl,
+    Csv,
+}
+
+fn main() -> ExitCode {
+    let result = match Cli::parse().command {
+        Command::Validate { file } => validate(file),
+        Command::Analyze { file, model, up } => analyze(file, model, &up),
+        Command::Run {
+            string_values: true,
+            format: Format::Csv,
+            ..
+        } => Cli::command()
+            .error(
+                ErrorKind::ArgumentConflict,
+                "--string-values only applies to --format jsonl, CSV values are always strings",
+            )
+            .exit(),
+        Command::Run {
+            run: args,
+            format,
+            output,
+            string_values,
+        } => run(args, format, output, string_values),
+        Command::Replicate {
+            run: args,
+            replications,
+            confidence,
+            threads,
+        } => replicate(args, replications, confidence, threads),
+        Command::Timeline { run: args, agent } => timeline(args, &agent),
+    };
+
+    // a closed pipe, i.e. `agsim timeline ... | head`, ends the output early rather than failing
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(error)
+            if error
+                .downcast_ref::<io::Error>()
+                .is_some_and(|error| error.kind() == io::ErrorKind::BrokenPipe) =>
+        {
+            ExitCode::SUCCESS
+        }
+        Err(error) => {
+            eprintln!("error: {}", error);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+// validate loads a model file, which checks it, and reports on the structure of each model from its initial modes
+fn validate(file: PathBuf) -> Result<(), Box<dyn Error>> {
+    let file = ModelFile::load(file)?;
+    for name in file.models() {
+        let model = file.model(name)?;
+        let InitialMode::Mode(initial) = model.initial() else {
+            continue;
+        };
+        let report = validation::validate(model.transitions(), initial)?;
+
+        println!("model {}: {} modes, valid", name, model.transitions().len());
+        print_modes("  unreachable from initial mode", &report.unreachable);
+        print_modes("  absorbing", &report.absorbing);
+        let components: Vec<String> = report
+            .components
+            .iter()
+            .map(|component| format!("[{}]", sorted(component).join(", ")))
+            .collect();
+        println!("  strongly connected components: {}", components.join(" "));
+    }
+    println!("{} agents", file.agents()?.len());
+    Ok(())
+}
+
+// analyze prints the analytical metrics of the chain of each model
+fn analyze(file: PathBuf, only: Option<String>, up: &[String]) -> Result<(), Box<dyn Error>> {
+    let file = ModelFile::load(file)?;
+    let names: Vec<String> = match only {
+        Some(name) => vec![name],
+        None => file.models().map(str::to_string).collect(),
+    };
+
+    for name in names {
+        let model = file.model(&name)?;
+        let InitialMode::Mode(initial) = model.initial() else {
+            continue;
+        };
+        let generator = Generator::from_transitions(model.transitions());
+        println!("model {}", name);
+        for caveat in caveats(model.transitions()) {
+            eprintln!("warning: model {}: {}", name, caveat);
+        }
+
+        let stationary: HashMap<String, f64> = generator
+            .stationary_distribution()
+            .unwrap_or_default()
+            .into_iter()
+            .collect();
+        let visits: HashMap<String, f64> = generator
+            .visit_frequencies()
+            .unwrap_or_default()
+            .into_iter()
+            .collect();
+        println!(
+            "  {:<20} {:>12} {:>16} {:>16}",
+            "mode", "stationary", "holding time (s)", "visits per hour"
+        );
+        for (mode, holding_time) in sorted_by_mode(generator.holding_times()) {
+            println!(
+                "  {:<20} {:>12} {:>16.2} {:>16}",
+                mode,
+                format_optional(stationary.get(&mode), 4),
+                holding_time,
+                format_optional(visits.get(&mode).map(|visits| visits * 3600.0).as_ref(), 4)
+            );
+        }
+
+        let absorbing = generator.absorbing_modes();
+        if !absorbing.is_empty() {
+            print_modes("  absorbing", &absorbing);
+            if let Some(time) = generator.mean_time_to_absorption(initial) {
+                println!("  mean time to absorption from {}: {:.2} s", initial, time);
+            }
+        }
+        if !up.is_empty() {
+            match ReliabilityMetrics::analytic(&generator, initial, up) {
+                Some(metrics) => {
+                    println!("  availability: {:.6}", metrics.availability);
+                    println!("  MTTF: {:.2} s", metrics.mttf);
+                    println!("  MTTR: {:.2} s", metrics.mttr);
+                    println!("  MTBF: {:.2} s", metrics.mtbf);
+                }
+                None => println!(
+                    "  no reliability metrics: the chain has no unique stationary distribution"
+                ),
+            }
+        }
+    }
+    Ok(())
+}
+
+// caveats describes what the chain analyzed for a model leaves out of it (see `ctmc::Generator`)
+fn caveats(transitions: &TransitionMatrix<String, DynamicState>) -> Vec<String> {
+    let state_types = || transitions.iter().map(|(_, def)| def);
+    let ignored: Vec<&str> = [
+        ("timers", state_types().any(|def| !def.timers.is_empty())),
+        ("signals", state_types().any(|def| !def.signals.is_empty())),
+        ("guards", state_types().any(|def| !def.guards.is_empty())),
+        (
+            "rate functions",
+            state_types().any(|def| def.rate_fn.is_some()),
+        ),
+        (
+            "weight functions",
+            state_types().any(|def| def.weight_fn.is_some()),
+        ),
+    ]
+    .into_iter()
+    .filter_map(|(feature, used)| used.then_some(feature))
+    .collect();
+
+    let mut caveats = Vec::new();
+    if !ignored.is_empty() {
+        caveats.push(format!("the analysis ignores its {}", ignored.join(", ")));
+    }
+    if state_types().any(|def| def.holding_time != HoldingTime::Exponential) {
+        caveats.push(
+            "holding times that are not exponential are analyzed as exponential ones with the same mean, which \
+             keeps the stationary distribution and mean times exact"
+                .to_string(),
+        );
+    }
+    caveats
+}
+
+// run streams the events of a run to stdout or a file, and prints summary statistics, to stderr if the events go
+// to stdout
+fn run(
+    args: RunArgs,
+    format: Format,
+    output: Option<PathBuf>,
+    string_values: bool,
+) -> Result<(), Box<dyn Error>> {
+    let (file, mut sim, duration) = prepare(&args)?;
+    let writer: Box<dyn Write> = match &output {
+        Some(path) => Box::new(BufWriter::new(File::create(path)?)),
+        None => Box::new(BufWriter::new(io::stdout().lock())),
+    };
+    let mut sink: Box<dyn EventSink> = match format {
+        Format::Jsonl if string_values => Box::new(JsonlSink::new(writer).with_string_values()),
+        Format::Jsonl => Box::new(JsonlSink::new(writer)),
+        Format::Csv => Box::new(CsvSink::new(writer)),
+    };
+
+    let start = file.start();
+    let mut stats = FieldStats::new(start);
+    sim.finish_warm_up();
+    stats.seed(&sim);
+    // the run stops at the first write that fails
+    let failed = Cell::new(false);
+    let mut failure = None;
+    sim.run_streaming_until(
+        duration,
+        |_| failed.get(),
+        |event| {
+            if failure.is_none() {
+                failure = sink.write(&event).err();
+                failed.set(failure.is_some());
+            }
+            stats.record(&event);
+        },
+    );
+    match failure {
+        Some(error) => return Err(error.into()),
+        None => sink.flush()?,
+    }
+
+    let summary = stats.summary(start + duration);
+    if output.is_some() {
+        print!("{}", summary);
+    } else {
+        eprint!("{}", summary);
+    }
+    Ok(())
+}
+
+// replicate runs independent replications and summarizes the time-weighted mean of each numeric field across them
+fn replicate(
+    args: RunArgs,
+    replications: usize,
+    confidence: f64,
+    threads: Option<usize>,
+) -> Result<(), Box<dyn Error>> {
+    let (file, _, duration) = prepare(&args)?;
+    let seed = args.seed.unwrap_or(file.seed());
+    let mut runner = Replications::new(replications, seed);
+    if let Some(threads) = threads {
+        runner = runner.with_threads(threads);
+    }
+
+    let start = file.start();
+    let results = runner.run(
+        |replication| {
+            let sim = file
+                .simulation_with_seed(replication.seed)
+                .expect("the model file was validated when loaded");
+            match args.warm_up {
+                Some(warm_up) => sim.with_warm_up(warm_up),
+                None => sim,
+            }
+        },
+        |_, sim| {
+            let mut stats = FieldStats::new(start);
+            sim.finish_warm_up();
+            stats.seed(sim);
+            sim.run_streaming(duration, |event| stats.record(&event));
+            stats.means(start + duration)
+        },
+    );
+
+    let mut values: BTreeMap<FieldName, Vec<f64>> = BTreeMap::new();
+    for result in &results {
+        for (field, mean) in &result.value {
+            values.entry(*field).or_default().push(*mean);
+        }
+    }
+    println!(
+        "{} replications of {} s, {:.0}% confidence intervals of time-weighted means",
+        replications,
+        duration.num_seconds(),
+        confidence * 100.0
+    );
+    println!(
+        "{:<24} {:>14} {:>14} {:>14} {:>14}",
+        "field", "mean", "half width", "lower", "upper"
+    );
+    for (field, values) in values {
+        let summary = Summary::from_values(&values, confidence);
+        println!(
+            "{:<24} {:>14.4} {:>14.4} {:>14.4} {:>14.4}",
+            field,
+            summary.mean,
+            summary.half_width,
+            summary.lower(),
+            summary.upper()
+        );
+    }
+    Ok(())
+}
+
+// timeline prints the timeline of one agent over a run, starting from the state it is in at the start time
+fn timeline(args: RunArgs, agent: &str) -> Result<(), Box<dyn Error>> {
+    let (_, mut sim, duration) = prepare(&args)?;
+    if !sim.agent_ids().any(|id| id == agent) {
+        return Err(format!("there is no agent {:?} in {}", agent, args.file.display()).into());
+    }
+
+    sim.finish_warm_up();
+    let mut current = TimelineEntry {
+        timestamp: sim.current_time(),
+        state: sim
+            .data::<DynamicState>(agent)
+            .map(|data| {
+                data.iter()
+                    .map(|(field, value)| (*field, value.clone()))
+                    .collect()
+            })
+            .unwrap_or_default(),
+        events: Vec::new(),
+    };
+    let mut entries = Vec::new();
+    sim.run_streaming(duration, |event| {
+        if event.agent_id != agent {
+            return;
+        }
+        // the initial state keeps an entry of its own, even if the agent changes at the start time
+        if current.events.is_empty() || event.time != current.timestamp {
+            let next = TimelineEntry {
+                timestamp: event.time,
+                state: current.state.clone(),
+                events: Vec::new(),
+            };
+            entries.push(std::mem::replace(&mut current, next));
+        }
+        current.state.insert(event.field, event.new_value);
+        current.events.push(event.field);
+    });
+    entries.push(current);
+
+    let mut out = io::stdout().lock();
+    for entry in &entries {
+        writeln!(out, "{}", entry)?;
+    }
+    Ok(())
+}
+
+// prepare loads a model file and creates the simulation to run, with the seed, duration and warm-up of the
+// arguments taking precedence over those of the file
+fn prepare(args: &RunArgs) -> Result<(ModelFile, Simulation, Duration), Box<dyn Error>> {
+    let file = ModelFile::load(&args.file)?;
+    let duration = args
+        .duration
+        .or(file.duration())
+        .ok_or("the run has no duration, set one in the model file or with --duration")?;
+    let mut sim = file.simulation_with_seed(args.seed.unwrap_or(file.seed()))?;
+    if let Some(warm_up) = args.warm_up {
+        sim = sim.with_warm_up(warm_up);
+    }
+    Ok((file, sim, duration))
+}
+
+/// FieldStats accumulates statistics on the events of a run as they stream by: the number of changes of each
+/// field, and the time-weighted mean of numeric fields (booleans counting as 0 and 1) over all the agents, from the
+/// start of the run. Agents that never change a field count with the value they start with.
+struct FieldStats {
+    start: DateTime<Utc>,
+    events: usize,
+    changes: BTreeMap<FieldName, usize>,
+    // last holds the value of each field of each agent since when, and the time-weighted sum so far
+    last: HashMap<(AgentId, FieldName), Track>,
+}
+
+type Track = (DateTime<Utc>, Option<f64>, f64);
+
+impl FieldStats {
+    fn new(start: DateTime<Utc>) -> Self {
+        FieldStats {
+            start,
+            events: 0,
+            changes: BTreeMap::new(),
+            last: HashMap::new(),
+        }
+    }
+
+    // seed starts tracking the fields of every agent of a simulation from their value at the start time
+    fn seed(&mut self, sim: &Simulation) {
+        for id in sim.agent_ids() {
+            let Some(data) = sim.data::<DynamicState>(id) else {
+                continue;
+            };
+            let id = AgentId::from(id);
+            for (field, value) in data.iter() {
+                self.last
+                    .insert((id.clone(), *field), (self.start, value.as_f64(), 0.0));
+            }
+        }
+    }
+
+    fn record(&mut self, event: &StateChangeEvent) {
+        self.events += 1;
+        *self.changes.entry(event.field).or_default() += 1;
+
+        let start = self.start;
+        let (since, value, sum) = self
+            .last
+            .entry((event.agent_id.clone(), event.field))
+            .or_insert_with(|| (start, event.old_value.as_f64(), 0.0));
+        if let Some(value) = value {
+            *sum += *value * seconds(event.time - *since);
+        }
+        *since = event.time;
+        *value = event.new_value.as_f64();
+    }
+
+    // means returns the time-weighted mean of each numeric field up to `end`, averaged over agents
+    fn means(&self, end: DateTime<Utc>) -> BTreeMap<FieldName, f64> {
+        let span = seconds(end - self.start);
+        let mut per_field: BTreeMap<FieldName, Vec<f64>> = BTreeMap::new();
+        for ((_, field), (since, value, sum)) in &self.last {
+            if let Some(value) = value
+                && span > 0.0
+            {
+                per_field
+                    .entry(*field)
+                    .or_default()
+                    .push((sum + value * seconds(end - *since)) / span);
+            }
+        }
+        per_field
+            .into_iter()
+            .map(|(field, means)| (field, means.iter().sum::<f64>() / means.len() as f64))
+            .collect()
+    }
+
+    fn summary(&self, end: DateTime<Utc>) -> String {
+        let agents = self
+            .last
+            .keys()
+            .map(|(agent, _)| agent)
+            .collect::<std::collections::HashSet<_>>()
+            .len();
+        let means = self.means(end);
+
+        let mut summary = format!(
+            "{} events from {} agents between {} and {}\n{:<24} {:>10} {:>16}\n",
+            self.events,
+            agents,
+            self.start.to_rfc3339(),
+            end.to_rfc3339(),
+            "field",
+            "changes",
+            "time-weighted mean"
+        );
+        let fields: BTreeSet<&FieldName> = self.changes.keys().chain(means.keys()).collect();
+        for field in fields {
+            summary += &format!(
+                "{:<24} {:>10} {:>16}\n",
+                field,
+                self.changes.get(field).copied().unwrap_or(0),
+                format_optional(means.get(field), 4)
+            );
+        }
+        summary
+    }
+}
+
+fn seconds(duration: Duration) -> f64 {
+    duration.num_milliseconds() as f64 / 1000.0
+}
+
+fn format_optional(value: Option<&f64>, precision: usize) -> String {
+    value.map_or_else(
+        || "-".to_string(),
+        |value| format!("{:.*}", precision, value),
+    )
+}
+
+fn sorted(modes: &[String]) -> Vec<String> {
+    let mut modes = modes.to_vec();
+    modes.sort();
+    modes
+}
+
+fn sorted_by_mode(mut values: Vec<(String, f64)>) -> Vec<(String, f64)> {
+    values.sort_by(|a, b| a.0.cmp(&b.0));
+    values
+}
+
+fn print_modes(label: &str, modes: &[String]) {
+    if modes.is_empty() {
+        println!("{}: none", label);
+    } else {
+        println!("{}: {}", label, sorted(modes).join(", "));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use agsim::value::Value;
+    use chrono::TimeZone;
+
+    #[test]
+    fn test_field_stats_time_weighted_means() {
+        let start = Utc.timestamp_opt(0, 0).unwrap();
+        let event = |seconds: i64, agent: &str, field: FieldName, old: Value, new: Value| {
+            StateChangeEvent {
+                time: start + Duration::seconds(seconds),
+                agent_id: agent.into(),
+                field,
+                old_value: old,
+                new_value: new,
+            }
+        };
+
+        let mut stats = FieldStats::new(start);
+        stats.record(&event(10, "a", "load", Value::Int(0), Value::Int(10)));
+        stats.record(&event(60, "b", "load", Value::Int(20), Value::Int(0)));
+        stats.record(&event(30, "a", "online", false.into(), true.into()));
+        stats.record(&event(40, "a", "status", "idle".into(), "busy".into()));
+
+        let means = stats.means(start + Duration::seconds(100));
+        assert_eq!(
+            means["load"],
+            (90.0 * 10.0 / 100.0 + 60.0 * 20.0 / 100.0) / 2.0
+        );
+        assert_eq!(means["online"], 0.7);
+        assert!(!means.contains_key("status"));
+        assert_eq!(stats.changes["load"], 2);
+
+        let cli = Cli::try_parse_from([
+            "agsim",
+            "run",
+            "model.toml",
+            "--duration",
+            "2h",
+            "--format",
+            "csv",
+        ])
+        .unwrap();
+        let Command::Run {
+            run,
+            format,
+            output,
+            ..
+        } = cli.command
+        else {
+            panic!("expected the run command");
+        };
+        assert_eq!(run.duration, Some(Duration::hours(2)));
+        assert!(matches!(format, Format::Csv) && output.is_none());
+    }
+}
@@ -0,0 +1,172 @@
+use std::process::{Command, Output};
+
+const DEVICES: &str = concat!(
+    env!("CARGO_MANIFEST_DIR"),
+    "/../examples/models/devices.toml"
+);
+
+// agsim runs the binary with the given arguments, expecting it to succeed
+fn agsim(args: &[&str]) -> (String, String) {
+    let output = run(args);
+    assert!(
+        output.status.success(),
+        "agsim {:?} failed: {}",
+        args,
+        String::from_utf8_lossy(&output.stderr)
+    );
+    (
+        String::from_utf8(output.stdout).unwrap(),
+        String::from_utf8(output.stderr).unwrap(),
+    )
+}
+
+fn run(args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_agsim"))
+        .args(args)
+        .output()
+        .unwrap()
+}
+
+#[test]
+fn test_validate_reports_models_and_agents() {
+    let (stdout, _) = agsim(&["validate", DEVICES]);
+    assert!(
+        stdout.contains("model device: 4 modes, valid"),
+        "{}",
+        stdout
+    );
+    assert!(stdout.contains("absorbing: none"), "{}", stdout);
+    assert!(
+        stdout.contains("strongly connected components: [heavy_load, idle, offline, working]"),
+        "{}",
+        stdout
+    );
+    assert!(stdout.ends_with("5 agents\n"), "{}", stdout);
+
+    let output = run(&["validate", "missing.toml"]);
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).starts_with("error: "));
+}
+
+#[test]
+fn test_analyze_prints_stationary_distribution_and_reliability() {
+    let (stdout, stderr) = agsim(&["analyze", DEVICES, "--up", "idle,working,heavy_load"]);
+    let stationary: f64 = ["heavy_load", "idle", "offline", "working"]
+        .iter()
+        .map(|mode| {
+            let line = stdout
+                .lines()
+                .find(|line| line.trim_start().starts_with(mode))
+                .unwrap();
+            line.split_whitespace()
+                .nth(1)
+                .unwrap()
+                .parse::<f64>()
+                .unwrap()
+        })
+        .sum();
+    assert!((stationary - 1.0).abs() < 1e-3, "{}", stdout);
+    assert!(stdout.contains("availability: 0.78"), "{}", stdout);
+    assert!(stdout.contains("MTTR: 14400.00 s"), "{}", stdout);
+    // the devices model has timers, and an Erlang holding time the chain only accounts for through its mean
+    assert!(
+        stderr.contains("warning: model device: the analysis ignores its timers\n"),
+        "{}",
+        stderr
+    );
+    assert!(
+        stderr.contains("warning: model device: holding times that are not exponential"),
+        "{}",
+        stderr
+    );
+}
+
+#[test]
+fn test_run_streams_events_and_summarizes_every_field() {
+    let (stdout, stderr) = agsim(&["run", DEVICES, "--duration", "1d", "--seed", "3"]);
+    let events: Vec<&str> = stdout.lines().collect();
+    assert!(!events.is_empty());
+    assert!(events.iter().all(|event| event.starts_with('{')));
+    assert!(
+        stderr.starts_with(&format!("{} events from 5 agents", events.len())),
+        "{}",
+        stderr
+    );
+    // fields that never change are summarized with the value agents start with
+    assert!(
+        stderr
+            .lines()
+            .any(|line| line.split_whitespace().eq(["reboots", "0", "0.0000"])),
+        "{}",
+        stderr
+    );
+
+    // the same seed gives the same run, written as CSV to a file
+    let output = std::env::temp_dir().join(format!("agsim_cli_run_{}.csv", std::process::id()));
+    let (summary, _) = agsim(&[
+        "run",
+        DEVICES,
+        "--duration",
+        "1d",
+        "--seed",
+        "3",
+        "--format",
+        "csv",
+        "--output",
+        output.to_str().unwrap(),
+    ]);
+    let csv = std::fs::read_to_string(&output).unwrap();
+    std::fs::remove_file(&output).unwrap();
+    assert_eq!(csv.lines().count(), events.len() + 1);
+    assert_eq!(summary, stderr);
+
+    // CSV values are always strings
+    let output = run(&[
+        "run",
+        DEVICES,
+        "--duration",
+        "1d",
+        "--format",
+        "csv",
+        "--string-values",
+    ]);
+    assert!(!output.status.success());
+    assert!(
+        String::from_utf8_lossy(&output.stderr).contains("--string-values only applies"),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+}
+
+#[test]
+fn test_timeline_prints_the_history_of_one_agent() {
+    let (stdout, _) = agsim(&["timeline", DEVICES, "--agent", "device_001"]);
+    assert!(stdout.lines().count() > 1, "{}", stdout);
+    // the timeline starts at the start time, with every field of the agent
+    let first = stdout.lines().next().unwrap();
+    assert!(first.starts_with("[2024-01-01 00:00:00]"), "{}", first);
+    assert!(
+        first.contains("reboots: 0") && first.ends_with("*(Initial State)*"),
+        "{}",
+        first
+    );
+
+    // an agent without events in the run only has its initial state
+    let (stdout, _) = agsim(&[
+        "timeline",
+        DEVICES,
+        "--agent",
+        "device_001",
+        "--duration",
+        "1s",
+    ]);
+    assert_eq!(stdout.lines().count(), 1, "{}", stdout);
+
+    let output = run(&["timeline", DEVICES, "--agent", "device_999"]);
+    assert!(!output.status.success());
+    assert!(
+        String::from_utf8_lossy(&output.stderr).contains("there is no agent \"device_999\""),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+}
@@ -1,4 +1,5 @@
 use agsim::agent::{Agent, StateType};
+use agsim::matrix::TransitionMatrix;
 use agsim::simulation::Simulation;
 use agsim::state::Timeline;
 use chrono::{Duration, Utc};
@@ -6,8 +7,9 @@ use rand::rngs::StdRng;
 use rand::{Rng, SeedableRng};
 use state_macros::{State, StateDisplay};
 use std::collections::HashMap;
+use std::error::Error;
 
-#[derive(Debug, Clone, PartialEq, Eq, Hash, Copy)]
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Copy)]
 enum DeviceOperationalMode {
     Offline,
     Idle,
@@ -23,7 +25,7 @@ struct DeviceState {
     cpu_in_use_percent: f32,
 }
 
-fn main() {
+fn main() -> Result<(), Box<dyn Error>> {
     let mut transitions = HashMap::new();
 
     transitions.insert(
@@ -96,6 +98,8 @@ fn main() {
         ),
     );
 
+    // the agents share one copy of the transition matrix
+    let transitions = TransitionMatrix::from(transitions);
     let mut agents = Vec::new();
     let start_time = Utc::now();
 
@@ -107,15 +111,15 @@ fn main() {
             DeviceOperationalMode::Idle,
             transitions.clone(),
             &mut rng,
-        ));
+        )?);
     }
 
-    let mut sim = Simulation::new(agents, start_time);
+    let mut sim = Simulation::new(agents, start_time)?;
     let events = sim.run(Duration::days(7));
 
     println!("Generated {} events over 7 days.", events.len());
 
-    let timelines = Timeline::generate(&events);
+    let timelines = Timeline::generate(events);
 
     let mut sorted_agents: Vec<_> = timelines.keys().collect();
     sorted_agents.sort();
@@ -128,4 +132,6 @@ fn main() {
             }
         }
     }
+
+    Ok(())
 }
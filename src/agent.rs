@@ -1,17 +1,98 @@
-use crate::state::{State, StateChangeEvent};
+use crate::ctmc::Generator;
+use crate::importance::{Bias, Sojourn};
+use crate::matrix::TransitionMatrix;
+use crate::rng::{Purpose, RandomStreams};
+use crate::state::{AgentId, State, StateChangeEvent};
+use crate::stats::AgentStats;
+use crate::timer::{
+    self, ActiveTimer, TIMEOUT_TIMER, TimerSpec, TransitionContext, TransitionHook,
+};
+use crate::validation::{self, ModelError};
 use chrono::{DateTime, Utc};
 use rand::seq::SliceRandom;
 use rand::{Rng, RngCore};
-use rand_distr::{Distribution, Exp};
+use std::any::Any;
 use std::collections::HashMap;
 use std::hash::Hash;
 use std::sync::Arc;
 
+pub type StateFactory<C, S> = Arc<dyn Fn(&mut dyn RngCore, &AgentStats<C>) -> S + Send + Sync>;
+pub type WeightFn<C> = Arc<dyn Fn(&C, f64, &AgentStats<C>) -> f64 + Send + Sync>;
+pub type GuardFn<C> = Arc<dyn Fn(&AgentStats<C>) -> bool + Send + Sync>;
+pub type RateFn<C> = Arc<dyn Fn(f64, &AgentStats<C>) -> f64 + Send + Sync>;
+pub type CarryOverFn<S> = Arc<dyn Fn(&S, &mut S) + Send + Sync>;
+
+/// HoldingTime is the shape of the distribution of the delay before the stochastic transitions of a state type fire.
+/// Its mean is always the `event_rate` of the state type (or the result of its rate function), so that overrides and
+/// rate functions apply whatever the shape. Only exponential holding times make the model a Markov chain; with other
+/// shapes it is semi-Markov, whose stationary distribution still only depends on the means (see `ctmc::Generator`).
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum HoldingTime {
+    #[default]
+    Exponential,
+    // Deterministic always waits for the mean
+    Deterministic,
+    // Uniform is uniform on mean * [1 - spread, 1 + spread], with a spread between 0 and 1
+    Uniform {
+        spread: f64,
+    },
+    // Erlang is the sum of `shape` exponential phases, less variable than an exponential as the shape grows
+    Erlang {
+        shape: u32,
+    },
+    // LogNormal is log-normal with the given standard deviation of the logarithm of the delay
+    LogNormal {
+        sigma: f64,
+    },
+}
+
+impl HoldingTime {
+    // is_valid tells whether the parameters of the shape are in range
+    pub fn is_valid(&self) -> bool {
+        match *self {
+            HoldingTime::Uniform { spread } => (0.0..=1.0).contains(&spread),
+            HoldingTime::Erlang { shape } => shape > 0,
+            HoldingTime::LogNormal { sigma } => sigma >= 0.0 && sigma.is_finite(),
+            HoldingTime::Exponential | HoldingTime::Deterministic => true,
+        }
+    }
+
+    // sample draws a delay with the given mean, or returns None for a mean of 0 or less, which means the state type
+    // has no stochastic transitions
+    fn sample(&self, mean: f64, rng: &mut impl Rng) -> Option<f64> {
+        if !(mean > 0.0 && mean.is_finite()) {
+            return None;
+        }
+
+        Some(match *self {
+            HoldingTime::Exponential => return sample_delay(mean, rng),
+            HoldingTime::Deterministic => mean,
+            HoldingTime::Uniform { spread } => {
+                mean * (1.0 + spread * (2.0 * rng.r#gen::<f64>() - 1.0))
+            }
+            HoldingTime::Erlang { shape } => (0..shape)
+                .filter_map(|_| sample_delay(mean / shape as f64, rng))
+                .sum(),
+            HoldingTime::LogNormal { sigma } => {
+                (mean.ln() - sigma * sigma / 2.0 + sigma * standard_normal(rng)).exp()
+            }
+        })
+    }
+}
+
 #[derive(Clone)]
 pub struct StateType<C, S: State> {
-    pub factory: Arc<dyn Fn(&mut dyn RngCore) -> S + Send + Sync>,
+    pub factory: StateFactory<C, S>,
     pub transitions: Vec<(C, f64)>,
     pub event_rate: f64,
+    pub timers: Vec<TimerSpec<C>>,
+    pub on_enter: Option<TransitionHook<C>>,
+    pub weight_fn: Option<WeightFn<C>>,
+    pub guards: Vec<(C, GuardFn<C>)>,
+    pub rate_fn: Option<RateFn<C>>,
+    pub signals: Vec<(String, C)>,
+    pub holding_time: HoldingTime,
+    pub carry_over: Option<CarryOverFn<S>>,
 }
 
 impl<C, S> StateType<C, S>
@@ -22,21 +103,166 @@ where
     where
         F: Fn(&mut dyn RngCore) -> S + Send + Sync + 'static,
     {
-        StateType {
-            factory: Arc::new(factory),
+        Self::from_factory(
+            Arc::new(move |rng, _| factory(rng)),
             transitions,
             event_rate,
-        }
+        )
     }
 
     pub fn new_deterministic<F>(factory: F, transitions: Vec<(C, f64)>, event_rate: f64) -> Self
     where
         F: Fn() -> S + Send + Sync + 'static,
     {
+        Self::from_factory(Arc::new(move |_, _| factory()), transitions, event_rate)
+    }
+
+    // new_with_stats creates a state type whose factory can take the history of the agent into account, i.e. to
+    // degrade performance figures as a device ages
+    pub fn new_with_stats<F>(factory: F, transitions: Vec<(C, f64)>, event_rate: f64) -> Self
+    where
+        F: Fn(&mut dyn RngCore, &AgentStats<C>) -> S + Send + Sync + 'static,
+    {
+        Self::from_factory(Arc::new(factory), transitions, event_rate)
+    }
+
+    fn from_factory(
+        factory: StateFactory<C, S>,
+        transitions: Vec<(C, f64)>,
+        event_rate: f64,
+    ) -> Self {
         StateType {
-            factory: Arc::new(move |_| factory()),
+            factory,
             transitions,
             event_rate,
+            timers: Vec::new(),
+            on_enter: None,
+            weight_fn: None,
+            guards: Vec::new(),
+            rate_fn: None,
+            signals: Vec::new(),
+            holding_time: HoldingTime::Exponential,
+            carry_over: None,
+        }
+    }
+
+    // with_signal makes agents in this state type transition to `target` immediately when they receive the named
+    // signal from an interaction, i.e. devices dropping offline when their gateway fails
+    pub fn with_signal(mut self, name: &str, target: C) -> Self {
+        self.signals.push((name.to_string(), target));
+        self
+    }
+
+    // with_weight_fn replaces the static transition weights with a function of the target state type, its static
+    // weight and the agent's history, i.e. to escalate retries after a number of failures
+    pub fn with_weight_fn<F>(mut self, weight_fn: F) -> Self
+    where
+        F: Fn(&C, f64, &AgentStats<C>) -> f64 + Send + Sync + 'static,
+    {
+        self.weight_fn = Some(Arc::new(weight_fn));
+        self
+    }
+
+    // with_guard only allows transitions to `target` while the guard holds. Multiple guards on the same target
+    // must all hold.
+    pub fn with_guard<F>(mut self, target: C, guard: F) -> Self
+    where
+        F: Fn(&AgentStats<C>) -> bool + Send + Sync + 'static,
+    {
+        self.guards.push((target, Arc::new(guard)));
+        self
+    }
+
+    // with_rate_fn derives the mean delay until the next event from the static `event_rate` and the agent's
+    // history, i.e. to model a bathtub curve where failures become more frequent with age. The delay is sampled
+    // when the event is scheduled, using the history at that time.
+    pub fn with_rate_fn<F>(mut self, rate_fn: F) -> Self
+    where
+        F: Fn(f64, &AgentStats<C>) -> f64 + Send + Sync + 'static,
+    {
+        self.rate_fn = Some(Arc::new(rate_fn));
+        self
+    }
+
+    // with_holding_time sets the shape of the distribution of the delay before the stochastic transitions fire, whose
+    // mean stays `event_rate`
+    pub fn with_holding_time(mut self, holding_time: HoldingTime) -> Self {
+        self.holding_time = holding_time;
+        self
+    }
+
+    // with_carry_over runs `carry_over` on the data of agents entering this state type, with the data they had before,
+    // i.e. to keep a counter across state types instead of generating it afresh
+    pub fn with_carry_over<F>(mut self, carry_over: F) -> Self
+    where
+        F: Fn(&S, &mut S) + Send + Sync + 'static,
+    {
+        self.carry_over = Some(Arc::new(carry_over));
+        self
+    }
+
+    // with_timeout forces a transition to `target` if the agent stays in this state type for longer than `after`
+    // seconds, racing the sampled delay of the stochastic transitions.
+    pub fn with_timeout(self, after: f64, target: C) -> Self {
+        self.with_timer(TIMEOUT_TIMER, after, target)
+    }
+
+    // with_timer arms a named timer whenever an agent enters this state type. Timers are kept across
+    // self-transitions and cancelled when the agent moves to a different state type; a timer whose target is this
+    // state type is re-armed every time it fires, i.e. to model a watchdog.
+    pub fn with_timer(mut self, name: &str, after: f64, target: C) -> Self {
+        self.timers.push(TimerSpec {
+            name: name.to_string(),
+            after,
+            target,
+        });
+        self
+    }
+
+    // with_on_enter registers a hook run every time an agent transitions into this state type, including
+    // self-transitions. Hooks can set and cancel per agent timers through the context.
+    pub fn with_on_enter<F>(mut self, hook: F) -> Self
+    where
+        F: Fn(&mut TransitionContext<C>) + Send + Sync + 'static,
+    {
+        self.on_enter = Some(Arc::new(hook));
+        self
+    }
+}
+
+impl<C, S> StateType<C, S>
+where
+    C: PartialEq,
+    S: State,
+{
+    // weight returns the weight of the transition to `target` given the agent's history, or 0 if it is guarded. A
+    // weight function returning a negative, infinite or NaN weight rules the transition out.
+    fn weight(&self, target: &C, weight: f64, stats: &AgentStats<C>) -> f64 {
+        let allowed = self
+            .guards
+            .iter()
+            .filter(|(guarded, _)| guarded == target)
+            .all(|(_, guard)| guard(stats));
+
+        match (&self.weight_fn, allowed) {
+            (_, false) => 0.0,
+            (Some(weight_fn), true) => match weight_fn(target, weight, stats) {
+                weight if weight.is_finite() && weight > 0.0 => weight,
+                _ => 0.0,
+            },
+            (None, true) => weight,
+        }
+    }
+
+    // mean_delay returns the mean delay until the next event given the agent's history. A rate function returning a
+    // non-positive, infinite or NaN delay stops the stochastic transitions, as a mean delay of 0 does.
+    fn mean_delay(&self, stats: &AgentStats<C>) -> f64 {
+        match &self.rate_fn {
+            Some(rate_fn) => match rate_fn(self.event_rate, stats) {
+                mean if mean.is_finite() && mean > 0.0 => mean,
+                _ => 0.0,
+            },
+            None => self.event_rate,
         }
     }
 }
@@ -46,10 +272,16 @@ where
     C: Eq + Hash + Clone,
     S: State,
 {
-    transition_matrix: HashMap<C, StateType<C, S>>,
-    current_state_type: C,
+    transition_matrix: TransitionMatrix<C, S>,
+    mode: usize,
+    timers: Vec<ActiveTimer<C>>,
+    stats: AgentStats<C>,
+    pending: Option<C>,
+    biases: HashMap<C, Bias<C>>,
+    sojourn: Option<Sojourn>,
+    log_likelihood_ratio: f64,
     pub data: S,
-    pub id: String,
+    pub id: AgentId,
 }
 
 impl<C, S> Agent<C, S>
@@ -57,70 +289,249 @@ where
     C: Eq + Hash + Clone,
     S: State + Clone,
 {
+    // new creates an agent in its initial state type, after validating its transition matrix (see
+    // `validation::validate`). Agents created from clones of one `TransitionMatrix` share it, and it is only
+    // validated for the first of them.
     pub fn new(
         id: String,
         initial_state_type: C,
-        transition_matrix: HashMap<C, StateType<C, S>>,
+        transition_matrix: impl Into<TransitionMatrix<C, S>>,
+        rng: &mut dyn RngCore,
+    ) -> Result<Self, ModelError<C>> {
+        let transition_matrix = transition_matrix.into();
+        validation::check(&transition_matrix, &initial_state_type)?;
+        Ok(Self::new_unchecked(
+            id,
+            initial_state_type,
+            transition_matrix,
+            rng,
+        ))
+    }
+
+    // new_unchecked creates an agent from a transition matrix that has already been validated for its initial state
+    // type, i.e. once for a whole population
+    pub(crate) fn new_unchecked(
+        id: String,
+        initial_state_type: C,
+        transition_matrix: TransitionMatrix<C, S>,
         rng: &mut dyn RngCore,
     ) -> Self {
-        let initial_def = transition_matrix
-            .get(&initial_state_type)
-            .expect("Initial state type must exist in transition matrix");
-        let data = (initial_def.factory)(rng);
+        let mode = transition_matrix
+            .index(&initial_state_type)
+            .expect("initial state type was validated");
+        let stats = AgentStats::new(transition_matrix.shared_modes(), mode);
+        let data = (transition_matrix.state_type(mode).factory)(rng, &stats);
 
         Agent {
-            id,
+            id: id.into(),
             transition_matrix,
-            current_state_type: initial_state_type,
+            mode,
+            timers: Vec::new(),
+            stats,
+            pending: None,
+            biases: HashMap::new(),
+            sojourn: None,
+            log_likelihood_ratio: 0.0,
             data,
         }
     }
 
+    // new_with_distribution creates an agent whose initial state type is drawn from a distribution, given as weights
+    // per state type
+    pub fn new_with_distribution(
+        id: String,
+        distribution: &[(C, f64)],
+        transition_matrix: impl Into<TransitionMatrix<C, S>>,
+        rng: &mut dyn RngCore,
+    ) -> Result<Self, ModelError<C>> {
+        let initial_state_type = draw_initial(distribution, rng)?;
+        Self::new(id, initial_state_type, transition_matrix, rng)
+    }
+
+    // new_stationary creates an agent whose initial state type is drawn from the stationary distribution of its
+    // chain, so that a population starts in steady state without a warm-up (see `ctmc::Generator`)
+    pub fn new_stationary(
+        id: String,
+        transition_matrix: impl Into<TransitionMatrix<C, S>>,
+        rng: &mut dyn RngCore,
+    ) -> Result<Self, ModelError<C>> {
+        let transition_matrix = transition_matrix.into();
+        let distribution = Generator::from_transitions(&transition_matrix)
+            .stationary_distribution()
+            .ok_or(ModelError::NoStationaryDistribution)?;
+        Self::new_with_distribution(id, &distribution, transition_matrix, rng)
+    }
+
+    // with_bias runs the agent under a biased change of measure while it is in the `mode` state type, i.e. to make
+    // failures more frequent. The agent tracks the likelihood ratio of its trajectory against the nominal model.
+    // Factors that are not positive and finite are rejected.
+    pub fn with_bias(mut self, mode: C, bias: Bias<C>) -> Result<Self, ModelError<C>> {
+        if let Some(factor) = bias.invalid_factor() {
+            return Err(ModelError::InvalidBias { mode, factor });
+        }
+        self.biases.insert(mode, bias);
+        Ok(self)
+    }
+
+    // current_state_type returns the mode the agent is currently in
+    pub fn current_state_type(&self) -> &C {
+        self.transition_matrix.mode(self.mode)
+    }
+
+    // transition_matrix returns the model the agent follows, which may be shared with other agents
+    pub fn transition_matrix(&self) -> &TransitionMatrix<C, S> {
+        &self.transition_matrix
+    }
+
+    // timers returns the timers currently armed on the agent
+    pub fn timers(&self) -> &[ActiveTimer<C>] {
+        &self.timers
+    }
+
+    // next_timer returns the armed timer that expires first, if any
+    pub fn next_timer(&self) -> Option<&ActiveTimer<C>> {
+        timer::next_timer(&self.timers)
+    }
+
+    // entered_at returns when the agent entered its current state type, or None if it has not been started
+    pub fn entered_at(&self) -> Option<DateTime<Utc>> {
+        self.stats.entered_at()
+    }
+
+    // generator returns the generator matrix of the chain the agent follows, to compute its behaviour analytically
+    pub fn generator(&self) -> Generator<C> {
+        Generator::from_transitions(&self.transition_matrix)
+    }
+
+    // stats returns the history of the agent, i.e. its age and the time spent in each state type
+    pub fn stats(&self) -> &AgentStats<C> {
+        &self.stats
+    }
+
+    // start enters the initial state type at the given time, arming its timers. Starting an agent that has already
+    // been started is a no-op, so timers survive repeated simulation runs.
+    pub fn start(&mut self, time: DateTime<Utc>) {
+        if self.stats.started_at().is_some() {
+            return;
+        }
+
+        self.stats.start(time);
+        self.enter_state(self.mode, time, true, &[]);
+    }
+
     // step moves to the next state change in the chain
     pub fn step(&self, rng: &mut impl Rng) -> Option<C> {
-        let current_def = self.transition_matrix.get(&self.current_state_type)?;
+        let current_def = self.transition_matrix.state_type(self.mode);
+
+        self.choose(current_def, None, rng)
+            .map(|(next_state, _)| next_state)
+    }
 
-        if current_def.transitions.is_empty() {
+    // choose picks the next state type, under the bias if given, returning it with the ratio of its nominal and
+    // biased probabilities
+    fn choose(
+        &self,
+        def: &StateType<C, S>,
+        bias: Option<&Bias<C>>,
+        rng: &mut impl Rng,
+    ) -> Option<(C, f64)> {
+        if def.transitions.is_empty() {
             return None;
         }
 
-        current_def
+        let nominal: Vec<(&C, f64)> = def
             .transitions
-            .choose_weighted(rng, |item| item.1)
-            .ok()
-            .map(|(next_state, _)| next_state.clone())
+            .iter()
+            .map(|(target, weight)| (target, def.weight(target, *weight, &self.stats)))
+            .collect();
+
+        let Some(bias) = bias else {
+            return nominal
+                .choose_weighted(rng, |item| item.1)
+                .ok()
+                .map(|(next_state, _)| ((*next_state).clone(), 1.0));
+        };
+
+        let biased: Vec<(&C, f64)> = nominal
+            .iter()
+            .map(|(target, weight)| (*target, weight * bias.weight_factor(target)))
+            .collect();
+        let (next_state, _) = biased.choose_weighted(rng, |item| item.1).ok()?;
+
+        let probability = |weights: &[(&C, f64)]| {
+            let total: f64 = weights.iter().map(|(_, weight)| weight).sum();
+            let chosen: f64 = weights
+                .iter()
+                .filter(|(target, _)| target == next_state)
+                .map(|(_, weight)| weight)
+                .sum();
+            chosen / total
+        };
+
+        Some((
+            (*next_state).clone(),
+            probability(&nominal) / probability(&biased),
+        ))
     }
 
-    // peek_next_event_delay calculates the time until the next event using an exponential distribution based on the event rate
+    // peek_next_event_delay samples the delay until the next stochastic transition from the holding time shape of
+    // the current mode, with the mean given by its event rate (or rate function)
     pub fn peek_next_event_delay(&self, rng: &mut impl Rng) -> Option<f64> {
-        let current_def = self.transition_matrix.get(&self.current_state_type)?;
+        let current_def = self.transition_matrix.state_type(self.mode);
 
-        // lambda = 1 / Mean.
-        // if the mean is 0, we can assume instant transition
-        if current_def.event_rate <= 0.0 {
-            return None;
-        }
+        current_def
+            .holding_time
+            .sample(current_def.mean_delay(&self.stats), rng)
+    }
 
-        let lambda = 1.0 / current_def.event_rate;
-        let exp = Exp::new(lambda).ok()?;
+    // log_likelihood_ratio returns the log likelihood ratio of the trajectory of the agent up to `time` against the
+    // nominal model, which is 0 unless the agent runs under a bias
+    pub fn log_likelihood_ratio(&self, time: DateTime<Utc>) -> f64 {
+        self.log_likelihood_ratio
+            + self
+                .sojourn
+                .as_ref()
+                .map_or(0.0, |sojourn| sojourn.log_ratio(time, false))
+    }
 
-        Some(exp.sample(rng))
+    // end_sojourn accounts for the current sojourn in the likelihood ratio
+    fn end_sojourn(&mut self, time: DateTime<Utc>, fired: bool) {
+        if let Some(sojourn) = self.sojourn.take() {
+            self.log_likelihood_ratio += sojourn.log_ratio(time, fired);
+        }
     }
 
-    // apply_transition transitions the agent to a new state type
+    // apply_transition transitions the agent to a new state type. State types missing from the transition matrix,
+    // which validation rules out, are ignored.
     pub fn apply_transition(
         &mut self,
         new_type: C,
         time: DateTime<Utc>,
         rng: &mut dyn RngCore,
     ) -> Vec<StateChangeEvent> {
-        self.current_state_type = new_type.clone();
-
-        let target_state = match self.get_target_state(&new_type, rng) {
-            Some(state) => state,
-            None => return Vec::new(),
+        let Some(mode) = self.transition_matrix.index(&new_type) else {
+            return Vec::new();
         };
 
+        // timers that have expired by now have either fired or been raced, and never fire twice
+        let expired: Vec<String> = self
+            .timers
+            .iter()
+            .filter(|timer| timer.deadline <= time)
+            .map(|timer| timer.name.clone())
+            .collect();
+        self.timers.retain(|timer| timer.deadline > time);
+
+        self.stats.record_transition(mode, time);
+        let previous = std::mem::replace(&mut self.mode, mode);
+        self.enter_state(previous, time, previous != mode, &expired);
+
+        let def = self.transition_matrix.state_type(mode);
+        let mut target_state = (def.factory)(rng, &self.stats);
+        if let Some(carry_over) = &def.carry_over {
+            carry_over(&self.data, &mut target_state);
+        }
+
         let mut events = self.data.diff(&target_state, time);
         for event in &mut events {
             event.agent_id = self.id.clone();
@@ -131,43 +542,320 @@ where
         events
     }
 
-    fn get_target_state(&self, state_type: &C, rng: &mut dyn RngCore) -> Option<S> {
-        self.transition_matrix
-            .get(state_type)
-            .map(|def| (def.factory)(rng))
+    // enter_state re-arms the timers of the current state type and runs its on-enter hook. On a self-transition only
+    // the timers of the state type that expired by now are re-armed, so that a timeout back into its own state type
+    // (a watchdog reset) keeps firing while the other timers keep running.
+    fn enter_state(
+        &mut self,
+        previous: usize,
+        time: DateTime<Utc>,
+        changed_type: bool,
+        expired: &[String],
+    ) {
+        let def = self.transition_matrix.state_type(self.mode);
+
+        if changed_type {
+            self.timers.clear();
+        }
+        for spec in &def.timers {
+            if changed_type || expired.contains(&spec.name) {
+                timer::set_timer(
+                    &mut self.timers,
+                    &spec.name,
+                    time + timer::seconds_to_duration(spec.after),
+                    spec.target.clone(),
+                );
+            }
+        }
+
+        if let Some(hook) = &def.on_enter {
+            let mut ctx = TransitionContext::new(
+                self.transition_matrix.mode(previous),
+                self.transition_matrix.mode(self.mode),
+                time,
+                &mut self.timers,
+            );
+            hook(&mut ctx);
+        }
+    }
+}
+
+// draw_initial draws an initial state type from a distribution, given as weights per state type
+pub(crate) fn draw_initial<C: Clone>(
+    distribution: &[(C, f64)],
+    rng: &mut dyn RngCore,
+) -> Result<C, ModelError<C>> {
+    if distribution
+        .iter()
+        .any(|(_, weight)| !(*weight >= 0.0 && weight.is_finite()))
+    {
+        return Err(ModelError::InvalidDistribution);
     }
+
+    distribution
+        .choose_weighted(rng, |(_, weight)| *weight)
+        .map(|(mode, _)| mode.clone())
+        .map_err(|_| ModelError::InvalidDistribution)
+}
+
+// standard_normal draws from the standard normal distribution with the Box-Muller transform
+pub(crate) fn standard_normal(rng: &mut (impl Rng + ?Sized)) -> f64 {
+    let u: f64 = rng.r#gen();
+    let v: f64 = rng.r#gen();
+    (-2.0 * (1.0 - u).ln()).sqrt() * (2.0 * std::f64::consts::PI * v).cos()
+}
+
+// sample_delay draws an exponential delay with the given mean, or returns None for a mean of 0 or less, which means
+// the state type has no stochastic transitions. Inverse transform sampling keeps the delay monotone in the uniform
+// draw, so that antithetic streams yield antithetic delays.
+fn sample_delay(mean: f64, rng: &mut impl Rng) -> Option<f64> {
+    if !(mean > 0.0 && mean.is_finite()) {
+        return None;
+    }
+
+    let u: f64 = rng.r#gen();
+    Some(-mean * (1.0 - u).ln())
+}
+
+/// Checkpoint is an opaque snapshot of the dynamic state of an agent.
+pub type Checkpoint = Box<dyn Any + Send>;
+
+/// SimAgent is the interface between an agent and the simulation scheduler. The simulation asks an agent when its
+/// next event is due, and fires it once the clock reaches that time; the changes it returns flow through the same
+/// event log, streaming callbacks, interactions and `Timeline` tooling as those of the built-in Markov `Agent`. This
+/// allows rule-based, trace-replay or externally driven agents to be mixed with Markov agents in one simulation.
+pub trait SimAgent: Send {
+    fn id(&self) -> &str;
+
+    // start is called before the agent is first scheduled, with the current simulation time
+    fn start(&mut self, _time: DateTime<Utc>) {}
+
+    // schedule decides the next event of the agent, returning when it is due, or None if the agent has no further
+    // events. Any previously scheduled event is discarded. Returned times earlier than `now` are treated as `now`.
+    fn schedule(
+        &mut self,
+        now: DateTime<Utc>,
+        rng: &mut dyn RandomStreams,
+    ) -> Option<DateTime<Utc>>;
+
+    // fire applies the event decided by the last call to schedule, returning the resulting changes
+    fn fire(&mut self, time: DateTime<Utc>, rng: &mut dyn RandomStreams) -> Vec<StateChangeEvent>;
+
+    // signal delivers a named signal from an interaction, returning the resulting changes if the agent reacted to
+    // it. Agents that react are rescheduled.
+    fn signal(
+        &mut self,
+        _name: &str,
+        _time: DateTime<Utc>,
+        _rng: &mut dyn RandomStreams,
+    ) -> Option<Vec<StateChangeEvent>> {
+        None
+    }
+
+    // mode exposes the current mode of the agent to composite roll-up rules
+    fn mode(&self) -> &dyn Any {
+        &()
+    }
+
+    // data exposes the current data of the agent, i.e. to read the state of a population at the start of a run
+    fn data(&self) -> &dyn Any {
+        &()
+    }
+
+    // checkpoint captures the dynamic state of the agent so that it can later be rolled back with restore, or
+    // returns None if the agent does not support it. Engines that execute speculatively require it.
+    fn checkpoint(&self) -> Option<Checkpoint> {
+        None
+    }
+
+    // restore rolls the agent back to a checkpoint it produced earlier
+    fn restore(&mut self, _checkpoint: &Checkpoint) {}
+
+    // log_likelihood_ratio returns the log likelihood ratio of the trajectory of the agent up to `time` against its
+    // nominal model, for agents running under a biased change of measure (importance sampling)
+    fn log_likelihood_ratio(&self, _time: DateTime<Utc>) -> f64 {
+        0.0
+    }
+
+    // reset_stats discards the history gathered by the agent so far, as if it had been started in its current mode
+    // at `time`, i.e. at the end of a warm-up period
+    fn reset_stats(&mut self, _time: DateTime<Utc>) {}
+}
+
+impl<C, S> SimAgent for Agent<C, S>
+where
+    C: Eq + Hash + Clone + Send + Sync + 'static,
+    S: State + Send + 'static,
+{
+    fn id(&self) -> &str {
+        &self.id
+    }
+
+    fn start(&mut self, time: DateTime<Utc>) {
+        Agent::start(self, time);
+    }
+
+    // the sampled stochastic transition races the agent's earliest timer, with the timer winning ties
+    fn schedule(
+        &mut self,
+        now: DateTime<Utc>,
+        rng: &mut dyn RandomStreams,
+    ) -> Option<DateTime<Utc>> {
+        self.end_sojourn(now, false);
+        self.stats.advance(now);
+
+        let bias = self.biases.get(self.transition_matrix.mode(self.mode));
+        let def = self.transition_matrix.state_type(self.mode);
+        let mut next = None;
+        let mut sojourn = None;
+
+        // only exponential holding times can be biased, other shapes keep their nominal delays
+        let nominal_mean = def.mean_delay(&self.stats);
+        let mean = match bias {
+            Some(bias) if def.holding_time == HoldingTime::Exponential => {
+                nominal_mean / bias.rate_factor
+            }
+            _ => nominal_mean,
+        };
+        // when guards close every transition, the agent wakes up after the delay without transitioning and draws
+        // again, as its history may have opened a guard by then
+        if !def.transitions.is_empty()
+            && let Some(delay_sec) = def
+                .holding_time
+                .sample(mean, &mut rng.stream(Purpose::Delay))
+        {
+            let choice = self.choose(def, bias, &mut rng.stream(Purpose::Choice));
+            let choice_ratio = choice.as_ref().map_or(1.0, |(_, ratio)| *ratio);
+            next = Some((
+                now + timer::seconds_to_duration(delay_sec),
+                choice.map(|(next_state, _)| next_state),
+            ));
+            sojourn = bias.map(|_| Sojourn {
+                start: now,
+                nominal_rate: 1.0 / nominal_mean,
+                biased_rate: 1.0 / mean,
+                choice_ratio,
+                stochastic: true,
+            });
+        }
+
+        if let Some(timer) = self.next_timer()
+            && next
+                .as_ref()
+                .is_none_or(|(time, _)| timer.deadline <= *time)
+        {
+            next = Some((timer.deadline.max(now), Some(timer.target.clone())));
+            if let Some(sojourn) = &mut sojourn {
+                sojourn.stochastic = false;
+            }
+        }
+
+        let (time, next_state) = next.unzip();
+        self.pending = next_state.flatten();
+        self.sojourn = sojourn;
+        time
+    }
+
+    fn fire(&mut self, time: DateTime<Utc>, rng: &mut dyn RandomStreams) -> Vec<StateChangeEvent> {
+        self.end_sojourn(time, true);
+
+        match self.pending.take() {
+            Some(next_state) => {
+                self.apply_transition(next_state, time, rng.stream(Purpose::Factory))
+            }
+            None => Vec::new(),
+        }
+    }
+
+    fn signal(
+        &mut self,
+        name: &str,
+        time: DateTime<Utc>,
+        rng: &mut dyn RandomStreams,
+    ) -> Option<Vec<StateChangeEvent>> {
+        let target = self
+            .transition_matrix
+            .state_type(self.mode)
+            .signals
+            .iter()
+            .find(|(signal, _)| signal == name)
+            .map(|(_, target)| target.clone())?;
+
+        self.pending = None;
+        self.end_sojourn(time, false);
+        Some(self.apply_transition(target, time, rng.stream(Purpose::Factory)))
+    }
+
+    fn mode(&self) -> &dyn Any {
+        self.current_state_type()
+    }
+
+    fn data(&self) -> &dyn Any {
+        &self.data
+    }
+
+    // the transition matrix is immutable, so only the mode, timers, history, pending transition and data are saved
+    fn checkpoint(&self) -> Option<Checkpoint> {
+        Some(Box::new(AgentCheckpoint {
+            mode: self.mode,
+            timers: self.timers.clone(),
+            stats: self.stats.clone(),
+            pending: self.pending.clone(),
+            sojourn: self.sojourn.clone(),
+            log_likelihood_ratio: self.log_likelihood_ratio,
+            data: self.data.clone(),
+        }))
+    }
+
+    fn restore(&mut self, checkpoint: &Checkpoint) {
+        let checkpoint = checkpoint
+            .downcast_ref::<AgentCheckpoint<C, S>>()
+            .expect("checkpoint was taken from an agent of the same type");
+
+        self.mode = checkpoint.mode;
+        self.timers = checkpoint.timers.clone();
+        self.stats = checkpoint.stats.clone();
+        self.pending = checkpoint.pending.clone();
+        self.sojourn = checkpoint.sojourn.clone();
+        self.log_likelihood_ratio = checkpoint.log_likelihood_ratio;
+        self.data = checkpoint.data.clone();
+    }
+
+    fn log_likelihood_ratio(&self, time: DateTime<Utc>) -> f64 {
+        Agent::log_likelihood_ratio(self, time)
+    }
+
+    fn reset_stats(&mut self, time: DateTime<Utc>) {
+        self.stats.reset(time);
+    }
+}
+
+// AgentCheckpoint is the dynamic state of an Agent, captured by SimAgent::checkpoint
+struct AgentCheckpoint<C, S> {
+    mode: usize,
+    timers: Vec<ActiveTimer<C>>,
+    stats: AgentStats<C>,
+    pending: Option<C>,
+    sojourn: Option<Sojourn>,
+    log_likelihood_ratio: f64,
+    data: S,
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::state::{State, StateChangeEvent};
+    use crate::rng::AgentStreams;
     use chrono::TimeZone;
     use rand::SeedableRng;
     use rand::rngs::StdRng;
+    use state_macros::State;
 
-    #[derive(Clone, Default, Debug, PartialEq)]
+    #[derive(Clone, Default, Debug, PartialEq, State)]
     struct MockState {
         value: i32,
     }
 
-    impl State for MockState {
-        fn diff(&self, other: &Self, time: DateTime<Utc>) -> Vec<StateChangeEvent> {
-            if self.value != other.value {
-                vec![StateChangeEvent {
-                    time,
-                    agent_id: String::new(),
-                    field: "value".to_string(),
-                    old_value: self.value.to_string(),
-                    new_value: other.value.to_string(),
-                }]
-            } else {
-                vec![]
-            }
-        }
-    }
-
-    #[derive(Eq, Hash, PartialEq, Clone, Debug)]
+    #[derive(Eq, Hash, PartialEq, PartialOrd, Ord, Clone, Debug)]
     enum AgentState {
         Idle,
         Active,
@@ -188,10 +876,11 @@ mod tests {
             AgentState::Idle,
             transitions,
             &mut rng,
-        );
+        )
+        .unwrap();
 
         assert_eq!(agent.id, "agent_1");
-        assert_eq!(agent.current_state_type, AgentState::Idle);
+        assert_eq!(*agent.current_state_type(), AgentState::Idle);
         assert_eq!(agent.data.value, 0);
     }
 
@@ -208,8 +897,13 @@ mod tests {
                 1.0,
             ),
         );
+        transitions.insert(
+            AgentState::Active,
+            StateType::new_deterministic(|| MockState { value: 1 }, vec![], 0.0),
+        );
 
-        let agent = Agent::new("test".to_string(), AgentState::Idle, transitions, &mut rng);
+        let agent =
+            Agent::new("test".to_string(), AgentState::Idle, transitions, &mut rng).unwrap();
 
         let next_state = agent.step(&mut rng);
         assert_eq!(next_state, Some(AgentState::Active));
@@ -235,13 +929,14 @@ mod tests {
             AgentState::Idle,
             transitions.clone(),
             &mut rng,
-        );
+        )
+        .unwrap();
 
         let delay = agent.peek_next_event_delay(&mut rng);
         assert!(delay.is_some());
         assert!(delay.unwrap() > 0.0);
 
-        agent.current_state_type = AgentState::Active;
+        agent.mode = agent.transition_matrix.index(&AgentState::Active).unwrap();
         let delay_none = agent.peek_next_event_delay(&mut rng);
         assert!(delay_none.is_none());
     }
@@ -266,11 +961,12 @@ mod tests {
             AgentState::Idle,
             transitions,
             &mut rng,
-        );
+        )
+        .unwrap();
 
         let events = agent.apply_transition(AgentState::Active, time, &mut rng);
 
-        assert_eq!(agent.current_state_type, AgentState::Active);
+        assert_eq!(*agent.current_state_type(), AgentState::Active);
         assert_eq!(agent.data.value, 10);
 
         assert_eq!(events.len(), 1);
@@ -279,4 +975,239 @@ mod tests {
         assert_eq!(events[0].new_value, "10");
         assert_eq!(events[0].agent_id, "agent_x");
     }
+
+    #[test]
+    fn test_holding_time_shapes_and_carry_over() {
+        let mut rng = StdRng::seed_from_u64(42);
+        let mean_of = |holding_time: HoldingTime, rng: &mut StdRng| {
+            let delays: Vec<f64> = (0..20_000)
+                .map(|_| holding_time.sample(100.0, rng).unwrap())
+                .collect();
+            let mean = delays.iter().sum::<f64>() / delays.len() as f64;
+            let spread = delays.iter().fold(0.0f64, |max, delay| max.max(*delay))
+                - delays
+                    .iter()
+                    .fold(f64::INFINITY, |min, delay| min.min(*delay));
+            (mean, spread)
+        };
+
+        assert_eq!(mean_of(HoldingTime::Deterministic, &mut rng), (100.0, 0.0));
+        let (mean, spread) = mean_of(HoldingTime::Uniform { spread: 0.5 }, &mut rng);
+        assert!((mean - 100.0).abs() < 1.0 && spread <= 100.0);
+        for shape in [
+            HoldingTime::Erlang { shape: 4 },
+            HoldingTime::LogNormal { sigma: 0.5 },
+        ] {
+            let (mean, _) = mean_of(shape, &mut rng);
+            assert!((mean - 100.0).abs() < 2.0, "{:?}: {}", shape, mean);
+        }
+        assert!(!HoldingTime::Erlang { shape: 0 }.is_valid());
+
+        // carry over keeps the value the agent had before entering the state type
+        let mut transitions = HashMap::new();
+        transitions.insert(
+            AgentState::Idle,
+            StateType::new_deterministic(|| MockState { value: 0 }, vec![], 1.0)
+                .with_carry_over(|previous: &MockState, next| next.value += previous.value),
+        );
+        transitions.insert(
+            AgentState::Active,
+            StateType::new_deterministic(|| MockState { value: 10 }, vec![], 1.0),
+        );
+        let mut agent = Agent::new(
+            "carry".to_string(),
+            AgentState::Active,
+            transitions,
+            &mut rng,
+        )
+        .unwrap();
+        let time = Utc.timestamp_opt(1000, 0).unwrap();
+        agent.apply_transition(AgentState::Idle, time, &mut rng);
+        assert_eq!(agent.data.value, 10);
+        agent.apply_transition(AgentState::Idle, time, &mut rng);
+        assert_eq!(agent.data.value, 10);
+    }
+
+    #[test]
+    fn test_timers_armed_and_cancelled_with_state_type() {
+        let mut rng = StdRng::seed_from_u64(42);
+        let mut transitions = HashMap::new();
+        let time = Utc.timestamp_opt(1000, 0).unwrap();
+
+        transitions.insert(
+            AgentState::Idle,
+            StateType::new_deterministic(|| MockState { value: 0 }, vec![], 1.0),
+        );
+        transitions.insert(
+            AgentState::Active,
+            StateType::new_deterministic(|| MockState { value: 10 }, vec![], 1.0)
+                .with_timeout(7200.0, AgentState::Idle)
+                .with_on_enter(|ctx| ctx.set_timer("session", 60.0, AgentState::Idle)),
+        );
+
+        let mut agent =
+            Agent::new("timed".to_string(), AgentState::Idle, transitions, &mut rng).unwrap();
+        agent.start(time);
+        assert!(agent.timers().is_empty());
+
+        agent.apply_transition(AgentState::Active, time, &mut rng);
+        assert_eq!(agent.timers().len(), 2);
+        assert_eq!(agent.next_timer().unwrap().name, "session");
+
+        // a self-transition keeps the state type timer but resets the one set by the hook
+        let later = time + chrono::Duration::seconds(30);
+        agent.apply_transition(AgentState::Active, later, &mut rng);
+        let session = agent.timers().iter().find(|t| t.name == "session").unwrap();
+        assert_eq!(session.deadline, later + chrono::Duration::seconds(60));
+        let timeout = agent.timers().iter().find(|t| t.name == "timeout").unwrap();
+        assert_eq!(timeout.deadline, time + chrono::Duration::seconds(7200));
+
+        agent.apply_transition(AgentState::Idle, later, &mut rng);
+        assert!(agent.timers().is_empty());
+    }
+
+    #[test]
+    fn test_history_drives_weights_guards_and_rates() {
+        let mut rng = StdRng::seed_from_u64(42);
+        let mut transitions = HashMap::new();
+        let time = Utc.timestamp_opt(1000, 0).unwrap();
+
+        transitions.insert(
+            AgentState::Idle,
+            StateType::new_with_stats(
+                |_, stats| MockState {
+                    value: stats.visits(&AgentState::Idle) as i32,
+                },
+                vec![(AgentState::Active, 1.0), (AgentState::Idle, 1.0)],
+                10.0,
+            )
+            .with_guard(AgentState::Active, |stats| {
+                stats.visits(&AgentState::Idle) >= 2
+            })
+            .with_rate_fn(|mean, stats| if stats.age() > 60.0 { 0.0 } else { mean }),
+        );
+        transitions.insert(
+            AgentState::Active,
+            StateType::new_deterministic(|| MockState { value: 10 }, vec![], 1.0),
+        );
+
+        let mut agent =
+            Agent::new("aging".to_string(), AgentState::Idle, transitions, &mut rng).unwrap();
+        agent.start(time);
+        assert_eq!(agent.data.value, 0);
+
+        // the guard keeps the agent idle on its first visit
+        for _ in 0..20 {
+            assert_eq!(agent.step(&mut rng), Some(AgentState::Idle));
+        }
+
+        agent.apply_transition(
+            AgentState::Active,
+            time + chrono::Duration::seconds(10),
+            &mut rng,
+        );
+        agent.apply_transition(
+            AgentState::Idle,
+            time + chrono::Duration::seconds(30),
+            &mut rng,
+        );
+        assert_eq!(agent.data.value, 2);
+        assert_eq!(agent.stats().cumulative_time_in(&AgentState::Active), 20.0);
+        assert!(
+            (0..20).any(|_| agent.step(&mut rng) == Some(AgentState::Active)),
+            "the guard should allow leaving idle on the second visit"
+        );
+        assert!(agent.peek_next_event_delay(&mut rng).is_some());
+
+        agent.apply_transition(
+            AgentState::Idle,
+            time + chrono::Duration::seconds(90),
+            &mut rng,
+        );
+        assert!(agent.peek_next_event_delay(&mut rng).is_none());
+    }
+
+    #[test]
+    fn test_invalid_dynamic_rates_and_weights_rule_transitions_out() {
+        let mut rng = StdRng::seed_from_u64(42);
+        let time = Utc.timestamp_opt(1000, 0).unwrap();
+        let agent = |rate: f64, weight: f64, rng: &mut StdRng| {
+            let transitions = HashMap::from([
+                (
+                    AgentState::Idle,
+                    StateType::new_deterministic(
+                        || MockState { value: 0 },
+                        vec![(AgentState::Active, 1.0), (AgentState::Idle, 1.0)],
+                        10.0,
+                    )
+                    .with_rate_fn(move |_, _| rate)
+                    .with_weight_fn(move |target, nominal, _| {
+                        if *target == AgentState::Active {
+                            weight
+                        } else {
+                            nominal
+                        }
+                    }),
+                ),
+                (
+                    AgentState::Active,
+                    StateType::new_deterministic(|| MockState { value: 1 }, vec![], 1.0),
+                ),
+            ]);
+            let mut agent =
+                Agent::new("a".to_string(), AgentState::Idle, transitions, rng).unwrap();
+            agent.start(time);
+            agent
+        };
+
+        for rate in [f64::NAN, f64::INFINITY, -1.0, 0.0] {
+            let mut agent = agent(rate, 1.0, &mut rng);
+            assert!(agent.peek_next_event_delay(&mut rng).is_none(), "{}", rate);
+            let mut streams = AgentStreams::new(1, "a");
+            assert!(agent.schedule(time, &mut streams).is_none(), "{}", rate);
+        }
+        for weight in [f64::NAN, f64::INFINITY, -1.0] {
+            let agent = agent(10.0, weight, &mut rng);
+            for _ in 0..20 {
+                assert_eq!(agent.step(&mut rng), Some(AgentState::Idle), "{}", weight);
+            }
+        }
+    }
+
+    #[test]
+    fn test_stationary_initial_mode_is_pinned_by_the_seed() {
+        // agents are active 3/4 of the time
+        let mut transitions = HashMap::new();
+        transitions.insert(
+            AgentState::Idle,
+            StateType::new_deterministic(
+                || MockState { value: 0 },
+                vec![(AgentState::Active, 1.0)],
+                1.0,
+            ),
+        );
+        transitions.insert(
+            AgentState::Active,
+            StateType::new_deterministic(
+                || MockState { value: 1 },
+                vec![(AgentState::Idle, 1.0)],
+                3.0,
+            ),
+        );
+        let matrix = TransitionMatrix::from(transitions);
+
+        // the modes drawn only depend on the seed, not on the iteration order of the map the model was built from
+        let drawn: Vec<AgentState> = (0..8)
+            .map(|seed| {
+                let mut rng = StdRng::seed_from_u64(seed);
+                let agent = Agent::new_stationary("agent".to_string(), matrix.clone(), &mut rng);
+                agent.unwrap().current_state_type().clone()
+            })
+            .collect();
+        use AgentState::{Active, Idle};
+        assert_eq!(
+            drawn,
+            [Active, Active, Active, Idle, Active, Active, Active, Idle]
+        );
+    }
 }
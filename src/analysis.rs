@@ -0,0 +1,325 @@
+use crate::state::StateChangeEvent;
+use crate::summary::Summary;
+use crate::timer;
+use crate::value::Value;
+use chrono::{DateTime, Duration, Utc};
+use std::collections::BTreeMap;
+
+// Segment is an interval over which a field held a value
+pub(crate) type Segment<'a> = (DateTime<Utc>, DateTime<Utc>, &'a Value);
+
+/// Trajectory is the value of one field of one agent over an observation window, rebuilt from the events of a run.
+/// It is the starting point of output analysis: time-weighted means of numeric fields (`true`/`false` count as 1/0),
+/// the fraction of time spent in each value (i.e. mode occupancy), batch means over a single long run and warm-up
+/// detection. For confidence intervals with the replication method, reduce each replication to a trajectory mean
+/// and use `replication::summarize`.
+#[derive(Debug, Clone)]
+pub struct Trajectory {
+    start: DateTime<Utc>,
+    end: DateTime<Utc>,
+    initial: Option<Value>,
+    changes: Vec<(DateTime<Utc>, Value)>,
+}
+
+impl Trajectory {
+    // from_events rebuilds the trajectory of `field` of `agent_id` between `start` and `end`. The value at `start` is
+    // the last value set before it, or the old value of the first change after it.
+    pub fn from_events(
+        events: &[StateChangeEvent],
+        agent_id: &str,
+        field: &str,
+        start: DateTime<Utc>,
+        end: DateTime<Utc>,
+    ) -> Self {
+        let mut matching: Vec<&StateChangeEvent> = events
+            .iter()
+            .filter(|event| event.agent_id == agent_id && event.field == field)
+            .collect();
+        matching.sort_by_key(|event| event.time);
+
+        let mut initial = matching.first().map(|event| event.old_value.clone());
+        let mut changes = Vec::new();
+        for event in matching {
+            if event.time <= start {
+                initial = Some(event.new_value.clone());
+            } else if event.time < end {
+                changes.push((event.time, event.new_value.clone()));
+            }
+        }
+
+        Trajectory {
+            start,
+            end,
+            initial,
+            changes,
+        }
+    }
+
+    // with_initial sets the value at the start of the window when no event tells it, i.e. for an agent that never
+    // left its initial state
+    pub fn with_initial(mut self, value: impl Into<Value>) -> Self {
+        self.initial.get_or_insert_with(|| value.into());
+        self
+    }
+
+    // truncate drops everything before `from`, i.e. the warm-up period
+    pub fn truncate(&self, from: DateTime<Utc>) -> Self {
+        self.window(from.max(self.start), self.end)
+    }
+
+    // time_weighted_mean returns the mean of a numeric field weighted by how long it held each value, or None if the
+    // window is empty, the initial value is unknown or a value is not numeric
+    pub fn time_weighted_mean(&self) -> Option<f64> {
+        let span = timer::duration_to_seconds(self.end - self.start);
+        if span <= 0.0 {
+            return None;
+        }
+
+        let mut total = 0.0;
+        for (from, to, value) in self.segments()? {
+            total += value.as_f64()? * timer::duration_to_seconds(to - from);
+        }
+        Some(total / span)
+    }
+
+    // occupancy returns the fraction of the window spent in each value
+    pub fn occupancy(&self) -> BTreeMap<String, f64> {
+        let span = timer::duration_to_seconds(self.end - self.start);
+        let mut occupancy = BTreeMap::new();
+        if span <= 0.0 {
+            return occupancy;
+        }
+
+        for (from, to, value) in self.segments().unwrap_or_default() {
+            *occupancy.entry(value.to_string()).or_insert(0.0) +=
+                timer::duration_to_seconds(to - from) / span;
+        }
+        occupancy
+    }
+
+    // first_passage returns the seconds from the start of the window until the field first takes one of `targets`
+    // (compared in their string form), i.e. the time to failure, or None if it never does within the window
+    pub fn first_passage(&self, targets: &[&str]) -> Option<f64> {
+        self.segments()?
+            .into_iter()
+            .find(|(_, _, value)| targets.contains(&value.to_string().as_str()))
+            .map(|(from, _, _)| timer::duration_to_seconds(from - self.start))
+    }
+
+    // sample returns the time-weighted mean over consecutive intervals of the window, dropping a partial last one
+    pub fn sample(&self, interval: Duration) -> Option<Vec<f64>> {
+        assert!(interval > Duration::zero(), "intervals must be positive");
+
+        let mut means = Vec::new();
+        let mut from = self.start;
+        while from + interval <= self.end {
+            means.push(self.window(from, from + interval).time_weighted_mean()?);
+            from += interval;
+        }
+        Some(means)
+    }
+
+    // batch_means estimates the steady-state mean from a single long run: the window is split into `batches` equal
+    // batches whose means are treated as independent observations. Batches must be long enough for that to hold,
+    // 10 to 30 batches being usual.
+    pub fn batch_means(&self, batches: usize, confidence: f64) -> Option<Summary> {
+        assert!(batches > 0, "batch means need at least one batch");
+
+        let length = (self.end - self.start) / batches as i32;
+        let means = (0..batches as i32)
+            .map(|i| {
+                let from = self.start + length * i;
+                self.window(from, from + length).time_weighted_mean()
+            })
+            .collect::<Option<Vec<f64>>>()?;
+        Some(Summary::from_values(&means, confidence))
+    }
+
+    // warmup detects the end of the warm-up period with MSER-5 over the means of consecutive intervals
+    pub fn warmup(&self, interval: Duration) -> Option<DateTime<Utc>> {
+        let observations = self.sample(interval)?;
+        Some(self.start + interval * mser5(&observations) as i32)
+    }
+
+    fn window(&self, start: DateTime<Utc>, end: DateTime<Utc>) -> Self {
+        let mut initial = self.initial.clone();
+        let mut changes = Vec::new();
+        for (time, value) in &self.changes {
+            if *time <= start {
+                initial = Some(value.clone());
+            } else if *time < end {
+                changes.push((*time, value.clone()));
+            }
+        }
+
+        Trajectory {
+            start,
+            end,
+            initial,
+            changes,
+        }
+    }
+
+    // segments returns the intervals over which the field held each value, or None if the initial value is unknown
+    pub(crate) fn segments(&self) -> Option<Vec<Segment<'_>>> {
+        let mut value = self.initial.as_ref()?;
+        let mut from = self.start;
+        let mut segments = Vec::with_capacity(self.changes.len() + 1);
+
+        for (time, next) in &self.changes {
+            segments.push((from, *time, value));
+            from = *time;
+            value = next;
+        }
+        segments.push((from, self.end, value));
+        Some(segments)
+    }
+}
+
+// mser5 returns how many observations to delete from the start of an output series as warm-up, using the MSER-5
+// rule: the observations are averaged in batches of 5 and the truncation minimizing the standard error of the mean
+// of the remaining batches is picked, among truncations of at most half of the series.
+pub fn mser5(observations: &[f64]) -> usize {
+    let batches: Vec<f64> = observations
+        .chunks_exact(5)
+        .map(|batch| batch.iter().sum::<f64>() / 5.0)
+        .collect();
+
+    let mut best = (f64::INFINITY, 0);
+    for deleted in 0..=batches.len() / 2 {
+        let kept = &batches[deleted..];
+        if kept.is_empty() {
+            break;
+        }
+
+        let mean = kept.iter().sum::<f64>() / kept.len() as f64;
+        let sum_sq: f64 = kept.iter().map(|z| (z - mean).powi(2)).sum();
+        let statistic = sum_sq / (kept.len() as f64).powi(2);
+        if statistic < best.0 {
+            best = (statistic, deleted);
+        }
+    }
+
+    best.1 * 5
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::agent::{Agent, StateType};
+    use crate::replication::{Replications, summarize};
+    use crate::simulation::Simulation;
+    use chrono::TimeZone;
+    use rand::SeedableRng;
+    use rand::rngs::StdRng;
+    use state_macros::State;
+    use std::collections::HashMap;
+
+    #[derive(Clone, Default, Debug, State)]
+    struct Link {
+        up: bool,
+    }
+
+    // link fails after 9 hours and is repaired after 1 hour on average, for an availability of 0.9
+    fn link(seed: u64) -> Simulation {
+        let mut transitions = HashMap::new();
+        transitions.insert(
+            true,
+            StateType::new_deterministic(|| Link { up: true }, vec![(false, 1.0)], 32_400.0),
+        );
+        transitions.insert(
+            false,
+            StateType::new_deterministic(|| Link { up: false }, vec![(true, 1.0)], 3_600.0),
+        );
+
+        let mut rng = StdRng::seed_from_u64(seed);
+        let agent = Agent::new("link".to_string(), true, transitions, &mut rng).unwrap();
+        Simulation::new_with_seed(vec![agent], Utc.timestamp_opt(0, 0).unwrap(), seed).unwrap()
+    }
+
+    fn event(time: i64, old_value: &str, new_value: &str) -> StateChangeEvent {
+        StateChangeEvent {
+            time: Utc.timestamp_opt(time, 0).unwrap(),
+            agent_id: "a".into(),
+            field: "load",
+            old_value: old_value.into(),
+            new_value: new_value.into(),
+        }
+    }
+
+    #[test]
+    fn test_time_weighted_mean_and_occupancy() {
+        let events = vec![event(10, "0", "4"), event(30, "4", "1")];
+        let at = |seconds| Utc.timestamp_opt(seconds, 0).unwrap();
+        let trajectory = Trajectory::from_events(&events, "a", "load", at(0), at(40));
+
+        assert_eq!(
+            trajectory.time_weighted_mean(),
+            Some((4.0 * 20.0 + 10.0) / 40.0)
+        );
+        let occupancy = trajectory.occupancy();
+        assert_eq!(occupancy["0"], 0.25);
+        assert_eq!(occupancy["4"], 0.5);
+
+        // values set before the window carry over, and agents without events need an initial value
+        let late = Trajectory::from_events(&events, "a", "load", at(20), at(40));
+        assert_eq!(late.time_weighted_mean(), Some(2.5));
+        let quiet = Trajectory::from_events(&events, "b", "load", at(0), at(40));
+        assert_eq!(quiet.time_weighted_mean(), None);
+        assert_eq!(quiet.with_initial("3").time_weighted_mean(), Some(3.0));
+    }
+
+    #[test]
+    fn test_mser5_truncates_transient() {
+        // the load starts at 50 and settles to alternate between 0 and 2 after 100 seconds
+        let mut events = vec![event(100, "50", "0")];
+        for i in 1..190 {
+            let (old, new) = if i % 2 == 1 { ("0", "2") } else { ("2", "0") };
+            events.push(event(100 + i * 10, old, new));
+        }
+        let trajectory = Trajectory::from_events(
+            &events,
+            "a",
+            "load",
+            Utc.timestamp_opt(0, 0).unwrap(),
+            Utc.timestamp_opt(2000, 0).unwrap(),
+        );
+
+        let warmup = trajectory.warmup(Duration::seconds(10)).unwrap();
+        assert_eq!(warmup, Utc.timestamp_opt(100, 0).unwrap());
+        assert!(trajectory.time_weighted_mean().unwrap() > 3.0);
+        assert!((trajectory.truncate(warmup).time_weighted_mean().unwrap() - 1.0).abs() < 0.01);
+    }
+
+    #[test]
+    fn test_availability_confidence_intervals() {
+        let start = Utc.timestamp_opt(0, 0).unwrap();
+
+        // a single long run, with batch means
+        let horizon = Duration::days(2000);
+        let mut sim = link(1);
+        let events = sim.run(horizon);
+        let trajectory = Trajectory::from_events(events, "link", "up", start, start + horizon);
+        let batched = trajectory.batch_means(20, 0.95).unwrap();
+        assert!((batched.mean - 0.9).abs() < 3.0 * batched.half_width);
+        assert!((trajectory.occupancy()["false"] - 0.1).abs() < 0.01);
+
+        // independent replications
+        let day = Duration::days(30);
+        let results = Replications::new(40, 5).run(
+            |replication| link(replication.seed),
+            |_, sim| {
+                let events = sim.run(day);
+                Trajectory::from_events(events, "link", "up", start, start + day)
+                    .with_initial("true")
+                    .time_weighted_mean()
+                    .unwrap()
+            },
+        );
+        let replicated = summarize(&results, 0.95, |availability| *availability);
+        assert!((replicated.mean - 0.9).abs() < 3.0 * replicated.half_width);
+
+        let required = replicated.required_replications(0.001).unwrap();
+        assert!(required > 40);
+    }
+}
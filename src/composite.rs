@@ -0,0 +1,306 @@
+use crate::agent::{Agent, Checkpoint};
+use crate::state::{AgentId, State, StateChangeEvent};
+use chrono::{DateTime, Utc};
+use std::any::Any;
+use std::fmt::Display;
+use std::hash::Hash;
+use std::sync::Arc;
+
+pub const ID_SEPARATOR: char = '/';
+
+pub type RollupPredicate<C> = Arc<dyn Fn(&[(&str, &C)]) -> bool + Send + Sync>;
+
+// join_id builds a hierarchical agent id, i.e. `server_01` + `psu_2` -> `server_01/psu_2`
+pub fn join_id(parent: &str, child: &str) -> String {
+    format!("{}{}{}", parent, ID_SEPARATOR, child)
+}
+
+// parent_id returns the id of the agent that directly contains the given agent, if any
+pub fn parent_id(id: &str) -> Option<&str> {
+    id.rsplit_once(ID_SEPARATOR).map(|(parent, _)| parent)
+}
+
+// root_id returns the id of the top level agent in the hierarchy the given agent belongs to
+pub fn root_id(id: &str) -> &str {
+    id.split(ID_SEPARATOR).next().unwrap_or(id)
+}
+
+/// A CompositeAgent groups a set of child agents (i.e. the disks, PSUs and NICs of a server) under a parent whose
+/// mode is derived from the modes of its children through an ordered list of roll-up rules.
+pub struct CompositeAgent<C, S, P>
+where
+    C: Eq + Hash + Clone,
+    S: State,
+{
+    pub id: String,
+    children: Vec<Agent<C, S>>,
+    child_names: Vec<String>,
+    rules: Vec<(P, RollupPredicate<C>)>,
+    default_mode: P,
+}
+
+impl<C, S, P> CompositeAgent<C, S, P>
+where
+    C: Eq + Hash + Clone,
+    S: State + Clone,
+    P: Clone + PartialEq + Display,
+{
+    pub fn new(id: String, default_mode: P) -> Self {
+        CompositeAgent {
+            id,
+            children: Vec::new(),
+            child_names: Vec::new(),
+            rules: Vec::new(),
+            default_mode,
+        }
+    }
+
+    // with_child adds a child agent, prefixing its id with the id of the composite
+    pub fn with_child(mut self, mut child: Agent<C, S>) -> Self {
+        self.child_names.push(child.id.to_string());
+        child.id = join_id(&self.id, &child.id).into();
+        self.children.push(child);
+        self
+    }
+
+    // with_rule adds a roll-up rule. Rules are evaluated in the order they were added and the first rule whose
+    // predicate matches decides the parent mode; if none match, the composite falls back to its default mode.
+    pub fn with_rule<F>(mut self, mode: P, predicate: F) -> Self
+    where
+        F: Fn(&[(&str, &C)]) -> bool + Send + Sync + 'static,
+    {
+        self.rules.push((mode, Arc::new(predicate)));
+        self
+    }
+
+    pub fn children(&self) -> &[Agent<C, S>] {
+        &self.children
+    }
+
+    // current_mode evaluates the roll-up rules against the current modes of the children
+    pub fn current_mode(&self) -> P {
+        let names: Vec<&str> = self.child_names.iter().map(String::as_str).collect();
+        let modes: Vec<&C> = self
+            .children
+            .iter()
+            .map(|c| c.current_state_type())
+            .collect();
+        self.rollup().evaluate(&names, &modes)
+    }
+
+    fn rollup(&self) -> Rollup<C, P> {
+        Rollup {
+            id: self.id.as_str().into(),
+            child_names: self.child_names.clone(),
+            rules: self.rules.clone(),
+            default_mode: self.default_mode.clone(),
+            current_mode: self.default_mode.clone(),
+        }
+    }
+
+    pub(crate) fn into_parts(self) -> (Rollup<C, P>, Vec<Agent<C, S>>) {
+        let mut rollup = self.rollup();
+        rollup.current_mode = self.current_mode();
+        (rollup, self.children)
+    }
+}
+
+/// Rollup holds the roll-up rules and last known mode of a composite agent once its children have been handed over
+/// to a simulation.
+pub(crate) struct Rollup<C, P> {
+    id: AgentId,
+    child_names: Vec<String>,
+    rules: Vec<(P, RollupPredicate<C>)>,
+    default_mode: P,
+    current_mode: P,
+}
+
+impl<C, P> Rollup<C, P>
+where
+    P: Clone + PartialEq + Display,
+{
+    fn evaluate(&self, names: &[&str], modes: &[&C]) -> P {
+        let view: Vec<(&str, &C)> = names.iter().copied().zip(modes.iter().copied()).collect();
+
+        self.rules
+            .iter()
+            .find(|(_, predicate)| predicate(&view))
+            .map(|(mode, _)| mode.clone())
+            .unwrap_or_else(|| self.default_mode.clone())
+    }
+}
+
+/// ModeRollup erases the child and parent mode types of a composite so that composites of any kind can be held by
+/// the same simulation.
+pub(crate) trait ModeRollup: Send {
+    // update re-evaluates the parent mode, returning a change event if it differs from the previous one
+    fn update(&mut self, modes: &[&dyn Any], time: DateTime<Utc>) -> Option<StateChangeEvent>;
+
+    // checkpoint and restore save and roll back the last known parent mode
+    fn checkpoint(&self) -> Checkpoint;
+
+    fn restore(&mut self, checkpoint: &Checkpoint);
+}
+
+impl<C, P> ModeRollup for Rollup<C, P>
+where
+    C: 'static,
+    P: Clone + PartialEq + Display + Send + 'static,
+{
+    fn update(&mut self, modes: &[&dyn Any], time: DateTime<Utc>) -> Option<StateChangeEvent> {
+        let names: Vec<&str> = self.child_names.iter().map(String::as_str).collect();
+        let modes: Vec<&C> = modes
+            .iter()
+            .map(|mode| {
+                mode.downcast_ref::<C>()
+                    .expect("children of a composite share its mode type")
+            })
+            .collect();
+        let next_mode = self.evaluate(&names, &modes);
+
+        if next_mode == self.current_mode {
+            return None;
+        }
+
+        let event = StateChangeEvent {
+            time,
+            agent_id: self.id.clone(),
+            field: "mode",
+            old_value: self.current_mode.to_string().into(),
+            new_value: next_mode.to_string().into(),
+        };
+        self.current_mode = next_mode;
+
+        Some(event)
+    }
+
+    fn checkpoint(&self) -> Checkpoint {
+        Box::new(self.current_mode.clone())
+    }
+
+    fn restore(&mut self, checkpoint: &Checkpoint) {
+        self.current_mode = checkpoint
+            .downcast_ref::<P>()
+            .expect("checkpoint was taken from the same roll-up")
+            .clone();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::agent::StateType;
+    use chrono::TimeZone;
+    use rand::SeedableRng;
+    use rand::rngs::StdRng;
+    use state_macros::State;
+    use std::collections::HashMap;
+
+    #[derive(Clone, Default, Debug, PartialEq, State)]
+    struct MockState {
+        up: bool,
+    }
+
+    #[derive(Eq, Hash, PartialEq, PartialOrd, Ord, Clone, Debug)]
+    enum PsuMode {
+        Ok,
+        Failed,
+    }
+
+    #[derive(PartialEq, Clone, Debug)]
+    enum ServerMode {
+        Online,
+        Degraded,
+        Offline,
+    }
+
+    impl Display for ServerMode {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            write!(f, "{:?}", self)
+        }
+    }
+
+    fn psu(id: &str, mode: PsuMode, rng: &mut StdRng) -> Agent<PsuMode, MockState> {
+        let mut transitions = HashMap::new();
+        transitions.insert(
+            PsuMode::Ok,
+            StateType::new_deterministic(|| MockState { up: true }, vec![], 1.0),
+        );
+        transitions.insert(
+            PsuMode::Failed,
+            StateType::new_deterministic(|| MockState { up: false }, vec![], 1.0),
+        );
+        Agent::new(id.to_string(), mode, transitions, rng).unwrap()
+    }
+
+    fn server(
+        first: PsuMode,
+        second: PsuMode,
+        rng: &mut StdRng,
+    ) -> CompositeAgent<PsuMode, MockState, ServerMode> {
+        let failed = |children: &[(&str, &PsuMode)]| {
+            children
+                .iter()
+                .filter(|(_, mode)| **mode == PsuMode::Failed)
+                .count()
+        };
+
+        CompositeAgent::new("server_01".to_string(), ServerMode::Online)
+            .with_child(psu("psu_1", first, rng))
+            .with_child(psu("psu_2", second, rng))
+            .with_rule(ServerMode::Offline, move |c| failed(c) == 2)
+            .with_rule(ServerMode::Degraded, move |c| failed(c) == 1)
+    }
+
+    #[test]
+    fn test_hierarchical_ids() {
+        assert_eq!(join_id("server_01", "psu_2"), "server_01/psu_2");
+        assert_eq!(parent_id("dc/server_01/psu_2"), Some("dc/server_01"));
+        assert_eq!(parent_id("server_01"), None);
+        assert_eq!(root_id("dc/server_01/psu_2"), "dc");
+        assert_eq!(root_id("server_01"), "server_01");
+    }
+
+    #[test]
+    fn test_composite_rule_evaluation() {
+        let mut rng = StdRng::seed_from_u64(42);
+
+        let healthy = server(PsuMode::Ok, PsuMode::Ok, &mut rng);
+        assert_eq!(healthy.current_mode(), ServerMode::Online);
+        assert_eq!(healthy.children()[1].id, "server_01/psu_2");
+
+        let degraded = server(PsuMode::Ok, PsuMode::Failed, &mut rng);
+        assert_eq!(degraded.current_mode(), ServerMode::Degraded);
+
+        let offline = server(PsuMode::Failed, PsuMode::Failed, &mut rng);
+        assert_eq!(offline.current_mode(), ServerMode::Offline);
+    }
+
+    #[test]
+    fn test_rollup_emits_parent_mode_change() {
+        let mut rng = StdRng::seed_from_u64(42);
+        let time = Utc.timestamp_opt(1000, 0).unwrap();
+
+        let (mut rollup, _) = server(PsuMode::Ok, PsuMode::Ok, &mut rng).into_parts();
+
+        assert!(
+            rollup
+                .update(&[&PsuMode::Ok as &dyn Any, &PsuMode::Ok], time)
+                .is_none()
+        );
+
+        let event = rollup
+            .update(&[&PsuMode::Failed as &dyn Any, &PsuMode::Ok], time)
+            .unwrap();
+        assert_eq!(event.agent_id, "server_01");
+        assert_eq!(event.field, "mode");
+        assert_eq!(event.old_value, "Online");
+        assert_eq!(event.new_value, "Degraded");
+
+        assert!(
+            rollup
+                .update(&[&PsuMode::Ok as &dyn Any, &PsuMode::Failed], time)
+                .is_none()
+        );
+    }
+}
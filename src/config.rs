@@ -0,0 +1,1209 @@
+use crate::agent::{self, Agent, HoldingTime};
+use crate::dynamic::DynamicState;
+use crate::model::{self, Model, ModelBuilder};
+use crate::rng::DefaultRng;
+use crate::simulation::Simulation;
+use crate::state::{self, FieldName};
+use crate::timer;
+use crate::validation::ModelError;
+use crate::value::Value;
+use chrono::{DateTime, Duration, Utc};
+use rand::seq::SliceRandom;
+use rand::{Rng, RngCore, SeedableRng};
+use serde::Deserialize;
+use serde::de::{self, Deserializer, MapAccess};
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::marker::PhantomData;
+use std::path::{Path, PathBuf};
+
+/// Format is the syntax of a model file. All formats describe the same structure.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Toml,
+    Yaml,
+    Json,
+}
+
+impl Format {
+    // from_path tells the format of a file from its extension
+    pub fn from_path(path: &Path) -> Option<Self> {
+        match path.extension()?.to_str()?.to_ascii_lowercase().as_str() {
+            "toml" => Some(Format::Toml),
+            "yaml" | "yml" => Some(Format::Yaml),
+            "json" => Some(Format::Json),
+            _ => None,
+        }
+    }
+}
+
+/// ConfigError describes why a model file cannot be loaded. Errors in the file point at the offending line (and
+/// column, for syntax errors) when it can be found.
+#[derive(Debug)]
+pub enum ConfigError {
+    Io {
+        path: PathBuf,
+        error: io::Error,
+    },
+    UnknownFormat(PathBuf),
+    Invalid {
+        line: Option<usize>,
+        column: Option<usize>,
+        message: String,
+    },
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Io { path, error } => {
+                write!(f, "cannot read {}: {}", path.display(), error)
+            }
+            ConfigError::UnknownFormat(path) => write!(
+                f,
+                "cannot tell the format of {}, expected a .toml, .yaml, .yml or .json file",
+                path.display()
+            ),
+            ConfigError::Invalid {
+                line: Some(line),
+                column: Some(column),
+                message,
+            } => write!(f, "line {}, column {}: {}", line, column, message),
+            ConfigError::Invalid {
+                line: Some(line),
+                message,
+                ..
+            } => write!(f, "line {}: {}", line, message),
+            ConfigError::Invalid { message, .. } => write!(f, "{}", message),
+        }
+    }
+}
+
+impl Error for ConfigError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            ConfigError::Io { error, .. } => Some(error),
+            _ => None,
+        }
+    }
+}
+
+/// ModelFile is a simulation described in a TOML, YAML or JSON file, so that models can be edited without
+/// recompiling: the models with their modes, transitions, holding times and the generators of their fields, the
+/// populations of agents following them, and the start time, duration and seed of the run. Agents keep their data
+/// in a `DynamicState` and their mode is its name. See the README for the format.
+#[derive(Debug)]
+pub struct ModelFile {
+    spec: FileSpec,
+    seed: u64,
+    source: String,
+}
+
+impl ModelFile {
+    // load reads a model file, telling its format from its extension
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, ConfigError> {
+        let path = path.as_ref();
+        let format = Format::from_path(path)
+            .ok_or_else(|| ConfigError::UnknownFormat(path.to_path_buf()))?;
+        let source = std::fs::read_to_string(path).map_err(|error| ConfigError::Io {
+            path: path.to_path_buf(),
+            error,
+        })?;
+        Self::parse(&source, format)
+    }
+
+    // parse reads a model file from its text, and checks that its models are valid
+    pub fn parse(source: &str, format: Format) -> Result<Self, ConfigError> {
+        let spec: FileSpec = match format {
+            Format::Toml => toml::from_str(source).map_err(|error| {
+                let position = error.span().map(|span| position(source, span.start));
+                ConfigError::Invalid {
+                    line: position.map(|(line, _)| line),
+                    column: position.map(|(_, column)| column),
+                    message: error.message().to_string(),
+                }
+            })?,
+            Format::Yaml => serde_yaml_ng::from_str(source).map_err(|error| {
+                let location = error.location();
+                ConfigError::Invalid {
+                    line: location.as_ref().map(|location| location.line()),
+                    column: location.as_ref().map(|location| location.column()),
+                    message: strip_location(&error.to_string()),
+                }
+            })?,
+            Format::Json => serde_json::from_str(source).map_err(|error| ConfigError::Invalid {
+                line: Some(error.line()),
+                column: Some(error.column()),
+                message: strip_location(&error.to_string()),
+            })?,
+        };
+
+        let file = ModelFile {
+            seed: spec.seed.unwrap_or_else(rand::random),
+            spec,
+            source: source.to_string(),
+        };
+        for name in file.spec.models.keys() {
+            file.model(name)?;
+        }
+        for population in &file.spec.populations {
+            file.population_model(population)?;
+        }
+        Ok(file)
+    }
+
+    // seed returns the seed of the run, drawn from the OS if the file does not set one
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
+
+    // start returns when the run starts, the Unix epoch if the file does not tell
+    pub fn start(&self) -> DateTime<Utc> {
+        self.spec.start
+    }
+
+    // duration returns how long the run lasts, if the file tells
+    pub fn duration(&self) -> Option<Duration> {
+        self.spec
+            .duration
+            .map(|Seconds(seconds)| timer::seconds_to_duration(seconds))
+    }
+
+    // models returns the names of the models of the file
+    pub fn models(&self) -> impl Iterator<Item = &str> {
+        self.spec.models.keys().map(String::as_str)
+    }
+
+    // model builds the model of the given name, with its agents starting in its initial mode
+    pub fn model(&self, name: &str) -> Result<Model<String, DynamicState>, ConfigError> {
+        let spec = self
+            .spec
+            .models
+            .get(name)
+            .ok_or_else(|| self.invalid(name, format!("there is no model named {:?}", name)))?;
+        self.builder(name, spec)?
+            .initial(spec.initial.clone())
+            .build()
+            .map_err(|error| self.model_error(name, error))
+    }
+
+    // agents creates the agents of every population
+    pub fn agents(&self) -> Result<Vec<Agent<String, DynamicState>>, ConfigError> {
+        self.agents_with_seed(self.seed)
+    }
+
+    // simulation creates a simulation of every population, starting at the start time of the file
+    pub fn simulation(&self) -> Result<Simulation, ConfigError> {
+        self.simulation_with_seed(self.seed)
+    }
+
+    // simulation_with_seed creates a simulation of every population with another seed than that of the file, i.e.
+    // for replications
+    pub fn simulation_with_seed(&self, seed: u64) -> Result<Simulation, ConfigError> {
+        Simulation::new_with_seed(self.agents_with_seed(seed)?, self.spec.start, seed).map_err(
+            |error| {
+                let needle = match &error {
+                    ModelError::DuplicateAgent(id) => id.clone(),
+                    _ => String::new(),
+                };
+                self.invalid(&needle, error.to_string())
+            },
+        )
+    }
+
+    fn agents_with_seed(&self, seed: u64) -> Result<Vec<Agent<String, DynamicState>>, ConfigError> {
+        let mut seeds = DefaultRng::seed_from_u64(seed);
+        let mut agents = Vec::new();
+        for population in &self.spec.populations {
+            let model = self.population_model(population)?;
+            let pattern = population
+                .id_pattern
+                .clone()
+                .unwrap_or_else(|| format!("{}_{{}}", population.model));
+            agents.extend(
+                model
+                    .population(population.count)
+                    .id_pattern(&pattern)
+                    .seed(seeds.next_u64())
+                    .agents()
+                    .map_err(|error| self.model_error(&population.model, error))?,
+            );
+        }
+        Ok(agents)
+    }
+
+    // population_model builds the model followed by a population, with its own initial modes
+    fn population_model(
+        &self,
+        population: &PopulationSpec,
+    ) -> Result<Model<String, DynamicState>, ConfigError> {
+        let name = &population.model;
+        let spec = self
+            .spec
+            .models
+            .get(name)
+            .ok_or_else(|| self.invalid(name, format!("there is no model named {:?}", name)))?;
+        if model::placeholder(population.id_pattern.as_deref().unwrap_or("{}")).is_none() {
+            return Err(self.invalid(
+                population.id_pattern.as_deref().unwrap_or_default(),
+                "id patterns need a {} placeholder, i.e. \"device_{:03}\"".to_string(),
+            ));
+        }
+
+        let builder = self.builder(name, spec)?;
+        let builder = match (&population.initial, population.stationary) {
+            (Some(_), true) => {
+                return Err(self.invalid(
+                    "stationary",
+                    "a population cannot set both initial and stationary".to_string(),
+                ));
+            }
+            (None, true) => builder.stationary(),
+            (Some(InitialSpec::Mode(mode)), false) => builder.initial(mode.clone()),
+            (Some(InitialSpec::Distribution(weights)), false) => builder.initial_distribution(
+                weights
+                    .iter()
+                    .map(|(mode, weight)| (mode.clone(), *weight))
+                    .collect(),
+            ),
+            (None, false) => builder.initial(spec.initial.clone()),
+        };
+        builder
+            .build()
+            .map_err(|error| self.model_error(name, error))
+    }
+
+    // builder describes the modes of a model with the model builder
+    fn builder(
+        &self,
+        name: &str,
+        spec: &ModelSpec,
+    ) -> Result<ModelBuilder<String, DynamicState>, ConfigError> {
+        let mut builder = Model::builder();
+        for (mode, mode_spec) in &spec.modes {
+            // field names are interned once here, so that entering a mode neither allocates nor locks for them
+            let mut fields = spec.fields.clone();
+            fields.extend(mode_spec.fields.clone());
+            let fields: Vec<(FieldName, FieldSpec)> = fields
+                .into_iter()
+                .map(|(field, spec)| (state::intern(&field), spec))
+                .collect();
+            let carried: Vec<FieldName> = fields
+                .iter()
+                .filter(|(_, field)| matches!(field, FieldSpec::Carry(_)))
+                .map(|(field, _)| *field)
+                .collect();
+
+            builder = builder.state(mode.clone()).factory(move |rng| {
+                fields
+                    .iter()
+                    .map(|(field, spec)| (*field, spec.generate(rng)))
+                    .collect()
+            });
+            if !carried.is_empty() {
+                builder = builder.carry_over(move |previous: &DynamicState, next| {
+                    for field in &carried {
+                        if let Some(value) = previous.get(field) {
+                            next.insert(field, value.clone());
+                        }
+                    }
+                });
+            }
+
+            let in_mode = |message: &str| {
+                self.invalid_in(
+                    &[name, mode],
+                    None,
+                    format!("model {:?}, mode {:?}: {}", name, mode, message),
+                )
+            };
+            let rates: Vec<f64> = mode_spec
+                .transitions
+                .iter()
+                .filter_map(|transition| transition.rate)
+                .collect();
+            if !rates.is_empty() {
+                if rates.len() < mode_spec.transitions.len() {
+                    return Err(in_mode("transitions mix weights and rates"));
+                }
+                if mode_spec.holding.is_some() {
+                    return Err(in_mode(
+                        "the holding time of a mode with rates follows from them and cannot be set",
+                    ));
+                }
+                let total: f64 = rates.iter().sum();
+                if total > 0.0 {
+                    builder = builder.mean_dwell(timer::seconds_to_duration(1.0 / total));
+                }
+            } else if let Some(holding) = &mode_spec.holding {
+                builder = builder
+                    .mean_dwell(timer::seconds_to_duration(holding.mean))
+                    .holding_time(holding.shape);
+            } else if !mode_spec.transitions.is_empty() {
+                return Err(in_mode("transitions need a holding time or rates"));
+            }
+
+            for transition in &mode_spec.transitions {
+                let weight = transition.rate.or(transition.weight).unwrap_or_default();
+                builder = builder.to(transition.to.clone(), weight);
+            }
+            if let Some(timeout) = &mode_spec.timeout {
+                builder = builder.timeout(
+                    timer::seconds_to_duration(timeout.after.0),
+                    timeout.to.clone(),
+                );
+            }
+        }
+        Ok(builder)
+    }
+
+    // model_error locates an error found when validating a model within the model, at the mode at fault
+    fn model_error(&self, name: &str, error: ModelError<String>) -> ConfigError {
+        let message = format!("model {:?}: {}", name, error);
+        match &error {
+            ModelError::MissingMode(mode) => self.invalid_in(&[name], Some(mode), message),
+            ModelError::MissingTarget { from, to } => {
+                self.invalid_in(&[name, from], Some(to), message)
+            }
+            ModelError::NoPositiveWeight(mode)
+            | ModelError::InvalidHoldingTime(mode)
+            | ModelError::InvalidWeight { from: mode, .. }
+            | ModelError::InvalidRate { mode, .. }
+            | ModelError::InvalidTimer { mode, .. } => {
+                self.invalid_in(&[name, mode], None, message)
+            }
+            ModelError::DuplicateAgent(id) | ModelError::InvalidIdPattern(id) => {
+                self.invalid(id, message)
+            }
+            _ => self.invalid_in(&[name], None, message),
+        }
+    }
+
+    // invalid reports an error on the line of the first mention of `needle` in the file
+    fn invalid(&self, needle: &str, message: String) -> ConfigError {
+        self.invalid_in(&[], Some(needle), message)
+    }
+
+    // invalid_in reports an error within a section of the file, i.e. a mode of a model given as `&[model, mode]`, on
+    // the line of the first mention of `needle` in it, or of the section itself
+    fn invalid_in(&self, keys: &[&str], needle: Option<&str>, message: String) -> ConfigError {
+        ConfigError::Invalid {
+            line: locate(&self.source, keys, needle).map(|offset| position(&self.source, offset).0),
+            column: None,
+            message,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct FileSpec {
+    seed: Option<u64>,
+    #[serde(default)]
+    start: DateTime<Utc>,
+    duration: Option<Seconds>,
+    models: BTreeMap<String, ModelSpec>,
+    #[serde(default)]
+    populations: Vec<PopulationSpec>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ModelSpec {
+    initial: String,
+    #[serde(default)]
+    fields: BTreeMap<String, FieldSpec>,
+    modes: BTreeMap<String, ModeSpec>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ModeSpec {
+    holding: Option<HoldingSpec>,
+    #[serde(default)]
+    transitions: Vec<TransitionSpec>,
+    #[serde(default)]
+    fields: BTreeMap<String, FieldSpec>,
+    timeout: Option<TimeoutSpec>,
+}
+
+// TransitionSpec is a transition with either a weight, the holding time of the mode being given, or a rate, i.e. an
+// exponential race between the transitions of the mode
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields, try_from = "RawTransition")]
+struct TransitionSpec {
+    to: String,
+    weight: Option<f64>,
+    rate: Option<f64>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawTransition {
+    to: String,
+    weight: Option<Weight>,
+    rate: Option<Rate>,
+}
+
+impl TryFrom<RawTransition> for TransitionSpec {
+    type Error = String;
+
+    fn try_from(raw: RawTransition) -> Result<Self, String> {
+        match (raw.weight, raw.rate) {
+            (Some(_), Some(_)) => Err(format!(
+                "transition to {:?} has both a weight and a rate",
+                raw.to
+            )),
+            (None, None) => Err(format!(
+                "transition to {:?} needs a weight or a rate",
+                raw.to
+            )),
+            (weight, rate) => Ok(TransitionSpec {
+                to: raw.to,
+                weight: weight.map(|Weight(weight)| weight),
+                rate: rate.map(|Rate(rate)| rate),
+            }),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct TimeoutSpec {
+    after: Seconds,
+    to: String,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct PopulationSpec {
+    model: String,
+    count: usize,
+    id_pattern: Option<String>,
+    initial: Option<InitialSpec>,
+    #[serde(default)]
+    stationary: bool,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum InitialSpec {
+    Mode(String),
+    Distribution(BTreeMap<String, f64>),
+}
+
+// Seconds is a duration, written as a number of seconds or with units, i.e. "90s", "15m", "1h30m", "2d" or "1w"
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(try_from = "Value")]
+struct Seconds(f64);
+
+impl TryFrom<Value> for Seconds {
+    type Error = String;
+
+    fn try_from(value: Value) -> Result<Self, String> {
+        let seconds = match value {
+            Value::Int(seconds) => seconds as f64,
+            Value::Float(seconds) => seconds,
+            Value::Str(text) => parse_seconds(&text)?,
+            _ => return Err("expected a duration, i.e. \"15m\"".to_string()),
+        };
+        if !(seconds >= 0.0 && seconds.is_finite()) {
+            return Err(format!("invalid duration {}", seconds));
+        }
+        Ok(Seconds(seconds))
+    }
+}
+
+// Rate is a number of events per unit of time, written as a number per second or with a unit, i.e. "3/h"
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(try_from = "Value")]
+struct Rate(f64);
+
+impl TryFrom<Value> for Rate {
+    type Error = String;
+
+    fn try_from(value: Value) -> Result<Self, String> {
+        let rate = match value {
+            Value::Int(rate) => rate as f64,
+            Value::Float(rate) => rate,
+            Value::Str(text) => {
+                let (count, unit) = text
+                    .split_once('/')
+                    .ok_or_else(|| format!("invalid rate {:?}, expected i.e. \"3/h\"", text))?;
+                let count: f64 = count
+                    .trim()
+                    .parse()
+                    .map_err(|_| format!("invalid rate {:?}, expected i.e. \"3/h\"", text))?;
+                count
+                    / unit_seconds(unit.trim()).ok_or_else(|| {
+                        format!("invalid rate {:?}, unknown unit {:?}", text, unit.trim())
+                    })?
+            }
+            _ => return Err("expected a rate, i.e. \"3/h\"".to_string()),
+        };
+        if !(rate >= 0.0 && rate.is_finite()) {
+            return Err(format!("invalid rate {}", rate));
+        }
+        Ok(Rate(rate))
+    }
+}
+
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(try_from = "f64")]
+struct Weight(f64);
+
+impl TryFrom<f64> for Weight {
+    type Error = String;
+
+    fn try_from(weight: f64) -> Result<Self, String> {
+        if weight >= 0.0 && weight.is_finite() {
+            Ok(Weight(weight))
+        } else {
+            Err(format!(
+                "invalid weight {}, weights cannot be negative",
+                weight
+            ))
+        }
+    }
+}
+
+// HoldingSpec is the distribution of the time spent in a mode before its transitions fire, written as its mean for
+// an exponential distribution, or as a table naming the distribution
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(try_from = "ScalarOrTable<HoldingTable>")]
+struct HoldingSpec {
+    mean: f64,
+    shape: HoldingTime,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct HoldingTable {
+    distribution: Distribution,
+    mean: Option<Seconds>,
+    min: Option<Seconds>,
+    max: Option<Seconds>,
+    shape: Option<u32>,
+    sigma: Option<f64>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum Distribution {
+    Exponential,
+    Deterministic,
+    Uniform,
+    Erlang,
+    LogNormal,
+}
+
+impl TryFrom<ScalarOrTable<HoldingTable>> for HoldingSpec {
+    type Error = String;
+
+    fn try_from(holding: ScalarOrTable<HoldingTable>) -> Result<Self, String> {
+        let table = match holding {
+            ScalarOrTable::Scalar(mean) => {
+                return Ok(HoldingSpec {
+                    mean: Seconds::try_from(mean)?.0,
+                    shape: HoldingTime::Exponential,
+                });
+            }
+            ScalarOrTable::Table(table) => table,
+        };
+
+        let mean = || {
+            table
+                .mean
+                .map(|Seconds(mean)| mean)
+                .ok_or_else(|| "the holding time needs a mean".to_string())
+        };
+        let (mean, shape) = match table.distribution {
+            Distribution::Exponential => (mean()?, HoldingTime::Exponential),
+            Distribution::Deterministic => (mean()?, HoldingTime::Deterministic),
+            Distribution::Uniform => {
+                let (Some(Seconds(min)), Some(Seconds(max))) = (table.min, table.max) else {
+                    return Err("a uniform holding time needs a min and a max".to_string());
+                };
+                if min > max {
+                    return Err(format!("min {} is greater than max {}", min, max));
+                }
+                let mean = (min + max) / 2.0;
+                let spread = if mean > 0.0 {
+                    (max - min) / (max + min)
+                } else {
+                    0.0
+                };
+                (mean, HoldingTime::Uniform { spread })
+            }
+            Distribution::Erlang => {
+                let shape = table.shape.filter(|shape| *shape > 0).ok_or_else(|| {
+                    "an erlang holding time needs a shape of 1 or more".to_string()
+                })?;
+                (mean()?, HoldingTime::Erlang { shape })
+            }
+            Distribution::LogNormal => {
+                let sigma = table
+                    .sigma
+                    .filter(|sigma| *sigma >= 0.0 && sigma.is_finite())
+                    .ok_or_else(|| {
+                        "a lognormal holding time needs a sigma of 0 or more".to_string()
+                    })?;
+                (mean()?, HoldingTime::LogNormal { sigma })
+            }
+        };
+        Ok(HoldingSpec { mean, shape })
+    }
+}
+
+// FieldSpec generates the value of a field when an agent enters a mode, written as a constant, or as a table naming
+// a generator: `uniform = [min, max]` (floats), `range = [min, max]` (integers, both included),
+// `normal = { mean, std_dev }`, `exponential = mean`, `choice = [values]` with optional `weights`, `constant = value`,
+// or `carry = generator` to keep the value the field had in the previous mode, generating it if it had none
+#[derive(Debug, Clone, Deserialize)]
+#[serde(try_from = "ScalarOrTable<FieldTable>")]
+enum FieldSpec {
+    Constant(Value),
+    Uniform(f64, f64),
+    Range(i64, i64),
+    Normal { mean: f64, std_dev: f64 },
+    Exponential(f64),
+    Choice(Vec<Value>, Option<Vec<f64>>),
+    Carry(Box<FieldSpec>),
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct FieldTable {
+    constant: Option<Value>,
+    uniform: Option<[f64; 2]>,
+    range: Option<[i64; 2]>,
+    normal: Option<NormalSpec>,
+    exponential: Option<f64>,
+    choice: Option<Vec<Value>>,
+    weights: Option<Vec<Weight>>,
+    carry: Option<Box<FieldSpec>>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct NormalSpec {
+    mean: f64,
+    std_dev: f64,
+}
+
+impl TryFrom<ScalarOrTable<FieldTable>> for FieldSpec {
+    type Error = String;
+
+    fn try_from(field: ScalarOrTable<FieldTable>) -> Result<Self, String> {
+        let table = match field {
+            ScalarOrTable::Scalar(value) => return Ok(FieldSpec::Constant(value)),
+            ScalarOrTable::Table(table) => table,
+        };
+
+        let mut generators = Vec::new();
+        if let Some(value) = table.constant {
+            generators.push(FieldSpec::Constant(value));
+        }
+        if let Some([min, max]) = table.uniform {
+            // gen_range panics if the span of the bounds overflows, i.e. for [-1e308, 1e308]
+            if !(max - min).is_finite() || min > max {
+                return Err(format!("invalid uniform bounds [{}, {}]", min, max));
+            }
+            generators.push(FieldSpec::Uniform(min, max));
+        }
+        if let Some([min, max]) = table.range {
+            if min > max {
+                return Err(format!("range min {} is greater than max {}", min, max));
+            }
+            generators.push(FieldSpec::Range(min, max));
+        }
+        if let Some(NormalSpec { mean, std_dev }) = table.normal {
+            if !(std_dev >= 0.0 && std_dev.is_finite()) {
+                return Err(format!("invalid standard deviation {}", std_dev));
+            }
+            generators.push(FieldSpec::Normal { mean, std_dev });
+        }
+        if let Some(mean) = table.exponential {
+            if !(mean > 0.0 && mean.is_finite()) {
+                return Err(format!("invalid exponential mean {}", mean));
+            }
+            generators.push(FieldSpec::Exponential(mean));
+        }
+        if let Some(values) = table.choice {
+            let weights = table.weights.map(|weights| {
+                weights
+                    .into_iter()
+                    .map(|Weight(weight)| weight)
+                    .collect::<Vec<_>>()
+            });
+            match &weights {
+                _ if values.is_empty() => return Err("choice needs at least one value".to_string()),
+                Some(weights) if weights.len() != values.len() => {
+                    return Err(format!(
+                        "choice has {} values but {} weights",
+                        values.len(),
+                        weights.len()
+                    ));
+                }
+                Some(weights) if !weights.iter().any(|weight| *weight > 0.0) => {
+                    return Err("choice weights are all 0".to_string());
+                }
+                _ => {}
+            }
+            generators.push(FieldSpec::Choice(values, weights));
+        } else if table.weights.is_some() {
+            return Err("weights are only used with choice".to_string());
+        }
+        if let Some(inner) = table.carry {
+            generators.push(FieldSpec::Carry(inner));
+        }
+
+        match generators.len() {
+            1 => Ok(generators.remove(0)),
+            0 => Err(
+                "expected a generator: constant, uniform, range, normal, exponential, choice or carry"
+                    .to_string(),
+            ),
+            _ => Err("a field has a single generator".to_string()),
+        }
+    }
+}
+
+impl FieldSpec {
+    fn generate(&self, rng: &mut dyn RngCore) -> Value {
+        match self {
+            FieldSpec::Constant(value) => value.clone(),
+            FieldSpec::Uniform(min, max) if min == max => Value::Float(*min),
+            FieldSpec::Uniform(min, max) => Value::Float(rng.gen_range(*min..*max)),
+            FieldSpec::Range(min, max) => Value::Int(rng.gen_range(*min..=*max)),
+            FieldSpec::Normal { mean, std_dev } => {
+                Value::Float(mean + std_dev * agent::standard_normal(rng))
+            }
+            FieldSpec::Exponential(mean) => Value::Float(-mean * (1.0 - rng.r#gen::<f64>()).ln()),
+            FieldSpec::Choice(values, None) => {
+                values.choose(rng).cloned().unwrap_or(Value::Bool(false))
+            }
+            FieldSpec::Choice(values, Some(weights)) => {
+                let index = (0..values.len())
+                    .collect::<Vec<_>>()
+                    .choose_weighted(rng, |index| weights[*index])
+                    .copied()
+                    .unwrap_or_default();
+                values[index].clone()
+            }
+            FieldSpec::Carry(inner) => inner.generate(rng),
+        }
+    }
+}
+
+// ScalarOrTable is a value written either inline, i.e. `holding = "1h"`, or as a table, i.e.
+// `holding = { distribution = "erlang", mean = "1h", shape = 3 }`. Tables are deserialized directly so that their
+// errors keep their location.
+enum ScalarOrTable<T> {
+    Scalar(Value),
+    Table(T),
+}
+
+impl<'de, T: Deserialize<'de>> Deserialize<'de> for ScalarOrTable<T> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct Visitor<T>(PhantomData<T>);
+
+        impl<'de, T: Deserialize<'de>> de::Visitor<'de> for Visitor<T> {
+            type Value = ScalarOrTable<T>;
+
+            fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                f.write_str("a value or a table")
+            }
+
+            fn visit_bool<E: de::Error>(self, value: bool) -> Result<Self::Value, E> {
+                Ok(ScalarOrTable::Scalar(Value::Bool(value)))
+            }
+
+            fn visit_i64<E: de::Error>(self, value: i64) -> Result<Self::Value, E> {
+                Ok(ScalarOrTable::Scalar(Value::Int(value)))
+            }
+
+            fn visit_u64<E: de::Error>(self, value: u64) -> Result<Self::Value, E> {
+                i64::try_from(value)
+                    .map(|value| ScalarOrTable::Scalar(Value::Int(value)))
+                    .map_err(E::custom)
+            }
+
+            fn visit_f64<E: de::Error>(self, value: f64) -> Result<Self::Value, E> {
+                Ok(ScalarOrTable::Scalar(Value::Float(value)))
+            }
+
+            fn visit_str<E: de::Error>(self, value: &str) -> Result<Self::Value, E> {
+                Ok(ScalarOrTable::Scalar(Value::Str(value.to_string())))
+            }
+
+            fn visit_map<A: MapAccess<'de>>(self, map: A) -> Result<Self::Value, A::Error> {
+                T::deserialize(de::value::MapAccessDeserializer::new(map)).map(ScalarOrTable::Table)
+            }
+        }
+
+        deserializer.deserialize_any(Visitor(PhantomData))
+    }
+}
+
+// parse_duration parses a duration written as in model files, i.e. "90s", "1h30m" or a plain number of seconds
+pub fn parse_duration(text: &str) -> Result<Duration, String> {
+    match Seconds::try_from(Value::Str(text.to_string())) {
+        Ok(Seconds(seconds)) => Ok(timer::seconds_to_duration(seconds)),
+        Err(error) => Err(error),
+    }
+}
+
+// parse_seconds parses a duration with units, i.e. "90s", "1h30m" or "1.5d", or a plain number of seconds
+fn parse_seconds(text: &str) -> Result<f64, String> {
+    let invalid = || {
+        format!(
+            "invalid duration {:?}, expected i.e. \"90s\", \"15m\" or \"1h30m\"",
+            text
+        )
+    };
+    let text = text.trim();
+    if let Ok(seconds) = text.parse::<f64>() {
+        return Ok(seconds);
+    }
+
+    let mut seconds = 0.0;
+    let mut rest = text;
+    while !rest.is_empty() {
+        let number_end = rest
+            .find(|c: char| !(c.is_ascii_digit() || c == '.'))
+            .ok_or_else(invalid)?;
+        let unit_end = rest[number_end..]
+            .find(|c: char| c.is_ascii_digit() || c == '.')
+            .map_or(rest.len(), |end| number_end + end);
+        let number: f64 = rest[..number_end].parse().map_err(|_| invalid())?;
+        seconds += number * unit_seconds(rest[number_end..unit_end].trim()).ok_or_else(invalid)?;
+        rest = &rest[unit_end..];
+    }
+    Ok(seconds)
+}
+
+fn unit_seconds(unit: &str) -> Option<f64> {
+    match unit {
+        "ms" => Some(0.001),
+        "s" => Some(1.0),
+        "m" | "min" => Some(60.0),
+        "h" => Some(3_600.0),
+        "d" => Some(86_400.0),
+        "w" => Some(604_800.0),
+        _ => None,
+    }
+}
+
+// locate finds the section of the source named by a path of keys, each searched for after the previous one, and
+// then the first mention of `needle` as a whole word in it, returning its byte offset. Keys are told from values by
+// what follows them in TOML, YAML and JSON alike (`.`, `]`, `=` or `:`), so that a mode named like the mode of
+// another model, or like a target, is found in the model at fault.
+fn locate(source: &str, keys: &[&str], needle: Option<&str>) -> Option<usize> {
+    let mut start = 0;
+    for key in keys {
+        start = words(source, key, start).find(|&offset| {
+            source[offset + key.len()..]
+                .trim_start_matches(['"', '\''])
+                .trim_start_matches([' ', '\t'])
+                .starts_with(['.', ']', '=', ':'])
+        })?;
+    }
+    match needle {
+        Some(needle) => words(source, needle, start).next(),
+        None if keys.is_empty() => None,
+        None => Some(start),
+    }
+}
+
+// words finds the mentions of `word` as a whole word in the source from the byte offset `start`
+fn words<'a>(source: &'a str, word: &'a str, start: usize) -> impl Iterator<Item = usize> + 'a {
+    let is_word = |c: char| c.is_alphanumeric() || c == '_';
+    source[start..]
+        .match_indices(word)
+        .map(move |(offset, _)| start + offset)
+        .filter(move |&offset| {
+            !word.is_empty()
+                && !source[..offset].chars().next_back().is_some_and(is_word)
+                && !source[offset + word.len()..]
+                    .chars()
+                    .next()
+                    .is_some_and(is_word)
+        })
+}
+
+// position converts a byte offset in the source into a line and column, both starting at 1
+fn position(source: &str, offset: usize) -> (usize, usize) {
+    let before = &source[..offset.min(source.len())];
+    let line = before.matches('\n').count() + 1;
+    let column = before.len() - before.rfind('\n').map_or(0, |newline| newline + 1) + 1;
+    (line, column)
+}
+
+// strip_location drops the location that serde_json and serde_yaml_ng append to their messages, as it is reported
+// separately
+fn strip_location(message: &str) -> String {
+    match message.rfind(" at line ") {
+        Some(index) => message[..index].to_string(),
+        None => message.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DEVICES: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/examples/models/devices.toml");
+
+    fn invalid(source: &str, format: Format) -> (Option<usize>, String) {
+        match ModelFile::parse(source, format).unwrap_err() {
+            ConfigError::Invalid { line, message, .. } => (line, message),
+            error => panic!("unexpected error {}", error),
+        }
+    }
+
+    #[test]
+    fn test_load_and_run_model_file() {
+        let file = ModelFile::load(DEVICES).unwrap();
+        assert_eq!(file.seed(), 42);
+        assert_eq!(file.duration(), Some(Duration::days(7)));
+
+        let model = file.model("device").unwrap();
+        let heavy_load = &model.transitions()[&"heavy_load".to_string()];
+        assert!((heavy_load.event_rate - 600.0).abs() < 1e-3);
+        assert_eq!(
+            heavy_load.transitions[0],
+            ("working".to_string(), 4.8 / 3600.0)
+        );
+        assert_eq!(
+            model.transitions()[&"working".to_string()].holding_time,
+            HoldingTime::Erlang { shape: 2 }
+        );
+
+        let agents = file.agents().unwrap();
+        assert_eq!(agents.len(), 5);
+        assert_eq!(agents[1].id, "device_001");
+        assert_eq!(agents[0].current_state_type(), "idle");
+        assert_eq!(
+            agents[0].data.get("connected_status"),
+            Some(&Value::Bool(true))
+        );
+        assert!(matches!(
+            agents[0].data.get("memory_in_use_mb"),
+            Some(Value::Int(400..=799))
+        ));
+
+        let run = || {
+            let mut sim = file.simulation().unwrap();
+            sim.run(file.duration().unwrap());
+            sim.into_events()
+                .into_iter()
+                .map(|event| (event.time, event.agent_id, event.field, event.new_value))
+                .collect::<Vec<_>>()
+        };
+        let events = run();
+        assert!(events.len() > 100);
+        assert_eq!(events, run());
+
+        // carried fields keep their value across modes
+        assert!(!events.iter().any(|(_, _, field, _)| *field == "reboots"));
+        assert!(
+            events
+                .iter()
+                .any(|(_, _, field, value)| *field == "connected_status" && value == "false")
+        );
+    }
+
+    #[test]
+    fn test_formats_describe_the_same_model() {
+        let toml = r#"
+            seed = 7
+            duration = 3600
+
+            [models.lamp]
+            initial = "off"
+            modes.off = { holding = "10m", transitions = [{ to = "on", weight = 1 }], fields = { lit = false } }
+            modes.on = { holding = { distribution = "deterministic", mean = 60 }, transitions = [{ to = "off", weight = 1 }], fields = { lit = true } }
+
+            [[populations]]
+            model = "lamp"
+            count = 3
+        "#;
+        let yaml = r#"
+seed: 7
+duration: 3600
+models:
+  lamp:
+    initial: "off"
+    modes:
+      "off": { holding: 10m, transitions: [{ to: "on", weight: 1 }], fields: { lit: false } }
+      "on":
+        holding: { distribution: deterministic, mean: 60 }
+        transitions: [{ to: "off", weight: 1 }]
+        fields: { lit: true }
+populations:
+  - { model: lamp, count: 3 }
+"#;
+        let json = r#"{
+            "seed": 7,
+            "duration": 3600,
+            "models": { "lamp": { "initial": "off", "modes": {
+                "off": { "holding": "10m", "transitions": [{ "to": "on", "weight": 1 }], "fields": { "lit": false } },
+                "on": { "holding": { "distribution": "deterministic", "mean": 60 },
+                        "transitions": [{ "to": "off", "weight": 1 }], "fields": { "lit": true } }
+            } } },
+            "populations": [{ "model": "lamp", "count": 3 }]
+        }"#;
+
+        let run = |source, format| {
+            let file = ModelFile::parse(source, format).unwrap();
+            let mut sim = file.simulation().unwrap();
+            sim.run(file.duration().unwrap());
+            sim.into_events()
+                .into_iter()
+                .map(|event| (event.time, event.agent_id, event.new_value))
+                .collect::<Vec<_>>()
+        };
+        let events = run(toml, Format::Toml);
+        assert!(events.iter().any(|(_, id, _)| id == "lamp_2"));
+        assert_eq!(events, run(yaml, Format::Yaml));
+        assert_eq!(events, run(json, Format::Json));
+
+        // lamps stay on for exactly a minute
+        let on: Vec<_> = events.iter().filter(|(_, id, _)| id == "lamp_0").collect();
+        for pair in on.windows(2) {
+            if pair[0].2 == "true" {
+                assert_eq!(pair[1].0 - pair[0].0, Duration::seconds(60));
+            }
+        }
+    }
+
+    #[test]
+    fn test_errors_point_at_the_offending_line() {
+        let model = |modes: &str| {
+            format!(
+                "[models.disk]\ninitial = \"idle\"\n{}\n[[populations]]\nmodel = \"disk\"\ncount = 1\n",
+                modes
+            )
+        };
+
+        let (line, message) = invalid(
+            &model("[models.disk.modes.idle]\nholding = \"5x\"\n"),
+            Format::Toml,
+        );
+        assert_eq!(line, Some(4));
+        assert!(message.contains("invalid duration \"5x\""), "{}", message);
+
+        let (line, message) = invalid(
+            &model(
+                "[models.disk.modes.idle]\nholding = \"1h\"\ntransitions = [{ to = \"spinning\", weight = 1 }]\n",
+            ),
+            Format::Toml,
+        );
+        assert_eq!(line, Some(5));
+        assert!(message.contains("\"spinning\""), "{}", message);
+
+        let (line, message) = invalid(
+            &model(
+                "[models.disk.modes.idle]\nholding = \"1h\"\ntransitions = [{ to = \"idle\", weight = -1 }]\n",
+            ),
+            Format::Toml,
+        );
+        assert_eq!(line, Some(5));
+        assert!(message.contains("cannot be negative"), "{}", message);
+
+        let (line, message) = invalid(
+            &model(
+                "[models.disk.modes.idle]\nfields = { temperature = { normal = { mean = 40 } } }\n",
+            ),
+            Format::Toml,
+        );
+        assert_eq!(line, Some(4));
+        assert!(message.contains("std_dev"), "{}", message);
+
+        let (line, message) = invalid(
+            &model(
+                "[models.disk.modes.idle]\nfields = { temperature = { uniform = [-1e308, 1e308] } }\n",
+            ),
+            Format::Toml,
+        );
+        assert_eq!(line, Some(4));
+        assert!(message.contains("invalid uniform bounds"), "{}", message);
+
+        let (line, message) = invalid(
+            "models:\n  disk:\n    initial: idle\n    modes:\n      idle: { holding: { distribution: gamma, mean: 1 } }\n",
+            Format::Yaml,
+        );
+        assert_eq!(line, Some(5));
+        assert!(message.contains("unknown variant `gamma`"), "{}", message);
+
+        let (line, _) = invalid(
+            "{\n  \"models\": {},\n  \"populations\": [\n    { \"model\": \"disk\", \"count\": 1 }\n  ]\n}",
+            Format::Json,
+        );
+        assert_eq!(line, Some(4));
+
+        let error = ModelFile::load("disk.ini").unwrap_err();
+        assert!(matches!(error, ConfigError::UnknownFormat(_)));
+        assert_eq!(parse_seconds("1h30m"), Ok(5400.0));
+        assert_eq!(parse_duration("1.5d"), Ok(Duration::hours(36)));
+    }
+
+    #[test]
+    fn test_errors_point_at_the_model_at_fault() {
+        let models = |disk_spinning: &str| {
+            format!(
+                r#"[models.fan]
+initial = "idle"
+
+[models.fan.modes.idle]
+holding = "1h"
+transitions = [{{ to = "spinning", weight = 1 }}]
+
+[models.fan.modes.spinning]
+holding = "1h"
+transitions = [{{ to = "parked", weight = 1 }}]
+
+[models.fan.modes.parked]
+
+[models.disk]
+initial = "idle"
+
+[models.disk.modes.idle]
+holding = "1h"
+transitions = [{{ to = "spinning", weight = 1 }}]
+
+[models.disk.modes.spinning]
+holding = "1h"
+{}
+"#,
+                disk_spinning
+            )
+        };
+        assert!(
+            ModelFile::parse(
+                &models(r#"transitions = [{ to = "idle", weight = 1 }]"#),
+                Format::Toml
+            )
+            .is_ok()
+        );
+
+        // the fan has a parked mode, the disk does not
+        let (line, message) = invalid(
+            &models(r#"transitions = [{ to = "parked", weight = 1 }]"#),
+            Format::Toml,
+        );
+        assert_eq!(line, Some(23));
+        assert!(message.contains("model \"disk\""), "{}", message);
+
+        // both have a spinning mode, only that of the disk is wrong
+        let (line, message) = invalid(
+            &models(r#"transitions = [{ to = "idle", weight = 0 }]"#),
+            Format::Toml,
+        );
+        assert_eq!(line, Some(21));
+        assert!(message.contains("model \"disk\""), "{}", message);
+    }
+}
@@ -0,0 +1,495 @@
+use crate::matrix::TransitionMatrix;
+use crate::state::State;
+use crate::timer;
+use chrono::Duration;
+use std::collections::HashMap;
+use std::hash::Hash;
+
+/// Generator is the infinitesimal generator matrix of the continuous-time Markov chain an agent follows, derived from
+/// its transition matrix: an agent leaves a state type after an exponential delay of mean `event_rate` seconds and
+/// moves to each target with probability proportional to its weight, so the rate from i to j is the share of j in
+/// the weights of i divided by the mean delay. Self-transitions do not change the mode and are left out.
+///
+/// Only the static structure of the model is taken into account: timers, guards, weight and rate functions make an
+/// agent's behaviour depend on its history, which the chain cannot represent. Holding times that are not exponential
+/// are replaced by exponential ones with the same mean: the stationary distribution, visit frequencies and mean
+/// times to absorption or first passage remain exact, transient distributions become approximations.
+#[derive(Debug, Clone)]
+pub struct Generator<C> {
+    modes: Vec<C>,
+    index: HashMap<C, usize>,
+    rates: Vec<Vec<f64>>,
+}
+
+impl<C> Generator<C>
+where
+    C: Eq + Hash + Clone,
+{
+    pub fn from_transitions<S: State>(transition_matrix: &TransitionMatrix<C, S>) -> Self {
+        let mut modes: Vec<C> = Vec::new();
+        let mut index = HashMap::new();
+        for (mode, def) in transition_matrix.iter() {
+            for mode in
+                std::iter::once(mode).chain(def.transitions.iter().map(|(target, _)| target))
+            {
+                if !index.contains_key(mode) {
+                    index.insert(mode.clone(), modes.len());
+                    modes.push(mode.clone());
+                }
+            }
+        }
+
+        let mut rates = vec![vec![0.0; modes.len()]; modes.len()];
+        for (mode, def) in transition_matrix.iter() {
+            let total: f64 = def.transitions.iter().map(|(_, weight)| weight).sum();
+            if def.event_rate <= 0.0 || total <= 0.0 {
+                continue;
+            }
+
+            let from = index[mode];
+            for (target, weight) in &def.transitions {
+                let to = index[target];
+                if to != from {
+                    rates[from][to] += weight / total / def.event_rate;
+                }
+            }
+        }
+        for (from, row) in rates.iter_mut().enumerate() {
+            row[from] = -row.iter().sum::<f64>();
+        }
+
+        Generator {
+            modes,
+            index,
+            rates,
+        }
+    }
+
+    // modes returns the modes of the chain, in the order of the rows and columns of the matrix
+    pub fn modes(&self) -> &[C] {
+        &self.modes
+    }
+
+    // rate returns the rate of moving from one mode to another, or the negated total rate of leaving `from` if they
+    // are the same mode
+    pub fn rate(&self, from: &C, to: &C) -> f64 {
+        match (self.index.get(from), self.index.get(to)) {
+            (Some(&from), Some(&to)) => self.rates[from][to],
+            _ => 0.0,
+        }
+    }
+
+    // stationary_distribution returns the long-run fraction of time spent in each mode, or None if it is not unique,
+    // i.e. because the chain has several absorbing modes
+    pub fn stationary_distribution(&self) -> Option<Vec<(C, f64)>> {
+        let n = self.modes.len();
+        if n == 0 {
+            return None;
+        }
+
+        // pi Q = 0 with one balance equation replaced by the normalization sum(pi) = 1
+        let mut system: Vec<Vec<f64>> = (0..n)
+            .map(|row| {
+                let mut equation: Vec<f64> = (0..n).map(|col| self.rates[col][row]).collect();
+                equation.push(0.0);
+                equation
+            })
+            .collect();
+        system[n - 1] = vec![1.0; n + 1];
+
+        let solution = solve(system)?;
+        Some(self.modes.iter().cloned().zip(solution).collect())
+    }
+
+    // transient_distribution returns the probability of being in each mode after `time`, starting from the given
+    // distribution over modes (weights are normalized). It is computed with uniformization: the chain is embedded in
+    // a Poisson process of rate at least that of leaving any mode, and the distributions after each number of jumps
+    // are weighted with Poisson probabilities.
+    pub fn transient_distribution(&self, initial: &[(C, f64)], time: Duration) -> Vec<(C, f64)> {
+        let total: f64 = initial.iter().map(|(_, weight)| weight).sum();
+        let mut distribution = vec![0.0; self.modes.len()];
+        for (mode, weight) in initial {
+            if let Some(&i) = self.index.get(mode) {
+                distribution[i] += weight / total;
+            }
+        }
+
+        let rate = self
+            .rates
+            .iter()
+            .enumerate()
+            .map(|(i, row)| -row[i])
+            .fold(0.0, f64::max);
+        let expected_jumps = rate * timer::duration_to_seconds(time);
+
+        // Poisson weights underflow for many expected jumps, so long horizons are covered in steps
+        let steps = (expected_jumps / UNIFORMIZATION_STEP).ceil().max(1.0);
+        for _ in 0..steps as usize {
+            distribution = self.uniformize(&distribution, rate, expected_jumps / steps);
+        }
+
+        self.modes.iter().cloned().zip(distribution).collect()
+    }
+
+    // holding_times returns the expected time in seconds an agent stays in each mode once it entered it, which is
+    // infinite for absorbing modes. Self-transitions lengthen the stay beyond the mean delay of the state type.
+    pub fn holding_times(&self) -> Vec<(C, f64)> {
+        self.modes
+            .iter()
+            .enumerate()
+            .map(|(i, mode)| (mode.clone(), -1.0 / self.rates[i][i]))
+            .collect()
+    }
+
+    // visit_frequencies returns the long-run expected number of times per second each mode is entered, or None
+    // without a unique stationary distribution
+    pub fn visit_frequencies(&self) -> Option<Vec<(C, f64)>> {
+        let stationary = self.stationary_distribution()?;
+        Some(
+            stationary
+                .into_iter()
+                .enumerate()
+                .map(|(i, (mode, share))| (mode, -share * self.rates[i][i]))
+                .collect(),
+        )
+    }
+
+    // absorbing_modes returns the modes an agent never leaves, i.e. failures without repair
+    pub fn absorbing_modes(&self) -> Vec<C> {
+        self.modes
+            .iter()
+            .enumerate()
+            .filter(|(i, _)| self.rates[*i][*i] == 0.0)
+            .map(|(_, mode)| mode.clone())
+            .collect()
+    }
+
+    // mean_time_to_absorption returns the expected time in seconds until an agent starting in `from` enters an
+    // absorbing mode, i.e. the mean time to failure of a non-repairable system, or None if it may never be absorbed
+    pub fn mean_time_to_absorption(&self, from: &C) -> Option<f64> {
+        let absorbing = self.absorbing_modes();
+        if absorbing.is_empty() {
+            return None;
+        }
+        self.mean_first_passage(from, &absorbing)
+    }
+
+    // mean_first_passage returns the expected time in seconds until an agent starting in `from` first enters one of
+    // `targets`, or None if it may never do so
+    pub fn mean_first_passage(&self, from: &C, targets: &[C]) -> Option<f64> {
+        let start = *self.index.get(from)?;
+        let targeted = self.indices(targets);
+        if targeted[start] {
+            return Some(0.0);
+        }
+
+        // the expected passage times m solve Q m = -1 over the modes outside of the targets
+        let others: Vec<usize> = (0..self.modes.len()).filter(|i| !targeted[*i]).collect();
+        let system = others
+            .iter()
+            .map(|&i| {
+                let mut equation: Vec<f64> = others.iter().map(|&j| self.rates[i][j]).collect();
+                equation.push(-1.0);
+                equation
+            })
+            .collect();
+
+        let times = solve(system)?;
+        let time = times[others.iter().position(|&i| i == start)?];
+        (time.is_finite() && time >= 0.0).then_some(time)
+    }
+
+    // first_passage_probability returns the probability that an agent starting in `from` enters one of `targets`
+    // within `time`, i.e. the distribution function of the time to failure
+    pub fn first_passage_probability(&self, from: &C, targets: &[C], time: Duration) -> f64 {
+        let targeted = self.indices(targets);
+        let mut absorbed = self.clone();
+        for (i, row) in absorbed.rates.iter_mut().enumerate() {
+            if targeted[i] {
+                row.fill(0.0);
+            }
+        }
+
+        absorbed
+            .transient_distribution(&[(from.clone(), 1.0)], time)
+            .into_iter()
+            .enumerate()
+            .filter(|(i, _)| targeted[*i])
+            .map(|(_, (_, probability))| probability)
+            .sum()
+    }
+
+    // hitting_probability returns the probability that an agent starting in `from` enters one of `targets` before
+    // any of `avoided`, i.e. reaching a failure mode before a repair mode, or None if it is not determined by the
+    // chain (a closed group of modes outside of both)
+    pub fn hitting_probability(&self, from: &C, targets: &[C], avoided: &[C]) -> Option<f64> {
+        let start = *self.index.get(from)?;
+        let targeted = self.indices(targets);
+        let avoid = self.indices(avoided);
+
+        // h = 1 on the targets, 0 on the avoided and absorbing modes, and Q h = 0 everywhere else
+        let n = self.modes.len();
+        let system = (0..n)
+            .map(|i| {
+                let mut equation = vec![0.0; n + 1];
+                if targeted[i] || avoid[i] || self.rates[i][i] == 0.0 {
+                    equation[i] = 1.0;
+                    equation[n] = if targeted[i] { 1.0 } else { 0.0 };
+                } else {
+                    equation[..n].copy_from_slice(&self.rates[i]);
+                }
+                equation
+            })
+            .collect();
+
+        solve(system).map(|probabilities| probabilities[start])
+    }
+
+    // indices flags the given modes by index
+    fn indices(&self, modes: &[C]) -> Vec<bool> {
+        let mut flags = vec![false; self.modes.len()];
+        for mode in modes {
+            if let Some(&i) = self.index.get(mode) {
+                flags[i] = true;
+            }
+        }
+        flags
+    }
+
+    // flow_out returns the rate at which a distribution over the modes, given in the order of `modes`, flows out of
+    // the `from` modes
+    pub(crate) fn flow_out(&self, distribution: &[(C, f64)], from: &[C]) -> f64 {
+        let inside = self.indices(from);
+        distribution
+            .iter()
+            .enumerate()
+            .filter(|(i, _)| inside[*i])
+            .map(|(i, (_, share))| {
+                let leaving: f64 = (0..self.modes.len())
+                    .filter(|j| !inside[*j])
+                    .map(|j| self.rates[i][j])
+                    .sum();
+                share * leaving
+            })
+            .sum()
+    }
+
+    // uniformize advances a distribution by a Poisson number of jumps of the uniformized chain, with the given mean
+    fn uniformize(&self, distribution: &[f64], rate: f64, expected_jumps: f64) -> Vec<f64> {
+        if rate <= 0.0 || expected_jumps <= 0.0 {
+            return distribution.to_vec();
+        }
+
+        let mut poisson = (-expected_jumps).exp();
+        let mut covered = poisson;
+        let mut jumped = distribution.to_vec();
+        let mut result: Vec<f64> = jumped.iter().map(|p| p * poisson).collect();
+
+        let mut jumps = 0.0;
+        while covered < 1.0 - UNIFORMIZATION_TOLERANCE && poisson > 0.0 {
+            jumps += 1.0;
+            jumped = self.jump(&jumped, rate);
+            poisson *= expected_jumps / jumps;
+            covered += poisson;
+            for (total, p) in result.iter_mut().zip(&jumped) {
+                *total += p * poisson;
+            }
+        }
+
+        result
+    }
+
+    // jump applies one jump of the uniformized chain, P = I + Q / rate, to a distribution
+    fn jump(&self, distribution: &[f64], rate: f64) -> Vec<f64> {
+        let mut next = distribution.to_vec();
+        for (from, p) in distribution.iter().enumerate() {
+            for (to, q) in self.rates[from].iter().enumerate() {
+                next[to] += p * q / rate;
+            }
+        }
+        next
+    }
+}
+
+// the expected number of jumps handled in one uniformization step, small enough for e^-n not to underflow
+const UNIFORMIZATION_STEP: f64 = 100.0;
+// the Poisson probability mass left out when truncating uniformization
+const UNIFORMIZATION_TOLERANCE: f64 = 1e-12;
+
+// solve solves a linear system given as an augmented matrix with Gaussian elimination and partial pivoting, or
+// returns None if it is singular
+pub(crate) fn solve(mut system: Vec<Vec<f64>>) -> Option<Vec<f64>> {
+    let n = system.len();
+
+    for col in 0..n {
+        let pivot =
+            (col..n).max_by(|&a, &b| system[a][col].abs().total_cmp(&system[b][col].abs()))?;
+        if system[pivot][col].abs() < 1e-12 {
+            return None;
+        }
+        system.swap(col, pivot);
+
+        let pivot_row = system[col].clone();
+        for (row, equation) in system.iter_mut().enumerate() {
+            let factor = equation[col] / pivot_row[col];
+            if row != col && factor != 0.0 {
+                for (value, pivot) in equation[col..].iter_mut().zip(&pivot_row[col..]) {
+                    *value -= factor * pivot;
+                }
+            }
+        }
+    }
+
+    Some(
+        (0..n)
+            .map(|row| system[row][n] / system[row][row])
+            .collect(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::agent::{Agent, StateType};
+    use crate::analysis::Trajectory;
+    use crate::simulation::Simulation;
+    use chrono::{TimeZone, Utc};
+    use rand::SeedableRng;
+    use rand::rngs::StdRng;
+    use state_macros::State;
+
+    #[derive(Clone, Default, Debug, State)]
+    struct Machine {
+        mode: &'static str,
+    }
+
+    fn mode(
+        mode: &'static str,
+        transitions: Vec<(&'static str, f64)>,
+        event_rate: f64,
+    ) -> StateType<&'static str, Machine> {
+        StateType::new_deterministic(move || Machine { mode }, transitions, event_rate)
+    }
+
+    // machine runs for 8 seconds on average, then needs a quick fix (3 times out of 4) or an overhaul
+    fn machine() -> HashMap<&'static str, StateType<&'static str, Machine>> {
+        HashMap::from([
+            (
+                "running",
+                mode("running", vec![("fix", 3.0), ("overhaul", 1.0)], 8.0),
+            ),
+            ("fix", mode("fix", vec![("running", 1.0)], 1.0)),
+            (
+                "overhaul",
+                mode("overhaul", vec![("running", 1.0), ("overhaul", 1.0)], 2.0),
+            ),
+        ])
+    }
+
+    fn lookup(distribution: Vec<(&'static str, f64)>) -> HashMap<&'static str, f64> {
+        distribution.into_iter().collect()
+    }
+
+    #[test]
+    fn test_generator_and_stationary_distribution() {
+        let generator = Generator::from_transitions(&machine().into());
+
+        assert_eq!(generator.rate(&"running", &"fix"), 3.0 / 32.0);
+        assert_eq!(generator.rate(&"running", &"running"), -1.0 / 8.0);
+        assert_eq!(generator.rate(&"overhaul", &"running"), 0.25);
+        assert_eq!(generator.modes().len(), 3);
+
+        // time shares are proportional to visits times holding times: 8 running, 0.75 fixing, 0.25 * 4 overhauling
+        let stationary = lookup(generator.stationary_distribution().unwrap());
+        assert!((stationary["running"] - 8.0 / 9.75).abs() < 1e-12);
+        assert!((stationary["fix"] - 0.75 / 9.75).abs() < 1e-12);
+        assert!((stationary["overhaul"] - 1.0 / 9.75).abs() < 1e-12);
+
+        // the self-transition doubles the stay in overhaul, and every cycle enters running once
+        let holding = lookup(generator.holding_times());
+        assert_eq!(holding["overhaul"], 4.0);
+        let visits = lookup(generator.visit_frequencies().unwrap());
+        assert!((visits["running"] - 1.0 / 9.75).abs() < 1e-12);
+        assert!((visits["fix"] - 0.75 / 9.75).abs() < 1e-12);
+
+        // two absorbing modes have no unique stationary distribution
+        let mut absorbing = machine();
+        absorbing.insert("fix", mode("fix", vec![], 0.0));
+        absorbing.insert("overhaul", mode("overhaul", vec![], 0.0));
+        let generator = Generator::from_transitions(&absorbing.into());
+        assert!(generator.stationary_distribution().is_none());
+        assert!(lookup(generator.holding_times())["fix"].is_infinite());
+    }
+
+    #[test]
+    fn test_transient_distribution() {
+        // up for 3 seconds and down for 1 second on average
+        let transitions = HashMap::from([
+            ("up", mode("up", vec![("down", 1.0)], 3.0)),
+            ("down", mode("down", vec![("up", 1.0)], 1.0)),
+        ]);
+        let generator = Generator::from_transitions(&transitions.into());
+
+        // P(up at t | up at 0) = 3/4 + 1/4 e^-(4/3)t
+        for seconds in [0, 1, 2, 5] {
+            let transient = lookup(
+                generator.transient_distribution(&[("up", 1.0)], Duration::seconds(seconds)),
+            );
+            let exact = 0.75 + 0.25 * (-4.0 / 3.0 * seconds as f64).exp();
+            assert!(
+                (transient["up"] - exact).abs() < 1e-9,
+                "{} at {}s",
+                transient["up"],
+                seconds
+            );
+        }
+
+        // horizons spanning many jumps converge to the stationary distribution
+        let transient = lookup(
+            generator
+                .transient_distribution(&[("down", 2.0), ("up", 2.0)], Duration::seconds(1000)),
+        );
+        assert!((transient["up"] - 0.75).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_simulation_matches_theory() {
+        let mut rng = StdRng::seed_from_u64(4);
+        let agent = Agent::new("m".to_string(), "running", machine(), &mut rng).unwrap();
+        let stationary = lookup(agent.generator().stationary_distribution().unwrap());
+
+        let start = Utc.timestamp_opt(0, 0).unwrap();
+        let horizon = Duration::seconds(200_000);
+        let mut sim = Simulation::new_with_seed(vec![agent], start, 4).unwrap();
+        let events = sim.run(horizon);
+        let occupancy =
+            Trajectory::from_events(events, "m", "mode", start, start + horizon).occupancy();
+
+        for (mode, share) in stationary {
+            assert!(
+                (occupancy[mode] - share).abs() < 0.01,
+                "{}: {}",
+                mode,
+                occupancy[mode]
+            );
+        }
+    }
+
+    #[test]
+    fn test_agents_start_in_stationary_distribution() {
+        let mut rng = StdRng::seed_from_u64(4);
+        let agents: Vec<_> = (0..4000)
+            .map(|i| Agent::new_stationary(format!("m{}", i), machine(), &mut rng).unwrap())
+            .collect();
+        let running = agents
+            .iter()
+            .filter(|agent| *agent.current_state_type() == "running")
+            .count();
+        assert!((running as f64 / 4000.0 - 8.0 / 9.75).abs() < 0.02);
+
+        let fixed =
+            Agent::new_with_distribution("m".to_string(), &[("fix", 1.0)], machine(), &mut rng)
+                .unwrap();
+        assert_eq!(*fixed.current_state_type(), "fix");
+    }
+}
@@ -0,0 +1,299 @@
+use crate::state::{AgentId, FieldName, State, StateChangeEvent, intern};
+use crate::value::Value;
+use chrono::{DateTime, Utc};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Deserializer, Serialize};
+use std::collections::BTreeMap;
+use std::collections::btree_map;
+use std::fmt;
+
+/// DynamicState is state data whose fields are only known at runtime, i.e. for models loaded from files: a map of
+/// field names to values, ordered by name. Its diff reports the fields that changed or appeared, and those that
+/// disappeared with a null new value. Field names are interned when set, so events name them without allocating.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+#[serde(transparent)]
+pub struct DynamicState {
+    fields: BTreeMap<FieldName, Value>,
+}
+
+impl DynamicState {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // with sets a field, for building states in one expression
+    pub fn with(mut self, name: &str, value: impl Into<Value>) -> Self {
+        self.set(name, value);
+        self
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Value> {
+        self.fields.get(name)
+    }
+
+    // set sets a field, returning its previous value
+    pub fn set(&mut self, name: &str, value: impl Into<Value>) -> Option<Value> {
+        self.fields.insert(intern(name), value.into())
+    }
+
+    // insert sets a field whose name is a literal or already interned, without looking it up in the interner, i.e.
+    // in factories that run on every transition
+    pub fn insert(&mut self, name: FieldName, value: impl Into<Value>) -> Option<Value> {
+        self.fields.insert(name, value.into())
+    }
+
+    pub fn remove(&mut self, name: &str) -> Option<Value> {
+        self.fields.remove(name)
+    }
+
+    // iter walks the fields in the order of their names
+    pub fn iter(&self) -> btree_map::Iter<'_, FieldName, Value> {
+        self.fields.iter()
+    }
+
+    pub fn len(&self) -> usize {
+        self.fields.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.fields.is_empty()
+    }
+
+    // from_state converts typed state data field by field through its serde form, so it requires `Serialize` and has
+    // nothing to do with `#[derive(State)]`: fields are named and typed as serde writes them, i.e. after
+    // `#[serde(rename)]`, an enum field holds its variant name rather than the `Display` form its events carry, and a
+    // timestamp its RFC 3339 string. It fails if a field is a nested struct or map.
+    pub fn from_state<T: Serialize>(state: &T) -> Result<Self, serde_json::Error> {
+        serde_json::from_value(serde_json::to_value(state)?)
+    }
+
+    // to_state converts back to typed state data through its serde form, so it requires `Deserialize`. It fails if a
+    // field is missing or of the wrong type.
+    pub fn to_state<T: DeserializeOwned>(&self) -> Result<T, serde_json::Error> {
+        serde_json::from_value(serde_json::to_value(self)?)
+    }
+}
+
+// formats as `StateDisplay` does, i.e. `connected: true | cpu: 80`
+impl fmt::Display for DynamicState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, (name, value)) in self.fields.iter().enumerate() {
+            if i > 0 {
+                write!(f, " | ")?;
+            }
+            write!(f, "{}: {}", name, value)?;
+        }
+        Ok(())
+    }
+}
+
+impl FromIterator<(String, Value)> for DynamicState {
+    fn from_iter<I: IntoIterator<Item = (String, Value)>>(fields: I) -> Self {
+        DynamicState {
+            fields: fields
+                .into_iter()
+                .map(|(name, value)| (intern(&name), value))
+                .collect(),
+        }
+    }
+}
+
+// collecting field names that are literals or already interned does not go through the interner
+impl FromIterator<(FieldName, Value)> for DynamicState {
+    fn from_iter<I: IntoIterator<Item = (FieldName, Value)>>(fields: I) -> Self {
+        DynamicState {
+            fields: fields.into_iter().collect(),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for DynamicState {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        BTreeMap::<String, Value>::deserialize(deserializer).map(DynamicState::from_iter)
+    }
+}
+
+impl State for DynamicState {
+    // diff walks both maps in order at once, so it is linear in the number of fields
+    fn diff(&self, other: &Self, time: DateTime<Utc>) -> Vec<StateChangeEvent> {
+        let event = |field: FieldName, old: Option<&Value>, new: Option<&Value>| StateChangeEvent {
+            time,
+            agent_id: AgentId::default(),
+            field,
+            old_value: old.cloned().unwrap_or_default(),
+            new_value: new.cloned().unwrap_or_default(),
+        };
+
+        let mut events = Vec::new();
+        let mut old = self.fields.iter().peekable();
+        let mut new = other.fields.iter().peekable();
+        loop {
+            match (old.peek(), new.peek()) {
+                (Some((old_name, old_value)), Some((new_name, new_value))) => {
+                    match old_name.cmp(new_name) {
+                        std::cmp::Ordering::Less => {
+                            events.push(event(old_name, Some(old_value), None));
+                            old.next();
+                        }
+                        std::cmp::Ordering::Greater => {
+                            events.push(event(new_name, None, Some(new_value)));
+                            new.next();
+                        }
+                        std::cmp::Ordering::Equal => {
+                            if old_value != new_value {
+                                events.push(event(old_name, Some(old_value), Some(new_value)));
+                            }
+                            old.next();
+                            new.next();
+                        }
+                    }
+                }
+                (Some((old_name, old_value)), None) => {
+                    events.push(event(old_name, Some(old_value), None));
+                    old.next();
+                }
+                (None, Some((new_name, new_value))) => {
+                    events.push(event(new_name, None, Some(new_value)));
+                    new.next();
+                }
+                (None, None) => return events,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::agent::{Agent, StateType};
+    use crate::output::{EventSink, JsonlSink};
+    use crate::replay::{ReplayAgent, read_jsonl};
+    use crate::simulation::Simulation;
+    use crate::state::Timeline;
+    use chrono::{Duration, TimeZone};
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+    use std::collections::HashMap;
+
+    #[test]
+    fn test_dynamic_state_diff() {
+        let time = Utc.timestamp_opt(0, 0).unwrap();
+        let before = DynamicState::new()
+            .with("connected", true)
+            .with("cpu", 12.5)
+            .with("firmware", "1.0");
+        let after = DynamicState::new()
+            .with("connected", true)
+            .with("cpu", 80.0)
+            .with("sessions", 3);
+
+        let changes: Vec<(&str, Value, Value)> = before
+            .diff(&after, time)
+            .into_iter()
+            .map(|event| (event.field, event.old_value, event.new_value))
+            .collect();
+        assert_eq!(
+            changes,
+            vec![
+                ("cpu", Value::Float(12.5), Value::Float(80.0)),
+                ("firmware", "1.0".into(), Value::Null),
+                ("sessions", Value::Null, Value::Int(3)),
+            ]
+        );
+        assert!(after.diff(&after, time).is_empty());
+
+        let json = serde_json::to_string(&after).unwrap();
+        assert_eq!(json, r#"{"connected":true,"cpu":80.0,"sessions":3}"#);
+        assert_eq!(serde_json::from_str::<DynamicState>(&json).unwrap(), after);
+    }
+
+    #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+    struct Device {
+        connected: bool,
+        cpu: f64,
+        sessions: u32,
+        firmware: String,
+    }
+
+    #[test]
+    fn test_dynamic_state_conversion_and_simulation() {
+        let device = Device {
+            connected: true,
+            cpu: 12.0,
+            sessions: 2,
+            firmware: "1.0".to_string(),
+        };
+        let state = DynamicState::from_state(&device).unwrap();
+        assert_eq!(state.get("sessions"), Some(&Value::Int(2)));
+        assert_eq!(state.get("cpu"), Some(&Value::Float(12.0)));
+        assert_eq!(
+            state.to_string(),
+            "connected: true | cpu: 12 | firmware: 1.0 | sessions: 2"
+        );
+        assert_eq!(state.to_state::<Device>().unwrap(), device);
+        let mut interned: DynamicState = [("cpu", Value::Float(12.0))].into_iter().collect();
+        interned.insert("sessions", 2);
+        assert_eq!(interned.get("sessions"), state.get("sessions"));
+        assert_eq!(interned.get("cpu"), state.get("cpu"));
+        assert!(
+            state
+                .clone()
+                .with("sessions", "many")
+                .to_state::<Device>()
+                .is_err()
+        );
+
+        // agents of runtime-defined state run, stream and replay like any other
+        let transitions = HashMap::from([
+            (
+                "idle",
+                StateType::new_deterministic(
+                    || DynamicState::new().with("load", 0.0),
+                    vec![("busy", 1.0)],
+                    60.0,
+                ),
+            ),
+            (
+                "busy",
+                StateType::new(
+                    |rng| {
+                        DynamicState::new()
+                            .with("load", rng.gen_range(50.0..100.0))
+                            .with("job", "backup")
+                    },
+                    vec![("idle", 1.0)],
+                    30.0,
+                ),
+            ),
+        ]);
+        let mut rng = StdRng::seed_from_u64(7);
+        let agent = Agent::new("worker".to_string(), "idle", transitions, &mut rng).unwrap();
+        let mut sim =
+            Simulation::new_with_seed(vec![agent], Utc.timestamp_opt(0, 0).unwrap(), 7).unwrap();
+        let events = sim.run(Duration::hours(1));
+        assert!(events.iter().any(|event| event.field == "job"));
+
+        let mut sink = JsonlSink::new(Vec::new());
+        for event in events {
+            sink.write(event).unwrap();
+        }
+        sink.flush().unwrap();
+        let written = String::from_utf8(sink.into_inner()).unwrap();
+        assert_eq!(written.lines().count(), events.len());
+        let timelines = Timeline::generate(events);
+        let last = timelines["worker"].entries.last().unwrap();
+        assert_eq!(last.state.len(), 2);
+        assert!(last.state.contains_key("load"));
+
+        // replaying the written events gives the same timeline
+        let mut replay = Simulation::empty_with_seed(Utc.timestamp_opt(0, 0).unwrap(), 0);
+        for agent in ReplayAgent::from_events(read_jsonl(written.as_bytes()).unwrap()) {
+            replay.add_agent(agent).unwrap();
+        }
+        let replayed = Timeline::generate(replay.run(Duration::hours(1)));
+        assert_eq!(
+            replayed["worker"].to_string(),
+            timelines["worker"].to_string()
+        );
+    }
+}
@@ -0,0 +1,248 @@
+use crate::timer;
+use chrono::{DateTime, Utc};
+
+/// Bias is a change of measure for one state type, used to make rare transitions frequent (importance sampling).
+/// Under the bias, agents leave the state type `rate_factor` times faster and the weight of each transition is
+/// multiplied by its factor (1 by default). Agents keep track of the likelihood ratio of their trajectory against
+/// the nominal model, so that estimates for the nominal model can be recovered by weighting each run with it.
+#[derive(Clone, Debug)]
+pub struct Bias<C> {
+    pub rate_factor: f64,
+    pub weight_factors: Vec<(C, f64)>,
+}
+
+impl<C> Default for Bias<C> {
+    fn default() -> Self {
+        Bias {
+            rate_factor: 1.0,
+            weight_factors: Vec::new(),
+        }
+    }
+}
+
+impl<C: PartialEq> Bias<C> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // with_rate_factor multiplies the rate of leaving the state type, i.e. 10 for failures 10 times as frequent
+    pub fn with_rate_factor(mut self, factor: f64) -> Self {
+        self.rate_factor = factor;
+        self
+    }
+
+    // with_weight_factor multiplies the weight of the transition to `target`
+    pub fn with_weight_factor(mut self, target: C, factor: f64) -> Self {
+        self.weight_factors.push((target, factor));
+        self
+    }
+
+    // invalid_factor returns the first factor that is not positive and finite, which `Agent::with_bias` rejects
+    pub(crate) fn invalid_factor(&self) -> Option<f64> {
+        std::iter::once(self.rate_factor)
+            .chain(self.weight_factors.iter().map(|(_, factor)| *factor))
+            .find(|factor| !(factor.is_finite() && *factor > 0.0))
+    }
+
+    // weight_factor returns the product of the factors applying to the transition to `target`
+    pub(crate) fn weight_factor(&self, target: &C) -> f64 {
+        self.weight_factors
+            .iter()
+            .filter(|(biased, _)| biased == target)
+            .map(|(_, factor)| factor)
+            .product()
+    }
+}
+
+/// Sojourn is the stay of a biased agent in a state type since its last scheduled event, holding what is needed to
+/// account for it in the likelihood ratio once it ends.
+#[derive(Clone, Debug)]
+pub(crate) struct Sojourn {
+    pub(crate) start: DateTime<Utc>,
+    pub(crate) nominal_rate: f64,
+    pub(crate) biased_rate: f64,
+    pub(crate) choice_ratio: f64,
+    pub(crate) stochastic: bool,
+}
+
+impl Sojourn {
+    // log_ratio returns the log likelihood ratio of the sojourn ending at `end`. If the sampled stochastic transition
+    // fired, it is the ratio of the exponential densities and of the probabilities of the chosen transition;
+    // otherwise (a timer, signal or the end of the run cut it short) it is the ratio of the survival functions.
+    pub(crate) fn log_ratio(&self, end: DateTime<Utc>, fired: bool) -> f64 {
+        let elapsed = timer::duration_to_seconds(end - self.start);
+        let survival = -(self.nominal_rate - self.biased_rate) * elapsed;
+
+        if fired && self.stochastic {
+            survival + (self.nominal_rate / self.biased_rate).ln() + self.choice_ratio.ln()
+        } else {
+            survival
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::agent::{Agent, StateType};
+    use crate::replication::{Replication, Replications, summarize};
+    use crate::simulation::Simulation;
+    use crate::validation::ModelError;
+    use chrono::{Duration, TimeZone};
+    use rand::SeedableRng;
+    use rand::rngs::StdRng;
+    use state_macros::State;
+    use std::collections::HashMap;
+
+    #[derive(Clone, Default, Debug, State)]
+    struct Health {
+        mode: &'static str,
+    }
+
+    // disk fails after 10 hours on average, 1 in 10 failures being fatal and the rest recoverable
+    fn disk(replication: &Replication, bias: Option<Bias<&'static str>>) -> Simulation {
+        let mut transitions = HashMap::new();
+        transitions.insert(
+            "ok",
+            StateType::new_deterministic(
+                || Health { mode: "ok" },
+                vec![("fatal", 1.0), ("recoverable", 9.0)],
+                36_000.0,
+            ),
+        );
+        for mode in ["fatal", "recoverable"] {
+            transitions.insert(
+                mode,
+                StateType::new_deterministic(move || Health { mode }, vec![], 0.0),
+            );
+        }
+
+        let mut rng = StdRng::seed_from_u64(0);
+        let mut agent = Agent::new("disk".to_string(), "ok", transitions, &mut rng).unwrap();
+        if let Some(bias) = bias {
+            agent = agent.with_bias("ok", bias).unwrap();
+        }
+        Simulation::new_with_seed(
+            vec![agent],
+            Utc.timestamp_opt(0, 0).unwrap(),
+            replication.seed,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_likelihood_ratio_of_single_trajectory() {
+        let bias = Bias::new()
+            .with_rate_factor(10.0)
+            .with_weight_factor("fatal", 9.0);
+        assert_eq!(bias.invalid_factor(), None);
+        let (nominal, biased) = (1.0 / 36_000.0, 10.0 / 36_000.0);
+        let mut survived = 0;
+
+        for seed in 0..20 {
+            let replication = Replication {
+                index: 0,
+                seed,
+                antithetic: false,
+            };
+            let mut sim = disk(&replication, Some(bias.clone()));
+            let events = sim.run(Duration::hours(1));
+
+            // a disk that does not fail is observed until the end of the run, not just until its last event
+            let expected = match events.first() {
+                Some(event) => {
+                    let elapsed =
+                        (event.time - Utc.timestamp_opt(0, 0).unwrap()).num_milliseconds();
+                    let choice = if event.new_value == "fatal" {
+                        0.1 / 0.5
+                    } else {
+                        0.9 / 0.5
+                    };
+                    nominal / biased
+                        * (-(nominal - biased) * elapsed as f64 / 1000.0).exp()
+                        * choice
+                }
+                None => {
+                    survived += 1;
+                    (-(nominal - biased) * 3600.0f64).exp()
+                }
+            };
+            assert!((sim.likelihood_ratio() - expected).abs() < 1e-9 * expected);
+        }
+        assert!(survived > 0 && survived < 20);
+    }
+
+    #[test]
+    fn test_importance_sampling_is_unbiased() {
+        let fatal_within_hour = |sim: &mut Simulation| {
+            let fatal = sim
+                .run(Duration::hours(1))
+                .iter()
+                .any(|event| event.new_value == "fatal");
+            (fatal, sim.likelihood_ratio())
+        };
+        let biased = Replications::new(4000, 11).run(
+            |replication| {
+                let bias = Bias::new()
+                    .with_rate_factor(10.0)
+                    .with_weight_factor("fatal", 9.0);
+                disk(replication, Some(bias))
+            },
+            |_, sim| fatal_within_hour(sim),
+        );
+
+        // P(fatal within an hour) = (1 - e^-0.1) / 10, which the biased runs hit about a third of the time
+        let exact = (1.0 - (-0.1f64).exp()) / 10.0;
+        let hits = biased.iter().filter(|result| result.value.0).count();
+        assert!(hits > 1000);
+
+        let estimate = summarize(
+            &biased,
+            0.95,
+            |(fatal, ratio)| {
+                if *fatal { *ratio } else { 0.0 }
+            },
+        );
+        assert!((estimate.mean - exact).abs() < 3.0 * estimate.half_width);
+        assert!(estimate.relative_precision() < 0.05);
+
+        // without a bias the likelihood ratio is always 1
+        let nominal =
+            Replications::new(20, 11).run(|r| disk(r, None), |_, sim| fatal_within_hour(sim));
+        assert!(nominal.iter().all(|result| result.value.1 == 1.0));
+    }
+
+    #[test]
+    fn test_invalid_bias_factors_are_rejected() {
+        let mut transitions = HashMap::new();
+        transitions.insert(
+            "ok",
+            StateType::new_deterministic(|| Health { mode: "ok" }, vec![("fatal", 1.0)], 36_000.0),
+        );
+        transitions.insert(
+            "fatal",
+            StateType::new_deterministic(|| Health { mode: "fatal" }, vec![], 0.0),
+        );
+        let agent = || {
+            Agent::new(
+                "disk".to_string(),
+                "ok",
+                transitions.clone(),
+                &mut StdRng::seed_from_u64(0),
+            )
+            .unwrap()
+        };
+
+        for factor in [0.0, -1.0, f64::INFINITY, f64::NAN] {
+            for bias in [
+                Bias::new().with_rate_factor(factor),
+                Bias::new().with_weight_factor("fatal", factor),
+            ] {
+                assert!(matches!(
+                    agent().with_bias("ok", bias),
+                    Err(ModelError::InvalidBias { mode: "ok", .. })
+                ));
+            }
+        }
+    }
+}
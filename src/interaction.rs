@@ -0,0 +1,35 @@
+use crate::state::StateChangeEvent;
+
+pub type Interaction = Box<dyn FnMut(&StateChangeEvent) -> Vec<Signal> + Send>;
+
+/// SignalTarget selects which agents a signal is delivered to.
+#[derive(Clone, Debug, PartialEq)]
+pub enum SignalTarget {
+    Agent(String),
+    All,
+}
+
+/// A Signal is emitted by an interaction in response to a state change of one agent, and delivered to other agents
+/// at the same simulated time. Agents react to signals through `StateType::with_signal`; agents whose current state
+/// type does not handle the signal ignore it.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Signal {
+    pub target: SignalTarget,
+    pub name: String,
+}
+
+impl Signal {
+    pub fn to_agent(agent_id: &str, name: &str) -> Self {
+        Signal {
+            target: SignalTarget::Agent(agent_id.to_string()),
+            name: name.to_string(),
+        }
+    }
+
+    pub fn to_all(name: &str) -> Self {
+        Signal {
+            target: SignalTarget::All,
+            name: name.to_string(),
+        }
+    }
+}
@@ -1,3 +1,29 @@
+// lets `#[derive(State)]`, which refers to `agsim::`, be used by the tests of this crate
+#[cfg(test)]
+extern crate self as agsim;
+
 pub mod agent;
+pub mod analysis;
+pub mod composite;
+pub mod config;
+pub mod ctmc;
+pub mod dynamic;
+pub mod importance;
+pub mod interaction;
+pub mod matrix;
+pub mod model;
+pub mod optimistic;
+pub mod output;
+pub mod parallel;
+pub mod rare;
+pub mod reliability;
+pub mod replay;
+pub mod replication;
+pub mod rng;
 pub mod simulation;
 pub mod state;
+pub mod stats;
+pub mod summary;
+pub mod timer;
+pub mod validation;
+pub mod value;
@@ -0,0 +1,287 @@
+use crate::agent::StateType;
+use crate::state::State;
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::ops::Index;
+use std::sync::{Arc, OnceLock};
+
+/// TransitionMatrix is the model an agent follows: the state type of each mode, indexed densely so that agents only
+/// keep the index of their current mode. It is reference counted and immutable once shared, so a population of
+/// agents built from one matrix holds a single copy of it, however large. Changing the matrix of one agent, i.e. to
+/// make a batch of devices fail more often, copies it first (copy-on-write) and leaves the other agents untouched.
+pub struct TransitionMatrix<C, S: State> {
+    shared: Arc<Dense<C, S>>,
+}
+
+#[derive(Clone)]
+struct Dense<C, S: State> {
+    modes: Arc<Modes<C>>,
+    state_types: Vec<StateType<C, S>>,
+    validated: OnceLock<()>,
+}
+
+/// Modes numbers the modes of a transition matrix densely. It is shared with the statistics of the agents following
+/// the matrix, which count their visits and time spent per mode index.
+#[derive(Debug)]
+pub(crate) struct Modes<C> {
+    modes: Vec<C>,
+    index: HashMap<C, usize>,
+}
+
+impl<C: Eq + Hash + Clone> Modes<C> {
+    pub(crate) fn new(modes: Vec<C>) -> Self {
+        let index = modes
+            .iter()
+            .enumerate()
+            .map(|(index, mode)| (mode.clone(), index))
+            .collect();
+        Modes { modes, index }
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.modes.len()
+    }
+
+    pub(crate) fn index(&self, mode: &C) -> Option<usize> {
+        self.index.get(mode).copied()
+    }
+
+    pub(crate) fn mode(&self, index: usize) -> &C {
+        &self.modes[index]
+    }
+}
+
+impl<C, S> TransitionMatrix<C, S>
+where
+    C: Eq + Hash + Clone,
+    S: State,
+{
+    // new builds the matrix of a map of state types, numbering the modes in sorted order so that the indices, and
+    // the draws made with them, do not depend on the iteration order of the map
+    pub fn new(transitions: HashMap<C, StateType<C, S>>) -> Self
+    where
+        C: Ord,
+    {
+        let mut transitions: Vec<_> = transitions.into_iter().collect();
+        transitions.sort_by(|(a, _), (b, _)| a.cmp(b));
+        Self::from_ordered(transitions)
+    }
+
+    // from_ordered builds the matrix of a list of state types, numbering the modes in the order given. A mode listed
+    // more than once keeps its first index and its last state type.
+    pub fn from_ordered(transitions: Vec<(C, StateType<C, S>)>) -> Self {
+        let mut modes: Vec<C> = Vec::with_capacity(transitions.len());
+        let mut state_types: Vec<StateType<C, S>> = Vec::with_capacity(transitions.len());
+        let mut index = HashMap::new();
+        for (mode, state_type) in transitions {
+            match index.get(&mode) {
+                Some(&existing) => state_types[existing] = state_type,
+                None => {
+                    index.insert(mode.clone(), modes.len());
+                    modes.push(mode);
+                    state_types.push(state_type);
+                }
+            }
+        }
+
+        TransitionMatrix {
+            shared: Arc::new(Dense {
+                modes: Arc::new(Modes::new(modes)),
+                state_types,
+                validated: OnceLock::new(),
+            }),
+        }
+    }
+
+    // len returns the number of modes in the matrix
+    pub fn len(&self) -> usize {
+        self.shared.modes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.shared.modes.len() == 0
+    }
+
+    // modes returns the modes of the matrix, in the order of their indices
+    pub fn modes(&self) -> &[C] {
+        &self.shared.modes.modes
+    }
+
+    // index returns the dense index of `mode`, or None if it is not in the matrix
+    pub fn index(&self, mode: &C) -> Option<usize> {
+        self.shared.modes.index(mode)
+    }
+
+    pub fn contains(&self, mode: &C) -> bool {
+        self.shared.modes.index.contains_key(mode)
+    }
+
+    // get returns the state type of `mode`
+    pub fn get(&self, mode: &C) -> Option<&StateType<C, S>> {
+        self.index(mode)
+            .map(|index| &self.shared.state_types[index])
+    }
+
+    // mode returns the mode at a dense index
+    pub fn mode(&self, index: usize) -> &C {
+        self.shared.modes.mode(index)
+    }
+
+    // state_type returns the state type at a dense index
+    pub fn state_type(&self, index: usize) -> &StateType<C, S> {
+        &self.shared.state_types[index]
+    }
+
+    // iter walks the modes with their state types, in the order of their indices
+    pub fn iter(&self) -> impl Iterator<Item = (&C, &StateType<C, S>)> {
+        self.modes().iter().zip(&self.shared.state_types)
+    }
+
+    // get_mut gives write access to the state type of `mode`, copying the matrix first if it is shared. The copy has
+    // to be validated again.
+    pub fn get_mut(&mut self, mode: &C) -> Option<&mut StateType<C, S>> {
+        let index = self.index(mode)?;
+        let dense = Arc::make_mut(&mut self.shared);
+        dense.validated = OnceLock::new();
+        Some(&mut dense.state_types[index])
+    }
+
+    // shared_modes returns the dense numbering of the modes, shared with the statistics of the agents
+    pub(crate) fn shared_modes(&self) -> Arc<Modes<C>> {
+        Arc::clone(&self.shared.modes)
+    }
+
+    // is_validated tells whether the matrix passed `validation::validate` since it was created or last changed
+    pub(crate) fn is_validated(&self) -> bool {
+        self.shared.validated.get().is_some()
+    }
+
+    // mark_validated records that the matrix passed `validation::validate`, for all the agents sharing it
+    pub(crate) fn mark_validated(&self) {
+        let _ = self.shared.validated.set(());
+    }
+
+    // is_shared_with tells whether two matrices are the same copy, i.e. neither was overridden since one was cloned
+    // from the other
+    pub fn is_shared_with(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.shared, &other.shared)
+    }
+}
+
+// cloning shares the matrix rather than copying it
+impl<C, S: State> Clone for TransitionMatrix<C, S> {
+    fn clone(&self) -> Self {
+        TransitionMatrix {
+            shared: Arc::clone(&self.shared),
+        }
+    }
+}
+
+impl<C, S> From<HashMap<C, StateType<C, S>>> for TransitionMatrix<C, S>
+where
+    C: Eq + Hash + Clone + Ord,
+    S: State,
+{
+    fn from(transitions: HashMap<C, StateType<C, S>>) -> Self {
+        Self::new(transitions)
+    }
+}
+
+impl<C, S> Index<&C> for TransitionMatrix<C, S>
+where
+    C: Eq + Hash + Clone,
+    S: State,
+{
+    type Output = StateType<C, S>;
+
+    fn index(&self, mode: &C) -> &StateType<C, S> {
+        self.get(mode).expect("mode is in the transition matrix")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use state_macros::State;
+
+    #[derive(Clone, Default, Debug, State)]
+    struct Fan;
+
+    #[test]
+    fn test_matrix_is_shared_until_written() {
+        let matrix = TransitionMatrix::from(HashMap::from([
+            (
+                "on",
+                StateType::new_deterministic(|| Fan, vec![("off", 1.0)], 60.0),
+            ),
+            (
+                "off",
+                StateType::new_deterministic(|| Fan, vec![("on", 1.0)], 30.0),
+            ),
+        ]));
+        assert_eq!(matrix.len(), 2);
+        let on = matrix.index(&"on").unwrap();
+        assert_eq!(*matrix.mode(on), "on");
+        assert_eq!(matrix.state_type(on).event_rate, 60.0);
+        assert!(matrix.get(&"broken").is_none());
+
+        let shared = matrix.clone();
+        let mut overridden = matrix.clone();
+        assert!(shared.is_shared_with(&matrix));
+        overridden.get_mut(&"on").unwrap().event_rate = 5.0;
+
+        assert!(!overridden.is_shared_with(&matrix));
+        assert_eq!(overridden.get(&"on").unwrap().event_rate, 5.0);
+        assert_eq!(matrix.get(&"on").unwrap().event_rate, 60.0);
+        assert_eq!(overridden.index(&"off"), matrix.index(&"off"));
+    }
+
+    #[test]
+    fn test_matrix_is_validated_once_while_shared() {
+        let matrix = TransitionMatrix::from(HashMap::from([
+            (
+                "on",
+                StateType::new_deterministic(|| Fan, vec![("off", 1.0)], 60.0),
+            ),
+            (
+                "off",
+                StateType::new_deterministic(|| Fan, vec![("on", 1.0)], 30.0),
+            ),
+        ]));
+        assert!(!matrix.is_validated());
+        crate::validation::check(&matrix, &"on").unwrap();
+
+        let shared = matrix.clone();
+        assert!(shared.is_validated());
+        assert!(crate::validation::check(&shared, &"broken").is_err());
+
+        // a copy changed by an override is validated again
+        let mut overridden = matrix.clone();
+        overridden.get_mut(&"on").unwrap().event_rate = -1.0;
+        assert!(!overridden.is_validated());
+        assert!(crate::validation::check(&overridden, &"on").is_err());
+        assert!(matrix.is_validated());
+    }
+
+    #[test]
+    fn test_mode_order_is_deterministic() {
+        let fan = |mean| StateType::new_deterministic(|| Fan, vec![], mean);
+
+        // a map is numbered in sorted order, whatever its iteration order
+        let matrix = TransitionMatrix::new(HashMap::from([
+            ("standby", fan(1.0)),
+            ("on", fan(2.0)),
+            ("off", fan(3.0)),
+        ]));
+        assert_eq!(matrix.modes(), ["off", "on", "standby"]);
+
+        // a list is numbered in order, a repeated mode keeping its first index and its last state type
+        let matrix = TransitionMatrix::from_ordered(vec![
+            ("standby", fan(1.0)),
+            ("on", fan(2.0)),
+            ("standby", fan(4.0)),
+        ]);
+        assert_eq!(matrix.modes(), ["standby", "on"]);
+        assert_eq!(matrix[&"standby"].event_rate, 4.0);
+    }
+}
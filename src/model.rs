@@ -0,0 +1,605 @@
+use crate::agent::{self, Agent, HoldingTime, StateType};
+use crate::ctmc::Generator;
+use crate::matrix::TransitionMatrix;
+use crate::rng::DefaultRng;
+use crate::simulation::Simulation;
+use crate::state::State;
+use crate::stats::AgentStats;
+use crate::timer::TransitionContext;
+use crate::validation::{self, ModelError};
+use chrono::{DateTime, Duration, Utc};
+use rand::{RngCore, SeedableRng};
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::sync::Arc;
+
+/// InitialMode decides the mode each agent of a population starts in.
+#[derive(Debug, Clone)]
+pub enum InitialMode<C> {
+    Mode(C),
+    Distribution(Vec<(C, f64)>),
+    Stationary,
+}
+
+/// Model is a validated transition matrix with the initial mode of its agents, built with `Model::builder`, from
+/// which populations of agents are created. The agents of a population share the transition matrix of the model,
+/// except for those with overrides, which get their own copy.
+#[derive(Clone)]
+pub struct Model<C, S: State> {
+    transitions: TransitionMatrix<C, S>,
+    initial: InitialMode<C>,
+}
+
+impl<C, S> Model<C, S>
+where
+    C: Eq + Hash + Clone,
+    S: State,
+{
+    pub fn builder() -> ModelBuilder<C, S> {
+        ModelBuilder {
+            transitions: HashMap::new(),
+            order: Vec::new(),
+            current: None,
+            initial: None,
+            error: None,
+        }
+    }
+
+    // transitions returns the transition matrix of the model
+    pub fn transitions(&self) -> &TransitionMatrix<C, S> {
+        &self.transitions
+    }
+
+    // initial returns how the agents of the model start
+    pub fn initial(&self) -> &InitialMode<C> {
+        &self.initial
+    }
+
+    // population starts describing `count` agents following the model
+    pub fn population(&self, count: usize) -> Population<'_, C, S> {
+        Population {
+            model: self,
+            count,
+            id_pattern: "agent_{}".to_string(),
+            seed: None,
+            overrides: Vec::new(),
+        }
+    }
+}
+
+/// ModelBuilder assembles a transition matrix state type by state type: `state` opens a state type, and the calls
+/// that follow (`factory`, `mean_dwell`, `to`, `timeout`...) describe it until the next `state`. Modes are numbered
+/// in the order their state types were first opened.
+pub struct ModelBuilder<C, S: State> {
+    transitions: HashMap<C, StateType<C, S>>,
+    order: Vec<C>,
+    current: Option<C>,
+    initial: Option<InitialMode<C>>,
+    error: Option<ModelError<C>>,
+}
+
+impl<C, S> ModelBuilder<C, S>
+where
+    C: Eq + Hash + Clone,
+    S: State + 'static,
+{
+    // state opens the state type of `mode`, creating it without transitions and with default data if it is new
+    pub fn state(mut self, mode: C) -> Self {
+        if !self.transitions.contains_key(&mode) {
+            let def = StateType::new_deterministic(S::default, Vec::new(), 0.0);
+            self.transitions.insert(mode.clone(), def);
+            self.order.push(mode.clone());
+        }
+        self.current = Some(mode);
+        self
+    }
+
+    // factory sets how the data of agents entering the state type is generated
+    pub fn factory<F>(self, factory: F) -> Self
+    where
+        F: Fn(&mut dyn RngCore) -> S + Send + Sync + 'static,
+    {
+        self.update(|def| def.factory = Arc::new(move |rng, _| factory(rng)))
+    }
+
+    // factory_with_stats sets how the data of agents entering the state type is generated from their history
+    pub fn factory_with_stats<F>(self, factory: F) -> Self
+    where
+        F: Fn(&mut dyn RngCore, &AgentStats<C>) -> S + Send + Sync + 'static,
+    {
+        self.update(|def| def.factory = Arc::new(factory))
+    }
+
+    // value makes agents entering the state type take a copy of `data`
+    pub fn value(self, data: S) -> Self
+    where
+        S: Send + Sync + 'static,
+    {
+        self.update(|def| def.factory = Arc::new(move |_, _| data.clone()))
+    }
+
+    // mean_dwell sets the mean delay before the stochastic transitions of the state type fire
+    pub fn mean_dwell(self, dwell: Duration) -> Self {
+        self.update(|def| def.event_rate = seconds(dwell))
+    }
+
+    // holding_time sets the shape of the distribution of the dwell, whose mean is set by `mean_dwell` (see
+    // `HoldingTime`)
+    pub fn holding_time(self, holding_time: HoldingTime) -> Self {
+        self.update(|def| def.holding_time = holding_time)
+    }
+
+    // carry_over updates the data generated when entering the state type from the data the agent had before (see
+    // `StateType::with_carry_over`)
+    pub fn carry_over<F>(self, carry_over: F) -> Self
+    where
+        F: Fn(&S, &mut S) + Send + Sync + 'static,
+    {
+        self.update(|def| def.carry_over = Some(Arc::new(carry_over)))
+    }
+
+    // to adds a stochastic transition to `target` with the given weight
+    pub fn to(self, target: C, weight: f64) -> Self {
+        self.update(|def| def.transitions.push((target, weight)))
+    }
+
+    // timeout forces a transition to `target` after staying `after` in the state type
+    pub fn timeout(self, after: Duration, target: C) -> Self {
+        self.map(|def| def.with_timeout(seconds(after), target))
+    }
+
+    // timer arms a named timer when entering the state type (see `StateType::with_timer`)
+    pub fn timer(self, name: &str, after: Duration, target: C) -> Self {
+        self.map(|def| def.with_timer(name, seconds(after), target))
+    }
+
+    // on_signal transitions to `target` when the named signal is received (see `StateType::with_signal`)
+    pub fn on_signal(self, name: &str, target: C) -> Self {
+        self.map(|def| def.with_signal(name, target))
+    }
+
+    // guard only allows transitions to `target` while the guard holds (see `StateType::with_guard`)
+    pub fn guard<F>(self, target: C, guard: F) -> Self
+    where
+        F: Fn(&AgentStats<C>) -> bool + Send + Sync + 'static,
+    {
+        self.map(|def| def.with_guard(target, guard))
+    }
+
+    // weight_fn derives transition weights from the agent's history (see `StateType::with_weight_fn`)
+    pub fn weight_fn<F>(self, weight_fn: F) -> Self
+    where
+        F: Fn(&C, f64, &AgentStats<C>) -> f64 + Send + Sync + 'static,
+    {
+        self.map(|def| def.with_weight_fn(weight_fn))
+    }
+
+    // rate_fn derives the mean dwell from the agent's history (see `StateType::with_rate_fn`)
+    pub fn rate_fn<F>(self, rate_fn: F) -> Self
+    where
+        F: Fn(f64, &AgentStats<C>) -> f64 + Send + Sync + 'static,
+    {
+        self.map(|def| def.with_rate_fn(rate_fn))
+    }
+
+    // on_enter runs a hook whenever an agent enters the state type (see `StateType::with_on_enter`)
+    pub fn on_enter<F>(self, hook: F) -> Self
+    where
+        F: Fn(&mut TransitionContext<C>) + Send + Sync + 'static,
+    {
+        self.map(|def| def.with_on_enter(hook))
+    }
+
+    // initial makes every agent start in `mode`
+    pub fn initial(mut self, mode: C) -> Self {
+        self.initial = Some(InitialMode::Mode(mode));
+        self
+    }
+
+    // initial_distribution draws the initial mode of each agent from a distribution, given as weights per mode
+    pub fn initial_distribution(mut self, distribution: Vec<(C, f64)>) -> Self {
+        self.initial = Some(InitialMode::Distribution(distribution));
+        self
+    }
+
+    // stationary draws the initial mode of each agent from the stationary distribution of the chain
+    pub fn stationary(mut self) -> Self {
+        self.initial = Some(InitialMode::Stationary);
+        self
+    }
+
+    // build validates the model (see `validation::validate`), or returns the first mistake made while describing it.
+    // Without an explicit initial mode, agents start in the first state type that was opened.
+    pub fn build(mut self) -> Result<Model<C, S>, ModelError<C>> {
+        if let Some(error) = self.error {
+            return Err(error);
+        }
+        let initial = match (self.initial, self.order.first()) {
+            (Some(initial), _) => initial,
+            (None, Some(first)) => InitialMode::Mode(first.clone()),
+            (None, None) => return Err(ModelError::EmptyModel),
+        };
+
+        let transitions = TransitionMatrix::from_ordered(
+            self.order
+                .into_iter()
+                .filter_map(|mode| {
+                    let def = self.transitions.remove(&mode)?;
+                    Some((mode, def))
+                })
+                .collect(),
+        );
+        let modes: Vec<&C> = match &initial {
+            InitialMode::Mode(mode) => vec![mode],
+            InitialMode::Distribution(distribution) => {
+                distribution.iter().map(|(mode, _)| mode).collect()
+            }
+            InitialMode::Stationary => transitions.modes().iter().take(1).collect(),
+        };
+        if modes.is_empty() {
+            return Err(ModelError::InvalidDistribution);
+        }
+        for mode in modes {
+            validation::validate(&transitions, mode)?;
+        }
+
+        Ok(Model {
+            transitions,
+            initial,
+        })
+    }
+
+    // update changes the state type opened last, recording a mistake for `build` if none was opened
+    fn update(mut self, change: impl FnOnce(&mut StateType<C, S>)) -> Self {
+        match self
+            .current
+            .as_ref()
+            .and_then(|mode| self.transitions.get_mut(mode))
+        {
+            Some(def) => change(def),
+            None => {
+                self.error.get_or_insert(ModelError::StateNotOpened);
+            }
+        }
+        self
+    }
+
+    // map replaces the state type opened last, for the builder methods of StateType
+    fn map(mut self, change: impl FnOnce(StateType<C, S>) -> StateType<C, S>) -> Self {
+        let def = self
+            .current
+            .as_ref()
+            .and_then(|mode| self.transitions.remove(mode));
+        match (self.current.clone(), def) {
+            (Some(mode), Some(def)) => {
+                self.transitions.insert(mode, change(def));
+            }
+            _ => {
+                self.error.get_or_insert(ModelError::StateNotOpened);
+            }
+        }
+        self
+    }
+}
+
+/// Overrides gives a per-agent override access to the transition matrix of one agent of a population, i.e. to make
+/// a batch of devices fail more often. The matrix is copied on the first change only.
+pub struct Overrides<'a, C, S: State> {
+    transitions: &'a mut TransitionMatrix<C, S>,
+}
+
+impl<C, S> Overrides<'_, C, S>
+where
+    C: Eq + Hash + Clone,
+    S: State,
+{
+    // mean_dwell overrides the mean delay of the stochastic transitions of `mode`
+    pub fn mean_dwell(&mut self, mode: &C, dwell: Duration) -> &mut Self {
+        if let Some(def) = self.transitions.get_mut(mode) {
+            def.event_rate = seconds(dwell);
+        }
+        self
+    }
+
+    // weight overrides the weight of the transitions from `mode` to `target`
+    pub fn weight(&mut self, mode: &C, target: &C, weight: f64) -> &mut Self {
+        if let Some(def) = self.transitions.get_mut(mode) {
+            for (to, w) in &mut def.transitions {
+                if to == target {
+                    *w = weight;
+                }
+            }
+        }
+        self
+    }
+
+    // state_type gives full access to the state type of `mode`
+    pub fn state_type(&mut self, mode: &C) -> Option<&mut StateType<C, S>> {
+        self.transitions.get_mut(mode)
+    }
+}
+
+type Override<C, S> = Box<dyn Fn(usize, &mut Overrides<C, S>)>;
+
+/// Population describes a number of agents following a model, with their ids and per-agent overrides.
+pub struct Population<'a, C, S: State> {
+    model: &'a Model<C, S>,
+    count: usize,
+    id_pattern: String,
+    seed: Option<u64>,
+    overrides: Vec<Override<C, S>>,
+}
+
+impl<C, S> Population<'_, C, S>
+where
+    C: Eq + Hash + Clone + Send + Sync + 'static,
+    S: State + Send + 'static,
+{
+    // id_pattern sets how agents are named from their index, with a `{}` placeholder that can be zero padded as in
+    // `device_{:05}`. A pattern without a placeholder is reported when the agents are created.
+    pub fn id_pattern(mut self, pattern: &str) -> Self {
+        self.id_pattern = pattern.to_string();
+        self
+    }
+
+    // seed sets the seed of the initial data and modes of the agents, and of the simulation built from them.
+    // Without it, a seed is drawn from the OS.
+    pub fn seed(mut self, seed: u64) -> Self {
+        self.seed = Some(seed);
+        self
+    }
+
+    // with_override changes the model of some agents, given their index
+    pub fn with_override<F>(mut self, apply: F) -> Self
+    where
+        F: Fn(usize, &mut Overrides<C, S>) + 'static,
+    {
+        self.overrides.push(Box::new(apply));
+        self
+    }
+
+    // agents creates the agents, validating the models that were overridden. Agents without overrides share the
+    // transition matrix of the model.
+    pub fn agents(&self) -> Result<Vec<Agent<C, S>>, ModelError<C>> {
+        self.agents_with_seed(self.seed.unwrap_or_else(rand::random))
+    }
+
+    // simulation creates a simulation of the agents
+    pub fn simulation(&self, start_time: DateTime<Utc>) -> Result<Simulation, ModelError<C>> {
+        let seed = self.seed.unwrap_or_else(rand::random);
+        Simulation::new_with_seed(self.agents_with_seed(seed)?, start_time, seed)
+    }
+
+    fn agents_with_seed(&self, seed: u64) -> Result<Vec<Agent<C, S>>, ModelError<C>> {
+        let mut rng = DefaultRng::seed_from_u64(seed);
+        let model = &self.model.transitions;
+        let shared_distribution = match &self.model.initial {
+            InitialMode::Mode(mode) => vec![(mode.clone(), 1.0)],
+            InitialMode::Distribution(distribution) => distribution.clone(),
+            InitialMode::Stationary => stationary(model)?,
+        };
+
+        (0..self.count)
+            .map(|index| {
+                let id = format_id(&self.id_pattern, index)
+                    .ok_or_else(|| ModelError::InvalidIdPattern(self.id_pattern.clone()))?;
+                let mut transitions = model.clone();
+                for apply in &self.overrides {
+                    apply(
+                        index,
+                        &mut Overrides {
+                            transitions: &mut transitions,
+                        },
+                    );
+                }
+
+                // the model was validated as a whole when it was built, overridden copies are validated on their own
+                if transitions.is_shared_with(model) {
+                    let initial = agent::draw_initial(&shared_distribution, &mut rng)?;
+                    return Ok(Agent::new_unchecked(id, initial, transitions, &mut rng));
+                }
+                match &self.model.initial {
+                    InitialMode::Stationary => {
+                        let distribution = stationary(&transitions)?;
+                        Agent::new_with_distribution(id, &distribution, transitions, &mut rng)
+                    }
+                    _ => Agent::new_with_distribution(
+                        id,
+                        &shared_distribution,
+                        transitions,
+                        &mut rng,
+                    ),
+                }
+            })
+            .collect()
+    }
+}
+
+// stationary returns the stationary distribution of a transition matrix
+fn stationary<C, S>(transitions: &TransitionMatrix<C, S>) -> Result<Vec<(C, f64)>, ModelError<C>>
+where
+    C: Eq + Hash + Clone,
+    S: State,
+{
+    Generator::from_transitions(transitions)
+        .stationary_distribution()
+        .ok_or(ModelError::NoStationaryDistribution)
+}
+
+fn seconds(duration: Duration) -> f64 {
+    crate::timer::duration_to_seconds(duration)
+}
+
+// placeholder finds the `{...}` placeholder of an id pattern, returning its range and zero padded width
+pub(crate) fn placeholder(pattern: &str) -> Option<(std::ops::Range<usize>, usize)> {
+    let start = pattern.find('{')?;
+    let end = start + pattern[start..].find('}')?;
+    let width = match &pattern[start + 1..end] {
+        "" => 0,
+        spec => spec.strip_prefix(":0")?.parse().ok()?,
+    };
+    Some((start..end + 1, width))
+}
+
+// format_id names the agent at `index` after an id pattern, if it has a placeholder
+fn format_id(pattern: &str, index: usize) -> Option<String> {
+    let (range, width) = placeholder(pattern)?;
+    Some(format!(
+        "{}{:0width$}{}",
+        &pattern[..range.start],
+        index,
+        &pattern[range.end..],
+        width = width
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+    use rand::Rng;
+    use state_macros::State;
+
+    #[derive(Clone, Default, Debug, PartialEq, State)]
+    struct Device {
+        sessions: u32,
+    }
+
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+    enum Mode {
+        Idle,
+        Working,
+        Offline,
+    }
+
+    fn device() -> ModelBuilder<Mode, Device> {
+        Model::builder()
+            .state(Mode::Idle)
+            .mean_dwell(Duration::hours(1))
+            .to(Mode::Working, 0.4)
+            .to(Mode::Offline, 0.1)
+            .to(Mode::Idle, 0.5)
+            .state(Mode::Working)
+            .factory(|rng| Device {
+                sessions: rng.gen_range(1..4),
+            })
+            .mean_dwell(Duration::minutes(30))
+            .to(Mode::Idle, 1.0)
+            .timeout(Duration::hours(2), Mode::Idle)
+            .state(Mode::Offline)
+            .value(Device { sessions: 0 })
+            .mean_dwell(Duration::minutes(10))
+            .to(Mode::Idle, 1.0)
+    }
+
+    #[test]
+    fn test_builder_assembles_transition_matrix() {
+        let model = device().build().unwrap();
+        let transitions = model.transitions();
+
+        assert_eq!(transitions.len(), 3);
+        assert_eq!(transitions[&Mode::Idle].event_rate, 3600.0);
+        assert_eq!(transitions[&Mode::Idle].transitions.len(), 3);
+        assert_eq!(transitions[&Mode::Working].timers[0].after, 7200.0);
+
+        // the first state type opened is the initial one, unless told otherwise
+        let agents = model.population(3).seed(1).agents().unwrap();
+        assert!(agents.iter().all(|a| *a.current_state_type() == Mode::Idle));
+        let agents = device()
+            .initial(Mode::Offline)
+            .build()
+            .unwrap()
+            .population(3)
+            .agents()
+            .unwrap();
+        assert_eq!(agents[0].data, Device { sessions: 0 });
+
+        // mistakes are caught by validation
+        let broken = device()
+            .state(Mode::Offline)
+            .to(Mode::Idle, f64::NAN)
+            .build();
+        assert!(matches!(broken, Err(ModelError::InvalidWeight { .. })));
+        let empty = Model::<Mode, Device>::builder().build();
+        assert!(matches!(empty, Err(ModelError::EmptyModel)));
+    }
+
+    #[test]
+    fn test_population_with_ids_and_overrides() {
+        let model = device().build().unwrap();
+        let population = model
+            .population(1000)
+            .id_pattern("device_{:05}")
+            .seed(7)
+            .with_override(|index, overrides| {
+                if index < 10 {
+                    overrides.mean_dwell(&Mode::Idle, Duration::minutes(1));
+                }
+            });
+
+        let agents = population.agents().unwrap();
+        assert_eq!(agents.len(), 1000);
+        assert_eq!(agents[42].id, "device_00042");
+        let idle_dwell = |index: usize| {
+            agents[index]
+                .generator()
+                .holding_times()
+                .into_iter()
+                .find(|(mode, _)| *mode == Mode::Idle)
+                .unwrap()
+                .1
+        };
+        assert_eq!(idle_dwell(3), 120.0);
+        assert_eq!(idle_dwell(10), 7200.0);
+
+        // only the overridden agents own a copy of the transition matrix
+        let shared = |index: usize| {
+            agents[index]
+                .transition_matrix()
+                .is_shared_with(model.transitions())
+        };
+        assert!((0..10).all(|index| !shared(index)));
+        assert!((10..1000).all(shared));
+
+        // the same seed yields the same simulation
+        let start = Utc.timestamp_opt(0, 0).unwrap();
+        let run = || {
+            let mut sim = population.simulation(start).unwrap();
+            sim.run(Duration::hours(6));
+            sim.into_events()
+        };
+        let (first, second) = (run(), run());
+        assert!(!first.is_empty());
+        assert_eq!(
+            first
+                .iter()
+                .map(|e| (e.time, e.agent_id.clone()))
+                .collect::<Vec<_>>(),
+            second
+                .iter()
+                .map(|e| (e.time, e.agent_id.clone()))
+                .collect::<Vec<_>>()
+        );
+
+        // overrides are validated
+        let invalid = model
+            .population(2)
+            .with_override(|_, overrides| {
+                overrides.weight(&Mode::Working, &Mode::Idle, -1.0);
+            })
+            .agents();
+        assert!(matches!(invalid, Err(ModelError::InvalidWeight { .. })));
+        assert_eq!(format_id("node-{}", 7).as_deref(), Some("node-7"));
+
+        // mistakes are reported as errors rather than panics
+        assert!(matches!(
+            model.population(2).id_pattern("node").agents(),
+            Err(ModelError::InvalidIdPattern(pattern)) if pattern == "node"
+        ));
+        let unopened = Model::<Mode, Device>::builder()
+            .to(Mode::Idle, 1.0)
+            .state(Mode::Idle)
+            .build();
+        assert!(matches!(unopened, Err(ModelError::StateNotOpened)));
+    }
+}
@@ -0,0 +1,580 @@
+use crate::agent::Checkpoint;
+use crate::interaction::Interaction;
+use crate::parallel::{
+    Directory, Item, MessageKey, ParallelError, Shard, TaggedEvent, WINDOWS_WITHOUT_LOOKAHEAD,
+    partition, run_parallel, sort_tagged,
+};
+use crate::rng::AgentStreams;
+use crate::simulation::Simulation;
+use crate::state::StateChangeEvent;
+use chrono::{DateTime, Duration, Utc};
+use std::collections::VecDeque;
+
+// Saved is the state of an agent (and of the roll-up of its composite) before a speculatively processed item
+struct Saved {
+    // checkpoint is None only for agents without checkpoints, which `from_simulation` rejects
+    checkpoint: Option<Checkpoint>,
+    rng: AgentStreams,
+    scheduled: Option<DateTime<Utc>>,
+    emitted: u64,
+    rollup: Option<Checkpoint>,
+}
+
+// Processed is an item that has been processed but not committed yet, with what is needed to undo it
+struct Processed {
+    item: Item,
+    saved: Saved,
+    output: Vec<TaggedEvent>,
+    sent: Vec<MessageKey>,
+}
+
+impl Processed {
+    // follows tells whether the item must be undone for a signal with the given key to be delivered in order, i.e.
+    // if it is due later, is an event due at the same time, or a signal ordered after it
+    fn follows(&self, key: &MessageKey) -> bool {
+        match &self.item {
+            Item::Signal { key: processed, .. } => processed >= key,
+            Item::Event { time, .. } => *time >= key.time,
+        }
+    }
+}
+
+/// OptimisticStats counts the work done by an optimistic simulation, including the work that was thrown away.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct OptimisticStats {
+    pub rounds: u64,
+    pub processed: u64,
+    pub rolled_back: u64,
+    pub anti_messages: u64,
+}
+
+/// A LogicalProcess is a shard that runs ahead speculatively. It keeps every item it has processed since the last
+/// commit, along with the state it overwrote, so that it can roll back when a signal arrives in its past.
+struct LogicalProcess {
+    shard: Shard,
+    processed: VecDeque<Processed>,
+    evaluated: usize,
+    stats: OptimisticStats,
+}
+
+impl LogicalProcess {
+    fn run_until(&mut self, limit: DateTime<Utc>, inclusive: bool) {
+        while let Some(item) = self.shard.next_due(limit, inclusive) {
+            let saved = self.save(item.agent());
+            let mut output = Vec::new();
+            self.shard.process(&item, &mut output);
+
+            self.stats.processed += 1;
+            self.processed.push_back(Processed {
+                item,
+                saved,
+                output,
+                sent: Vec::new(),
+            });
+        }
+    }
+
+    fn save(&self, agent: usize) -> Saved {
+        let shard = &self.shard;
+        Saved {
+            checkpoint: shard.agents[agent].checkpoint(),
+            rng: shard.rngs[agent].clone(),
+            scheduled: shard.scheduled[agent],
+            emitted: shard.emitted[agent],
+            rollup: shard.parent_of[agent].map(|group| shard.composites[group].rollup.checkpoint()),
+        }
+    }
+
+    fn restore(&mut self, agent: usize, saved: Saved) {
+        let shard = &mut self.shard;
+        if let Some(checkpoint) = &saved.checkpoint {
+            shard.agents[agent].restore(checkpoint);
+        }
+        shard.rngs[agent] = saved.rng;
+        shard.emitted[agent] = saved.emitted;
+        if let (Some(group), Some(rollup)) = (shard.parent_of[agent], &saved.rollup) {
+            shard.composites[group].rollup.restore(rollup);
+        }
+        shard.set_scheduled(agent, saved.scheduled);
+    }
+
+    // rollback undoes every processed item that follows `key`, newest first, and returns the keys of the signals
+    // they caused, which must be cancelled
+    fn rollback(&mut self, key: &MessageKey) -> Vec<MessageKey> {
+        let mut cancelled = Vec::new();
+
+        while self.processed.back().is_some_and(|last| last.follows(key)) {
+            let undone = self.processed.pop_back().expect("checked above");
+            self.restore(undone.item.agent(), undone.saved);
+            if let Item::Signal { key, agent, name } = undone.item {
+                self.shard.signals.insert(key, (agent, name));
+            }
+
+            self.stats.rolled_back += 1;
+            cancelled.extend(undone.sent);
+        }
+
+        self.evaluated = self.evaluated.min(self.processed.len());
+        cancelled
+    }
+
+    // deliver queues a signal, first rolling back if it is a straggler
+    fn deliver(&mut self, key: MessageKey, agent: usize, name: String) -> Vec<MessageKey> {
+        let cancelled = self.rollback(&key);
+        self.shard.signals.insert(key, (agent, name));
+        cancelled
+    }
+
+    // cancel annihilates a signal with its anti-message, first rolling back if it has already been processed
+    fn cancel(&mut self, key: &MessageKey) -> Vec<MessageKey> {
+        let cancelled = if self.shard.signals.contains_key(key) {
+            Vec::new()
+        } else {
+            self.rollback(key)
+        };
+
+        self.shard.signals.remove(key);
+        cancelled
+    }
+
+    // fossil_collect commits and forgets the items due before `gvt` (or all of them), returning their changes
+    fn fossil_collect(&mut self, gvt: Option<DateTime<Utc>>) -> Vec<TaggedEvent> {
+        let mut committed = Vec::new();
+
+        while let Some(first) = self.processed.front()
+            && gvt.is_none_or(|gvt| first.item.time() < gvt)
+        {
+            let first = self.processed.pop_front().expect("checked above");
+            committed.extend(first.output);
+            self.evaluated = self.evaluated.saturating_sub(1);
+        }
+
+        committed
+    }
+}
+
+/// OptimisticSimulation runs one large simulation across threads using Time Warp, an optimistic synchronization
+/// protocol. Unlike `ParallelSimulation`, logical processes are not held back by the signal delay: they run
+/// speculatively up to an optimism window past the global virtual time (GVT), checkpointing each agent before it
+/// changes. A signal arriving in the past of a process (a straggler) rolls it back; the signals caused by the undone
+/// changes are cancelled with anti-messages, which may roll back other processes in turn. Changes older than the GVT
+/// can no longer be undone and are committed and streamed in time order.
+///
+/// Processes synchronize in rounds: they run in parallel up to the window, then exchange signals and anti-messages
+/// and agree on the GVT. Results are identical to those of `ParallelSimulation` with a lookahead equal to the signal
+/// delay (and, without interactions, to those of the sequential `Simulation`), and therefore independent of the
+/// number of threads and of the window. Agents must support checkpoints, which the built-in `Agent` and
+/// `ReplayAgent` do. Interactions may be evaluated more than once for changes that are rolled back and redone, so
+/// they should not keep state.
+pub struct OptimisticSimulation {
+    processes: Vec<LogicalProcess>,
+    directory: Directory,
+    interactions: Vec<Interaction>,
+    signal_delay: Option<Duration>,
+    window: Option<Duration>,
+    current_time: DateTime<Utc>,
+    rounds: u64,
+    anti_messages: u64,
+    started: bool,
+}
+
+impl OptimisticSimulation {
+    // from_simulation distributes the population of a simulation across `threads` logical processes, or returns
+    // `ParallelError::MissingCheckpoint` if one of its agents does not support checkpoints
+    pub fn from_simulation(sim: Simulation, threads: usize) -> Result<Self, ParallelError> {
+        let parts = sim.into_parts();
+        if let Some(agent) = parts
+            .agents
+            .iter()
+            .find(|agent| agent.checkpoint().is_none())
+        {
+            return Err(ParallelError::MissingCheckpoint(agent.id().to_string()));
+        }
+
+        let current_time = parts.current_time;
+        let (shards, directory, interactions) = partition(parts, threads);
+
+        Ok(OptimisticSimulation {
+            processes: shards
+                .into_iter()
+                .map(|shard| LogicalProcess {
+                    shard,
+                    processed: VecDeque::new(),
+                    evaluated: 0,
+                    stats: OptimisticStats::default(),
+                })
+                .collect(),
+            directory,
+            interactions,
+            signal_delay: None,
+            window: None,
+            current_time,
+            rounds: 0,
+            anti_messages: 0,
+            started: false,
+        })
+    }
+
+    // with_signal_delay sets the delay between a change and the delivery of the signals it causes. It is required
+    // when the simulation has interactions, but unlike a conservative lookahead it may be arbitrarily small.
+    pub fn with_signal_delay(mut self, delay: Duration) -> Self {
+        self.signal_delay = Some(delay);
+        self
+    }
+
+    // with_window bounds how far past the GVT processes may run speculatively. Larger windows need fewer rounds but
+    // risk longer rollbacks and hold more checkpoints. Defaults to 100 signal delays.
+    pub fn with_window(mut self, window: Duration) -> Self {
+        self.window = Some(window);
+        self
+    }
+
+    pub fn current_time(&self) -> DateTime<Utc> {
+        self.current_time
+    }
+
+    // stats returns the work done so far, summed over all logical processes
+    pub fn stats(&self) -> OptimisticStats {
+        let mut stats = OptimisticStats {
+            rounds: self.rounds,
+            anti_messages: self.anti_messages,
+            ..OptimisticStats::default()
+        };
+        for process in &self.processes {
+            stats.processed += process.stats.processed;
+            stats.rolled_back += process.stats.rolled_back;
+        }
+        stats
+    }
+
+    // run processes the simulation over a specified duration, returning the committed events
+    pub fn run(&mut self, duration: Duration) -> Result<Vec<StateChangeEvent>, ParallelError> {
+        let mut events = Vec::new();
+        self.run_streaming(duration, |event| events.push(event))?;
+        Ok(events)
+    }
+
+    // run_streaming processes the simulation over a specified duration, handing events to `callback` in time order
+    // as they are committed. It fails without running if the simulation has interactions but no positive signal
+    // delay.
+    pub fn run_streaming<F>(
+        &mut self,
+        duration: Duration,
+        mut callback: F,
+    ) -> Result<(), ParallelError>
+    where
+        F: FnMut(StateChangeEvent),
+    {
+        let delay = self.signal_delay.filter(|delay| *delay > Duration::zero());
+        if delay.is_none() && !self.interactions.is_empty() {
+            return Err(ParallelError::MissingLookahead);
+        }
+
+        let end_time = self.current_time + duration;
+        let window = match (self.window, delay) {
+            (Some(window), _) if window > Duration::zero() => window,
+            (_, Some(delay)) => delay * 100,
+            _ => (duration / WINDOWS_WITHOUT_LOOKAHEAD).max(Duration::nanoseconds(1)),
+        };
+
+        if !self.started {
+            self.started = true;
+            run_parallel(&mut self.processes, |process| {
+                process.shard.start();
+                Vec::<()>::new()
+            });
+        }
+
+        let mut gvt = self.current_time;
+        loop {
+            let limit = (gvt + window).min(end_time);
+            let inclusive = limit == end_time;
+
+            run_parallel(&mut self.processes, |process| {
+                process.run_until(limit, inclusive);
+                Vec::<()>::new()
+            });
+            self.rounds += 1;
+            self.exchange_signals();
+
+            // no signal is in transit once they have been exchanged, so the GVT is the earliest pending work
+            let next = self
+                .processes
+                .iter_mut()
+                .filter_map(|process| process.shard.next_time())
+                .min();
+            let done = next.is_none_or(|next| next > end_time);
+
+            let mut committed: Vec<TaggedEvent> = self
+                .processes
+                .iter_mut()
+                .flat_map(|process| process.fossil_collect(next.filter(|_| !done)))
+                .collect();
+            sort_tagged(&mut committed);
+            for tagged in committed {
+                callback(tagged.event);
+            }
+
+            match next {
+                Some(next) if !done => gvt = next,
+                _ => break,
+            }
+        }
+
+        self.current_time = end_time;
+        Ok(())
+    }
+
+    // exchange_signals runs the interactions over the changes processed since the last round, delivers the signals
+    // they emit, and then propagates anti-messages until every process is consistent again
+    fn exchange_signals(&mut self) {
+        let Some(delay) = self.signal_delay.filter(|_| !self.interactions.is_empty()) else {
+            return;
+        };
+
+        let mut fresh = Vec::new();
+        for (p, process) in self.processes.iter().enumerate() {
+            for (e, processed) in process.processed.iter().enumerate().skip(process.evaluated) {
+                for (o, tagged) in processed.output.iter().enumerate() {
+                    fresh.push((tagged.event.time, tagged.agent, tagged.seq, p, e, o));
+                }
+            }
+        }
+        fresh.sort();
+
+        let mut messages = Vec::new();
+        for (time, source, seq, p, e, o) in fresh {
+            let event = &self.processes[p].processed[e].output[o].event;
+            let signals: Vec<_> = self
+                .interactions
+                .iter_mut()
+                .flat_map(|interaction| interaction(event))
+                .collect();
+
+            for (index, signal) in signals.into_iter().enumerate() {
+                for target in self.directory.targets(&signal.target) {
+                    let key = MessageKey {
+                        time: time + delay,
+                        source,
+                        seq,
+                        index,
+                        target,
+                    };
+                    self.processes[p].processed[e].sent.push(key);
+                    messages.push((key, signal.name.clone()));
+                }
+            }
+        }
+
+        for process in &mut self.processes {
+            process.evaluated = process.processed.len();
+        }
+
+        let mut cancelled = VecDeque::new();
+        for (key, name) in messages {
+            let (p, agent) = self.directory.locations[key.target];
+            cancelled.extend(self.processes[p].deliver(key, agent, name));
+        }
+
+        while let Some(key) = cancelled.pop_front() {
+            self.anti_messages += 1;
+            let (p, _) = self.directory.locations[key.target];
+            cancelled.extend(self.processes[p].cancel(&key));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::agent::{Agent, SimAgent, StateType};
+    use crate::composite::CompositeAgent;
+    use crate::interaction::Signal;
+    use crate::parallel::ParallelSimulation;
+    use crate::rng::RandomStreams;
+    use crate::state::AgentId;
+    use crate::value::Value;
+    use chrono::TimeZone;
+    use rand::SeedableRng;
+    use rand::rngs::StdRng;
+    use state_macros::State;
+    use std::collections::HashMap;
+
+    #[derive(Clone, Default, Debug, State)]
+    struct Level {
+        value: u32,
+    }
+
+    fn transitions() -> HashMap<u32, StateType<u32, Level>> {
+        let mut transitions = HashMap::new();
+        for mode in 0..3 {
+            transitions.insert(
+                mode,
+                StateType::new_deterministic(
+                    move || Level { value: mode },
+                    vec![((mode + 1) % 3, 1.0), ((mode + 2) % 3, 1.0)],
+                    30.0,
+                )
+                .with_signal("reset", 0)
+                .with_signal("kick", 1),
+            );
+        }
+        transitions
+    }
+
+    // population couples agents densely so that speculative execution is frequently wrong: agent_00 resets everyone
+    // and every agent reaching mode 2 kicks its neighbour
+    fn population(with_interaction: bool) -> Simulation {
+        let start = Utc.timestamp_opt(0, 0).unwrap();
+        let mut rng = StdRng::seed_from_u64(0);
+
+        let agents = (0..12)
+            .map(|i| Agent::new(format!("agent_{:02}", i), 0, transitions(), &mut rng).unwrap())
+            .collect();
+        let mut sim = Simulation::new_with_seed(agents, start, 0).unwrap();
+
+        let composite = CompositeAgent::new("rack".to_string(), "ok")
+            .with_child(Agent::new("a".to_string(), 0, transitions(), &mut rng).unwrap())
+            .with_child(Agent::new("b".to_string(), 0, transitions(), &mut rng).unwrap())
+            .with_rule("hot", |children| {
+                children.iter().all(|(_, mode)| **mode == 2)
+            });
+        sim.add_composite(composite).unwrap();
+
+        if with_interaction {
+            sim.add_interaction(|event| {
+                let mut signals = Vec::new();
+                if event.agent_id == "agent_00" && event.new_value == "2" {
+                    signals.push(Signal::to_all("reset"));
+                }
+                if let Some(n) = event.agent_id.strip_prefix("agent_")
+                    && event.new_value == "2"
+                {
+                    let next = (n.parse::<usize>().unwrap() + 1) % 12;
+                    signals.push(Signal::to_agent(&format!("agent_{:02}", next), "kick"));
+                }
+                signals
+            });
+        }
+        sim
+    }
+
+    fn summarize(events: &[StateChangeEvent]) -> Vec<(i64, AgentId, Value)> {
+        events
+            .iter()
+            .map(|e| {
+                (
+                    e.time.timestamp_millis(),
+                    e.agent_id.clone(),
+                    e.new_value.clone(),
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_optimistic_matches_sequential_execution() {
+        let mut reference = population(false);
+        let expected = summarize(reference.run(Duration::hours(1)));
+        assert!(expected.iter().any(|(_, id, _)| id == "rack"));
+
+        for (threads, window) in [(1, 60), (3, 120), (4, 600)] {
+            let mut sim = OptimisticSimulation::from_simulation(population(false), threads)
+                .unwrap()
+                .with_window(Duration::seconds(window));
+            let mut events = sim.run(Duration::minutes(20)).unwrap();
+            events.extend(sim.run(Duration::minutes(40)).unwrap());
+            assert_eq!(summarize(&events), expected, "threads {}", threads);
+        }
+    }
+
+    // the sequential `Simulation` delivers signals at the time of the change that caused them, which optimistic
+    // execution cannot, so with interactions the reference is conservative execution with the same signal delay
+    #[test]
+    fn test_optimistic_interactions_match_conservative_execution() {
+        let delay = Duration::milliseconds(500);
+        let mut reference =
+            ParallelSimulation::from_simulation(population(true), 1).with_lookahead(delay);
+        let expected = summarize(&reference.run(Duration::hours(1)).unwrap());
+
+        for (threads, window) in [(1, 60), (3, 120), (4, 600)] {
+            let mut sim = OptimisticSimulation::from_simulation(population(true), threads)
+                .unwrap()
+                .with_signal_delay(delay)
+                .with_window(Duration::seconds(window));
+            let mut events = sim.run(Duration::minutes(20)).unwrap();
+            events.extend(sim.run(Duration::minutes(40)).unwrap());
+            assert_eq!(summarize(&events), expected, "threads {}", threads);
+
+            let stats = sim.stats();
+            assert!(stats.processed >= events.len() as u64 / 2);
+            if threads > 1 {
+                assert!(stats.rolled_back > 0, "speculation should have been wrong");
+            }
+        }
+    }
+
+    #[test]
+    fn test_checkpoint_restores_agent() {
+        let start = Utc.timestamp_opt(0, 0).unwrap();
+        let time = start + Duration::seconds(10);
+        let mut rng = StdRng::seed_from_u64(3);
+        let mut agent = Agent::new("a".to_string(), 0, transitions(), &mut rng).unwrap();
+        agent.start(start);
+        SimAgent::schedule(&mut agent, start, &mut rng);
+
+        let checkpoint = agent.checkpoint().unwrap();
+        let mut replayed_rng = rng.clone();
+        let first = summarize(&agent.fire(time, &mut rng));
+        assert_eq!(agent.stats().transitions(), 1);
+
+        agent.restore(&checkpoint);
+        assert_eq!(*agent.current_state_type(), 0);
+        assert_eq!(agent.data.value, 0);
+        assert_eq!(agent.stats().transitions(), 0);
+        assert_eq!(summarize(&agent.fire(time, &mut replayed_rng)), first);
+    }
+
+    #[test]
+    fn test_optimistic_interactions_require_delay() {
+        let mut sim = OptimisticSimulation::from_simulation(population(true), 2).unwrap();
+        assert!(matches!(
+            sim.run(Duration::hours(1)),
+            Err(ParallelError::MissingLookahead)
+        ));
+    }
+
+    #[test]
+    fn test_optimistic_requires_checkpoints() {
+        struct Silent;
+
+        impl SimAgent for Silent {
+            fn id(&self) -> &str {
+                "silent"
+            }
+
+            fn schedule(
+                &mut self,
+                _now: DateTime<Utc>,
+                _rng: &mut dyn RandomStreams,
+            ) -> Option<DateTime<Utc>> {
+                None
+            }
+
+            fn fire(
+                &mut self,
+                _time: DateTime<Utc>,
+                _rng: &mut dyn RandomStreams,
+            ) -> Vec<StateChangeEvent> {
+                Vec::new()
+            }
+        }
+
+        let mut sim = population(false);
+        sim.add_agent(Silent).unwrap();
+        assert!(matches!(
+            OptimisticSimulation::from_simulation(sim, 2),
+            Err(ParallelError::MissingCheckpoint(id)) if id == "silent"
+        ));
+    }
+}
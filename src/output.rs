@@ -0,0 +1,192 @@
+use crate::state::{StateChangeEvent, StringEvent};
+use std::io::{self, Write};
+
+/// EventSink writes state change events as they are produced, i.e. from `Simulation::run_streaming`, so that long
+/// runs do not need to hold their events in memory. Sinks write the formats `replay::read_jsonl` and
+/// `replay::read_csv` read back.
+pub trait EventSink {
+    fn write(&mut self, event: &StateChangeEvent) -> io::Result<()>;
+
+    // flush writes out anything buffered, and must be called once the last event has been written
+    fn flush(&mut self) -> io::Result<()>;
+}
+
+/// JsonlSink writes one serialized event per line (JSON Lines), with typed values unless asked for strings.
+pub struct JsonlSink<W: Write> {
+    writer: W,
+    string_values: bool,
+}
+
+impl<W: Write> JsonlSink<W> {
+    pub fn new(writer: W) -> Self {
+        JsonlSink {
+            writer,
+            string_values: false,
+        }
+    }
+
+    // with_string_values writes values in their string form (see `StringEvent`), for consumers of the JSON events
+    // written before values were typed
+    pub fn with_string_values(mut self) -> Self {
+        self.string_values = true;
+        self
+    }
+
+    // into_inner returns the underlying writer
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+}
+
+impl<W: Write> EventSink for JsonlSink<W> {
+    fn write(&mut self, event: &StateChangeEvent) -> io::Result<()> {
+        if self.string_values {
+            serde_json::to_writer(&mut self.writer, &StringEvent::from(event))?;
+        } else {
+            serde_json::to_writer(&mut self.writer, event)?;
+        }
+        self.writer.write_all(b"\n")
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+/// CsvSink writes events as CSV, with the serialized field names as headers and values in their string form, which
+/// is also how they read back.
+pub struct CsvSink<W: Write> {
+    writer: csv::Writer<W>,
+}
+
+impl<W: Write> CsvSink<W> {
+    pub fn new(writer: W) -> Self {
+        CsvSink {
+            writer: csv::Writer::from_writer(writer),
+        }
+    }
+}
+
+impl<W: Write> EventSink for CsvSink<W> {
+    fn write(&mut self, event: &StateChangeEvent) -> io::Result<()> {
+        self.writer
+            .serialize(StringEvent::from(event))
+            .map_err(io::Error::other)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::replay::{read_csv, read_jsonl};
+    use crate::value::Value;
+    use chrono::{TimeZone, Utc};
+
+    #[test]
+    fn test_sinks_round_trip_through_replay() {
+        let events: Vec<StateChangeEvent> = (0..3)
+            .map(|i| StateChangeEvent {
+                time: Utc.timestamp_opt(i * 60, 0).unwrap(),
+                agent_id: format!("device_{}", i).into(),
+                field: "status",
+                old_value: "idle, waiting".into(),
+                new_value: Value::Int(i),
+            })
+            .collect();
+        let fields = |events: Vec<StateChangeEvent>| {
+            events
+                .into_iter()
+                .map(|event| (event.time, event.agent_id, event.old_value, event.new_value))
+                .collect::<Vec<_>>()
+        };
+
+        let mut jsonl = JsonlSink::new(Vec::new());
+        let mut csv = CsvSink::new(Vec::new());
+        for event in &events {
+            jsonl.write(event).unwrap();
+            csv.write(event).unwrap();
+        }
+        jsonl.flush().unwrap();
+        csv.flush().unwrap();
+
+        let written_jsonl = String::from_utf8(jsonl.writer).unwrap();
+        assert_eq!(written_jsonl.lines().count(), 3);
+        assert_eq!(
+            fields(read_jsonl(written_jsonl.as_bytes()).unwrap()),
+            fields(events.clone())
+        );
+        // CSV carries the string form of values, which reads back as strings
+        let strings = events
+            .iter()
+            .map(|event| StateChangeEvent::from(StringEvent::from(event)))
+            .collect();
+        let written = csv.writer.into_inner().unwrap();
+        assert_eq!(
+            fields(read_csv(written.as_slice()).unwrap()),
+            fields(strings)
+        );
+
+        // values keep their type in JSON, unless written as strings
+        assert!(
+            written_jsonl
+                .lines()
+                .next()
+                .unwrap()
+                .contains(r#""NewValue":0"#)
+        );
+        let mut strings = JsonlSink::new(Vec::new()).with_string_values();
+        strings.write(&events[0]).unwrap();
+        let written = String::from_utf8(strings.into_inner()).unwrap();
+        assert!(written.contains(r#""NewValue":"0","OldValue":"idle, waiting""#));
+    }
+
+    #[test]
+    fn test_strings_that_look_like_other_types_round_trip() {
+        let time = Utc.timestamp_opt(0, 0).unwrap();
+        let events: Vec<StateChangeEvent> = ["1.10", "007", "2024-01-01T00:00:00Z"]
+            .into_iter()
+            .map(|text| StateChangeEvent {
+                time,
+                agent_id: "device_0".into(),
+                field: "firmware",
+                old_value: Value::Timestamp(time),
+                new_value: text.into(),
+            })
+            .collect();
+        let values = |events: Vec<StateChangeEvent>| {
+            events
+                .into_iter()
+                .map(|event| (event.old_value, event.new_value))
+                .collect::<Vec<_>>()
+        };
+
+        let mut jsonl = JsonlSink::new(Vec::new());
+        let mut csv = CsvSink::new(Vec::new());
+        for event in &events {
+            jsonl.write(event).unwrap();
+            csv.write(event).unwrap();
+        }
+        jsonl.flush().unwrap();
+        csv.flush().unwrap();
+
+        let written = jsonl.into_inner();
+        assert_eq!(
+            values(read_jsonl(written.as_slice()).unwrap()),
+            values(events.clone())
+        );
+        let written = csv.writer.into_inner().unwrap();
+        let read = values(read_csv(written.as_slice()).unwrap());
+        assert_eq!(
+            read.iter().map(|(_, new)| new).collect::<Vec<_>>(),
+            ["1.10", "007", "2024-01-01T00:00:00Z"]
+                .map(Value::from)
+                .iter()
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(read[0].0, Value::from("1970-01-01 00:00:00 UTC"));
+    }
+}
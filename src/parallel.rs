@@ -0,0 +1,672 @@
+use crate::agent::SimAgent;
+use crate::interaction::{Interaction, SignalTarget};
+use crate::rng::AgentStreams;
+use crate::simulation::{CompositeGroup, ScheduledEvent, Simulation, SimulationParts};
+use crate::state::StateChangeEvent;
+use chrono::{DateTime, Duration, Utc};
+use std::any::Any;
+use std::collections::{BTreeMap, BinaryHeap, HashMap};
+use std::fmt;
+use std::thread;
+
+// WINDOWS_WITHOUT_LOOKAHEAD is the number of windows a run is split into when there is no lookahead to size them, so
+// that streamed events are handed over as the run progresses
+pub(crate) const WINDOWS_WITHOUT_LOOKAHEAD: i32 = 100;
+
+/// ParallelError is returned when a simulation cannot be run by a parallel engine.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ParallelError {
+    // the simulation has interactions, but no positive delay to deliver the signals they emit with
+    MissingLookahead,
+    // an agent does not support checkpoints, which optimistic execution requires to roll it back
+    MissingCheckpoint(String),
+}
+
+impl fmt::Display for ParallelError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParallelError::MissingLookahead => write!(
+                f,
+                "a positive lookahead is required to run interactions in parallel"
+            ),
+            ParallelError::MissingCheckpoint(id) => write!(
+                f,
+                "agent {:?} does not support checkpoints, which optimistic execution requires",
+                id
+            ),
+        }
+    }
+}
+
+impl std::error::Error for ParallelError {}
+
+// TaggedEvent is a change tagged with the agent that caused it and its position in that agent's output, which gives
+// a total order over the output of all shards
+pub(crate) struct TaggedEvent {
+    pub(crate) agent: usize,
+    pub(crate) seq: u64,
+    pub(crate) event: StateChangeEvent,
+}
+
+impl TaggedEvent {
+    fn order_key(&self) -> (DateTime<Utc>, usize, u64) {
+        (self.event.time, self.agent, self.seq)
+    }
+}
+
+// sort_tagged sorts changes into the order the sequential engines would have produced them in
+pub(crate) fn sort_tagged(events: &mut [TaggedEvent]) {
+    events.sort_by_key(TaggedEvent::order_key);
+}
+
+/// MessageKey identifies a signal sent between agents by its delivery time, the change that caused it (agent and
+/// position in its output), its position among the signals emitted for that change and its target agent. Signals
+/// due at the same time are delivered in key order, which does not depend on how agents are sharded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub(crate) struct MessageKey {
+    pub(crate) time: DateTime<Utc>,
+    pub(crate) source: usize,
+    pub(crate) seq: u64,
+    pub(crate) index: usize,
+    pub(crate) target: usize,
+}
+
+// Item is the next piece of work of a shard: a signal to deliver or an event to fire
+pub(crate) enum Item {
+    Signal {
+        key: MessageKey,
+        agent: usize,
+        name: String,
+    },
+    Event {
+        agent: usize,
+        time: DateTime<Utc>,
+    },
+}
+
+impl Item {
+    pub(crate) fn agent(&self) -> usize {
+        match self {
+            Item::Signal { agent, .. } | Item::Event { agent, .. } => *agent,
+        }
+    }
+
+    pub(crate) fn time(&self) -> DateTime<Utc> {
+        match self {
+            Item::Signal { key, .. } => key.time,
+            Item::Event { time, .. } => *time,
+        }
+    }
+}
+
+/// A Shard owns a subset of the agents of a parallel simulation together with their event queue. Every agent draws
+/// from its own random streams, so the events of an agent do not depend on which shard it was assigned to.
+pub(crate) struct Shard {
+    pub(crate) agents: Vec<Box<dyn SimAgent>>,
+    global: Vec<usize>,
+    pub(crate) rngs: Vec<AgentStreams>,
+    generations: Vec<u64>,
+    started: Vec<bool>,
+    pub(crate) scheduled: Vec<Option<DateTime<Utc>>>,
+    pub(crate) emitted: Vec<u64>,
+    pub(crate) composites: Vec<CompositeGroup>,
+    pub(crate) parent_of: Vec<Option<usize>>,
+    queue: BinaryHeap<ScheduledEvent>,
+    pub(crate) signals: BTreeMap<MessageKey, (usize, String)>,
+    current_time: DateTime<Utc>,
+}
+
+impl Shard {
+    fn new(current_time: DateTime<Utc>) -> Self {
+        Shard {
+            agents: Vec::new(),
+            global: Vec::new(),
+            rngs: Vec::new(),
+            generations: Vec::new(),
+            started: Vec::new(),
+            scheduled: Vec::new(),
+            emitted: Vec::new(),
+            composites: Vec::new(),
+            parent_of: Vec::new(),
+            queue: BinaryHeap::new(),
+            signals: BTreeMap::new(),
+            current_time,
+        }
+    }
+
+    fn push_agent(
+        &mut self,
+        agent: Box<dyn SimAgent>,
+        streams: AgentStreams,
+        global: usize,
+        parent: Option<usize>,
+    ) {
+        self.rngs.push(streams);
+        self.agents.push(agent);
+        self.global.push(global);
+        self.generations.push(0);
+        self.started.push(false);
+        self.scheduled.push(None);
+        self.emitted.push(0);
+        self.parent_of.push(parent);
+    }
+
+    // start starts and schedules the agents that were not started by the simulation the shard was taken from
+    pub(crate) fn start(&mut self) {
+        for index in 0..self.agents.len() {
+            if !self.started[index] {
+                self.agents[index].start(self.current_time);
+                self.schedule_next_event(index);
+            }
+        }
+    }
+
+    // resume queues the event an agent had scheduled in the simulation the shard was taken from, i.e. after a warm-up
+    fn resume(&mut self, agent: usize, time: Option<DateTime<Utc>>) {
+        self.started[agent] = true;
+        self.set_scheduled(agent, time);
+    }
+
+    // run_until processes every event and signal due before `limit` (or at it, if inclusive)
+    fn run_until(&mut self, limit: DateTime<Utc>, inclusive: bool) -> Vec<TaggedEvent> {
+        let mut output = Vec::new();
+        while let Some(item) = self.next_due(limit, inclusive) {
+            self.process(&item, &mut output);
+        }
+        output
+    }
+
+    // next_due removes and returns the next signal or event due before `limit` (or at it, if inclusive). Signals
+    // are delivered before events due at the same time.
+    pub(crate) fn next_due(&mut self, limit: DateTime<Utc>, inclusive: bool) -> Option<Item> {
+        let due = |time: DateTime<Utc>| time < limit || (inclusive && time == limit);
+        let next_event = self.next_event_time();
+
+        if let Some((key, _)) = self.signals.first_key_value()
+            && due(key.time)
+            && next_event.is_none_or(|event| key.time <= event)
+        {
+            let (key, (agent, name)) = self.signals.pop_first().expect("peeked signal");
+            return Some(Item::Signal { key, agent, name });
+        }
+
+        match next_event {
+            Some(time) if due(time) => {
+                let event = self.queue.pop().expect("peeked event");
+                Some(Item::Event {
+                    agent: event.agent_index,
+                    time,
+                })
+            }
+            _ => None,
+        }
+    }
+
+    // next_time returns the time of the next signal or event of the shard, if any
+    pub(crate) fn next_time(&mut self) -> Option<DateTime<Utc>> {
+        let next_signal = self.signals.first_key_value().map(|(key, _)| key.time);
+        match (self.next_event_time(), next_signal) {
+            (Some(event), Some(signal)) => Some(event.min(signal)),
+            (event, signal) => event.or(signal),
+        }
+    }
+
+    // next_event_time discards superseded events from the top of the queue and returns the time of the next one
+    fn next_event_time(&mut self) -> Option<DateTime<Utc>> {
+        while let Some(event) = self.queue.peek() {
+            if event.generation == self.generations[event.agent_index] {
+                return Some(event.time);
+            }
+            self.queue.pop();
+        }
+        None
+    }
+
+    // process applies a signal or event taken from next_due, appending the resulting changes to `output`
+    pub(crate) fn process(&mut self, item: &Item, output: &mut Vec<TaggedEvent>) {
+        self.current_time = item.time();
+
+        match item {
+            Item::Signal { key, agent, name } => {
+                let agent = *agent;
+                if let Some(changes) =
+                    self.agents[agent].signal(name, key.time, &mut self.rngs[agent])
+                {
+                    self.emit(agent, changes, output);
+                    self.schedule_next_event(agent);
+                }
+            }
+            Item::Event { agent, time } => {
+                let agent = *agent;
+                let changes = self.agents[agent].fire(*time, &mut self.rngs[agent]);
+                self.emit(agent, changes, output);
+                self.schedule_next_event(agent);
+            }
+        }
+    }
+
+    fn emit(
+        &mut self,
+        agent: usize,
+        mut changes: Vec<StateChangeEvent>,
+        output: &mut Vec<TaggedEvent>,
+    ) {
+        if let Some(group) = self.parent_of[agent] {
+            let group = &mut self.composites[group];
+            let modes: Vec<&dyn Any> = self.agents[group.members.clone()]
+                .iter()
+                .map(|agent| agent.mode())
+                .collect();
+            changes.extend(group.rollup.update(&modes, self.current_time));
+        }
+
+        for event in changes {
+            output.push(TaggedEvent {
+                agent: self.global[agent],
+                seq: self.emitted[agent],
+                event,
+            });
+            self.emitted[agent] += 1;
+        }
+    }
+
+    fn schedule_next_event(&mut self, agent: usize) {
+        let time = self.agents[agent]
+            .schedule(self.current_time, &mut self.rngs[agent])
+            .map(|time| time.max(self.current_time));
+        self.set_scheduled(agent, time);
+    }
+
+    // set_scheduled replaces the queued event of an agent
+    pub(crate) fn set_scheduled(&mut self, agent: usize, time: Option<DateTime<Utc>>) {
+        self.generations[agent] += 1;
+        self.scheduled[agent] = time;
+
+        if let Some(time) = time {
+            self.queue.push(ScheduledEvent {
+                time,
+                agent_index: agent,
+                generation: self.generations[agent],
+            });
+        }
+    }
+}
+
+/// Directory locates the agents of a sharded simulation, as (shard, index within the shard), by global index or id.
+pub(crate) struct Directory {
+    pub(crate) locations: Vec<(usize, usize)>,
+    agent_index: HashMap<String, usize>,
+}
+
+impl Directory {
+    // targets resolves the target of a signal to global agent indices, in ascending order
+    pub(crate) fn targets(&self, target: &SignalTarget) -> Vec<usize> {
+        match target {
+            SignalTarget::Agent(id) => self.agent_index.get(id).copied().into_iter().collect(),
+            SignalTarget::All => (0..self.locations.len()).collect(),
+        }
+    }
+}
+
+// partition distributes agents across `count` shards. Agents are assigned round-robin, with the members of a
+// composite kept together as one unit so that roll-ups stay local to a shard.
+// Agents keep the events they had scheduled in the simulation. The interactions are handed back.
+pub(crate) fn partition(
+    parts: SimulationParts,
+    count: usize,
+) -> (Vec<Shard>, Directory, Vec<Interaction>) {
+    let count = count.max(1);
+    let mut shards: Vec<Shard> = (0..count).map(|_| Shard::new(parts.current_time)).collect();
+    let mut locations = vec![(0, 0); parts.agents.len()];
+    let mut agent_index = HashMap::new();
+
+    let mut composites: Vec<Option<CompositeGroup>> =
+        parts.composites.into_iter().map(Some).collect();
+    let mut units = 0;
+    let mut local_group = None;
+
+    let population = parts
+        .agents
+        .into_iter()
+        .zip(parts.streams)
+        .zip(parts.parent_of);
+    for (global, ((agent, streams), parent)) in population.enumerate() {
+        let starts_unit = parent.is_none_or(|group| composites[group].is_some());
+        if starts_unit {
+            units += 1;
+        }
+
+        let shard_index = (units - 1) % count;
+        let shard = &mut shards[shard_index];
+
+        if starts_unit {
+            local_group = parent.map(|group| {
+                let mut composite = composites[group].take().expect("composite assigned once");
+                let start = shard.agents.len();
+                composite.members = start..start + composite.members.len();
+                shard.composites.push(composite);
+                shard.composites.len() - 1
+            });
+        }
+
+        agent_index.insert(agent.id().to_string(), global);
+        let local = shard.agents.len();
+        locations[global] = (shard_index, local);
+        shard.push_agent(agent, streams, global, local_group);
+        if global < parts.started {
+            shard.resume(local, parts.scheduled[global]);
+        }
+    }
+
+    (
+        shards,
+        Directory {
+            locations,
+            agent_index,
+        },
+        parts.interactions,
+    )
+}
+
+// run_parallel runs `task` on every worker, one thread per worker, and concatenates their results in worker order
+pub(crate) fn run_parallel<W, T, F>(workers: &mut [W], task: F) -> Vec<T>
+where
+    W: Send,
+    T: Send,
+    F: Fn(&mut W) -> Vec<T> + Sync,
+{
+    if let [worker] = workers {
+        return task(worker);
+    }
+
+    thread::scope(|scope| {
+        let handles: Vec<_> = workers
+            .iter_mut()
+            .map(|worker| scope.spawn(|| task(worker)))
+            .collect();
+
+        handles
+            .into_iter()
+            .flat_map(|handle| handle.join().expect("worker panicked"))
+            .collect()
+    })
+}
+
+/// ParallelSimulation runs one large simulation across threads. Agents (composite agents as a whole) are sharded
+/// across workers with their own event queues, and the output of the shards is merged into one time-ordered stream.
+///
+/// Agents keep the random streams they were given by the simulation, so results are reproducible for a fixed seed
+/// regardless of the number of threads, and without interactions identical to those of the sequential `Simulation`.
+///
+/// Interactions are supported through conservative synchronization: signals take effect `lookahead` after the change
+/// that caused them, and shards advance in windows of that length, exchanging signals at the end of each window.
+pub struct ParallelSimulation {
+    shards: Vec<Shard>,
+    directory: Directory,
+    interactions: Vec<Interaction>,
+    lookahead: Option<Duration>,
+    current_time: DateTime<Utc>,
+    started: bool,
+}
+
+impl ParallelSimulation {
+    // from_simulation distributes the population of a simulation across `threads` shards
+    pub fn from_simulation(sim: Simulation, threads: usize) -> Self {
+        let parts = sim.into_parts();
+        let current_time = parts.current_time;
+        let (shards, directory, interactions) = partition(parts, threads);
+
+        ParallelSimulation {
+            shards,
+            directory,
+            interactions,
+            lookahead: None,
+            current_time,
+            started: false,
+        }
+    }
+
+    // with_lookahead sets the delay between a change and the delivery of the signals it causes, which is also the
+    // length of the synchronization windows. It is required when the simulation has interactions.
+    pub fn with_lookahead(mut self, lookahead: Duration) -> Self {
+        self.lookahead = Some(lookahead);
+        self
+    }
+
+    pub fn current_time(&self) -> DateTime<Utc> {
+        self.current_time
+    }
+
+    // run processes the simulation over a specified duration, returning the merged events
+    pub fn run(&mut self, duration: Duration) -> Result<Vec<StateChangeEvent>, ParallelError> {
+        let mut events = Vec::new();
+        self.run_streaming(duration, |event| events.push(event))?;
+        Ok(events)
+    }
+
+    // run_streaming processes the simulation over a specified duration, handing the merged events to `callback` in
+    // time order at the end of each synchronization window. Without a lookahead, the run is split into a hundred
+    // windows. It fails without running if the simulation has interactions but no positive lookahead.
+    pub fn run_streaming<F>(
+        &mut self,
+        duration: Duration,
+        mut callback: F,
+    ) -> Result<(), ParallelError>
+    where
+        F: FnMut(StateChangeEvent),
+    {
+        let end_time = self.current_time + duration;
+        let window = self.window(duration)?;
+
+        if !self.started {
+            self.started = true;
+            run_parallel(&mut self.shards, |shard| {
+                shard.start();
+                Vec::<()>::new()
+            });
+        }
+
+        loop {
+            let limit = (self.current_time + window).min(end_time);
+            let inclusive = limit == end_time;
+
+            let mut window_events =
+                run_parallel(&mut self.shards, |shard| shard.run_until(limit, inclusive));
+            sort_tagged(&mut window_events);
+
+            self.deliver_signals(&window_events);
+            self.current_time = limit;
+
+            for tagged in window_events {
+                callback(tagged.event);
+            }
+
+            if inclusive {
+                break;
+            }
+        }
+
+        Ok(())
+    }
+
+    // window returns the length of the synchronization windows of a run over `duration`
+    fn window(&self, duration: Duration) -> Result<Duration, ParallelError> {
+        match self.lookahead {
+            Some(lookahead) if lookahead > Duration::zero() => Ok(lookahead),
+            _ if !self.interactions.is_empty() => Err(ParallelError::MissingLookahead),
+            _ => Ok((duration / WINDOWS_WITHOUT_LOOKAHEAD).max(Duration::nanoseconds(1))),
+        }
+    }
+
+    // deliver_signals runs the interactions over the changes of a window, in time order, and queues the signals they
+    // emit on the shards of their targets
+    fn deliver_signals(&mut self, events: &[TaggedEvent]) {
+        let Some(lookahead) = self.lookahead else {
+            return;
+        };
+
+        for tagged in events {
+            let signals: Vec<_> = self
+                .interactions
+                .iter_mut()
+                .flat_map(|interaction| interaction(&tagged.event))
+                .collect();
+
+            for (index, signal) in signals.into_iter().enumerate() {
+                for target in self.directory.targets(&signal.target) {
+                    let (shard, agent) = self.directory.locations[target];
+                    let key = MessageKey {
+                        time: tagged.event.time + lookahead,
+                        source: tagged.agent,
+                        seq: tagged.seq,
+                        index,
+                        target,
+                    };
+                    self.shards[shard]
+                        .signals
+                        .insert(key, (agent, signal.name.clone()));
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::agent::{Agent, StateType};
+    use crate::composite::CompositeAgent;
+    use crate::interaction::Signal;
+    use crate::state::AgentId;
+    use crate::value::Value;
+    use chrono::TimeZone;
+    use rand::SeedableRng;
+    use rand::rngs::StdRng;
+    use state_macros::State;
+
+    #[derive(Clone, Default, Debug, State)]
+    struct Level {
+        value: u32,
+    }
+
+    fn transitions() -> HashMap<u32, StateType<u32, Level>> {
+        let mut transitions = HashMap::new();
+        for mode in 0..3 {
+            transitions.insert(
+                mode,
+                StateType::new_deterministic(
+                    move || Level { value: mode },
+                    vec![((mode + 1) % 3, 1.0), ((mode + 2) % 3, 1.0)],
+                    30.0,
+                )
+                .with_signal("reset", 0),
+            );
+        }
+        transitions
+    }
+
+    fn population(with_interaction: bool) -> Simulation {
+        let start = Utc.timestamp_opt(0, 0).unwrap();
+        let mut rng = StdRng::seed_from_u64(0);
+
+        let agents = (0..20)
+            .map(|i| Agent::new(format!("agent_{:02}", i), 0, transitions(), &mut rng).unwrap())
+            .collect();
+        let mut sim = Simulation::new_with_seed(agents, start, 0).unwrap();
+
+        let composite = CompositeAgent::new("rack".to_string(), "ok")
+            .with_child(Agent::new("a".to_string(), 0, transitions(), &mut rng).unwrap())
+            .with_child(Agent::new("b".to_string(), 0, transitions(), &mut rng).unwrap())
+            .with_rule("hot", |children| {
+                children.iter().all(|(_, mode)| **mode == 2)
+            });
+        sim.add_composite(composite).unwrap();
+
+        if with_interaction {
+            sim.add_interaction(|event| {
+                if event.agent_id == "agent_00" && event.new_value == "2" {
+                    vec![Signal::to_all("reset")]
+                } else {
+                    vec![]
+                }
+            });
+        }
+        sim
+    }
+
+    fn summarize(events: &[StateChangeEvent]) -> Vec<(i64, AgentId, Value)> {
+        events
+            .iter()
+            .map(|e| {
+                (
+                    e.time.timestamp_millis(),
+                    e.agent_id.clone(),
+                    e.new_value.clone(),
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_parallel_results_independent_of_thread_count() {
+        let run = |threads| {
+            let mut sim = ParallelSimulation::from_simulation(population(false), threads);
+            let mut events = sim.run(Duration::minutes(30)).unwrap();
+            events.extend(sim.run(Duration::minutes(30)).unwrap());
+            summarize(&events)
+        };
+
+        let single = run(1);
+        assert!(!single.is_empty());
+        assert_eq!(single, summarize(population(false).run(Duration::hours(1))));
+        assert!(single.windows(2).all(|w| w[0].0 <= w[1].0));
+        assert!(single.iter().any(|(_, id, _)| id == "rack/a"));
+        assert_eq!(single, run(3));
+        assert_eq!(single, run(8));
+    }
+
+    #[test]
+    fn test_parallel_interactions_with_lookahead() {
+        let run = |threads| {
+            let mut sim = ParallelSimulation::from_simulation(population(true), threads)
+                .with_lookahead(Duration::seconds(1));
+            summarize(&sim.run(Duration::hours(1)).unwrap())
+        };
+
+        let single = run(1);
+        assert_eq!(single, run(4));
+
+        // every agent not already in mode 0 is reset one second after agent_00 reaches mode 2
+        let trigger = single
+            .iter()
+            .find(|(_, id, value)| id == "agent_00" && value == "2")
+            .expect("agent_00 should reach mode 2 within an hour");
+        assert!(
+            single
+                .iter()
+                .any(|(time, id, value)| *time == trigger.0 + 1000
+                    && id != "agent_00"
+                    && value == "0")
+        );
+    }
+
+    #[test]
+    fn test_parallel_interactions_require_lookahead() {
+        let mut sim = ParallelSimulation::from_simulation(population(true), 2);
+        assert!(matches!(
+            sim.run(Duration::hours(1)),
+            Err(ParallelError::MissingLookahead)
+        ));
+        assert_eq!(sim.current_time(), Utc.timestamp_opt(0, 0).unwrap());
+    }
+
+    #[test]
+    fn test_parallel_streams_without_lookahead() {
+        // events are handed over every 36 seconds of an hour long run rather than once at its end
+        let sim = ParallelSimulation::from_simulation(population(false), 4);
+        assert_eq!(sim.window(Duration::hours(1)), Ok(Duration::seconds(36)));
+
+        let sim = sim.with_lookahead(Duration::seconds(5));
+        assert_eq!(sim.window(Duration::hours(1)), Ok(Duration::seconds(5)));
+    }
+}
@@ -0,0 +1,312 @@
+use crate::rng::DefaultRng;
+use crate::simulation::{Simulation, SimulationCheckpoint};
+use crate::state::StateChangeEvent;
+use chrono::Duration;
+use rand::{Rng, RngCore, SeedableRng};
+use std::fmt;
+
+/// SplittingError is returned when a splitting run cannot be set up.
+#[derive(Debug, Clone, PartialEq)]
+pub enum SplittingError {
+    // the levels are not strictly increasing
+    LevelsNotIncreasing,
+    // an agent of the simulation does not support checkpoints, which restarting trajectories requires
+    MissingCheckpoint,
+}
+
+impl fmt::Display for SplittingError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SplittingError::LevelsNotIncreasing => {
+                write!(f, "splitting levels must be strictly increasing")
+            }
+            SplittingError::MissingCheckpoint => {
+                write!(f, "splitting requires agents that support checkpoints")
+            }
+        }
+    }
+}
+
+impl std::error::Error for SplittingError {}
+
+/// RareEventEstimate is the outcome of a splitting run: the estimated probability of the rare event, its relative
+/// error, the conditional probability of climbing each level and a few trajectories that reached the event.
+#[derive(Debug, Clone)]
+pub struct RareEventEstimate {
+    pub probability: f64,
+    pub relative_error: f64,
+    pub level_probabilities: Vec<f64>,
+    pub examples: Vec<Vec<StateChangeEvent>>,
+}
+
+/// MultilevelSplitting estimates the probability of events too rare to observe with plain simulation, i.e. all three
+/// redundant PSUs of a server failing within an hour. An importance function scores how close the simulation is to
+/// the rare event, and increasing thresholds on it split the path to the event into levels that are each likely to
+/// be crossed. Trajectories that cross a level are checkpointed and restarted with fresh random streams to climb
+/// the next level (fixed effort splitting); the estimate is the product of the fractions that climbed each level.
+/// Restarted trajectories draw the pending events of every agent again, so holding times should be exponential.
+pub struct MultilevelSplitting<F>
+where
+    F: Fn(&Simulation) -> f64,
+{
+    importance: F,
+    levels: Vec<f64>,
+    horizon: Duration,
+    effort: usize,
+    seed: u64,
+    examples: usize,
+}
+
+impl<F> MultilevelSplitting<F>
+where
+    F: Fn(&Simulation) -> f64,
+{
+    // new sets up the estimation of the probability that `importance` reaches the last of `levels` within `horizon`
+    // of the current simulation time. Levels must be strictly increasing.
+    pub fn new(importance: F, levels: Vec<f64>, horizon: Duration) -> Result<Self, SplittingError> {
+        if !levels.windows(2).all(|pair| pair[0] < pair[1]) {
+            return Err(SplittingError::LevelsNotIncreasing);
+        }
+
+        Ok(MultilevelSplitting {
+            importance,
+            levels,
+            horizon,
+            effort: 1000,
+            seed: 0,
+            examples: 3,
+        })
+    }
+
+    // with_effort sets the number of trajectories run per level
+    pub fn with_effort(mut self, effort: usize) -> Self {
+        self.effort = effort.max(1);
+        self
+    }
+
+    // with_seed sets the seed the random streams of each trajectory are derived from
+    pub fn with_seed(mut self, seed: u64) -> Self {
+        self.seed = seed;
+        self
+    }
+
+    // with_examples sets how many trajectories reaching the rare event are returned
+    pub fn with_examples(mut self, examples: usize) -> Self {
+        self.examples = examples;
+        self
+    }
+
+    // estimate runs the splitting from the current state of `sim`, which is restored once done. Every agent of the
+    // simulation must support checkpoints.
+    pub fn estimate(&self, sim: &mut Simulation) -> Result<RareEventEstimate, SplittingError> {
+        let initial = sim.checkpoint().ok_or(SplittingError::MissingCheckpoint)?;
+        let end_time = sim.current_time() + self.horizon;
+        let mut rng = DefaultRng::seed_from_u64(self.seed);
+
+        let mut entrances: Vec<(SimulationCheckpoint, Vec<StateChangeEvent>)> = Vec::new();
+        let mut level_probabilities = Vec::with_capacity(self.levels.len());
+
+        for (index, &level) in self.levels.iter().enumerate() {
+            let mut crossed = Vec::new();
+
+            for _ in 0..self.effort {
+                let (start, path) = match index {
+                    0 => (&initial, None),
+                    _ => {
+                        let (start, path) = &entrances[rng.gen_range(0..entrances.len())];
+                        (start, Some(path))
+                    }
+                };
+                sim.restore(start);
+                sim.reseed(rng.next_u64());
+
+                let mut trajectory = path.cloned().unwrap_or_default();
+                let reached = (self.importance)(sim) >= level
+                    || sim.run_streaming_until(
+                        end_time - sim.current_time(),
+                        |sim| (self.importance)(sim) >= level,
+                        |event| trajectory.push(event),
+                    );
+
+                if reached {
+                    let checkpoint = sim.checkpoint().ok_or(SplittingError::MissingCheckpoint)?;
+                    crossed.push((checkpoint, trajectory));
+                }
+            }
+
+            level_probabilities.push(crossed.len() as f64 / self.effort as f64);
+            entrances = crossed;
+            if entrances.is_empty() {
+                break;
+            }
+        }
+        sim.restore(&initial);
+
+        let reached_all = level_probabilities.len() == self.levels.len() && !entrances.is_empty();
+        let probability = if reached_all {
+            level_probabilities.iter().product()
+        } else {
+            0.0
+        };
+
+        // the variance of a product of per level estimates, treating levels as independent
+        let relative_error = if reached_all {
+            level_probabilities
+                .iter()
+                .map(|p| (1.0 - p) / (self.effort as f64 * p))
+                .sum::<f64>()
+                .sqrt()
+        } else {
+            f64::INFINITY
+        };
+
+        Ok(RareEventEstimate {
+            probability,
+            relative_error,
+            level_probabilities,
+            examples: entrances
+                .into_iter()
+                .take(self.examples)
+                .map(|(_, trajectory)| trajectory)
+                .collect(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::agent::{Agent, SimAgent, StateType};
+    use crate::composite::CompositeAgent;
+    use crate::rng::RandomStreams;
+    use chrono::{DateTime, TimeZone, Utc};
+    use rand::rngs::StdRng;
+    use state_macros::State;
+    use std::collections::HashMap;
+
+    #[derive(Clone, Default, Debug, State)]
+    struct Psu {
+        up: bool,
+    }
+
+    // server has three PSUs that each fail after 10 hours on average and are never repaired
+    fn server() -> Simulation {
+        let mut transitions = HashMap::new();
+        transitions.insert(
+            true,
+            StateType::new_deterministic(|| Psu { up: true }, vec![(false, 1.0)], 36_000.0),
+        );
+        transitions.insert(
+            false,
+            StateType::new_deterministic(|| Psu { up: false }, vec![], 0.0),
+        );
+
+        let mut rng = StdRng::seed_from_u64(0);
+        let mut server = CompositeAgent::new("server".to_string(), "online")
+            .with_rule("offline", |psus: &[(&str, &bool)]| {
+                psus.iter().all(|(_, up)| !**up)
+            });
+        for i in 0..3 {
+            let psu =
+                Agent::new(format!("psu_{}", i), true, transitions.clone(), &mut rng).unwrap();
+            server = server.with_child(psu);
+        }
+
+        let mut sim = Simulation::empty_with_seed(Utc.timestamp_opt(0, 0).unwrap(), 1);
+        sim.add_composite(server).unwrap();
+        sim
+    }
+
+    fn failed_psus(sim: &Simulation) -> f64 {
+        (0..3)
+            .filter(|i| sim.mode::<bool>(&format!("server/psu_{}", i)) == Some(&false))
+            .count() as f64
+    }
+
+    #[test]
+    fn test_splitting_estimates_redundancy_loss() {
+        let mut sim = server();
+        let splitting =
+            MultilevelSplitting::new(failed_psus, vec![1.0, 2.0, 3.0], Duration::hours(1))
+                .unwrap()
+                .with_effort(2000)
+                .with_seed(7);
+        let estimate = splitting.estimate(&mut sim).unwrap();
+
+        // each PSU fails within the hour with probability 1 - e^-0.1
+        let exact = (1.0 - (-0.1f64).exp()).powi(3);
+        assert!(estimate.relative_error < 0.2, "{:?}", estimate.level_probabilities);
+        assert!(
+            (estimate.probability - exact).abs() < 4.0 * estimate.relative_error * exact,
+            "estimated {} for {}",
+            estimate.probability,
+            exact
+        );
+        assert_eq!(estimate.level_probabilities.len(), 3);
+
+        // the examples end with the server going offline, and the simulation is left untouched
+        assert_eq!(estimate.examples.len(), 3);
+        let last = estimate.examples[0].last().unwrap();
+        assert_eq!((last.agent_id.as_str(), last.field), ("server", "mode"));
+        assert!(last.time <= Utc.timestamp_opt(3600, 0).unwrap());
+        assert_eq!(failed_psus(&sim), 0.0);
+        assert_eq!(sim.current_time(), Utc.timestamp_opt(0, 0).unwrap());
+    }
+
+    #[test]
+    fn test_splitting_reports_unreached_event() {
+        let mut sim = server();
+        let estimate = MultilevelSplitting::new(failed_psus, vec![1.0, 3.0], Duration::seconds(1))
+            .unwrap()
+            .with_effort(50)
+            .estimate(&mut sim)
+            .unwrap();
+
+        assert_eq!(estimate.probability, 0.0);
+        assert!(estimate.relative_error.is_infinite());
+        assert!(estimate.examples.is_empty());
+    }
+
+    #[test]
+    fn test_splitting_rejects_invalid_setups() {
+        for levels in [vec![2.0, 1.0], vec![1.0, 1.0]] {
+            assert!(matches!(
+                MultilevelSplitting::new(failed_psus, levels, Duration::hours(1)),
+                Err(SplittingError::LevelsNotIncreasing)
+            ));
+        }
+
+        struct Silent;
+
+        impl SimAgent for Silent {
+            fn id(&self) -> &str {
+                "silent"
+            }
+
+            fn schedule(
+                &mut self,
+                _now: DateTime<Utc>,
+                _rng: &mut dyn RandomStreams,
+            ) -> Option<DateTime<Utc>> {
+                None
+            }
+
+            fn fire(
+                &mut self,
+                _time: DateTime<Utc>,
+                _rng: &mut dyn RandomStreams,
+            ) -> Vec<StateChangeEvent> {
+                Vec::new()
+            }
+        }
+
+        let mut sim = server();
+        sim.add_agent(Silent).unwrap();
+        let splitting =
+            MultilevelSplitting::new(failed_psus, vec![1.0, 3.0], Duration::hours(1)).unwrap();
+        assert!(matches!(
+            splitting.estimate(&mut sim),
+            Err(SplittingError::MissingCheckpoint)
+        ));
+    }
+}
@@ -0,0 +1,285 @@
+use crate::analysis::Trajectory;
+use crate::ctmc::Generator;
+use crate::timer;
+use std::fmt;
+use std::hash::Hash;
+
+/// ReliabilityMetrics summarizes the reliability of an agent whose modes are classified as up or down, with times in
+/// seconds: the steady-state availability, the mean time to the first failure (MTTF), the mean up time between
+/// failures, the mean time to repair (MTTR) and the mean time between failures (MTBF, up and down time together).
+/// Metrics that are not defined for a model, i.e. the MTTR of a system that is never repaired, are NaN.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ReliabilityMetrics {
+    pub availability: f64,
+    pub mttf: f64,
+    pub mean_up_time: f64,
+    pub mttr: f64,
+    pub mtbf: f64,
+}
+
+impl ReliabilityMetrics {
+    // analytic computes the metrics from the chain of an agent starting in `initial`, the modes outside of `up`
+    // being down. It returns None if the chain has no unique stationary distribution.
+    pub fn analytic<C>(generator: &Generator<C>, initial: &C, up: &[C]) -> Option<Self>
+    where
+        C: Eq + Hash + Clone,
+    {
+        let down: Vec<C> = generator
+            .modes()
+            .iter()
+            .filter(|mode| !up.contains(mode))
+            .cloned()
+            .collect();
+        let stationary = generator.stationary_distribution()?;
+
+        let availability: f64 = stationary
+            .iter()
+            .filter(|(mode, _)| up.contains(mode))
+            .map(|(_, share)| share)
+            .sum();
+        let failure_frequency = generator.flow_out(&stationary, up);
+        let per_failure = |time: f64| {
+            if failure_frequency > 0.0 {
+                time / failure_frequency
+            } else {
+                f64::NAN
+            }
+        };
+
+        Some(ReliabilityMetrics {
+            availability,
+            mttf: generator
+                .mean_first_passage(initial, &down)
+                .unwrap_or(f64::NAN),
+            mean_up_time: per_failure(availability),
+            mttr: per_failure(1.0 - availability),
+            mtbf: per_failure(1.0),
+        })
+    }
+
+    // empirical estimates the metrics from trajectories of the mode of agents (i.e. one per replication), whose
+    // values in `up` count as up. Trajectories that never fail do not contribute to the MTTF, which therefore
+    // underestimates it when the observation windows are short.
+    pub fn empirical(trajectories: &[Trajectory], up: &[&str]) -> Self {
+        let (mut up_time, mut down_time) = (0.0, 0.0);
+        let (mut failures, mut repairs) = (0, 0);
+        let mut first_failures = Vec::new();
+
+        for trajectory in trajectories {
+            let Some(segments) = trajectory.segments() else {
+                continue;
+            };
+            // modes are compared in their string form
+            let modes: Vec<String> = segments
+                .iter()
+                .map(|(_, _, value)| value.to_string())
+                .collect();
+            let down: Vec<&str> = modes
+                .iter()
+                .map(String::as_str)
+                .filter(|mode| !up.contains(mode))
+                .collect();
+            first_failures.extend(trajectory.first_passage(&down));
+
+            let mut was_up = None;
+            for ((from, to, _), mode) in segments.into_iter().zip(&modes) {
+                let is_up = up.contains(&mode.as_str());
+                let duration = timer::duration_to_seconds(to - from);
+                if is_up {
+                    up_time += duration;
+                } else {
+                    down_time += duration;
+                }
+
+                match (was_up, is_up) {
+                    (Some(true), false) => failures += 1,
+                    (Some(false), true) => repairs += 1,
+                    _ => {}
+                }
+                was_up = Some(is_up);
+            }
+        }
+
+        let per = |time: f64, count: usize| {
+            if count > 0 {
+                time / count as f64
+            } else {
+                f64::NAN
+            }
+        };
+
+        ReliabilityMetrics {
+            availability: up_time / (up_time + down_time),
+            mttf: per(first_failures.iter().sum(), first_failures.len()),
+            mean_up_time: per(up_time, failures),
+            mttr: per(down_time, repairs),
+            mtbf: per(up_time + down_time, failures),
+        }
+    }
+
+    fn rows(&self) -> [(&'static str, f64); 5] {
+        [
+            ("availability", self.availability),
+            ("MTTF (s)", self.mttf),
+            ("mean up time (s)", self.mean_up_time),
+            ("MTTR (s)", self.mttr),
+            ("MTBF (s)", self.mtbf),
+        ]
+    }
+}
+
+/// Comparison reports analytic and empirical reliability metrics side by side, to validate a simulation against
+/// theory or to spot where a model stops being Markovian.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Comparison {
+    pub analytic: ReliabilityMetrics,
+    pub empirical: ReliabilityMetrics,
+}
+
+impl Comparison {
+    // relative_error returns the largest relative difference between the analytic and empirical metrics that are
+    // defined on both sides
+    pub fn relative_error(&self) -> f64 {
+        self.analytic
+            .rows()
+            .iter()
+            .zip(self.empirical.rows())
+            .filter(|((_, analytic), (_, empirical))| analytic.is_finite() && empirical.is_finite())
+            .map(|((_, analytic), (_, empirical))| ((empirical - analytic) / analytic).abs())
+            .fold(0.0, f64::max)
+    }
+}
+
+impl fmt::Display for Comparison {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "{:<18} {:>14} {:>14} {:>10}",
+            "metric", "analytic", "empirical", "error"
+        )?;
+        for ((name, analytic), (_, empirical)) in
+            self.analytic.rows().iter().zip(self.empirical.rows())
+        {
+            writeln!(
+                f,
+                "{:<18} {:>14.4} {:>14.4} {:>9.2}%",
+                name,
+                analytic,
+                empirical,
+                (empirical - analytic) / analytic * 100.0
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::agent::{Agent, StateType};
+    use crate::replication::Replications;
+    use crate::simulation::Simulation;
+    use chrono::{Duration, TimeZone, Utc};
+    use rand::SeedableRng;
+    use rand::rngs::StdRng;
+    use state_macros::State;
+    use std::collections::HashMap;
+
+    #[derive(Clone, Default, Debug, State)]
+    struct Pump {
+        mode: &'static str,
+    }
+
+    fn mode(
+        mode: &'static str,
+        transitions: Vec<(&'static str, f64)>,
+        event_rate: f64,
+    ) -> StateType<&'static str, Pump> {
+        StateType::new_deterministic(move || Pump { mode }, transitions, event_rate)
+    }
+
+    // pump degrades after 100 seconds on average, and then either recovers (3 times out of 4) or fails after
+    // another 10 seconds. Repairs take 20 seconds.
+    fn pump() -> HashMap<&'static str, StateType<&'static str, Pump>> {
+        HashMap::from([
+            ("running", mode("running", vec![("degraded", 1.0)], 100.0)),
+            (
+                "degraded",
+                mode("degraded", vec![("running", 3.0), ("failed", 1.0)], 10.0),
+            ),
+            ("failed", mode("failed", vec![("running", 1.0)], 20.0)),
+        ])
+    }
+
+    #[test]
+    fn test_analytic_reliability() {
+        let generator = Generator::from_transitions(&pump().into());
+
+        // every cycle through running lasts 100 + 10 seconds up, and a quarter of them end in a 20 second repair
+        let metrics =
+            ReliabilityMetrics::analytic(&generator, &"running", &["running", "degraded"]).unwrap();
+        assert!((metrics.availability - 110.0 / 115.0).abs() < 1e-12);
+        assert!((metrics.mttf - 440.0).abs() < 1e-9);
+        assert!((metrics.mean_up_time - 440.0).abs() < 1e-9);
+        assert!((metrics.mttr - 20.0).abs() < 1e-9);
+        assert!((metrics.mtbf - 460.0).abs() < 1e-9);
+
+        let failing = generator.hitting_probability(&"degraded", &["failed"], &["running"]);
+        assert!((failing.unwrap() - 0.25).abs() < 1e-12);
+
+        // without repairs, the failed mode absorbs the pump
+        let mut unrepaired = pump();
+        unrepaired.insert("failed", mode("failed", vec![], 0.0));
+        let generator = Generator::from_transitions(&unrepaired.into());
+        assert_eq!(generator.absorbing_modes(), vec!["failed"]);
+        assert!((generator.mean_time_to_absorption(&"running").unwrap() - 440.0).abs() < 1e-9);
+        assert_eq!(
+            generator.mean_first_passage(&"failed", &["failed"]),
+            Some(0.0)
+        );
+
+        // the time to failure is not exponential, but its distribution function still climbs to 1
+        let within = |seconds| {
+            generator.first_passage_probability(&"running", &["failed"], Duration::seconds(seconds))
+        };
+        assert_eq!(within(0), 0.0);
+        assert!(within(100) < 1.0 - (-100.0f64 / 440.0).exp());
+        assert!(within(20_000) > 0.999_999);
+    }
+
+    #[test]
+    fn test_empirical_reliability_matches_analytic() {
+        let up = ["running", "degraded"];
+        let generator = Generator::from_transitions(&pump().into());
+        let analytic = ReliabilityMetrics::analytic(&generator, &"running", &up).unwrap();
+
+        let start = Utc.timestamp_opt(0, 0).unwrap();
+        let horizon = Duration::seconds(10_000);
+        let trajectories: Vec<Trajectory> = Replications::new(1000, 3)
+            .run(
+                |replication| {
+                    let mut rng = StdRng::seed_from_u64(replication.seed);
+                    let agent =
+                        Agent::new("pump".to_string(), "running", pump(), &mut rng).unwrap();
+                    Simulation::new_with_seed(vec![agent], start, replication.seed).unwrap()
+                },
+                |_, sim| {
+                    let events = sim.run(horizon);
+                    Trajectory::from_events(events, "pump", "mode", start, start + horizon)
+                        .with_initial("running")
+                },
+            )
+            .into_iter()
+            .map(|result| result.value)
+            .collect();
+        let empirical = ReliabilityMetrics::empirical(&trajectories, &up);
+
+        let comparison = Comparison {
+            analytic,
+            empirical,
+        };
+        assert!(comparison.relative_error() < 0.08, "{}", comparison);
+        assert!(comparison.to_string().starts_with("metric"));
+        assert_eq!(comparison.to_string().lines().count(), 6);
+    }
+}
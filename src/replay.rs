@@ -0,0 +1,266 @@
+use crate::agent::{Checkpoint, SimAgent};
+use crate::rng::RandomStreams;
+use crate::state::{AgentId, StateChangeEvent, StringEvent};
+use chrono::{DateTime, Duration, Utc};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io::{BufRead, Read};
+
+#[derive(Debug)]
+pub enum ReplayError {
+    Io(std::io::Error),
+    Json {
+        line: usize,
+        source: serde_json::Error,
+    },
+    Csv(csv::Error),
+}
+
+impl fmt::Display for ReplayError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ReplayError::Io(err) => write!(f, "failed to read trace: {}", err),
+            ReplayError::Json { line, source } => {
+                write!(f, "invalid event on line {}: {}", line, source)
+            }
+            ReplayError::Csv(err) => write!(f, "invalid csv trace: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for ReplayError {}
+
+impl From<std::io::Error> for ReplayError {
+    fn from(err: std::io::Error) -> Self {
+        ReplayError::Io(err)
+    }
+}
+
+impl From<csv::Error> for ReplayError {
+    fn from(err: csv::Error) -> Self {
+        ReplayError::Csv(err)
+    }
+}
+
+// read_jsonl reads a trace of state change events stored as JSON Lines, one serialized event per line. Blank lines
+// are skipped. Field names are interned and never freed (see `state::intern`), so a trace from an untrusted source
+// grows memory with every distinct field name it holds: bound or check them before reading it.
+pub fn read_jsonl<R: BufRead>(reader: R) -> Result<Vec<StateChangeEvent>, ReplayError> {
+    let mut events = Vec::new();
+
+    for (index, line) in reader.lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let event = serde_json::from_str(&line).map_err(|source| ReplayError::Json {
+            line: index + 1,
+            source,
+        })?;
+        events.push(event);
+    }
+
+    Ok(events)
+}
+
+// read_csv reads a trace of state change events stored as CSV, using the serialized field names as headers
+// (Time, AgentId, Field, NewValue, OldValue). CSV only carries the string form of values (see `StringEvent`), so
+// they are read back as strings rather than guessed, i.e. "007" stays "007". Field names are interned as with
+// `read_jsonl`.
+pub fn read_csv<R: Read>(reader: R) -> Result<Vec<StateChangeEvent>, ReplayError> {
+    csv::Reader::from_reader(reader)
+        .deserialize::<StringEvent>()
+        .map(|record| {
+            record
+                .map(StateChangeEvent::from)
+                .map_err(ReplayError::from)
+        })
+        .collect()
+}
+
+/// A ReplayAgent replays the recorded history of a real agent inside a simulation. Its events are emitted at their
+/// recorded timestamps (optionally shifted and scaled onto the simulation clock) and go through the same pipeline as
+/// those of simulated agents, so interactions can react to them.
+pub struct ReplayAgent {
+    id: AgentId,
+    events: Vec<StateChangeEvent>,
+    next: usize,
+    shift: Duration,
+    scale: f64,
+    origin: Option<DateTime<Utc>>,
+}
+
+impl ReplayAgent {
+    pub fn new(id: String, mut events: Vec<StateChangeEvent>) -> Self {
+        events.sort_by_key(|event| event.time);
+
+        ReplayAgent {
+            id: id.into(),
+            events,
+            next: 0,
+            shift: Duration::zero(),
+            scale: 1.0,
+            origin: None,
+        }
+    }
+
+    // from_events splits a trace into one replay agent per recorded agent id, ordered by id
+    pub fn from_events(events: Vec<StateChangeEvent>) -> Vec<ReplayAgent> {
+        let mut by_agent: BTreeMap<AgentId, Vec<StateChangeEvent>> = BTreeMap::new();
+        for event in events {
+            by_agent
+                .entry(event.agent_id.clone())
+                .or_default()
+                .push(event);
+        }
+
+        by_agent
+            .into_iter()
+            .map(|(id, events)| ReplayAgent::new(id.to_string(), events))
+            .collect()
+    }
+
+    // with_time_shift moves the whole trace by the given offset, i.e. to align a recording with the start time of
+    // the simulation
+    pub fn with_time_shift(mut self, shift: Duration) -> Self {
+        self.shift = shift;
+        self
+    }
+
+    // with_time_scale stretches (> 1) or compresses (< 1) the gaps between recorded events around the recorded
+    // `origin` timestamp. Agents replayed from the same recording should share the origin to stay aligned.
+    pub fn with_time_scale(mut self, scale: f64, origin: DateTime<Utc>) -> Self {
+        self.scale = scale;
+        self.origin = Some(origin);
+        self
+    }
+
+    // remaining returns the number of recorded events that have not been replayed yet
+    pub fn remaining(&self) -> usize {
+        self.events.len() - self.next
+    }
+
+    // replay_time maps a recorded timestamp onto the simulation clock
+    fn replay_time(&self, recorded: DateTime<Utc>) -> DateTime<Utc> {
+        let origin = self.origin.unwrap_or(recorded);
+        let offset_ms = (recorded - origin).num_milliseconds() as f64 * self.scale;
+
+        origin + Duration::milliseconds(offset_ms.round() as i64) + self.shift
+    }
+}
+
+impl SimAgent for ReplayAgent {
+    fn id(&self) -> &str {
+        &self.id
+    }
+
+    fn schedule(
+        &mut self,
+        _now: DateTime<Utc>,
+        _rng: &mut dyn RandomStreams,
+    ) -> Option<DateTime<Utc>> {
+        self.events
+            .get(self.next)
+            .map(|event| self.replay_time(event.time))
+    }
+
+    // fire replays every recorded event sharing the timestamp of the next one
+    fn fire(&mut self, time: DateTime<Utc>, _rng: &mut dyn RandomStreams) -> Vec<StateChangeEvent> {
+        let Some(recorded) = self.events.get(self.next).map(|event| event.time) else {
+            return Vec::new();
+        };
+
+        let mut changes = Vec::new();
+        while let Some(event) = self.events.get(self.next)
+            && event.time == recorded
+        {
+            changes.push(StateChangeEvent {
+                time,
+                agent_id: self.id.clone(),
+                ..event.clone()
+            });
+            self.next += 1;
+        }
+
+        changes
+    }
+
+    fn checkpoint(&self) -> Option<Checkpoint> {
+        Some(Box::new(self.next))
+    }
+
+    fn restore(&mut self, checkpoint: &Checkpoint) {
+        self.next = *checkpoint
+            .downcast_ref::<usize>()
+            .expect("checkpoint was taken from a replay agent");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::simulation::Simulation;
+    use chrono::TimeZone;
+
+    const TRACE: &str = r#"{"Time":"2024-01-01T00:00:10Z","AgentId":"dev_1","Field":"load","NewValue":"50","OldValue":"0"}
+
+{"Time":"2024-01-01T00:00:00Z","AgentId":"dev_1","Field":"status","NewValue":"up","OldValue":"down"}
+{"Time":"2024-01-01T00:00:10Z","AgentId":"dev_1","Field":"status","NewValue":"busy","OldValue":"up"}
+{"Time":"2024-01-01T00:00:05Z","AgentId":"dev_2","Field":"status","NewValue":"up","OldValue":"down"}
+"#;
+
+    #[test]
+    fn test_read_jsonl_and_csv() {
+        let events = read_jsonl(TRACE.as_bytes()).unwrap();
+        assert_eq!(events.len(), 4);
+        assert_eq!(events[0].field, "load");
+
+        let csv = "Time,AgentId,Field,NewValue,OldValue\n\
+                   2024-01-01T00:00:00Z,dev_1,status,up,down\n";
+        let events = read_csv(csv.as_bytes()).unwrap();
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].new_value, "up");
+
+        let err = read_jsonl("{}\n{\"Time\": 1}".as_bytes()).unwrap_err();
+        assert!(matches!(err, ReplayError::Json { line: 1, .. }));
+    }
+
+    #[test]
+    fn test_replay_in_simulation_with_shift_and_scale() {
+        let recorded_start = Utc.with_ymd_and_hms(2024, 1, 1, 0, 0, 0).unwrap();
+        let start_time = Utc.with_ymd_and_hms(2025, 6, 1, 12, 0, 0).unwrap();
+
+        let mut sim = Simulation::empty_with_seed(start_time, 1);
+        for agent in ReplayAgent::from_events(read_jsonl(TRACE.as_bytes()).unwrap()) {
+            sim.add_agent(
+                agent
+                    .with_time_shift(start_time - recorded_start)
+                    .with_time_scale(2.0, recorded_start),
+            )
+            .unwrap();
+        }
+
+        let events = sim.run(Duration::seconds(30));
+        let replayed: Vec<(&str, &str, i64)> = events
+            .iter()
+            .map(|e| {
+                (
+                    e.agent_id.as_str(),
+                    e.new_value.as_str().unwrap(),
+                    (e.time - start_time).num_seconds(),
+                )
+            })
+            .collect();
+
+        assert_eq!(
+            replayed,
+            vec![
+                ("dev_1", "up", 0),
+                ("dev_2", "up", 10),
+                ("dev_1", "50", 20),
+                ("dev_1", "busy", 20),
+            ]
+        );
+    }
+}
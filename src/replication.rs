@@ -0,0 +1,253 @@
+use crate::rng::derive_seed;
+use crate::simulation::Simulation;
+use crate::summary::Summary;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc;
+use std::thread;
+
+/// Replication identifies a single run of a model within a set of replications. Antithetic replications share the
+/// seed of the replication before them and should be run with `Simulation::with_antithetic`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Replication {
+    pub index: usize,
+    pub seed: u64,
+    pub antithetic: bool,
+}
+
+#[derive(Debug, Clone)]
+pub struct ReplicationResult<T> {
+    pub replication: Replication,
+    pub value: T,
+}
+
+/// Replications runs the same model many times with independent seeds derived from a master seed, spreading the
+/// runs across a pool of threads. Results only depend on the master seed, never on the number of threads.
+pub struct Replications {
+    count: usize,
+    master_seed: u64,
+    threads: usize,
+    antithetic: bool,
+}
+
+impl Replications {
+    pub fn new(count: usize, master_seed: u64) -> Self {
+        Replications {
+            count,
+            master_seed,
+            threads: thread::available_parallelism().map_or(1, |n| n.get()),
+            antithetic: false,
+        }
+    }
+
+    // with_antithetic runs the replications as antithetic pairs: every even replication is followed by its
+    // antithetic twin, which reuses its seed. `count` is rounded up to an even number.
+    pub fn with_antithetic(mut self) -> Self {
+        self.antithetic = true;
+        self.count += self.count % 2;
+        self
+    }
+
+    pub fn with_threads(mut self, threads: usize) -> Self {
+        self.threads = threads.max(1);
+        self
+    }
+
+    // replications returns the replications that will be run, with their derived seeds
+    pub fn replications(&self) -> Vec<Replication> {
+        (0..self.count)
+            .map(|index| {
+                let (stream, antithetic) = if self.antithetic {
+                    (index / 2, index % 2 == 1)
+                } else {
+                    (index, false)
+                };
+
+                Replication {
+                    index,
+                    seed: derive_seed(self.master_seed, stream as u64),
+                    antithetic,
+                }
+            })
+            .collect()
+    }
+
+    // run builds each replication with `model` and measures it with `experiment`, which is expected to run the
+    // simulation (i.e. `sim.run(duration)`) and reduce it to a result. Results are returned in replication order.
+    pub fn run<M, E, T>(&self, model: M, experiment: E) -> Vec<ReplicationResult<T>>
+    where
+        M: Fn(&Replication) -> Simulation + Sync,
+        E: Fn(&Replication, &mut Simulation) -> T + Sync,
+        T: Send,
+    {
+        let mut results = Vec::with_capacity(self.count);
+        self.run_streaming(model, experiment, |result| results.push(result));
+        results.sort_by_key(|result| result.replication.index);
+        results
+    }
+
+    // run_streaming behaves like run, but hands each result to `on_result` on the calling thread as soon as its
+    // replication completes, in completion order
+    pub fn run_streaming<M, E, T, F>(&self, model: M, experiment: E, mut on_result: F)
+    where
+        M: Fn(&Replication) -> Simulation + Sync,
+        E: Fn(&Replication, &mut Simulation) -> T + Sync,
+        T: Send,
+        F: FnMut(ReplicationResult<T>),
+    {
+        let replications = self.replications();
+        let next = AtomicUsize::new(0);
+        let (sender, receiver) = mpsc::channel();
+
+        thread::scope(|scope| {
+            for _ in 0..self.threads.min(self.count) {
+                let sender = sender.clone();
+                let (replications, next, model, experiment) =
+                    (&replications, &next, &model, &experiment);
+
+                scope.spawn(move || {
+                    while let Some(replication) =
+                        replications.get(next.fetch_add(1, Ordering::Relaxed))
+                    {
+                        let mut sim = model(replication);
+                        let value = experiment(replication, &mut sim);
+                        let result = ReplicationResult {
+                            replication: *replication,
+                            value,
+                        };
+                        if sender.send(result).is_err() {
+                            return;
+                        }
+                    }
+                });
+            }
+            drop(sender);
+
+            for result in receiver {
+                on_result(result);
+            }
+        });
+    }
+}
+
+// summarize computes summary statistics and a confidence interval of a metric across replication results. The two
+// runs of an antithetic pair are not independent, so each pair counts as one observation: the mean of its runs.
+pub fn summarize<T, F>(results: &[ReplicationResult<T>], confidence: f64, metric: F) -> Summary
+where
+    F: Fn(&T) -> f64,
+{
+    let mut sorted: Vec<&ReplicationResult<T>> = results.iter().collect();
+    sorted.sort_by_key(|result| result.replication.index);
+
+    let mut values = Vec::with_capacity(sorted.len());
+    let mut iter = sorted.into_iter().peekable();
+    while let Some(result) = iter.next() {
+        let mut value = metric(&result.value);
+        if let Some(twin) = iter.next_if(|next| next.replication.antithetic) {
+            value = (value + metric(&twin.value)) / 2.0;
+        }
+        values.push(value);
+    }
+
+    Summary::from_values(&values, confidence)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::agent::{Agent, StateType};
+    use chrono::{Duration, TimeZone, Utc};
+    use rand::SeedableRng;
+    use rand::rngs::StdRng;
+    use state_macros::State;
+    use std::collections::HashMap;
+
+    #[derive(Clone, Default, Debug, State)]
+    struct Toggle {
+        on: bool,
+    }
+
+    fn model(replication: &Replication) -> Simulation {
+        let mut rng = StdRng::seed_from_u64(replication.seed);
+        let mut transitions = HashMap::new();
+        transitions.insert(
+            false,
+            StateType::new_deterministic(|| Toggle { on: false }, vec![(true, 1.0)], 60.0),
+        );
+        transitions.insert(
+            true,
+            StateType::new_deterministic(|| Toggle { on: true }, vec![(false, 1.0)], 60.0),
+        );
+
+        let agents = (0..3)
+            .map(|i| Agent::new(format!("a{}", i), false, transitions.clone(), &mut rng).unwrap())
+            .collect();
+        let start = Utc.timestamp_opt(0, 0).unwrap();
+        Simulation::new_with_seed(agents, start, replication.seed)
+            .unwrap()
+            .with_antithetic(replication.antithetic)
+    }
+
+    fn count_events(_: &Replication, sim: &mut Simulation) -> usize {
+        sim.run(Duration::hours(1)).len()
+    }
+
+    #[test]
+    fn test_replications_independent_of_thread_count() {
+        let single = Replications::new(12, 7)
+            .with_threads(1)
+            .run(model, count_events);
+        let parallel = Replications::new(12, 7)
+            .with_threads(4)
+            .run(model, count_events);
+
+        let values = |results: &[ReplicationResult<usize>]| -> Vec<usize> {
+            results.iter().map(|r| r.value).collect()
+        };
+        assert_eq!(values(&single), values(&parallel));
+        assert_eq!(parallel[3].replication.index, 3);
+
+        let distinct: std::collections::HashSet<usize> = values(&single).into_iter().collect();
+        assert!(distinct.len() > 1, "replications should not share a seed");
+    }
+
+    #[test]
+    fn test_summarize_replications() {
+        let mut streamed = 0;
+        Replications::new(20, 1)
+            .with_threads(3)
+            .run_streaming(model, count_events, |_| streamed += 1);
+        assert_eq!(streamed, 20);
+
+        let results = Replications::new(20, 1).run(model, count_events);
+        let summary = summarize(&results, 0.95, |count| *count as f64);
+
+        // 3 agents with a 60s mean holding time produce about 180 events per hour
+        assert_eq!(summary.count, 20);
+        assert!((summary.mean - 180.0).abs() < 30.0);
+        assert!(summary.lower() < summary.mean && summary.mean < summary.upper());
+        assert!(summary.half_width > 0.0);
+    }
+
+    #[test]
+    fn test_antithetic_pairs_reduce_variance() {
+        let replications = Replications::new(39, 3).with_antithetic();
+        let plan = replications.replications();
+        assert_eq!(plan.len(), 40);
+        assert_eq!(plan[4].seed, plan[5].seed);
+        assert!(!plan[4].antithetic && plan[5].antithetic);
+
+        let first_toggle = |_: &Replication, sim: &mut Simulation| {
+            sim.run(Duration::hours(1))[0].time.timestamp() as f64
+        };
+        let paired = summarize(&replications.run(model, first_toggle), 0.95, |t| *t);
+        let plain = summarize(
+            &Replications::new(40, 3).run(model, first_toggle),
+            0.95,
+            |t| *t,
+        );
+
+        // the first of three exponential delays is negatively correlated with its antithetic twin
+        assert_eq!(paired.count, 20);
+        assert!(paired.std_dev < plain.std_dev / 2.0f64.sqrt());
+    }
+}
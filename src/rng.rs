@@ -0,0 +1,221 @@
+use rand::{RngCore, SeedableRng};
+use rand_chacha::ChaCha12Rng;
+
+/// DefaultRng is the algorithm agents draw from unless another one is chosen with `Simulation::with_rng`. It is the
+/// algorithm behind rand's `StdRng` today, but unlike `StdRng` it is guaranteed not to change between releases.
+pub type DefaultRng = ChaCha12Rng;
+
+/// DEFAULT_RNG_NAME names the algorithm of `DefaultRng` in `RunMetadata`.
+pub const DEFAULT_RNG_NAME: &str = "ChaCha12";
+
+/// Purpose identifies what an agent draws random numbers for. Each purpose has its own stream, so that i.e. a
+/// different choice of transition does not shift the delays drawn afterwards.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Purpose {
+    Delay,
+    Choice,
+    Factory,
+}
+
+/// RandomStreams hands an agent the stream to draw from for a given purpose. Any single generator is a
+/// RandomStreams that serves every purpose from the same stream.
+pub trait RandomStreams {
+    fn stream(&mut self, purpose: Purpose) -> &mut dyn RngCore;
+}
+
+impl<R: RngCore> RandomStreams for R {
+    fn stream(&mut self, _purpose: Purpose) -> &mut dyn RngCore {
+        self
+    }
+}
+
+/// AgentStreams holds the random streams of one agent, one per purpose, derived from a master seed and the agent id.
+/// An agent therefore draws the same numbers whatever other agents take part in the simulation, which gives common
+/// random numbers across variants of a model.
+#[derive(Clone)]
+pub struct AgentStreams {
+    delay: StreamRng,
+    choice: StreamRng,
+    factory: StreamRng,
+}
+
+impl AgentStreams {
+    pub fn new(master_seed: u64, agent_id: &str) -> Self {
+        Self::with_algorithm::<DefaultRng>(master_seed, agent_id)
+    }
+
+    // with_algorithm derives the streams with the given generator algorithm, each seeded through `seed_from_u64`
+    pub fn with_algorithm<R>(master_seed: u64, agent_id: &str) -> Self
+    where
+        R: RngCore + SeedableRng + Clone + Send + 'static,
+    {
+        let stream = |purpose| StreamRng {
+            rng: Box::new(R::seed_from_u64(stream_seed(
+                master_seed,
+                agent_id,
+                purpose,
+            ))),
+            antithetic: false,
+        };
+
+        AgentStreams {
+            delay: stream(Purpose::Delay),
+            choice: stream(Purpose::Choice),
+            factory: stream(Purpose::Factory),
+        }
+    }
+
+    // set_antithetic switches the streams to their antithetic counterparts, which draw 1 - u wherever the regular
+    // streams draw u. A run paired with its antithetic twin yields negatively correlated outputs.
+    pub fn set_antithetic(&mut self, antithetic: bool) {
+        for stream in [&mut self.delay, &mut self.choice, &mut self.factory] {
+            stream.antithetic = antithetic;
+        }
+    }
+}
+
+impl RandomStreams for AgentStreams {
+    fn stream(&mut self, purpose: Purpose) -> &mut dyn RngCore {
+        match purpose {
+            Purpose::Delay => &mut self.delay,
+            Purpose::Choice => &mut self.choice,
+            Purpose::Factory => &mut self.factory,
+        }
+    }
+}
+
+// StreamRng is one stream of an agent. The antithetic variant complements every bit it produces, which maps the
+// uniform numbers built from those bits from u to (almost exactly) 1 - u.
+#[derive(Clone)]
+struct StreamRng {
+    rng: Box<dyn CloneRng>,
+    antithetic: bool,
+}
+
+// CloneRng is a generator of any algorithm that can be cloned behind a pointer, i.e. to checkpoint an agent
+trait CloneRng: RngCore + Send {
+    fn clone_box(&self) -> Box<dyn CloneRng>;
+}
+
+impl<R: RngCore + Clone + Send + 'static> CloneRng for R {
+    fn clone_box(&self) -> Box<dyn CloneRng> {
+        Box::new(self.clone())
+    }
+}
+
+impl Clone for Box<dyn CloneRng> {
+    fn clone(&self) -> Self {
+        (**self).clone_box()
+    }
+}
+
+impl RngCore for StreamRng {
+    fn next_u32(&mut self) -> u32 {
+        let value = self.rng.next_u32();
+        if self.antithetic { !value } else { value }
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        let value = self.rng.next_u64();
+        if self.antithetic { !value } else { value }
+    }
+
+    fn fill_bytes(&mut self, dest: &mut [u8]) {
+        self.rng.fill_bytes(dest);
+        if self.antithetic {
+            dest.iter_mut().for_each(|byte| *byte = !*byte);
+        }
+    }
+
+    fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), rand::Error> {
+        self.fill_bytes(dest);
+        Ok(())
+    }
+}
+
+// splitmix64 advances and mixes a 64 bit state. It is used to derive well distributed, independent seeds from a
+// master seed, since seeding generators with consecutive integers can yield correlated streams.
+pub(crate) fn splitmix64(state: u64) -> u64 {
+    let mut z = state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    z ^ (z >> 31)
+}
+
+// derive_seed derives the seed of an independent random stream (i.e. a replication) from a master seed
+pub fn derive_seed(master_seed: u64, stream: u64) -> u64 {
+    splitmix64(splitmix64(master_seed) ^ splitmix64(stream.wrapping_add(0x5851_F42D_4C95_7F2D)))
+}
+
+// agent_seed derives the seed of an agent's own random stream from a master seed and the agent's id, so that the
+// stream does not depend on which other agents take part in the simulation or on how they are partitioned
+pub fn agent_seed(master_seed: u64, agent_id: &str) -> u64 {
+    derive_seed(master_seed, fnv1a(agent_id.as_bytes()))
+}
+
+// stream_seed derives the seed of the stream an agent draws from for one purpose
+pub fn stream_seed(master_seed: u64, agent_id: &str, purpose: Purpose) -> u64 {
+    derive_seed(agent_seed(master_seed, agent_id), purpose as u64)
+}
+
+// fnv1a is a stable hash of a byte string. std's hashers are randomly keyed or not guaranteed to be stable across
+// releases, which would break reproducibility.
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xCBF2_9CE4_8422_2325, |hash, byte| {
+        (hash ^ u64::from(*byte)).wrapping_mul(0x0100_0000_01B3)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_derive_seed_is_deterministic_and_distinct() {
+        assert_eq!(derive_seed(42, 0), derive_seed(42, 0));
+
+        let seeds: std::collections::HashSet<u64> = (0..1000).map(|i| derive_seed(42, i)).collect();
+        assert_eq!(seeds.len(), 1000);
+
+        assert_ne!(derive_seed(42, 1), derive_seed(43, 1));
+    }
+
+    #[test]
+    fn test_agent_seed_depends_on_id_only() {
+        assert_eq!(agent_seed(42, "device_001"), agent_seed(42, "device_001"));
+        assert_ne!(agent_seed(42, "device_001"), agent_seed(42, "device_002"));
+        assert_eq!(fnv1a(b"a"), 0xAF63_DC4C_8601_EC8C);
+    }
+
+    #[test]
+    fn test_agent_streams_are_independent_and_antithetic() {
+        use rand::Rng;
+
+        let mut streams = AgentStreams::new(42, "device_001");
+        let delay: f64 = streams.stream(Purpose::Delay).r#gen();
+        let choice: f64 = streams.stream(Purpose::Choice).r#gen();
+        assert_ne!(delay, choice);
+
+        let mut again = AgentStreams::new(42, "device_001");
+        assert_eq!(again.stream(Purpose::Delay).r#gen::<f64>(), delay);
+
+        let mut antithetic = AgentStreams::new(42, "device_001");
+        antithetic.set_antithetic(true);
+        let mirrored: f64 = antithetic.stream(Purpose::Delay).r#gen();
+        assert!((delay + mirrored - 1.0).abs() < 1e-12);
+    }
+
+    // the streams of a seed must never change between releases, or recorded runs could no longer be reproduced
+    #[test]
+    fn test_agent_streams_are_stable() {
+        let mut streams = AgentStreams::new(42, "device_001");
+        assert_eq!(
+            streams.stream(Purpose::Delay).next_u64(),
+            12139049606610244938
+        );
+        assert_eq!(
+            streams.stream(Purpose::Factory).next_u64(),
+            12978028546366645297
+        );
+    }
+}
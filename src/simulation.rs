@@ -1,194 +1,710 @@
-use crate::agent::Agent;
+use crate::agent::{Agent, Checkpoint, SimAgent};
+use crate::composite::{CompositeAgent, ModeRollup};
+use crate::interaction::{Interaction, Signal, SignalTarget};
+use crate::rng::{AgentStreams, DEFAULT_RNG_NAME, DefaultRng};
 use crate::state::{State, StateChangeEvent};
+use crate::timer;
+use crate::validation::ModelError;
 use chrono::{DateTime, Duration, Utc};
-use rand::rngs::StdRng;
 use rand::{RngCore, SeedableRng};
+use serde::{Deserialize, Serialize};
+use std::any::Any;
 use std::cmp::Ordering;
-use std::collections::BinaryHeap;
+use std::collections::{BinaryHeap, HashMap, HashSet, VecDeque};
+use std::fmt::Display;
 use std::hash::Hash;
+use std::ops::Range;
 
-struct ScheduledEvent<C> {
-    time: DateTime<Utc>,
-    agent_index: usize,
-    next_state_type: Option<C>,
+pub(crate) struct ScheduledEvent {
+    pub(crate) time: DateTime<Utc>,
+    pub(crate) agent_index: usize,
+    pub(crate) generation: u64,
 }
 
-impl<C> PartialEq for ScheduledEvent<C> {
+impl PartialEq for ScheduledEvent {
     fn eq(&self, other: &Self) -> bool {
-        self.time == other.time
+        self.time == other.time && self.agent_index == other.agent_index
     }
 }
-impl<C> Eq for ScheduledEvent<C> {}
-impl<C> PartialOrd for ScheduledEvent<C> {
+impl Eq for ScheduledEvent {}
+impl PartialOrd for ScheduledEvent {
     fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
         Some(self.cmp(other))
     }
 }
-impl<C> Ord for ScheduledEvent<C> {
+// events are ordered earliest first, with ties broken by agent index so that the order does not depend on the
+// state of the heap
+impl Ord for ScheduledEvent {
     fn cmp(&self, other: &Self) -> Ordering {
-        other.time.cmp(&self.time)
+        other
+            .time
+            .cmp(&self.time)
+            .then_with(|| other.agent_index.cmp(&self.agent_index))
     }
 }
 
-pub struct Simulation<C, S>
-where
-    C: Eq + Hash + Clone,
-    S: State,
-{
-    agents: Vec<Agent<C, S>>,
+// CompositeGroup links a contiguous range of agents (the children of a composite agent) to its roll-up rules
+pub(crate) struct CompositeGroup {
+    pub(crate) members: Range<usize>,
+    pub(crate) rollup: Box<dyn ModeRollup>,
+}
+
+// SimulationParts is the population of a simulation taken apart, i.e. to be distributed across shards
+pub(crate) struct SimulationParts {
+    pub(crate) agents: Vec<Box<dyn SimAgent>>,
+    pub(crate) streams: Vec<AgentStreams>,
+    pub(crate) composites: Vec<CompositeGroup>,
+    pub(crate) parent_of: Vec<Option<usize>>,
+    pub(crate) interactions: Vec<Interaction>,
+    pub(crate) current_time: DateTime<Utc>,
+    pub(crate) started: usize,
+    pub(crate) scheduled: Vec<Option<DateTime<Utc>>>,
+}
+
+/// SimulationCheckpoint is a snapshot of the state of a simulation, taken with `Simulation::checkpoint`.
+pub struct SimulationCheckpoint {
+    agents: Vec<Checkpoint>,
+    streams: Vec<AgentStreams>,
+    rollups: Vec<Checkpoint>,
     current_time: DateTime<Utc>,
+    started: usize,
+    scheduled: Vec<Option<DateTime<Utc>>>,
+    logged: usize,
+}
+
+/// RunMetadata records what is needed to reproduce a run, to be stored alongside its output.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct RunMetadata {
+    #[serde(rename = "Seed")]
+    pub seed: u64,
+    #[serde(rename = "Rng")]
+    pub rng: String,
+    #[serde(rename = "Antithetic")]
+    pub antithetic: bool,
+    #[serde(rename = "StartTime")]
+    pub start_time: DateTime<Utc>,
+    #[serde(rename = "WarmUpSeconds")]
+    pub warm_up_seconds: f64,
+    #[serde(rename = "Agents")]
+    pub agents: usize,
+    #[serde(rename = "Version")]
+    pub version: String,
+}
+
+/// CascadeOverflow reports a cascade of signals that was cut short at `time` because it exceeded the cascade limit of
+/// the simulation, i.e. because an interaction and a signal handler keep triggering each other. `signal` is the first
+/// signal that was not delivered.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CascadeOverflow {
+    pub time: DateTime<Utc>,
+    pub signal: String,
+}
+
+// DEFAULT_CASCADE_LIMIT bounds the signals delivered in response to a single event, see with_cascade_limit
+pub const DEFAULT_CASCADE_LIMIT: usize = 10_000;
+
+/// A Simulation advances a population of agents along a shared clock. Agents do not need to share a mode or state
+/// type: devices, gateways and users can be added side by side, and their events are merged into one stream.
+///
+/// Every agent draws its delays, choices and states from its own streams, derived from the seed of the simulation
+/// and the agent id. Adding or removing agents does not perturb the others, so variants of a model run with the
+/// same seed share common random numbers. `metadata` records what a run needs to be reproduced (see the
+/// reproducibility section of the README for what is guaranteed).
+pub struct Simulation {
+    agents: Vec<Box<dyn SimAgent>>,
+    streams: Vec<AgentStreams>,
+    agent_index: HashMap<String, usize>,
+    generations: Vec<u64>,
+    scheduled: Vec<Option<DateTime<Utc>>>,
+    started: usize,
+    queue: BinaryHeap<ScheduledEvent>,
+    composites: Vec<CompositeGroup>,
+    parent_of: Vec<Option<usize>>,
+    interactions: Vec<Interaction>,
+    start_time: DateTime<Utc>,
+    warm_up: Duration,
+    current_time: DateTime<Utc>,
+    observed_until: Option<DateTime<Utc>>,
     event_log: Vec<StateChangeEvent>,
-    rng: Box<dyn RngCore>,
+    seed: u64,
+    antithetic: bool,
+    new_streams: fn(u64, &str) -> AgentStreams,
+    rng_name: &'static str,
+    cascade_limit: usize,
+    cascade_overflows: Vec<CascadeOverflow>,
 }
 
-impl<C, S> Simulation<C, S>
-where
-    C: Eq + Hash + Clone + std::fmt::Debug,
-    S: State + Clone + std::fmt::Debug,
-{
-    pub fn new(agents: Vec<Agent<C, S>>, start_time: DateTime<Utc>) -> Self {
+impl Simulation {
+    // new creates a simulation of a population of agents, which must have distinct ids
+    pub fn new<C, S>(
+        agents: Vec<Agent<C, S>>,
+        start_time: DateTime<Utc>,
+    ) -> Result<Self, ModelError<C>>
+    where
+        C: Eq + Hash + Clone + Send + Sync + 'static,
+        S: State + Send + 'static,
+    {
+        Self::new_with_seed(agents, start_time, rand::random())
+    }
+
+    pub fn new_with_seed<C, S>(
+        agents: Vec<Agent<C, S>>,
+        start_time: DateTime<Utc>,
+        seed: u64,
+    ) -> Result<Self, ModelError<C>>
+    where
+        C: Eq + Hash + Clone + Send + Sync + 'static,
+        S: State + Send + 'static,
+    {
+        let mut sim = Self::empty_with_seed(start_time, seed);
+        sim.add_agents(agents)?;
+        Ok(sim)
+    }
+
+    // empty creates a simulation without any agents, to be populated through add_agents and add_composite. The
+    // seed is drawn from the OS and can be read back with seed to reproduce the run.
+    pub fn empty(start_time: DateTime<Utc>) -> Self {
+        Self::empty_with_seed(start_time, rand::random())
+    }
+
+    pub fn empty_with_seed(start_time: DateTime<Utc>, seed: u64) -> Self {
         Simulation {
-            agents,
+            agents: Vec::new(),
+            streams: Vec::new(),
+            agent_index: HashMap::new(),
+            generations: Vec::new(),
+            scheduled: Vec::new(),
+            started: 0,
+            queue: BinaryHeap::new(),
+            composites: Vec::new(),
+            parent_of: Vec::new(),
+            interactions: Vec::new(),
+            start_time,
+            warm_up: Duration::zero(),
             current_time: start_time,
+            observed_until: None,
             event_log: Vec::new(),
-            rng: Box::new(StdRng::from_entropy()),
+            seed,
+            antithetic: false,
+            new_streams: AgentStreams::with_algorithm::<DefaultRng>,
+            rng_name: DEFAULT_RNG_NAME,
+            cascade_limit: DEFAULT_CASCADE_LIMIT,
+            cascade_overflows: Vec::new(),
         }
     }
 
-    pub fn new_with_seed(agents: Vec<Agent<C, S>>, start_time: DateTime<Utc>, seed: u64) -> Self {
-        Simulation {
-            agents,
-            current_time: start_time,
-            event_log: Vec::new(),
-            rng: Box::new(StdRng::seed_from_u64(seed)),
+    // with_rng makes agents draw from generators of algorithm `R` (i.e. `rand_pcg::Pcg64`) instead of the default
+    // ChaCha12. `name` identifies the algorithm in the metadata of runs, i.e. "Pcg64", and should only change when
+    // its output does. It should be called before the simulation is run, since it restarts the streams of every
+    // agent.
+    pub fn with_rng<R>(mut self, name: &'static str) -> Self
+    where
+        R: RngCore + SeedableRng + Clone + Send + 'static,
+    {
+        self.new_streams = AgentStreams::with_algorithm::<R>;
+        self.rng_name = name;
+        self.reseed(self.seed);
+        self
+    }
+
+    // with_cascade_limit bounds how many signals are delivered in response to a single event (including the signals
+    // triggered by the changes they cause), so that interactions and signal handlers that keep triggering each other
+    // cannot stall the clock. Signals beyond the limit are dropped and reported by cascade_overflows.
+    pub fn with_cascade_limit(mut self, limit: usize) -> Self {
+        self.cascade_limit = limit;
+        self
+    }
+
+    // cascade_overflows returns the cascades of signals cut short by the cascade limit so far
+    pub fn cascade_overflows(&self) -> &[CascadeOverflow] {
+        &self.cascade_overflows
+    }
+
+    // seed returns the master seed the streams of the agents are derived from, including one picked from entropy
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
+
+    // metadata describes the run for reproduction: its seed, generator algorithm and population
+    pub fn metadata(&self) -> RunMetadata {
+        RunMetadata {
+            seed: self.seed,
+            rng: self.rng_name.to_string(),
+            antithetic: self.antithetic,
+            start_time: self.start_time,
+            warm_up_seconds: timer::duration_to_seconds(self.warm_up),
+            agents: self.agents.len(),
+            version: env!("CARGO_PKG_VERSION").to_string(),
         }
     }
 
-    // run processes the simulation over a specified duration
-    pub fn run(&mut self, duration: Duration) -> Vec<StateChangeEvent> {
-        let end_time = self.current_time + duration;
-        let mut queue = self.initialize_queue();
+    // with_warm_up runs the agents for `duration` before `start_time` when the simulation is first run, without
+    // emitting events, so that the output starts in (or closer to) steady state instead of in the initial modes. The
+    // history agents gathered over the warm-up is discarded. It should be called before the simulation is run.
+    pub fn with_warm_up(mut self, duration: Duration) -> Self {
+        self.warm_up = duration;
+        self.current_time = self.start_time - duration;
+        self
+    }
 
-        while let Some(event) = queue.pop() {
-            if event.time > end_time {
-                break;
+    // with_antithetic switches every agent to its antithetic streams. Pairing a run with its antithetic twin (same
+    // seed) reduces the variance of estimates averaged over the pair.
+    pub fn with_antithetic(mut self, antithetic: bool) -> Self {
+        self.antithetic = antithetic;
+        for streams in &mut self.streams {
+            streams.set_antithetic(antithetic);
+        }
+        self
+    }
+
+    // add_agents adds a population of agents. Populations with different mode and state types can be added to the
+    // same simulation. Nothing is added if one of the ids is already taken or used twice.
+    pub fn add_agents<C, S>(&mut self, agents: Vec<Agent<C, S>>) -> Result<(), ModelError<C>>
+    where
+        C: Eq + Hash + Clone + Send + Sync + 'static,
+        S: State + Send + 'static,
+    {
+        self.check_ids(agents.iter().map(|agent| agent.id.as_str()))
+            .map_err(ModelError::DuplicateAgent)?;
+
+        for agent in agents {
+            self.push_agent(Box::new(agent), None);
+        }
+        Ok(())
+    }
+
+    // add_agent adds a single agent of any kind implementing SimAgent, i.e. a custom rule-based agent, unless its id
+    // is already taken
+    pub fn add_agent<A>(&mut self, agent: A) -> Result<(), ModelError<String>>
+    where
+        A: SimAgent + 'static,
+    {
+        self.check_ids([agent.id()].into_iter())
+            .map_err(ModelError::DuplicateAgent)?;
+
+        self.push_agent(Box::new(agent), None);
+        Ok(())
+    }
+
+    // add_composite adds the children of a composite agent to the simulation. Whenever one of the children
+    // transitions, the composite's roll-up rules are re-evaluated and a `mode` event is emitted for the parent if
+    // its mode changed. Nothing is added if the id of one of the children is already taken.
+    pub fn add_composite<C, S, P>(
+        &mut self,
+        composite: CompositeAgent<C, S, P>,
+    ) -> Result<(), ModelError<C>>
+    where
+        C: Eq + Hash + Clone + Send + Sync + 'static,
+        S: State + Send + 'static,
+        P: Clone + PartialEq + Display + Send + 'static,
+    {
+        self.check_ids(composite.children().iter().map(|child| child.id.as_str()))
+            .map_err(ModelError::DuplicateAgent)?;
+
+        let (rollup, children) = composite.into_parts();
+        let start = self.agents.len();
+        let group_index = self.composites.len();
+
+        for child in children {
+            self.push_agent(Box::new(child), Some(group_index));
+        }
+        self.composites.push(CompositeGroup {
+            members: start..self.agents.len(),
+            rollup: Box::new(rollup),
+        });
+        Ok(())
+    }
+
+    // add_interaction registers a function that is called with every state change and can emit signals to other
+    // agents, i.e. to take every device offline when the gateway it is connected to fails. Signals are delivered at
+    // the time of the change that caused them, and the changes they cause are passed to interactions in turn.
+    pub fn add_interaction<F>(&mut self, interaction: F)
+    where
+        F: FnMut(&StateChangeEvent) -> Vec<Signal> + Send + 'static,
+    {
+        self.interactions.push(Box::new(interaction));
+    }
+
+    // check_ids returns the first of the given ids that is already taken, or repeated among them. Agents sharing an id
+    // would share their random streams, and signals would only reach the last one added.
+    fn check_ids<'a>(&self, ids: impl Iterator<Item = &'a str>) -> Result<(), String> {
+        let mut seen = HashSet::new();
+        for id in ids {
+            if self.agent_index.contains_key(id) || !seen.insert(id) {
+                return Err(id.to_string());
             }
-            self.process_event_step(event, &mut queue, |changes, log| {
+        }
+        Ok(())
+    }
+
+    fn push_agent(&mut self, agent: Box<dyn SimAgent>, parent: Option<usize>) {
+        let mut streams = (self.new_streams)(self.seed, agent.id());
+        streams.set_antithetic(self.antithetic);
+
+        self.agent_index
+            .insert(agent.id().to_string(), self.agents.len());
+        self.streams.push(streams);
+        self.agents.push(agent);
+        self.generations.push(0);
+        self.scheduled.push(None);
+        self.parent_of.push(parent);
+    }
+
+    pub(crate) fn into_parts(mut self) -> SimulationParts {
+        self.finish_warm_up();
+        SimulationParts {
+            agents: self.agents,
+            streams: self.streams,
+            composites: self.composites,
+            parent_of: self.parent_of,
+            interactions: self.interactions,
+            current_time: self.current_time,
+            started: self.started,
+            scheduled: self.scheduled,
+        }
+    }
+
+    // run processes the simulation over a specified duration, returning the events logged since the start (see
+    // `events`). Events scheduled past the end of the run are kept for the next one, so that running in several
+    // chunks gives the same events as a single run.
+    pub fn run(&mut self, duration: Duration) -> &[StateChangeEvent] {
+        self.finish_warm_up();
+        let end_time = self.current_time + duration;
+        self.start_agents();
+
+        while let Some(event) = self.next_event(end_time) {
+            self.process_event_step(event, |changes, log| {
                 log.extend(changes);
             });
         }
 
-        self.event_log.clone()
+        self.current_time = end_time;
+        self.observed_until = Some(end_time);
+        &self.event_log
+    }
+
+    // events returns the events logged by `run` since the start, in the order they happened; `to_vec` copies them
+    // cheaply, as events share their agent ids and field names
+    pub fn events(&self) -> &[StateChangeEvent] {
+        &self.event_log
+    }
+
+    // into_events hands the event log over without copying it
+    pub fn into_events(self) -> Vec<StateChangeEvent> {
+        self.event_log
     }
 
     // run_streaming processes the simulation over a specified duration, providing a closure to stream the output to
     // a desired source (i.e, a file/stdout etc). This is usefull when generating a large number of events.
-    pub fn run_streaming<F>(&mut self, duration: Duration, mut callback: F)
+    pub fn run_streaming<F>(&mut self, duration: Duration, callback: F)
     where
         F: FnMut(StateChangeEvent),
     {
-        let end_time = self.current_time + duration;
-        let mut queue = self.initialize_queue();
+        self.run_streaming_until(duration, |_| false, callback);
+    }
 
-        while let Some(event) = queue.pop() {
-            if event.time > end_time {
-                break;
-            }
+    // run_streaming_until behaves like run_streaming, but checks `stop` after every event and stops as soon as it
+    // returns true, i.e. once a failure scenario has been reached. Returns whether it stopped early.
+    pub fn run_streaming_until<P, F>(
+        &mut self,
+        duration: Duration,
+        mut stop: P,
+        mut callback: F,
+    ) -> bool
+    where
+        P: FnMut(&Simulation) -> bool,
+        F: FnMut(StateChangeEvent),
+    {
+        self.finish_warm_up();
+        let end_time = self.current_time + duration;
+        self.start_agents();
 
-            self.process_event_step(event, &mut queue, |changes, _| {
+        while let Some(event) = self.next_event(end_time) {
+            self.process_event_step(event, |changes, _| {
                 for change in changes {
                     callback(change);
                 }
             });
+
+            if stop(self) {
+                self.observed_until = Some(self.current_time);
+                return true;
+            }
+        }
+
+        self.current_time = end_time;
+        self.observed_until = Some(end_time);
+        false
+    }
+
+    pub fn current_time(&self) -> DateTime<Utc> {
+        self.current_time
+    }
+
+    // likelihood_ratio returns the likelihood ratio of the trajectory simulated so far, up to the end of the last run,
+    // against the nominal model of every agent. It is 1 unless agents run under a bias (see `Agent::with_bias`); the
+    // mean of a metric weighted by it over biased runs is an unbiased estimate of its mean under the nominal model.
+    pub fn likelihood_ratio(&self) -> f64 {
+        let until = self
+            .observed_until
+            .map_or(self.current_time, |time| time.max(self.current_time));
+
+        self.agents
+            .iter()
+            .map(|agent| agent.log_likelihood_ratio(until))
+            .sum::<f64>()
+            .exp()
+    }
+
+    // mode returns the current mode of an agent, or None if there is no such agent or its mode is not a `C`
+    pub fn mode<C: 'static>(&self, agent_id: &str) -> Option<&C> {
+        let index = *self.agent_index.get(agent_id)?;
+        self.agents[index].mode().downcast_ref()
+    }
+
+    // data returns the current data of an agent, or None if there is no such agent or its data is not an `S`
+    pub fn data<S: 'static>(&self, agent_id: &str) -> Option<&S> {
+        let index = *self.agent_index.get(agent_id)?;
+        self.agents[index].data().downcast_ref()
+    }
+
+    // agent_ids returns the ids of the agents, in the order they were added
+    pub fn agent_ids(&self) -> impl Iterator<Item = &str> {
+        self.agents.iter().map(|agent| agent.id())
+    }
+
+    // checkpoint captures the state of the simulation, i.e. to branch several futures from it, or returns None if
+    // one of its agents does not support checkpoints
+    pub fn checkpoint(&self) -> Option<SimulationCheckpoint> {
+        Some(SimulationCheckpoint {
+            agents: self
+                .agents
+                .iter()
+                .map(|agent| agent.checkpoint())
+                .collect::<Option<_>>()?,
+            streams: self.streams.clone(),
+            rollups: self
+                .composites
+                .iter()
+                .map(|group| group.rollup.checkpoint())
+                .collect(),
+            current_time: self.current_time,
+            started: self.started,
+            scheduled: self.scheduled.clone(),
+            logged: self.event_log.len(),
+        })
+    }
+
+    // restore rolls the simulation back to a checkpoint taken from it, including the state of the random streams and
+    // the events the agents had scheduled
+    pub fn restore(&mut self, checkpoint: &SimulationCheckpoint) {
+        for (agent, saved) in self.agents.iter_mut().zip(&checkpoint.agents) {
+            agent.restore(saved);
+        }
+        for (group, saved) in self.composites.iter_mut().zip(&checkpoint.rollups) {
+            group.rollup.restore(saved);
+        }
+        self.streams.clone_from(&checkpoint.streams);
+        self.current_time = checkpoint.current_time;
+        self.observed_until = None;
+        self.event_log.truncate(checkpoint.logged);
+
+        self.started = checkpoint.started;
+        self.queue.clear();
+        for (agent, &time) in checkpoint.scheduled.iter().enumerate() {
+            self.set_scheduled(agent, time);
         }
     }
 
-    fn initialize_queue(&mut self) -> BinaryHeap<ScheduledEvent<C>> {
-        let mut queue = BinaryHeap::new();
-        for index in 0..self.agents.len() {
-            self.schedule_next_event(index, &mut queue);
+    // reseed restarts the streams of every agent from a new seed, so that a restored simulation takes a different
+    // future. The events of started agents are drawn again from the new streams, which leaves the model unchanged
+    // for exponential holding times only. The seed recorded in the metadata is left unchanged.
+    pub(crate) fn reseed(&mut self, seed: u64) {
+        for (streams, agent) in self.streams.iter_mut().zip(&self.agents) {
+            *streams = (self.new_streams)(seed, agent.id());
+            streams.set_antithetic(self.antithetic);
+        }
+        for index in 0..self.started {
+            self.schedule_next_event(index);
         }
-        queue
     }
 
-    fn process_event_step<F>(
-        &mut self,
-        event: ScheduledEvent<C>,
-        queue: &mut BinaryHeap<ScheduledEvent<C>>,
-        mut handler: F,
-    ) where
-        F: FnMut(Vec<StateChangeEvent>, &mut Vec<StateChangeEvent>),
-    {
-        self.current_time = event.time;
+    // finish_warm_up runs what is left of the warm-up period, discarding its events and the history agents gathered.
+    // Runs do it first, calling it beforehand gives access to the agents as they are at the start time.
+    pub fn finish_warm_up(&mut self) {
+        if self.current_time >= self.start_time {
+            return;
+        }
 
-        if let Some(target_type) = event.next_state_type {
-            let agent_index = event.agent_index;
+        self.start_agents();
+        while let Some(event) = self.next_event(self.start_time) {
+            self.process_event_step(event, |_, _| {});
+        }
 
-            let changes = {
-                let agent = &mut self.agents[agent_index];
-                agent.apply_transition(target_type, self.current_time, &mut self.rng)
-            };
+        self.current_time = self.start_time;
+        for agent in &mut self.agents {
+            agent.reset_stats(self.start_time);
+        }
+    }
 
-            handler(changes, &mut self.event_log);
+    // start_agents starts and schedules the agents added since the last run. Agents started before keep the events
+    // they have scheduled.
+    fn start_agents(&mut self) {
+        for index in self.started..self.agents.len() {
+            self.agents[index].start(self.current_time);
+            self.schedule_next_event(index);
+        }
+        self.started = self.agents.len();
+    }
 
-            self.schedule_next_event(agent_index, queue);
+    // next_event removes and returns the next event due at `limit` or before, discarding superseded events
+    fn next_event(&mut self, limit: DateTime<Utc>) -> Option<ScheduledEvent> {
+        while let Some(event) = self.queue.peek() {
+            // the agent has been rescheduled since this event was queued, i.e. because a signal moved it
+            if event.generation != self.generations[event.agent_index] {
+                self.queue.pop();
+                continue;
+            }
+            if event.time > limit {
+                return None;
+            }
+            return self.queue.pop();
         }
+        None
     }
 
-    // seconds_to_duration converts a floating point value representing seconds to a Duration (TimeDelta) type.
-    fn seconds_to_duration(seconds: f64) -> Duration {
-        let millis = (seconds * 1000.0).round() as i64;
-        Duration::milliseconds(millis)
+    fn process_event_step<F>(&mut self, event: ScheduledEvent, mut handler: F)
+    where
+        F: FnMut(Vec<StateChangeEvent>, &mut Vec<StateChangeEvent>),
+    {
+        self.current_time = event.time;
+
+        let agent_index = event.agent_index;
+        let changes =
+            self.agents[agent_index].fire(self.current_time, &mut self.streams[agent_index]);
+        let changes = self.propagate(agent_index, changes);
+
+        handler(changes, &mut self.event_log);
+
+        self.schedule_next_event(agent_index);
     }
 
-    /// schedule_next_for_agent attempts to schedule the next event for an agent, if possible.
-    fn schedule_next_event(
+    // propagate rolls the changes of an agent up to its composite parent and delivers the signals they trigger,
+    // returning every change caused at the current time in the order it happened
+    fn propagate(
         &mut self,
         agent_index: usize,
-        queue: &mut BinaryHeap<ScheduledEvent<C>>,
-    ) {
-        if let Some(delay_sec) = self.agents[agent_index].peek_next_event_delay(&mut self.rng) {
-            if let Some(next_state) = self.agents[agent_index].step(&mut self.rng) {
-                let event_time = self.current_time + Self::seconds_to_duration(delay_sec);
-                queue.push(ScheduledEvent {
-                    time: event_time,
-                    agent_index,
-                    next_state_type: Some(next_state),
-                });
+        changes: Vec<StateChangeEvent>,
+    ) -> Vec<StateChangeEvent> {
+        let mut output = Vec::new();
+        let mut pending = VecDeque::from([(agent_index, changes)]);
+        let mut delivered = 0;
+        let mut overflowed = false;
+
+        while let Some((index, mut changes)) = pending.pop_front() {
+            changes.extend(self.rollup_parent(index));
+
+            let signals: Vec<Signal> = changes
+                .iter()
+                .flat_map(|change| {
+                    self.interactions
+                        .iter_mut()
+                        .flat_map(|interaction| interaction(change))
+                        .collect::<Vec<_>>()
+                })
+                .collect();
+            output.extend(changes);
+
+            for signal in signals {
+                if delivered == self.cascade_limit {
+                    if !overflowed {
+                        self.cascade_overflows.push(CascadeOverflow {
+                            time: self.current_time,
+                            signal: signal.name.clone(),
+                        });
+                        overflowed = true;
+                    }
+                    break;
+                }
+                delivered += 1;
+
+                for target in self.signal_targets(&signal.target) {
+                    if let Some(changes) = self.agents[target].signal(
+                        &signal.name,
+                        self.current_time,
+                        &mut self.streams[target],
+                    ) {
+                        self.schedule_next_event(target);
+                        pending.push_back((target, changes));
+                    }
+                }
             }
         }
+
+        output
+    }
+
+    fn signal_targets(&self, target: &SignalTarget) -> Vec<usize> {
+        match target {
+            SignalTarget::Agent(id) => self.agent_index.get(id).copied().into_iter().collect(),
+            SignalTarget::All => (0..self.agents.len()).collect(),
+        }
+    }
+
+    // rollup_parent re-evaluates the mode of the composite the given agent belongs to, if any
+    fn rollup_parent(&mut self, agent_index: usize) -> Option<StateChangeEvent> {
+        let group = &mut self.composites[self.parent_of[agent_index]?];
+        let modes: Vec<&dyn Any> = self.agents[group.members.clone()]
+            .iter()
+            .map(|agent| agent.mode())
+            .collect();
+
+        group.rollup.update(&modes, self.current_time)
+    }
+
+    /// schedule_next_for_agent attempts to schedule the next event for an agent, if possible. Events queued for the
+    /// agent before are invalidated.
+    fn schedule_next_event(&mut self, agent_index: usize) {
+        let time = self.agents[agent_index]
+            .schedule(self.current_time, &mut self.streams[agent_index])
+            .map(|time| time.max(self.current_time));
+        self.set_scheduled(agent_index, time);
+    }
+
+    // set_scheduled replaces the queued event of an agent
+    fn set_scheduled(&mut self, agent_index: usize, time: Option<DateTime<Utc>>) {
+        self.generations[agent_index] += 1;
+        self.scheduled[agent_index] = time;
+
+        if let Some(time) = time {
+            self.queue.push(ScheduledEvent {
+                time,
+                agent_index,
+                generation: self.generations[agent_index],
+            });
+        }
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::agent::StateType;
-    use crate::state::StateChangeEvent;
+    use crate::agent::{HoldingTime, StateType};
+    use crate::rng::RandomStreams;
+    use crate::state::{AgentId, StateChangeEvent};
+    use crate::value::Value;
+    use chrono::TimeZone;
+    use rand::rngs::StdRng;
+    use rand_pcg::Pcg64;
+    use state_macros::State;
     use std::collections::HashMap;
 
-    #[derive(Clone, Default, Debug, PartialEq)]
+    #[derive(Clone, Default, Debug, PartialEq, State)]
     struct MockState {
         counter: usize,
     }
 
-    impl State for MockState {
-        fn diff(&self, other: &Self, time: DateTime<Utc>) -> Vec<StateChangeEvent> {
-            if self.counter != other.counter {
-                vec![StateChangeEvent {
-                    time,
-                    agent_id: String::new(),
-                    field: "counter".to_string(),
-                    old_value: self.counter.to_string(),
-                    new_value: other.counter.to_string(),
-                }]
-            } else {
-                vec![]
-            }
-        }
-    }
-
-    #[derive(Eq, Hash, PartialEq, Clone, Debug)]
+    #[derive(Eq, Hash, PartialEq, PartialOrd, Ord, Clone, Debug)]
     enum SimState {
         Step1,
         Step2,
@@ -199,15 +715,15 @@ mod tests {
         let time = Utc::now();
 
         let event_early = ScheduledEvent {
-            time: time,
+            time,
             agent_index: 0,
-            next_state_type: Some(1),
+            generation: 1,
         };
 
         let event_late = ScheduledEvent {
             time: time + Duration::seconds(10),
             agent_index: 1,
-            next_state_type: Some(1),
+            generation: 1,
         };
 
         assert!(event_early > event_late);
@@ -248,9 +764,10 @@ mod tests {
             SimState::Step1,
             transitions,
             &mut rng,
-        );
+        )
+        .unwrap();
 
-        let mut sim = Simulation::new(vec![agent], start_time);
+        let mut sim = Simulation::new(vec![agent], start_time).unwrap();
 
         let events = sim.run(Duration::seconds(1));
 
@@ -266,6 +783,183 @@ mod tests {
         assert!(sim.current_time > start_time);
     }
 
+    #[test]
+    fn test_simulation_common_random_numbers() {
+        let start_time = Utc::now();
+        let mut rng = StdRng::seed_from_u64(123);
+        let mut transitions = HashMap::new();
+        transitions.insert(
+            SimState::Step1,
+            StateType::new(
+                |rng| MockState {
+                    counter: rand::Rng::gen_range(rng, 0..100),
+                },
+                vec![(SimState::Step2, 1.0), (SimState::Step1, 1.0)],
+                5.0,
+            ),
+        );
+        transitions.insert(
+            SimState::Step2,
+            StateType::new_deterministic(
+                || MockState { counter: 100 },
+                vec![(SimState::Step1, 1.0)],
+                5.0,
+            ),
+        );
+        let agent = |id: &str, rng: &mut StdRng| {
+            Agent::new(id.to_string(), SimState::Step1, transitions.clone(), rng).unwrap()
+        };
+
+        let trajectory = |events: &[StateChangeEvent]| -> Vec<(DateTime<Utc>, Value)> {
+            events
+                .iter()
+                .filter(|e| e.agent_id == "a")
+                .map(|e| (e.time, e.new_value.clone()))
+                .collect()
+        };
+
+        let mut baseline =
+            Simulation::new_with_seed(vec![agent("a", &mut rng)], start_time, 9).unwrap();
+        let baseline = baseline.run(Duration::minutes(10));
+
+        // adding an agent, before or after, leaves the trajectory of the existing one untouched
+        let mut variant = Simulation::new_with_seed(
+            vec![agent("b", &mut rng), agent("a", &mut rng)],
+            start_time,
+            9,
+        )
+        .unwrap();
+        variant.add_agents(vec![agent("c", &mut rng)]).unwrap();
+
+        // an id that is already taken is rejected, and nothing of the batch is added
+        assert!(matches!(
+            variant.add_agents(vec![agent("d", &mut rng), agent("a", &mut rng)]),
+            Err(ModelError::DuplicateAgent(id)) if id == "a"
+        ));
+        assert!(matches!(
+            variant.add_agents(vec![agent("d", &mut rng), agent("d", &mut rng)]),
+            Err(ModelError::DuplicateAgent(id)) if id == "d"
+        ));
+        assert_eq!(variant.agents.len(), 3);
+
+        assert!(baseline.len() > 10);
+        assert_eq!(
+            trajectory(baseline),
+            trajectory(variant.run(Duration::minutes(10)))
+        );
+    }
+
+    #[test]
+    fn test_simulation_reproducible_from_metadata() {
+        let start_time = Utc::now();
+        let mut transitions = HashMap::new();
+        transitions.insert(
+            SimState::Step1,
+            StateType::new_deterministic(
+                || MockState { counter: 1 },
+                vec![(SimState::Step2, 1.0)],
+                5.0,
+            ),
+        );
+        transitions.insert(
+            SimState::Step2,
+            StateType::new_deterministic(
+                || MockState { counter: 2 },
+                vec![(SimState::Step1, 1.0)],
+                5.0,
+            ),
+        );
+        let agents = || {
+            let mut rng = StdRng::seed_from_u64(1);
+            vec![
+                Agent::new(
+                    "a".to_string(),
+                    SimState::Step1,
+                    transitions.clone(),
+                    &mut rng,
+                )
+                .unwrap(),
+            ]
+        };
+        let times = |events: &[StateChangeEvent]| -> Vec<DateTime<Utc>> {
+            events.iter().map(|e| e.time).collect()
+        };
+
+        // a run seeded from entropy can be reproduced from its metadata
+        let mut original = Simulation::new(agents(), start_time).unwrap();
+        let metadata = original.metadata();
+        let events = times(original.run(Duration::minutes(5)));
+        assert_eq!(original.metadata().start_time, start_time);
+
+        let mut replayed = Simulation::new_with_seed(agents(), start_time, metadata.seed).unwrap();
+        assert_eq!(replayed.metadata(), metadata);
+        assert_eq!(times(replayed.run(Duration::minutes(5))), events);
+
+        let mut pcg = Simulation::new_with_seed(agents(), start_time, metadata.seed)
+            .unwrap()
+            .with_rng::<Pcg64>("Pcg64");
+        assert_eq!(metadata.rng, "ChaCha12");
+        assert_eq!(pcg.metadata().rng, "Pcg64");
+        let pcg_events = times(pcg.run(Duration::minutes(5)));
+        assert_ne!(pcg_events, events);
+
+        let mut pcg_again = Simulation::new_with_seed(agents(), start_time, metadata.seed)
+            .unwrap()
+            .with_rng::<Pcg64>("Pcg64");
+        assert_eq!(times(pcg_again.run(Duration::minutes(5))), pcg_events);
+    }
+
+    #[test]
+    fn test_simulation_warm_up() {
+        let start_time = Utc.timestamp_opt(0, 0).unwrap();
+        let mut transitions = HashMap::new();
+        transitions.insert(
+            SimState::Step1,
+            StateType::new_deterministic(
+                || MockState { counter: 1 },
+                vec![(SimState::Step2, 1.0)],
+                60.0,
+            ),
+        );
+        transitions.insert(
+            SimState::Step2,
+            StateType::new_deterministic(
+                || MockState { counter: 2 },
+                vec![(SimState::Step1, 1.0)],
+                20.0,
+            ),
+        );
+        let mut rng = StdRng::seed_from_u64(9);
+        let agents = (0..400)
+            .map(|i| {
+                Agent::new(
+                    format!("a{}", i),
+                    SimState::Step2,
+                    transitions.clone(),
+                    &mut rng,
+                )
+                .unwrap()
+            })
+            .collect();
+
+        let mut sim = Simulation::new_with_seed(agents, start_time, 9)
+            .unwrap()
+            .with_warm_up(Duration::hours(1));
+        assert_eq!(sim.metadata().warm_up_seconds, 3600.0);
+        let events = sim.run(Duration::minutes(1));
+        assert!(events.iter().all(|event| event.time >= start_time));
+
+        // all agents started in Step2, but the warm-up brought them to the stationary share of 3/4 in Step1
+        let in_step1 = (0..400)
+            .filter(|i| sim.mode::<SimState>(&format!("a{}", i)) == Some(&SimState::Step1))
+            .count();
+        assert!(
+            (in_step1 as f64 / 400.0 - 0.75).abs() < 0.08,
+            "{}",
+            in_step1
+        );
+    }
+
     #[test]
     fn test_simulation_termination() {
         let start_time = Utc::now();
@@ -282,11 +976,421 @@ mod tests {
             SimState::Step1,
             transitions,
             &mut rng,
-        );
+        )
+        .unwrap();
 
-        let mut sim = Simulation::new(vec![agent], start_time);
+        let mut sim = Simulation::new(vec![agent], start_time).unwrap();
 
         let events = sim.run(Duration::hours(1));
         assert!(events.is_empty());
     }
+
+    #[test]
+    fn test_simulation_composite_rollup() {
+        use crate::composite::CompositeAgent;
+
+        let start_time = Utc::now();
+        let mut rng = StdRng::seed_from_u64(7);
+        let mut transitions = HashMap::new();
+
+        transitions.insert(
+            SimState::Step1,
+            StateType::new_deterministic(
+                || MockState { counter: 1 },
+                vec![(SimState::Step2, 1.0)],
+                1.0,
+            ),
+        );
+        transitions.insert(
+            SimState::Step2,
+            StateType::new_deterministic(|| MockState { counter: 2 }, vec![], 1.0),
+        );
+
+        let failed = |children: &[(&str, &SimState)]| {
+            children
+                .iter()
+                .filter(|(_, mode)| **mode == SimState::Step2)
+                .count()
+        };
+        let server = CompositeAgent::new("server_01".to_string(), "online")
+            .with_child(
+                Agent::new(
+                    "psu_1".to_string(),
+                    SimState::Step1,
+                    transitions.clone(),
+                    &mut rng,
+                )
+                .unwrap(),
+            )
+            .with_child(
+                Agent::new("psu_2".to_string(), SimState::Step1, transitions, &mut rng).unwrap(),
+            )
+            .with_rule("offline", move |c| failed(c) == 2)
+            .with_rule("degraded", move |c| failed(c) == 1);
+
+        let mut sim = Simulation::empty_with_seed(start_time, 1);
+        sim.add_composite(server).unwrap();
+
+        let events = sim.run(Duration::hours(1));
+
+        let child_ids: Vec<&str> = events
+            .iter()
+            .filter(|e| e.field == "counter")
+            .map(|e| e.agent_id.as_str())
+            .collect();
+        assert_eq!(child_ids.len(), 2);
+        assert!(child_ids.contains(&"server_01/psu_1"));
+        assert!(child_ids.contains(&"server_01/psu_2"));
+
+        let parent_modes: Vec<(&str, &str)> = events
+            .iter()
+            .filter(|e| e.agent_id == "server_01")
+            .map(|e| (e.old_value.as_str().unwrap(), e.new_value.as_str().unwrap()))
+            .collect();
+        assert_eq!(
+            parent_modes,
+            vec![("online", "degraded"), ("degraded", "offline")]
+        );
+    }
+
+    #[test]
+    fn test_simulation_timeout_races_stochastic_transition() {
+        let start_time = Utc::now();
+        let mut rng = StdRng::seed_from_u64(123);
+        let mut transitions = HashMap::new();
+
+        transitions.insert(
+            SimState::Step1,
+            StateType::new_deterministic(|| MockState { counter: 1 }, vec![], 0.0),
+        );
+        transitions.insert(
+            SimState::Step2,
+            StateType::new_deterministic(
+                || MockState { counter: 2 },
+                vec![(SimState::Step1, 1.0)],
+                1.0e9,
+            )
+            .with_timeout(2.0 * 3600.0, SimState::Step1),
+        );
+
+        let agent = Agent::new(
+            "timed_agent".to_string(),
+            SimState::Step2,
+            transitions,
+            &mut rng,
+        )
+        .unwrap();
+
+        let mut sim = Simulation::new_with_seed(vec![agent], start_time, 1).unwrap();
+        let events = sim.run(Duration::hours(3));
+
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].time, start_time + Duration::hours(2));
+        assert_eq!(events[0].new_value, "1");
+    }
+
+    #[test]
+    fn test_simulation_watchdog_timeout_fires_repeatedly() {
+        let start_time = Utc::now();
+        let mut rng = StdRng::seed_from_u64(123);
+        let mut transitions = HashMap::new();
+
+        // the timeout targets its own state type, so every expiry resets the watchdog
+        transitions.insert(
+            SimState::Step1,
+            StateType::new_with_stats(
+                |_, stats| MockState {
+                    counter: stats.transitions() as usize,
+                },
+                vec![],
+                0.0,
+            )
+            .with_timeout(3600.0, SimState::Step1),
+        );
+
+        let agent = Agent::new(
+            "watchdog".to_string(),
+            SimState::Step1,
+            transitions,
+            &mut rng,
+        )
+        .unwrap();
+
+        let mut sim = Simulation::new_with_seed(vec![agent], start_time, 1).unwrap();
+        let times: Vec<DateTime<Utc>> = sim
+            .run(Duration::minutes(210))
+            .iter()
+            .map(|event| event.time)
+            .collect();
+
+        assert_eq!(
+            times,
+            (1..=3)
+                .map(|hours| start_time + Duration::hours(hours))
+                .collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn test_simulation_guarded_agent_waits_for_guard() {
+        let start_time = Utc::now();
+        let mut rng = StdRng::seed_from_u64(123);
+        let mut transitions = HashMap::new();
+
+        // the only transition is closed for the first hour, during which the agent stays put instead of stopping
+        transitions.insert(
+            SimState::Step1,
+            StateType::new_deterministic(
+                || MockState { counter: 1 },
+                vec![(SimState::Step2, 1.0)],
+                60.0,
+            )
+            .with_guard(SimState::Step2, |stats| stats.age() >= 3600.0),
+        );
+        transitions.insert(
+            SimState::Step2,
+            StateType::new_deterministic(|| MockState { counter: 2 }, vec![], 0.0),
+        );
+
+        let agent = Agent::new(
+            "guarded".to_string(),
+            SimState::Step1,
+            transitions,
+            &mut rng,
+        )
+        .unwrap();
+
+        let mut sim = Simulation::new_with_seed(vec![agent], start_time, 1).unwrap();
+        let events = sim.run(Duration::hours(3));
+
+        assert_eq!(events.len(), 1);
+        assert!(events[0].time >= start_time + Duration::hours(1));
+        assert_eq!(events[0].new_value, "2");
+    }
+
+    #[test]
+    fn test_simulation_heterogeneous_agents_interact() {
+        use crate::interaction::Signal;
+
+        #[derive(Clone, Default, Debug, State)]
+        struct GatewayState {
+            up: bool,
+        }
+
+        #[derive(Eq, Hash, PartialEq, PartialOrd, Ord, Clone, Debug)]
+        enum GatewayMode {
+            Up,
+            Down,
+        }
+
+        let start_time = Utc::now();
+        let mut rng = StdRng::seed_from_u64(123);
+
+        let mut gateway_transitions = HashMap::new();
+        gateway_transitions.insert(
+            GatewayMode::Up,
+            StateType::new_deterministic(
+                || GatewayState { up: true },
+                vec![(GatewayMode::Down, 1.0)],
+                60.0,
+            ),
+        );
+        gateway_transitions.insert(
+            GatewayMode::Down,
+            StateType::new_deterministic(|| GatewayState { up: false }, vec![], 0.0),
+        );
+
+        let mut device_transitions = HashMap::new();
+        device_transitions.insert(
+            SimState::Step1,
+            StateType::new_deterministic(|| MockState { counter: 1 }, vec![], 0.0)
+                .with_signal("gateway_down", SimState::Step2),
+        );
+        device_transitions.insert(
+            SimState::Step2,
+            StateType::new_deterministic(|| MockState { counter: 2 }, vec![], 0.0),
+        );
+
+        let devices = (0..2)
+            .map(|i| {
+                Agent::new(
+                    format!("device_{}", i),
+                    SimState::Step1,
+                    device_transitions.clone(),
+                    &mut rng,
+                )
+                .unwrap()
+            })
+            .collect();
+        let gateway = Agent::new(
+            "gateway".to_string(),
+            GatewayMode::Up,
+            gateway_transitions,
+            &mut rng,
+        )
+        .unwrap();
+
+        let mut sim = Simulation::new_with_seed(devices, start_time, 1).unwrap();
+        sim.add_agents(vec![gateway]).unwrap();
+        sim.add_interaction(|event| {
+            if event.agent_id == "gateway" && event.new_value == "false" {
+                vec![Signal::to_all("gateway_down")]
+            } else {
+                vec![]
+            }
+        });
+
+        let events = sim.run(Duration::hours(1));
+
+        let ids: Vec<&str> = events.iter().map(|e| e.agent_id.as_str()).collect();
+        assert_eq!(ids, vec!["gateway", "device_0", "device_1"]);
+        assert!(events.iter().all(|e| e.time == events[0].time));
+        assert_eq!(events[1].new_value, "2");
+    }
+
+    #[test]
+    fn test_simulation_cyclic_signals_are_cut_short() {
+        use crate::interaction::Signal;
+
+        let start_time = Utc::now();
+        let mut rng = StdRng::seed_from_u64(123);
+        let mut transitions = HashMap::new();
+
+        // every change pings the agent, whose ping handler changes it again
+        transitions.insert(
+            SimState::Step1,
+            StateType::new_with_stats(
+                |_, stats| MockState {
+                    counter: stats.transitions() as usize,
+                },
+                vec![(SimState::Step1, 1.0)],
+                600.0,
+            )
+            .with_signal("ping", SimState::Step1),
+        );
+        let agent = Agent::new("echo".to_string(), SimState::Step1, transitions, &mut rng).unwrap();
+
+        let mut sim = Simulation::new_with_seed(vec![agent], start_time, 1)
+            .unwrap()
+            .with_cascade_limit(100);
+        sim.add_interaction(|_| vec![Signal::to_agent("echo", "ping")]);
+
+        let events = sim.run(Duration::hours(1)).len();
+
+        let overflows = sim.cascade_overflows();
+        assert!(!overflows.is_empty());
+        assert!(overflows.iter().all(|overflow| overflow.signal == "ping"));
+        assert_eq!(events, overflows.len() * 101);
+    }
+
+    #[test]
+    fn test_simulation_custom_agent() {
+        use crate::agent::SimAgent;
+
+        // Heartbeat is a rule-based agent that flips a flag at a fixed interval
+        struct Heartbeat {
+            id: AgentId,
+            beats: u32,
+        }
+
+        impl SimAgent for Heartbeat {
+            fn id(&self) -> &str {
+                &self.id
+            }
+
+            fn schedule(
+                &mut self,
+                now: DateTime<Utc>,
+                _rng: &mut dyn RandomStreams,
+            ) -> Option<DateTime<Utc>> {
+                Some(now + Duration::seconds(10))
+            }
+
+            fn fire(
+                &mut self,
+                time: DateTime<Utc>,
+                _rng: &mut dyn RandomStreams,
+            ) -> Vec<StateChangeEvent> {
+                self.beats += 1;
+                vec![StateChangeEvent {
+                    time,
+                    agent_id: self.id.clone(),
+                    field: "beats",
+                    old_value: (self.beats - 1).into(),
+                    new_value: self.beats.into(),
+                }]
+            }
+        }
+
+        let start_time = Utc::now();
+        let mut sim = Simulation::empty_with_seed(start_time, 1);
+        sim.add_agent(Heartbeat {
+            id: "heartbeat".into(),
+            beats: 0,
+        })
+        .unwrap();
+
+        let events = sim.run(Duration::seconds(35));
+
+        assert_eq!(events.len(), 3);
+        assert_eq!(events[2].time, start_time + Duration::seconds(30));
+        assert_eq!(events[2].new_value, "3");
+
+        let timelines = crate::state::Timeline::generate(events);
+        assert_eq!(timelines["heartbeat"].entries.len(), 4);
+    }
+
+    #[test]
+    fn test_scheduled_events_survive_runs_warm_up_and_restore() {
+        // agent a switches mode every 100 s and agent b every 30 s, without randomness
+        let population = || {
+            let mut rng = StdRng::seed_from_u64(0);
+            let agents = [("a", 100.0), ("b", 30.0)].map(|(id, period)| {
+                let mut transitions = HashMap::new();
+                for (from, to, counter) in [
+                    (SimState::Step1, SimState::Step2, 1),
+                    (SimState::Step2, SimState::Step1, 2),
+                ] {
+                    let factory = move || MockState { counter };
+                    let def = StateType::new_deterministic(factory, vec![(to, 1.0)], period)
+                        .with_holding_time(HoldingTime::Deterministic);
+                    transitions.insert(from, def);
+                }
+                Agent::new(id.to_string(), SimState::Step1, transitions, &mut rng).unwrap()
+            });
+            Simulation::new_with_seed(agents.into(), Utc.timestamp_opt(0, 0).unwrap(), 1).unwrap()
+        };
+        let times_of_a = |events: &[StateChangeEvent]| -> Vec<i64> {
+            events
+                .iter()
+                .filter(|event| event.agent_id.as_str() == "a" && event.field == "counter")
+                .map(|event| event.time.timestamp())
+                .collect()
+        };
+
+        let mut sim = population();
+        assert_eq!(times_of_a(sim.run(Duration::seconds(200))), [100, 200]);
+
+        // running in chunks keeps the event a scheduled before the end of the first chunk
+        let mut sim = population();
+        sim.run(Duration::seconds(50));
+        assert_eq!(sim.current_time(), Utc.timestamp_opt(50, 0).unwrap());
+        let checkpoint = sim.checkpoint().unwrap();
+        assert_eq!(times_of_a(sim.run(Duration::seconds(150))), [100, 200]);
+        assert_eq!(sim.current_time(), Utc.timestamp_opt(200, 0).unwrap());
+
+        // and so does restoring a checkpoint taken between the chunks
+        sim.restore(&checkpoint);
+        assert_eq!(times_of_a(sim.run(Duration::seconds(150))), [100, 200]);
+
+        // an event scheduled during the warm-up fires after it
+        let mut sim = population().with_warm_up(Duration::seconds(50));
+        assert_eq!(times_of_a(sim.run(Duration::seconds(200))), [50, 150]);
+
+        // also when the simulation is handed to a parallel engine after its warm-up
+        let warmed_up = population().with_warm_up(Duration::seconds(50));
+        let mut parallel = crate::parallel::ParallelSimulation::from_simulation(warmed_up, 2);
+        let events = parallel.run(Duration::seconds(200)).unwrap();
+        assert_eq!(times_of_a(&events), [50, 150]);
+    }
 }
@@ -1,10 +1,34 @@
+use crate::composite::root_id;
+use crate::value::Value;
 use chrono::{DateTime, Duration, Utc};
-use serde::{Deserialize, Serialize};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::borrow::Borrow;
 use std::collections::{BTreeMap, HashMap, HashSet};
 use std::fmt;
+use std::ops::Deref;
+use std::sync::{Arc, LazyLock, RwLock};
 
+/// StateChangeEvent is the change of one field of one agent. Events are built for runs of millions of them: the agent
+/// id is shared with the agent rather than copied, and the field name is interned (see `intern`), so that an event
+/// only allocates for its values. They are owned and serializable as they are, and cheap to clone.
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct StateChangeEvent {
+    #[serde(rename = "Time")]
+    pub time: DateTime<Utc>,
+    #[serde(rename = "AgentId")]
+    pub agent_id: AgentId,
+    #[serde(rename = "Field", deserialize_with = "deserialize_field")]
+    pub field: FieldName,
+    #[serde(rename = "NewValue")]
+    pub new_value: Value,
+    #[serde(rename = "OldValue")]
+    pub old_value: Value,
+}
+
+/// StringEvent is a `StateChangeEvent` with its values in their string form, as events were serialized before values
+/// were typed. Sinks write it for consumers that expect strings, and for CSV.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct StringEvent {
     #[serde(rename = "Time")]
     pub time: DateTime<Utc>,
     #[serde(rename = "AgentId")]
@@ -17,6 +41,154 @@ pub struct StateChangeEvent {
     pub old_value: String,
 }
 
+impl From<&StateChangeEvent> for StringEvent {
+    fn from(event: &StateChangeEvent) -> Self {
+        StringEvent {
+            time: event.time,
+            agent_id: event.agent_id.to_string(),
+            field: event.field.to_string(),
+            new_value: event.new_value.to_string(),
+            old_value: event.old_value.to_string(),
+        }
+    }
+}
+
+// reading an event back from its string form keeps its values as strings, as their types were not recorded
+impl From<StringEvent> for StateChangeEvent {
+    fn from(event: StringEvent) -> Self {
+        StateChangeEvent {
+            time: event.time,
+            agent_id: event.agent_id.into(),
+            field: intern(&event.field),
+            new_value: Value::Str(event.new_value),
+            old_value: Value::Str(event.old_value),
+        }
+    }
+}
+
+/// FieldName is the name of a field, either a literal (as `#[derive(State)]` writes them) or interned. Spelled as an
+/// alias, serde does not tie deserialized events to the lifetime of their input as it would for a `&str` field.
+pub type FieldName = &'static str;
+
+/// AgentId is the id of an agent as carried by its events. It is reference counted, so tagging an event with the id
+/// of its agent does not allocate, and reads as a `&str`.
+#[derive(Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct AgentId(Arc<str>);
+
+impl AgentId {
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+// the default, empty id is shared too, as `State::diff` leaves the id of its events for the agent to fill in
+impl Default for AgentId {
+    fn default() -> Self {
+        static EMPTY: LazyLock<AgentId> = LazyLock::new(|| AgentId(Arc::from("")));
+        EMPTY.clone()
+    }
+}
+
+impl Deref for AgentId {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        &self.0
+    }
+}
+
+impl AsRef<str> for AgentId {
+    fn as_ref(&self) -> &str {
+        &self.0
+    }
+}
+
+impl Borrow<str> for AgentId {
+    fn borrow(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Display for AgentId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl fmt::Debug for AgentId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(&*self.0, f)
+    }
+}
+
+impl From<&str> for AgentId {
+    fn from(id: &str) -> Self {
+        AgentId(Arc::from(id))
+    }
+}
+
+impl From<String> for AgentId {
+    fn from(id: String) -> Self {
+        AgentId(Arc::from(id))
+    }
+}
+
+impl PartialEq<str> for AgentId {
+    fn eq(&self, other: &str) -> bool {
+        &*self.0 == other
+    }
+}
+
+impl PartialEq<&str> for AgentId {
+    fn eq(&self, other: &&str) -> bool {
+        &*self.0 == *other
+    }
+}
+
+impl PartialEq<String> for AgentId {
+    fn eq(&self, other: &String) -> bool {
+        *self.0 == **other
+    }
+}
+
+impl Serialize for AgentId {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.0)
+    }
+}
+
+impl<'de> Deserialize<'de> for AgentId {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer).map(AgentId::from)
+    }
+}
+
+// intern returns the one copy of a field name shared by every event, for field names only known at runtime, i.e.
+// those of a `DynamicState` or of events read back from a trace. Interned names are never freed, which is fine for
+// field names but not for arbitrary data: memory grows with every distinct name, so names from untrusted input, i.e.
+// traces or user-defined fields, should be bounded before they get here. It takes a lock, which callers on hot paths
+// avoid by interning their names once and keeping the `FieldName`.
+pub fn intern(name: &str) -> FieldName {
+    static NAMES: LazyLock<RwLock<HashSet<&'static str>>> = LazyLock::new(Default::default);
+
+    if let Some(interned) = NAMES.read().unwrap().get(name) {
+        return interned;
+    }
+    let mut names = NAMES.write().unwrap();
+    match names.get(name) {
+        Some(interned) => interned,
+        None => {
+            let interned: &'static str = Box::leak(name.into());
+            names.insert(interned);
+            interned
+        }
+    }
+}
+
+fn deserialize_field<'de, D: Deserializer<'de>>(deserializer: D) -> Result<FieldName, D::Error> {
+    String::deserialize(deserializer).map(|name| intern(&name))
+}
+
 pub trait State: Sized + Clone + Default {
     fn diff(&self, other: &Self, time: DateTime<Utc>) -> Vec<StateChangeEvent>;
 }
@@ -24,8 +196,8 @@ pub trait State: Sized + Clone + Default {
 #[derive(Debug, Clone)]
 pub struct TimelineEntry {
     pub timestamp: DateTime<Utc>,
-    pub state: BTreeMap<String, String>,
-    pub events: Vec<String>,
+    pub state: BTreeMap<FieldName, Value>,
+    pub events: Vec<FieldName>,
 }
 
 impl fmt::Display for TimelineEntry {
@@ -65,7 +237,7 @@ impl Timeline {
             return timelines;
         }
 
-        let mut events_by_agent: HashMap<String, Vec<StateChangeEvent>> = HashMap::new();
+        let mut events_by_agent: HashMap<AgentId, Vec<StateChangeEvent>> = HashMap::new();
         for event in events {
             events_by_agent
                 .entry(event.agent_id.clone())
@@ -75,13 +247,30 @@ impl Timeline {
 
         for (agent_id, agent_events) in events_by_agent {
             if let Some(timeline) = Self::generate_single_timeline(&agent_events) {
-                timelines.insert(agent_id, timeline);
+                timelines.insert(agent_id.to_string(), timeline);
             }
         }
 
         timelines
     }
 
+    // generate_grouped builds per-agent timelines grouped by the root of each agent's hierarchy, so that a
+    // composite agent (i.e. `server_01`) is reported together with all of its children (`server_01/psu_2`).
+    pub fn generate_grouped(
+        events: &[StateChangeEvent],
+    ) -> BTreeMap<String, BTreeMap<String, Timeline>> {
+        let mut groups: BTreeMap<String, BTreeMap<String, Timeline>> = BTreeMap::new();
+
+        for (agent_id, timeline) in Self::generate(events) {
+            groups
+                .entry(root_id(&agent_id).to_string())
+                .or_default()
+                .insert(agent_id, timeline);
+        }
+
+        groups
+    }
+
     fn generate_single_timeline(events: &[StateChangeEvent]) -> Option<Self> {
         if events.is_empty() {
             return None;
@@ -95,8 +284,8 @@ impl Timeline {
 
         for event in &sorted_events {
             if !seen_fields.contains(&event.field) {
-                current_state.insert(event.field.clone(), event.old_value.clone());
-                seen_fields.insert(event.field.clone());
+                current_state.insert(event.field, event.old_value.clone());
+                seen_fields.insert(event.field);
             }
         }
 
@@ -119,8 +308,8 @@ impl Timeline {
             let mut changed_fields = Vec::new();
 
             for event in event_group {
-                current_state.insert(event.field.clone(), event.new_value.clone());
-                changed_fields.push(event.field.clone());
+                current_state.insert(event.field, event.new_value.clone());
+                changed_fields.push(event.field);
             }
 
             entries.push(TimelineEntry {
@@ -147,6 +336,89 @@ impl fmt::Display for Timeline {
 mod tests {
     use super::*;
     use chrono::TimeZone;
+    use state_macros::State;
+
+    #[derive(Clone, Copy, Debug, PartialEq)]
+    enum Health {
+        Ok,
+        Degraded,
+    }
+
+    impl fmt::Display for Health {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            write!(f, "{:?}", self)
+        }
+    }
+
+    #[derive(Clone, Debug, State)]
+    struct Device {
+        online: bool,
+        sessions: u32,
+        load: f32,
+        firmware: Option<String>,
+        ports: Vec<u16>,
+        health: Health,
+        since: DateTime<Utc>,
+    }
+
+    impl Default for Device {
+        fn default() -> Self {
+            Device {
+                online: false,
+                sessions: 0,
+                load: 0.0,
+                firmware: None,
+                ports: Vec::new(),
+                health: Health::Ok,
+                since: DateTime::UNIX_EPOCH,
+            }
+        }
+    }
+
+    #[test]
+    fn test_derived_diff_keeps_field_types() {
+        let time = Utc.timestamp_opt(60, 0).unwrap();
+        let after = Device {
+            online: true,
+            sessions: 3,
+            load: 0.7,
+            firmware: Some("2.1".to_string()),
+            ports: vec![22, 443],
+            health: Health::Degraded,
+            since: time,
+        };
+
+        let changes: Vec<(&str, Value)> = Device::default()
+            .diff(&after, time)
+            .into_iter()
+            .map(|event| (event.field, event.new_value))
+            .collect();
+        assert_eq!(
+            changes,
+            vec![
+                ("online", Value::Bool(true)),
+                ("sessions", Value::Int(3)),
+                ("load", Value::Float(f64::from(0.7f32))),
+                ("firmware", "2.1".into()),
+                ("ports", vec![22, 443].into()),
+                ("health", "Degraded".into()),
+                ("since", Value::Timestamp(time)),
+            ]
+        );
+
+        // the string form remains that of `to_string`, and typed JSON reads back as typed values
+        let event = &Device::default().diff(&after, time)[0];
+        let legacy = serde_json::to_string(&StringEvent::from(event)).unwrap();
+        assert!(legacy.contains(r#""NewValue":"true","OldValue":"false""#));
+        let json = serde_json::to_string(event).unwrap();
+        assert!(json.contains(r#""NewValue":true,"OldValue":false"#));
+        let read: StateChangeEvent = serde_json::from_str(&json).unwrap();
+        assert_eq!(read.new_value, Value::Bool(true));
+        assert_eq!(
+            Device::default().diff(&after, time)[1].old_value,
+            Value::Int(0)
+        );
+    }
 
     #[test]
     fn test_timeline_generation_single_agent() {
@@ -155,24 +427,24 @@ mod tests {
         let events = vec![
             StateChangeEvent {
                 time: base_time,
-                agent_id: "agent_A".to_string(),
-                field: "status".to_string(),
-                old_value: "init".to_string(),
-                new_value: "running".to_string(),
+                agent_id: "agent_A".into(),
+                field: "status",
+                old_value: "init".into(),
+                new_value: "running".into(),
             },
             StateChangeEvent {
                 time: base_time + Duration::seconds(10),
-                agent_id: "agent_A".to_string(),
-                field: "load".to_string(),
-                old_value: "0".to_string(),
-                new_value: "50".to_string(),
+                agent_id: "agent_A".into(),
+                field: "load",
+                old_value: Value::Int(0),
+                new_value: Value::Int(50),
             },
             StateChangeEvent {
                 time: base_time + Duration::seconds(10),
-                agent_id: "agent_A".to_string(),
-                field: "status".to_string(),
-                old_value: "running".to_string(),
-                new_value: "busy".to_string(),
+                agent_id: "agent_A".into(),
+                field: "status",
+                old_value: "running".into(),
+                new_value: "busy".into(),
             },
         ];
 
@@ -196,8 +468,8 @@ mod tests {
         assert_eq!(second_trans.timestamp, base_time + Duration::seconds(10));
         assert_eq!(second_trans.state.get("load").unwrap(), "50");
         assert_eq!(second_trans.state.get("status").unwrap(), "busy");
-        assert!(second_trans.events.contains(&"load".to_string()));
-        assert!(second_trans.events.contains(&"status".to_string()));
+        assert!(second_trans.events.contains(&"load"));
+        assert!(second_trans.events.contains(&"status"));
     }
 
     #[test]
@@ -206,17 +478,17 @@ mod tests {
         let events = vec![
             StateChangeEvent {
                 time,
-                agent_id: "A".to_string(),
-                field: "f".to_string(),
-                old_value: "0".to_string(),
-                new_value: "1".to_string(),
+                agent_id: "A".into(),
+                field: "f",
+                old_value: Value::Int(0),
+                new_value: Value::Int(1),
             },
             StateChangeEvent {
                 time,
-                agent_id: "B".to_string(),
-                field: "f".to_string(),
-                old_value: "0".to_string(),
-                new_value: "2".to_string(),
+                agent_id: "B".into(),
+                field: "f",
+                old_value: Value::Int(0),
+                new_value: Value::Int(2),
             },
         ];
 
@@ -225,4 +497,94 @@ mod tests {
         assert!(timelines.contains_key("A"));
         assert!(timelines.contains_key("B"));
     }
+
+    #[test]
+    fn test_events_share_ids_and_field_names() {
+        use crate::agent::{Agent, StateType};
+        use crate::simulation::Simulation;
+        use rand::SeedableRng;
+        use rand::rngs::StdRng;
+
+        let transitions = HashMap::from([
+            (
+                true,
+                StateType::new_deterministic(
+                    || Device {
+                        online: true,
+                        ..Device::default()
+                    },
+                    vec![(false, 1.0)],
+                    60.0,
+                ),
+            ),
+            (
+                false,
+                StateType::new_deterministic(Device::default, vec![(true, 1.0)], 60.0),
+            ),
+        ]);
+        let mut rng = StdRng::seed_from_u64(3);
+        let agent = Agent::new("device".to_string(), true, transitions, &mut rng).unwrap();
+        let mut sim =
+            Simulation::new_with_seed(vec![agent], Utc.timestamp_opt(0, 0).unwrap(), 3).unwrap();
+
+        // run hands the log back rather than a copy of it, and its events share the id of their agent
+        let logged = sim.run(Duration::hours(1)).as_ptr();
+        let events = sim.events();
+        assert_eq!(logged, events.as_ptr());
+        assert!(events.len() > 10);
+        assert!(
+            events
+                .iter()
+                .all(|event| std::ptr::eq(event.agent_id.as_str(), events[0].agent_id.as_str()))
+        );
+
+        // names read back are interned, so they are shared as well
+        let json = serde_json::to_string(&events[0]).unwrap();
+        let read: StateChangeEvent = serde_json::from_str(&json).unwrap();
+        let again: StateChangeEvent = serde_json::from_str(&json).unwrap();
+        assert_eq!(read.agent_id, "device");
+        assert_eq!(
+            (read.field, &read.new_value),
+            (events[0].field, &events[0].new_value)
+        );
+        assert!(std::ptr::eq(read.field, again.field));
+        let name = String::from("online");
+        assert!(std::ptr::eq(intern("online"), intern(&name)));
+
+        let count = events.len();
+        assert_eq!(sim.into_events().len(), count);
+    }
+
+    #[test]
+    fn test_timeline_grouped_by_hierarchy() {
+        let time = Utc::now();
+        let event = |agent_id: &str| StateChangeEvent {
+            time,
+            agent_id: agent_id.into(),
+            field: "f",
+            old_value: Value::Int(0),
+            new_value: Value::Int(1),
+        };
+        let events = vec![
+            event("server_01"),
+            event("server_01/psu_1"),
+            event("server_01/psu_2"),
+            event("server_02/disk_1"),
+        ];
+
+        let groups = Timeline::generate_grouped(&events);
+        assert_eq!(groups.len(), 2);
+
+        let server_01 = groups.get("server_01").unwrap();
+        assert_eq!(
+            server_01.keys().collect::<Vec<_>>(),
+            vec!["server_01", "server_01/psu_1", "server_01/psu_2"]
+        );
+
+        let server_02 = groups.get("server_02").unwrap();
+        assert_eq!(
+            server_02.keys().collect::<Vec<_>>(),
+            vec!["server_02/disk_1"]
+        );
+    }
 }
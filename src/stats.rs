@@ -0,0 +1,187 @@
+use crate::matrix::Modes;
+use chrono::{DateTime, Utc};
+use std::hash::Hash;
+use std::sync::Arc;
+
+/// AgentStats records the history of an agent as it moves through the simulation: its age, how long it has been in
+/// its current state type, the cumulative time spent in each state type and how often each one was entered. It is
+/// handed to weight functions, guards, rate functions and factories so that aging and escalation behaviours can be
+/// modelled. All durations are in seconds of simulated time.
+///
+/// Modes are counted by their index in the transition matrix of the agent, whose numbering is shared rather than
+/// copied, and the counters are only allocated once the agent leaves its initial mode.
+#[derive(Clone, Debug)]
+pub struct AgentStats<C> {
+    started_at: Option<DateTime<Utc>>,
+    now: Option<DateTime<Utc>>,
+    modes: Arc<Modes<C>>,
+    initial: usize,
+    mode: usize,
+    entered_at: Option<DateTime<Utc>>,
+    time_in_mode: Vec<f64>,
+    visits: Vec<u64>,
+    transitions: u64,
+}
+
+impl<C> AgentStats<C>
+where
+    C: Eq + Hash + Clone,
+{
+    // new creates the statistics of an agent in the mode at index `mode` of `modes`
+    pub(crate) fn new(modes: Arc<Modes<C>>, mode: usize) -> Self {
+        AgentStats {
+            started_at: None,
+            now: None,
+            modes,
+            initial: mode,
+            mode,
+            entered_at: None,
+            time_in_mode: Vec::new(),
+            visits: Vec::new(),
+            transitions: 0,
+        }
+    }
+
+    // start marks the agent as having entered its initial state type at the given time
+    pub(crate) fn start(&mut self, time: DateTime<Utc>) {
+        self.started_at = Some(time);
+        self.now = Some(time);
+        self.entered_at = Some(time);
+    }
+
+    // reset discards the history gathered so far, as if the agent had been started in its current state type at the
+    // given time
+    pub(crate) fn reset(&mut self, time: DateTime<Utc>) {
+        *self = AgentStats::new(Arc::clone(&self.modes), self.mode);
+        self.start(time);
+    }
+
+    // record_transition accounts for a transition to the mode at index `to` applied at the given time.
+    // Self-transitions count towards the number of transitions, but not as a new visit.
+    pub(crate) fn record_transition(&mut self, to: usize, time: DateTime<Utc>) {
+        self.now = Some(time);
+        self.transitions += 1;
+
+        if to == self.mode {
+            return;
+        }
+
+        if self.visits.is_empty() {
+            self.time_in_mode = vec![0.0; self.modes.len()];
+            self.visits = vec![0; self.modes.len()];
+        }
+        self.time_in_mode[self.mode] += self.time_in_current_mode();
+        self.visits[to] += 1;
+        self.mode = to;
+        self.entered_at = Some(time);
+    }
+
+    // advance moves the clock of the statistics to the given time without a transition, i.e. when an agent whose
+    // transitions are all guarded off wakes up to draw again
+    pub(crate) fn advance(&mut self, time: DateTime<Utc>) {
+        if self.started_at.is_some() {
+            self.now = self.now.max(Some(time));
+        }
+    }
+
+    pub fn started_at(&self) -> Option<DateTime<Utc>> {
+        self.started_at
+    }
+
+    pub fn entered_at(&self) -> Option<DateTime<Utc>> {
+        self.entered_at
+    }
+
+    pub fn current_mode(&self) -> &C {
+        self.modes.mode(self.mode)
+    }
+
+    // age returns the seconds elapsed since the agent was started
+    pub fn age(&self) -> f64 {
+        Self::seconds_between(self.started_at, self.now)
+    }
+
+    // time_in_current_mode returns the seconds elapsed since the agent entered its current state type
+    pub fn time_in_current_mode(&self) -> f64 {
+        Self::seconds_between(self.entered_at, self.now)
+    }
+
+    // cumulative_time_in returns the total seconds spent in the given state type, including the current stint
+    pub fn cumulative_time_in(&self, mode: &C) -> f64 {
+        let Some(index) = self.modes.index(mode) else {
+            return 0.0;
+        };
+        let past = self.time_in_mode.get(index).copied().unwrap_or(0.0);
+        if index == self.mode {
+            past + self.time_in_current_mode()
+        } else {
+            past
+        }
+    }
+
+    // visits returns how many times the agent has entered the given state type, including its initial one
+    pub fn visits(&self, mode: &C) -> u64 {
+        let Some(index) = self.modes.index(mode) else {
+            return 0;
+        };
+        let initial = (self.started_at.is_some() && index == self.initial) as u64;
+        initial + self.visits.get(index).copied().unwrap_or(0)
+    }
+
+    // transitions returns the total number of transitions applied to the agent, including self-transitions
+    pub fn transitions(&self) -> u64 {
+        self.transitions
+    }
+
+    fn seconds_between(from: Option<DateTime<Utc>>, to: Option<DateTime<Utc>>) -> f64 {
+        match (from, to) {
+            (Some(from), Some(to)) => (to - from).num_milliseconds() as f64 / 1000.0,
+            _ => 0.0,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::{Duration, TimeZone};
+
+    #[test]
+    fn test_stats_track_time_and_visits() {
+        let start = Utc.timestamp_opt(1000, 0).unwrap();
+        let (idle, working) = (0, 1);
+        let mut stats = AgentStats::new(
+            Arc::new(Modes::new(vec!["idle", "working", "offline"])),
+            idle,
+        );
+
+        assert_eq!(stats.age(), 0.0);
+        assert_eq!(stats.visits(&"idle"), 0);
+        stats.start(start);
+        assert_eq!(stats.visits(&"idle"), 1);
+
+        stats.record_transition(working, start + Duration::seconds(10));
+        stats.record_transition(working, start + Duration::seconds(15));
+        stats.record_transition(idle, start + Duration::seconds(40));
+        stats.record_transition(working, start + Duration::seconds(45));
+        stats.record_transition(working, start + Duration::seconds(50));
+
+        assert_eq!(stats.age(), 50.0);
+        assert_eq!(stats.current_mode(), &"working");
+        assert_eq!(stats.time_in_current_mode(), 5.0);
+        assert_eq!(stats.cumulative_time_in(&"idle"), 15.0);
+        assert_eq!(stats.cumulative_time_in(&"working"), 35.0);
+        assert_eq!(stats.visits(&"idle"), 2);
+        assert_eq!(stats.visits(&"working"), 2);
+        assert_eq!(stats.visits(&"offline"), 0);
+        assert_eq!(stats.visits(&"broken"), 0);
+        assert_eq!(stats.transitions(), 5);
+
+        stats.reset(start + Duration::seconds(60));
+        assert_eq!(stats.age(), 0.0);
+        assert_eq!(stats.current_mode(), &"working");
+        assert_eq!(stats.cumulative_time_in(&"idle"), 0.0);
+        assert_eq!(stats.visits(&"working"), 1);
+        assert_eq!(stats.transitions(), 0);
+    }
+}
@@ -0,0 +1,330 @@
+use std::f64::consts::PI;
+use std::fmt;
+
+/// Summary describes a sample of independent observations of a metric (i.e. one per replication) with a
+/// confidence interval for its mean, based on the Student t distribution.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Summary {
+    pub count: usize,
+    pub mean: f64,
+    pub std_dev: f64,
+    pub min: f64,
+    pub max: f64,
+    pub confidence: f64,
+    pub half_width: f64,
+}
+
+impl Summary {
+    // from_values summarizes a sample, computing a confidence interval at the given level (i.e. 0.95). The
+    // interval is unbounded for samples with fewer than two observations.
+    pub fn from_values(values: &[f64], confidence: f64) -> Self {
+        let count = values.len();
+        let mean = if count == 0 {
+            f64::NAN
+        } else {
+            values.iter().sum::<f64>() / count as f64
+        };
+
+        let std_dev = if count < 2 {
+            f64::NAN
+        } else {
+            let sum_sq: f64 = values.iter().map(|v| (v - mean).powi(2)).sum();
+            (sum_sq / (count - 1) as f64).sqrt()
+        };
+
+        let half_width = if count < 2 {
+            f64::INFINITY
+        } else {
+            let t = student_t_quantile(0.5 + confidence / 2.0, (count - 1) as f64);
+            t * std_dev / (count as f64).sqrt()
+        };
+
+        Summary {
+            count,
+            mean,
+            std_dev,
+            min: values.iter().copied().fold(f64::INFINITY, f64::min),
+            max: values.iter().copied().fold(f64::NEG_INFINITY, f64::max),
+            confidence,
+            half_width,
+        }
+    }
+
+    pub fn lower(&self) -> f64 {
+        self.mean - self.half_width
+    }
+
+    pub fn upper(&self) -> f64 {
+        self.mean + self.half_width
+    }
+
+    // relative_precision returns the half width of the confidence interval relative to the mean
+    pub fn relative_precision(&self) -> f64 {
+        self.half_width / self.mean.abs()
+    }
+
+    // required_replications estimates how many observations are needed for the half width of the confidence interval
+    // to fall within `relative_precision` of the mean, assuming the sample variance holds. It returns None for samples
+    // with fewer than two observations or a zero mean.
+    pub fn required_replications(&self, relative_precision: f64) -> Option<usize> {
+        if self.count < 2 || self.mean == 0.0 {
+            return None;
+        }
+
+        let target = relative_precision * self.mean.abs();
+        let z = normal_quantile(0.5 + self.confidence / 2.0);
+        let mut count = self
+            .count
+            .max((z * self.std_dev / target).powi(2).ceil() as usize);
+        while student_t_quantile(0.5 + self.confidence / 2.0, (count - 1) as f64) * self.std_dev
+            / (count as f64).sqrt()
+            > target
+        {
+            count += 1;
+        }
+        Some(count)
+    }
+}
+
+impl fmt::Display for Summary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "mean {:.4} ± {:.4} ({:.0}% CI, n = {}, sd {:.4}, min {:.4}, max {:.4})",
+            self.mean,
+            self.half_width,
+            self.confidence * 100.0,
+            self.count,
+            self.std_dev,
+            self.min,
+            self.max
+        )
+    }
+}
+
+// normal_quantile returns the inverse of the standard normal CDF, using Acklam's rational approximation (relative
+// error below 1.2e-9)
+pub(crate) fn normal_quantile(p: f64) -> f64 {
+    const A: [f64; 6] = [
+        -3.969_683_028_665_376e1,
+        2.209_460_984_245_205e2,
+        -2.759_285_104_469_687e2,
+        1.383_577_518_672_69e2,
+        -3.066_479_806_614_716e1,
+        2.506_628_277_459_239,
+    ];
+    const B: [f64; 5] = [
+        -5.447_609_879_822_406e1,
+        1.615_858_368_580_409e2,
+        -1.556_989_798_598_866e2,
+        6.680_131_188_771_972e1,
+        -1.328_068_155_288_572e1,
+    ];
+    const C: [f64; 6] = [
+        -7.784_894_002_430_293e-3,
+        -3.223_964_580_411_365e-1,
+        -2.400_758_277_161_838,
+        -2.549_732_539_343_734,
+        4.374_664_141_464_968,
+        2.938_163_982_698_783,
+    ];
+    const D: [f64; 4] = [
+        7.784_695_709_041_462e-3,
+        3.224_671_290_700_398e-1,
+        2.445_134_137_142_996,
+        3.754_408_661_907_416,
+    ];
+    const P_LOW: f64 = 0.024_25;
+
+    if p <= 0.0 {
+        return f64::NEG_INFINITY;
+    }
+    if p >= 1.0 {
+        return f64::INFINITY;
+    }
+
+    if p < P_LOW {
+        let q = (-2.0 * p.ln()).sqrt();
+        (((((C[0] * q + C[1]) * q + C[2]) * q + C[3]) * q + C[4]) * q + C[5])
+            / ((((D[0] * q + D[1]) * q + D[2]) * q + D[3]) * q + 1.0)
+    } else if p <= 1.0 - P_LOW {
+        let q = p - 0.5;
+        let r = q * q;
+        (((((A[0] * r + A[1]) * r + A[2]) * r + A[3]) * r + A[4]) * r + A[5]) * q
+            / (((((B[0] * r + B[1]) * r + B[2]) * r + B[3]) * r + B[4]) * r + 1.0)
+    } else {
+        -normal_quantile(1.0 - p)
+    }
+}
+
+// student_t_quantile returns the inverse of the Student t CDF with `df` degrees of freedom. One and two degrees of
+// freedom are solved exactly; larger ones start from the Cornish-Fisher expansion around the normal quantile and
+// refine it with Newton's method on the exact CDF, which is accurate to about 1e-9.
+pub(crate) fn student_t_quantile(p: f64, df: f64) -> f64 {
+    if df <= 1.0 {
+        return (PI * (p - 0.5)).tan();
+    }
+    if df <= 2.0 {
+        return (2.0 * p - 1.0) / (2.0 * p * (1.0 - p)).sqrt();
+    }
+    if p <= 0.0 {
+        return f64::NEG_INFINITY;
+    }
+    if p >= 1.0 {
+        return f64::INFINITY;
+    }
+
+    let z = normal_quantile(p);
+    let z2 = z * z;
+    let g1 = (z2 + 1.0) * z / 4.0;
+    let g2 = ((5.0 * z2 + 16.0) * z2 + 3.0) * z / 96.0;
+    let g3 = (((3.0 * z2 + 19.0) * z2 + 17.0) * z2 - 15.0) * z / 384.0;
+    let g4 = ((((79.0 * z2 + 776.0) * z2 + 1482.0) * z2 - 1920.0) * z2 - 945.0) * z / 92160.0;
+    let mut t = z + g1 / df + g2 / df.powi(2) + g3 / df.powi(3) + g4 / df.powi(4);
+
+    let log_norm = ln_gamma((df + 1.0) / 2.0) - ln_gamma(df / 2.0) - 0.5 * (df * PI).ln();
+    for _ in 0..50 {
+        let density = (log_norm - (df + 1.0) / 2.0 * (t * t / df).ln_1p()).exp();
+        let step = (student_t_cdf(t, df) - p) / density;
+        t -= step;
+        if step.abs() <= 1e-12 * t.abs().max(1.0) {
+            break;
+        }
+    }
+    t
+}
+
+// student_t_cdf returns the Student t CDF with `df` degrees of freedom at `t`
+fn student_t_cdf(t: f64, df: f64) -> f64 {
+    let tail = 0.5 * incomplete_beta(df / 2.0, 0.5, df / (df + t * t));
+    if t > 0.0 { 1.0 - tail } else { tail }
+}
+
+// incomplete_beta returns the regularized incomplete beta function I_x(a, b), evaluated with the continued fraction
+// of Numerical Recipes (6.4) on whichever side converges faster
+fn incomplete_beta(a: f64, b: f64, x: f64) -> f64 {
+    if x <= 0.0 {
+        return 0.0;
+    }
+    if x >= 1.0 {
+        return 1.0;
+    }
+
+    let front =
+        (ln_gamma(a + b) - ln_gamma(a) - ln_gamma(b) + a * x.ln() + b * (1.0 - x).ln()).exp();
+    if x < (a + 1.0) / (a + b + 2.0) {
+        front * beta_fraction(a, b, x) / a
+    } else {
+        1.0 - front * beta_fraction(b, a, 1.0 - x) / b
+    }
+}
+
+fn beta_fraction(a: f64, b: f64, x: f64) -> f64 {
+    const TINY: f64 = 1e-300;
+
+    let mut c = 1.0;
+    let mut d = 1.0 - (a + b) * x / (a + 1.0);
+    if d.abs() < TINY {
+        d = TINY;
+    }
+    d = 1.0 / d;
+    let mut h = d;
+
+    for m in 1..300 {
+        let m = m as f64;
+        for numerator in [
+            m * (b - m) * x / ((a + 2.0 * m - 1.0) * (a + 2.0 * m)),
+            -(a + m) * (a + b + m) * x / ((a + 2.0 * m) * (a + 2.0 * m + 1.0)),
+        ] {
+            d = 1.0 + numerator * d;
+            if d.abs() < TINY {
+                d = TINY;
+            }
+            c = 1.0 + numerator / c;
+            if c.abs() < TINY {
+                c = TINY;
+            }
+            d = 1.0 / d;
+            h *= d * c;
+        }
+        if (d * c - 1.0).abs() < 1e-15 {
+            break;
+        }
+    }
+    h
+}
+
+// ln_gamma returns the natural logarithm of the gamma function for positive arguments, using the Lanczos
+// approximation (g = 7, n = 9)
+fn ln_gamma(x: f64) -> f64 {
+    const G: f64 = 7.0;
+    const COEFFICIENTS: [f64; 9] = [
+        0.999_999_999_999_809_9,
+        676.520_368_121_885_1,
+        -1_259.139_216_722_402_8,
+        771.323_428_777_653_1,
+        -176.615_029_162_140_6,
+        12.507_343_278_686_905,
+        -0.138_571_095_265_720_12,
+        9.984_369_578_019_572e-6,
+        1.505_632_735_149_311_6e-7,
+    ];
+
+    if x < 0.5 {
+        return (PI / (PI * x).sin()).ln() - ln_gamma(1.0 - x);
+    }
+
+    let x = x - 1.0;
+    let t = x + G + 0.5;
+    let series = COEFFICIENTS[1..]
+        .iter()
+        .enumerate()
+        .fold(COEFFICIENTS[0], |sum, (i, c)| {
+            sum + c / (x + i as f64 + 1.0)
+        });
+
+    0.5 * (2.0 * PI).ln() + (x + 0.5) * t.ln() - t + series.ln()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_quantiles_match_tables() {
+        assert!((normal_quantile(0.975) - 1.959_964).abs() < 1e-6);
+        assert!((normal_quantile(0.05) + 1.644_854).abs() < 1e-6);
+
+        assert!((student_t_quantile(0.975, 1.0) - 12.706).abs() < 1e-3);
+        assert!((student_t_quantile(0.975, 2.0) - 4.303).abs() < 1e-3);
+        assert!((student_t_quantile(0.975, 3.0) - 3.182_446).abs() < 1e-3);
+        assert!((student_t_quantile(0.995, 3.0) - 5.840_909).abs() < 1e-3);
+        assert!((student_t_quantile(0.975, 4.0) - 2.776_445).abs() < 1e-3);
+        assert!((student_t_quantile(0.995, 4.0) - 4.604_095).abs() < 1e-3);
+        assert!((student_t_quantile(0.975, 5.0) - 2.570_582).abs() < 1e-3);
+        assert!((student_t_quantile(0.005, 5.0) + 4.032_143).abs() < 1e-3);
+        assert!((student_t_quantile(0.975, 9.0) - 2.262).abs() < 1e-3);
+        assert!((student_t_quantile(0.95, 29.0) - 1.699).abs() < 1e-3);
+    }
+
+    #[test]
+    fn test_summary_confidence_interval() {
+        let summary = Summary::from_values(&[2.0, 4.0, 4.0, 4.0, 5.0, 5.0, 7.0, 9.0], 0.95);
+
+        assert_eq!(summary.count, 8);
+        assert_eq!(summary.mean, 5.0);
+        assert!((summary.std_dev - 2.138).abs() < 1e-3);
+        assert!((summary.half_width - 1.787).abs() < 1e-2);
+        assert_eq!(summary.min, 2.0);
+        assert_eq!(summary.max, 9.0);
+
+        let single = Summary::from_values(&[1.0], 0.95);
+        assert!(single.half_width.is_infinite());
+        assert_eq!(single.required_replications(0.1), None);
+
+        // a tenth of the mean needs a little over (1.96 * 2.138 / 0.5)^2 = 70.2 observations
+        let required = summary.required_replications(0.1).unwrap();
+        assert!((71..=75).contains(&required), "{}", required);
+        assert_eq!(summary.required_replications(1.0), Some(8));
+    }
+}
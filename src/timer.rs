@@ -0,0 +1,145 @@
+use chrono::{DateTime, Duration, Utc};
+use std::sync::Arc;
+
+pub const TIMEOUT_TIMER: &str = "timeout";
+
+pub type TransitionHook<C> = Arc<dyn Fn(&mut TransitionContext<C>) + Send + Sync>;
+
+/// TimerSpec describes a deterministic timer armed whenever an agent enters a state type. It races the sampled
+/// stochastic delay and forces a transition to `target` if it expires first.
+#[derive(Clone, Debug)]
+pub struct TimerSpec<C> {
+    pub name: String,
+    pub after: f64,
+    pub target: C,
+}
+
+/// ActiveTimer is a timer currently armed on an agent.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ActiveTimer<C> {
+    pub name: String,
+    pub deadline: DateTime<Utc>,
+    pub target: C,
+}
+
+/// TransitionContext is handed to transition hooks, giving them access to the transition being applied and the
+/// agent's timers.
+pub struct TransitionContext<'a, C> {
+    pub from: &'a C,
+    pub to: &'a C,
+    pub time: DateTime<Utc>,
+    timers: &'a mut Vec<ActiveTimer<C>>,
+}
+
+impl<'a, C> TransitionContext<'a, C> {
+    pub(crate) fn new(
+        from: &'a C,
+        to: &'a C,
+        time: DateTime<Utc>,
+        timers: &'a mut Vec<ActiveTimer<C>>,
+    ) -> Self {
+        TransitionContext {
+            from,
+            to,
+            time,
+            timers,
+        }
+    }
+
+    // set_timer arms a timer that forces a transition to `target` after `after` seconds. Setting a timer with the
+    // same name as an armed one replaces it, which can be used to model watchdog resets or inactivity expiry.
+    pub fn set_timer(&mut self, name: &str, after: f64, target: C) {
+        set_timer(
+            self.timers,
+            name,
+            self.time + seconds_to_duration(after),
+            target,
+        );
+    }
+
+    // cancel_timer disarms the timer with the given name, if it is armed
+    pub fn cancel_timer(&mut self, name: &str) {
+        self.timers.retain(|timer| timer.name != name);
+    }
+
+    pub fn timers(&self) -> &[ActiveTimer<C>] {
+        self.timers
+    }
+}
+
+pub(crate) fn set_timer<C>(
+    timers: &mut Vec<ActiveTimer<C>>,
+    name: &str,
+    deadline: DateTime<Utc>,
+    target: C,
+) {
+    timers.retain(|timer| timer.name != name);
+    timers.push(ActiveTimer {
+        name: name.to_string(),
+        deadline,
+        target,
+    });
+}
+
+// next_timer returns the armed timer that expires first. Ties are broken by the order the timers were armed in.
+pub(crate) fn next_timer<C>(timers: &[ActiveTimer<C>]) -> Option<&ActiveTimer<C>> {
+    timers.iter().reduce(|earliest, timer| {
+        if timer.deadline < earliest.deadline {
+            timer
+        } else {
+            earliest
+        }
+    })
+}
+
+// seconds_to_duration converts a floating point value representing seconds to a Duration (TimeDelta) type.
+pub(crate) fn seconds_to_duration(seconds: f64) -> Duration {
+    let millis = (seconds * 1000.0).round() as i64;
+    Duration::milliseconds(millis)
+}
+
+// duration_to_seconds converts a Duration back to floating point seconds, at millisecond precision
+pub(crate) fn duration_to_seconds(duration: Duration) -> f64 {
+    duration.num_milliseconds() as f64 / 1000.0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    #[test]
+    fn test_set_timer_replaces_by_name() {
+        let time = Utc.timestamp_opt(1000, 0).unwrap();
+        let mut timers = Vec::new();
+
+        set_timer(&mut timers, "watchdog", time + Duration::seconds(10), 1);
+        set_timer(&mut timers, "expiry", time + Duration::seconds(30), 2);
+        set_timer(&mut timers, "watchdog", time + Duration::seconds(60), 3);
+
+        assert_eq!(timers.len(), 2);
+        let next = next_timer(&timers).unwrap();
+        assert_eq!(next.name, "expiry");
+        assert_eq!(next.target, 2);
+    }
+
+    #[test]
+    fn test_context_timer_operations() {
+        let time = Utc.timestamp_opt(1000, 0).unwrap();
+        let mut timers = Vec::new();
+
+        let mut ctx = TransitionContext::new(&0, &1, time, &mut timers);
+        ctx.set_timer("session", 1.5, 0);
+        ctx.set_timer("watchdog", 60.0, 2);
+        ctx.cancel_timer("watchdog");
+
+        assert_eq!(
+            ctx.timers(),
+            &[ActiveTimer {
+                name: "session".to_string(),
+                deadline: time + Duration::milliseconds(1500),
+                target: 0,
+            }]
+        );
+    }
+}
@@ -0,0 +1,431 @@
+use crate::matrix::TransitionMatrix;
+use crate::state::State;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::error::Error;
+use std::fmt::{self, Debug};
+use std::hash::Hash;
+
+/// ModelError describes why a model cannot be simulated as specified. Without validation these mistakes fail
+/// silently, i.e. an agent moving to a mode missing from its transition matrix stops emitting events.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ModelError<C> {
+    // MissingMode is a mode used as initial mode that is not in the transition matrix
+    MissingMode(C),
+    // MissingTarget is a transition, timer or signal of `from` leading to a mode that is not in the transition matrix
+    MissingTarget { from: C, to: C },
+    // InvalidWeight is a negative, infinite or NaN transition weight
+    InvalidWeight { from: C, to: C, weight: f64 },
+    // NoPositiveWeight is a mode with stochastic transitions whose weights are all 0
+    NoPositiveWeight(C),
+    // InvalidRate is a negative, infinite or NaN mean delay (`event_rate`)
+    InvalidRate { mode: C, rate: f64 },
+    // InvalidHoldingTime is a holding time shape with parameters out of range (see `HoldingTime::is_valid`)
+    InvalidHoldingTime(C),
+    // InvalidTimer is a timer with a negative, infinite or NaN delay
+    InvalidTimer { mode: C, name: String, after: f64 },
+    // InvalidBias is a bias of `mode` with a rate or weight factor that is not positive and finite
+    InvalidBias { mode: C, factor: f64 },
+    // InvalidDistribution is an initial distribution without a positive weight, or with an invalid one
+    InvalidDistribution,
+    // NoStationaryDistribution is a chain whose stationary distribution is not unique
+    NoStationaryDistribution,
+    // DuplicateAgent is an agent id used more than once in a simulation
+    DuplicateAgent(String),
+    // InvalidIdPattern is an id pattern of a population without a `{}` placeholder
+    InvalidIdPattern(String),
+    // StateNotOpened is a state type described with a `ModelBuilder` before `state` opened one
+    StateNotOpened,
+    // EmptyModel is a model without any state type
+    EmptyModel,
+}
+
+impl<C: Debug> fmt::Display for ModelError<C> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ModelError::MissingMode(mode) => {
+                write!(f, "mode {:?} is not in the transition matrix", mode)
+            }
+            ModelError::MissingTarget { from, to } => write!(
+                f,
+                "mode {:?} leads to {:?}, which is not in the transition matrix",
+                from, to
+            ),
+            ModelError::InvalidWeight { from, to, weight } => write!(
+                f,
+                "transition from {:?} to {:?} has invalid weight {}",
+                from, to, weight
+            ),
+            ModelError::NoPositiveWeight(mode) => {
+                write!(f, "transitions of mode {:?} all have a weight of 0", mode)
+            }
+            ModelError::InvalidRate { mode, rate } => {
+                write!(f, "mode {:?} has invalid mean delay {}", mode, rate)
+            }
+            ModelError::InvalidHoldingTime(mode) => {
+                write!(f, "mode {:?} has an invalid holding time", mode)
+            }
+            ModelError::InvalidTimer { mode, name, after } => write!(
+                f,
+                "timer {:?} of mode {:?} has invalid delay {}",
+                name, mode, after
+            ),
+            ModelError::InvalidBias { mode, factor } => {
+                write!(f, "bias of mode {:?} has invalid factor {}", mode, factor)
+            }
+            ModelError::InvalidDistribution => {
+                write!(
+                    f,
+                    "initial distribution has no positive weight or an invalid one"
+                )
+            }
+            ModelError::NoStationaryDistribution => {
+                write!(f, "the chain has no unique stationary distribution")
+            }
+            ModelError::DuplicateAgent(id) => write!(f, "agent id {:?} is used more than once", id),
+            ModelError::InvalidIdPattern(pattern) => {
+                write!(f, "id pattern {:?} has no {{}} placeholder", pattern)
+            }
+            ModelError::StateNotOpened => {
+                write!(f, "a state type was described before state opened one")
+            }
+            ModelError::EmptyModel => write!(f, "the model has no state types"),
+        }
+    }
+}
+
+impl<C: Debug> Error for ModelError<C> {}
+
+/// ModelReport describes the structure of a valid model: the modes that cannot be reached from the initial mode, the
+/// absorbing modes that are never left once entered, and the strongly connected components, i.e. groups of modes
+/// that can all reach each other. Neither is an error (failures are often absorbing), but they are worth reviewing.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ModelReport<C> {
+    pub unreachable: Vec<C>,
+    pub absorbing: Vec<C>,
+    pub components: Vec<Vec<C>>,
+}
+
+// validate checks a transition matrix for mistakes, returning the first one found, and otherwise reports on its
+// structure. Modes are linked by stochastic transitions that can fire (with a positive mean delay and weight, or a
+// weight function), timers and signals. Timers set by on-enter hooks cannot be seen and are not accounted for.
+pub fn validate<C, S>(
+    transition_matrix: &TransitionMatrix<C, S>,
+    initial: &C,
+) -> Result<ModelReport<C>, ModelError<C>>
+where
+    C: Eq + Hash + Clone,
+    S: State,
+{
+    if !transition_matrix.contains(initial) {
+        return Err(ModelError::MissingMode(initial.clone()));
+    }
+
+    let mut edges: HashMap<&C, Vec<&C>> = HashMap::new();
+    for (mode, def) in transition_matrix.iter() {
+        let targets = edges.entry(mode).or_default();
+        let target_exists = |to: &C| {
+            if transition_matrix.contains(to) {
+                Ok(())
+            } else {
+                Err(ModelError::MissingTarget {
+                    from: mode.clone(),
+                    to: to.clone(),
+                })
+            }
+        };
+
+        if !(def.event_rate >= 0.0 && def.event_rate.is_finite()) {
+            return Err(ModelError::InvalidRate {
+                mode: mode.clone(),
+                rate: def.event_rate,
+            });
+        }
+
+        if !def.holding_time.is_valid() {
+            return Err(ModelError::InvalidHoldingTime(mode.clone()));
+        }
+
+        let fires = def.event_rate > 0.0 || def.rate_fn.is_some();
+        for (to, weight) in &def.transitions {
+            target_exists(to)?;
+            if !(*weight >= 0.0 && weight.is_finite()) {
+                return Err(ModelError::InvalidWeight {
+                    from: mode.clone(),
+                    to: to.clone(),
+                    weight: *weight,
+                });
+            }
+            if fires && (*weight > 0.0 || def.weight_fn.is_some()) {
+                targets.push(to);
+            }
+        }
+        if fires
+            && def.weight_fn.is_none()
+            && !def.transitions.is_empty()
+            && def.transitions.iter().all(|(_, weight)| *weight == 0.0)
+        {
+            return Err(ModelError::NoPositiveWeight(mode.clone()));
+        }
+
+        for timer in &def.timers {
+            target_exists(&timer.target)?;
+            if !(timer.after >= 0.0 && timer.after.is_finite()) {
+                return Err(ModelError::InvalidTimer {
+                    mode: mode.clone(),
+                    name: timer.name.clone(),
+                    after: timer.after,
+                });
+            }
+            targets.push(&timer.target);
+        }
+        for (_, to) in &def.signals {
+            target_exists(to)?;
+            targets.push(to);
+        }
+    }
+
+    // the checks above do not depend on the initial mode, so agents sharing the matrix can skip them (see `check`)
+    transition_matrix.mark_validated();
+
+    let mut reached: HashSet<&C> = HashSet::from([initial]);
+    let mut queue = VecDeque::from([initial]);
+    while let Some(mode) = queue.pop_front() {
+        for &to in &edges[mode] {
+            if reached.insert(to) {
+                queue.push_back(to);
+            }
+        }
+    }
+
+    Ok(ModelReport {
+        unreachable: transition_matrix
+            .modes()
+            .iter()
+            .filter(|mode| !reached.contains(mode))
+            .cloned()
+            .collect(),
+        absorbing: edges
+            .iter()
+            .filter(|(mode, targets)| targets.iter().all(|to| to == *mode))
+            .map(|(mode, _)| (*mode).clone())
+            .collect(),
+        components: strongly_connected_components(&edges),
+    })
+}
+
+// check returns the first mistake of a transition matrix like `validate`, without the report. The matrix is only
+// checked as a whole once, so that a million agents created from clones of one matrix do not validate it a million
+// times.
+pub(crate) fn check<C, S>(
+    transition_matrix: &TransitionMatrix<C, S>,
+    initial: &C,
+) -> Result<(), ModelError<C>>
+where
+    C: Eq + Hash + Clone,
+    S: State,
+{
+    if !transition_matrix.contains(initial) {
+        return Err(ModelError::MissingMode(initial.clone()));
+    }
+    if !transition_matrix.is_validated() {
+        validate(transition_matrix, initial)?;
+    }
+    Ok(())
+}
+
+// strongly_connected_components finds the strongly connected components of a graph with Tarjan's algorithm
+fn strongly_connected_components<C>(edges: &HashMap<&C, Vec<&C>>) -> Vec<Vec<C>>
+where
+    C: Eq + Hash + Clone,
+{
+    struct Search<'a, C> {
+        edges: &'a HashMap<&'a C, Vec<&'a C>>,
+        index: HashMap<&'a C, usize>,
+        low: HashMap<&'a C, usize>,
+        stack: Vec<&'a C>,
+        on_stack: HashSet<&'a C>,
+        components: Vec<Vec<C>>,
+    }
+
+    impl<'a, C: Eq + Hash + Clone> Search<'a, C> {
+        fn visit(&mut self, mode: &'a C) {
+            let index = self.index.len();
+            self.index.insert(mode, index);
+            self.low.insert(mode, index);
+            self.stack.push(mode);
+            self.on_stack.insert(mode);
+
+            for &to in &self.edges[mode] {
+                if !self.index.contains_key(to) {
+                    self.visit(to);
+                    let low = self.low[mode].min(self.low[to]);
+                    self.low.insert(mode, low);
+                } else if self.on_stack.contains(to) {
+                    let low = self.low[mode].min(self.index[to]);
+                    self.low.insert(mode, low);
+                }
+            }
+
+            if self.low[mode] == index {
+                let mut component = Vec::new();
+                while let Some(member) = self.stack.pop() {
+                    self.on_stack.remove(member);
+                    component.push(member.clone());
+                    if member == mode {
+                        break;
+                    }
+                }
+                self.components.push(component);
+            }
+        }
+    }
+
+    let mut search = Search {
+        edges,
+        index: HashMap::new(),
+        low: HashMap::new(),
+        stack: Vec::new(),
+        on_stack: HashSet::new(),
+        components: Vec::new(),
+    };
+    for &mode in edges.keys() {
+        if !search.index.contains_key(mode) {
+            search.visit(mode);
+        }
+    }
+    search.components
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::agent::{Agent, StateType};
+    use crate::simulation::Simulation;
+    use chrono::{TimeZone, Utc};
+    use rand::SeedableRng;
+    use rand::rngs::StdRng;
+    use state_macros::State;
+
+    #[derive(Clone, Default, Debug, State)]
+    struct Disk;
+
+    fn mode(
+        transitions: Vec<(&'static str, f64)>,
+        event_rate: f64,
+    ) -> StateType<&'static str, Disk> {
+        StateType::new_deterministic(|| Disk, transitions, event_rate)
+    }
+
+    // disk alternates between idle and busy, degrades and eventually fails for good. Spare is never used.
+    fn disk() -> HashMap<&'static str, StateType<&'static str, Disk>> {
+        HashMap::from([
+            ("idle", mode(vec![("busy", 1.0), ("degraded", 0.1)], 60.0)),
+            ("busy", mode(vec![("idle", 1.0)], 30.0)),
+            (
+                "degraded",
+                mode(vec![("failed", 1.0)], 3600.0).with_timeout(600.0, "failed"),
+            ),
+            ("failed", mode(vec![], 0.0)),
+            ("spare", mode(vec![("idle", 1.0)], 60.0)),
+        ])
+    }
+
+    fn sorted(mut modes: Vec<&'static str>) -> Vec<&'static str> {
+        modes.sort();
+        modes
+    }
+
+    #[test]
+    fn test_report_structure() {
+        let report = validate(&disk().into(), &"idle").unwrap();
+
+        assert_eq!(report.unreachable, vec!["spare"]);
+        assert_eq!(report.absorbing, vec!["failed"]);
+
+        let mut components: Vec<Vec<&str>> = report.components.into_iter().map(sorted).collect();
+        components.sort();
+        assert_eq!(
+            components,
+            vec![
+                vec!["busy", "idle"],
+                vec!["degraded"],
+                vec!["failed"],
+                vec!["spare"]
+            ]
+        );
+    }
+
+    #[test]
+    fn test_detects_invalid_models() {
+        let mut missing = disk();
+        missing.insert("busy", mode(vec![("idle", 1.0), ("crashed", 1.0)], 30.0));
+        assert_eq!(
+            validate(&missing.into(), &"idle"),
+            Err(ModelError::MissingTarget {
+                from: "busy",
+                to: "crashed"
+            })
+        );
+
+        let mut negative = disk();
+        negative.insert("busy", mode(vec![("idle", -1.0)], 30.0));
+        assert!(matches!(
+            validate(&negative.into(), &"idle"),
+            Err(ModelError::InvalidWeight { weight, .. }) if weight == -1.0
+        ));
+
+        let mut nan = disk();
+        nan.insert("busy", mode(vec![("idle", 1.0)], f64::NAN));
+        assert!(matches!(
+            validate(&nan.into(), &"idle"),
+            Err(ModelError::InvalidRate { mode: "busy", .. })
+        ));
+
+        let mut zero = disk();
+        zero.insert("busy", mode(vec![("idle", 0.0)], 30.0));
+        assert_eq!(
+            validate(&zero.into(), &"idle"),
+            Err(ModelError::NoPositiveWeight("busy"))
+        );
+
+        let mut timer = disk();
+        timer.insert(
+            "busy",
+            mode(vec![("idle", 1.0)], 30.0).with_timeout(-5.0, "idle"),
+        );
+        assert!(matches!(
+            validate(&timer.into(), &"idle"),
+            Err(ModelError::InvalidTimer { after, .. }) if after == -5.0
+        ));
+
+        let error = validate(&disk().into(), &"retired").unwrap_err();
+        assert_eq!(error, ModelError::MissingMode("retired"));
+        assert_eq!(
+            error.to_string(),
+            "mode \"retired\" is not in the transition matrix"
+        );
+    }
+
+    #[test]
+    fn test_constructors_return_errors() {
+        let mut rng = StdRng::seed_from_u64(0);
+        assert!(Agent::new("d".to_string(), "retired", disk(), &mut rng).is_err());
+        assert_eq!(
+            Agent::new_with_distribution("d".to_string(), &[("idle", 0.0)], disk(), &mut rng).err(),
+            Some(ModelError::InvalidDistribution)
+        );
+        let mut stuck = disk();
+        stuck.insert("busy", mode(vec![], 0.0));
+        assert_eq!(
+            Agent::new_stationary("d".to_string(), stuck, &mut rng).err(),
+            Some(ModelError::NoStationaryDistribution)
+        );
+
+        let agents = (0..2)
+            .map(|_| Agent::new("d".to_string(), "idle", disk(), &mut rng))
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        let duplicate = Simulation::new(agents, Utc.timestamp_opt(0, 0).unwrap()).err();
+        assert_eq!(duplicate, Some(ModelError::DuplicateAgent("d".to_string())));
+    }
+}
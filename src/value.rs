@@ -0,0 +1,435 @@
+use chrono::{DateTime, Utc};
+use serde::de::{self, Deserializer, MapAccess, SeqAccess, Visitor};
+use serde::ser::{SerializeMap, Serializer};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+/// Value is the typed value of a field, as carried by `StateChangeEvent` and held by `DynamicState`. `#[derive(State)]`
+/// fills it from the type of each field (see `ToValue`), so consumers need not parse strings back. It serializes as
+/// the plain JSON value, except for timestamps, which are marked as `{"timestamp": "<RFC 3339>"}` so that every value
+/// reads back as it was written, strings included. Its `Display` is the string form events carried before values were
+/// typed.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+#[serde(untagged)]
+pub enum Value {
+    #[default]
+    Null,
+    Bool(bool),
+    Int(i64),
+    Float(f64),
+    #[serde(serialize_with = "serialize_timestamp")]
+    Timestamp(DateTime<Utc>),
+    Str(String),
+    List(Vec<Value>),
+}
+
+impl Value {
+    // as_f64 returns the value as a number, booleans counting as 0 and 1. Strings are parsed, so that traces written
+    // before values were typed can still be analyzed
+    pub fn as_f64(&self) -> Option<f64> {
+        match self {
+            Value::Bool(value) => Some(if *value { 1.0 } else { 0.0 }),
+            Value::Int(value) => Some(*value as f64),
+            Value::Float(value) => Some(*value),
+            Value::Str(value) => match value.as_str() {
+                "true" => Some(1.0),
+                "false" => Some(0.0),
+                _ => value.parse().ok(),
+            },
+            Value::Null | Value::Timestamp(_) | Value::List(_) => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Value::Str(value) => Some(value),
+            _ => None,
+        }
+    }
+
+    pub fn is_null(&self) -> bool {
+        matches!(self, Value::Null)
+    }
+}
+
+// formats as `to_string` formats the underlying type, with null as an empty string and lists as `[a, b]`
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Value::Null => Ok(()),
+            Value::Bool(value) => write!(f, "{}", value),
+            Value::Int(value) => write!(f, "{}", value),
+            Value::Float(value) => write!(f, "{}", value),
+            Value::Timestamp(value) => write!(f, "{}", value),
+            Value::Str(value) => write!(f, "{}", value),
+            Value::List(values) => {
+                write!(f, "[")?;
+                for (i, value) in values.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{}", value)?;
+                }
+                write!(f, "]")
+            }
+        }
+    }
+}
+
+// TIMESTAMP is the key marking a timestamp in the serialized form of a value
+const TIMESTAMP: &str = "timestamp";
+
+fn serialize_timestamp<S: Serializer>(
+    time: &DateTime<Utc>,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    let mut map = serializer.serialize_map(Some(1))?;
+    map.serialize_entry(TIMESTAMP, time)?;
+    map.end()
+}
+
+// deserializes from any self-describing format; strings are read as they are, timestamps from their marker, and
+// integers too large for an i64 as floats
+impl<'de> Deserialize<'de> for Value {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct ValueVisitor;
+
+        impl<'de> Visitor<'de> for ValueVisitor {
+            type Value = Value;
+
+            fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                write!(f, "a boolean, number, string, null, list or timestamp")
+            }
+
+            fn visit_bool<E>(self, value: bool) -> Result<Value, E> {
+                Ok(Value::Bool(value))
+            }
+
+            fn visit_i64<E>(self, value: i64) -> Result<Value, E> {
+                Ok(Value::Int(value))
+            }
+
+            fn visit_u64<E>(self, value: u64) -> Result<Value, E> {
+                Ok(value.into())
+            }
+
+            fn visit_i128<E>(self, value: i128) -> Result<Value, E> {
+                Ok(i64::try_from(value).map_or(Value::Float(value as f64), Value::Int))
+            }
+
+            fn visit_u128<E>(self, value: u128) -> Result<Value, E> {
+                Ok(i64::try_from(value).map_or(Value::Float(value as f64), Value::Int))
+            }
+
+            fn visit_f64<E>(self, value: f64) -> Result<Value, E> {
+                Ok(Value::Float(value))
+            }
+
+            fn visit_str<E>(self, value: &str) -> Result<Value, E> {
+                Ok(Value::Str(value.to_string()))
+            }
+
+            fn visit_string<E>(self, value: String) -> Result<Value, E> {
+                Ok(Value::Str(value))
+            }
+
+            fn visit_unit<E>(self) -> Result<Value, E> {
+                Ok(Value::Null)
+            }
+
+            fn visit_none<E>(self) -> Result<Value, E> {
+                Ok(Value::Null)
+            }
+
+            fn visit_some<D: Deserializer<'de>>(self, deserializer: D) -> Result<Value, D::Error> {
+                Value::deserialize(deserializer)
+            }
+
+            fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Value, A::Error> {
+                let mut values = Vec::with_capacity(seq.size_hint().unwrap_or(0));
+                while let Some(value) = seq.next_element()? {
+                    values.push(value);
+                }
+                Ok(Value::List(values))
+            }
+
+            fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Value, A::Error> {
+                let time = match map.next_key::<String>()? {
+                    Some(key) if key == TIMESTAMP => map.next_value::<DateTime<Utc>>()?,
+                    _ => return Err(de::Error::custom("expected a value, found a map")),
+                };
+                if map.next_key::<de::IgnoredAny>()?.is_some() {
+                    return Err(de::Error::custom("a timestamp has a single key"));
+                }
+                Ok(Value::Timestamp(time))
+            }
+        }
+
+        deserializer.deserialize_any(ValueVisitor)
+    }
+}
+
+// comparing with a string compares the string form, as comparisons of events were written before values were typed
+impl PartialEq<str> for Value {
+    fn eq(&self, other: &str) -> bool {
+        match self {
+            Value::Str(value) => value == other,
+            _ => self.to_string().as_str() == other,
+        }
+    }
+}
+
+impl PartialEq<&str> for Value {
+    fn eq(&self, other: &&str) -> bool {
+        *self == **other
+    }
+}
+
+/// ToValue converts the type of a field to a `Value`. `#[derive(State)]` uses it for every field whose type
+/// implements it, and falls back to the `Display` string of the field (`Value::Str`) for the others, i.e. enums.
+pub trait ToValue {
+    fn to_value(&self) -> Value;
+}
+
+macro_rules! integer_values {
+    ($($integer:ty),*) => {$(
+        impl ToValue for $integer {
+            fn to_value(&self) -> Value {
+                Value::Int(*self as i64)
+            }
+        }
+
+        impl From<$integer> for Value {
+            fn from(value: $integer) -> Self {
+                Value::Int(value as i64)
+            }
+        }
+    )*};
+}
+
+integer_values!(i8, i16, i32, i64, u8, u16, u32);
+
+// integers that may not fit in an i64 become floats when they do not
+macro_rules! wide_integer_values {
+    ($($integer:ty),*) => {$(
+        impl ToValue for $integer {
+            fn to_value(&self) -> Value {
+                (*self).into()
+            }
+        }
+
+        impl From<$integer> for Value {
+            fn from(value: $integer) -> Self {
+                i64::try_from(value).map_or(Value::Float(value as f64), Value::Int)
+            }
+        }
+    )*};
+}
+
+wide_integer_values!(u64, usize, isize);
+
+impl ToValue for bool {
+    fn to_value(&self) -> Value {
+        Value::Bool(*self)
+    }
+}
+
+impl From<bool> for Value {
+    fn from(value: bool) -> Self {
+        Value::Bool(value)
+    }
+}
+
+impl ToValue for f32 {
+    fn to_value(&self) -> Value {
+        (*self).into()
+    }
+}
+
+// widens the f32 exactly, so its value displays with the digits of the f64, i.e. 0.1f32 as 0.10000000149011612
+impl From<f32> for Value {
+    fn from(value: f32) -> Self {
+        Value::Float(value as f64)
+    }
+}
+
+impl ToValue for f64 {
+    fn to_value(&self) -> Value {
+        Value::Float(*self)
+    }
+}
+
+impl From<f64> for Value {
+    fn from(value: f64) -> Self {
+        Value::Float(value)
+    }
+}
+
+impl ToValue for str {
+    fn to_value(&self) -> Value {
+        Value::Str(self.to_string())
+    }
+}
+
+impl ToValue for String {
+    fn to_value(&self) -> Value {
+        Value::Str(self.clone())
+    }
+}
+
+impl From<&str> for Value {
+    fn from(value: &str) -> Self {
+        Value::Str(value.to_string())
+    }
+}
+
+impl From<String> for Value {
+    fn from(value: String) -> Self {
+        Value::Str(value)
+    }
+}
+
+impl ToValue for DateTime<Utc> {
+    fn to_value(&self) -> Value {
+        Value::Timestamp(*self)
+    }
+}
+
+impl From<DateTime<Utc>> for Value {
+    fn from(value: DateTime<Utc>) -> Self {
+        Value::Timestamp(value)
+    }
+}
+
+impl<T: ToValue> ToValue for Option<T> {
+    fn to_value(&self) -> Value {
+        self.as_ref().map_or(Value::Null, ToValue::to_value)
+    }
+}
+
+impl<T: Into<Value>> From<Option<T>> for Value {
+    fn from(value: Option<T>) -> Self {
+        value.map_or(Value::Null, Into::into)
+    }
+}
+
+impl<T: ToValue> ToValue for [T] {
+    fn to_value(&self) -> Value {
+        Value::List(self.iter().map(ToValue::to_value).collect())
+    }
+}
+
+impl<T: ToValue> ToValue for Vec<T> {
+    fn to_value(&self) -> Value {
+        self.as_slice().to_value()
+    }
+}
+
+impl<T: Into<Value>> From<Vec<T>> for Value {
+    fn from(values: Vec<T>) -> Self {
+        Value::List(values.into_iter().map(Into::into).collect())
+    }
+}
+
+impl ToValue for Value {
+    fn to_value(&self) -> Value {
+        self.clone()
+    }
+}
+
+impl<T: ToValue + ?Sized> ToValue for &T {
+    fn to_value(&self) -> Value {
+        (**self).to_value()
+    }
+}
+
+// __private lets `#[derive(State)]` pick `ToValue` for the fields that implement it and `Display` for the others,
+// which trait bounds alone cannot express: method resolution tries `Wrap` itself before a reference to it.
+#[doc(hidden)]
+pub mod __private {
+    use super::{ToValue, Value};
+    use std::fmt::Display;
+
+    pub struct Wrap<'a, T: ?Sized>(pub &'a T);
+
+    pub trait ViaToValue {
+        fn __value(&self) -> Value;
+    }
+
+    impl<T: ToValue + ?Sized> ViaToValue for Wrap<'_, T> {
+        fn __value(&self) -> Value {
+            self.0.to_value()
+        }
+    }
+
+    pub trait ViaDisplay {
+        fn __value(&self) -> Value;
+    }
+
+    impl<T: Display + ?Sized> ViaDisplay for &Wrap<'_, T> {
+        fn __value(&self) -> Value {
+            Value::Str(self.0.to_string())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    #[test]
+    fn test_values_keep_their_types() {
+        let time = Utc.timestamp_opt(60, 0).unwrap();
+        let values = vec![
+            Value::Null,
+            Value::Bool(true),
+            Value::Int(-3),
+            Value::Float(2.5),
+            Value::Timestamp(time),
+            Value::Str("busy".to_string()),
+            Value::List(vec![Value::Int(1), Value::Str("a".to_string())]),
+        ];
+        let json = serde_json::to_string(&values).unwrap();
+        assert_eq!(
+            json,
+            r#"[null,true,-3,2.5,{"timestamp":"1970-01-01T00:01:00Z"},"busy",[1,"a"]]"#
+        );
+        assert_eq!(serde_json::from_str::<Vec<Value>>(&json).unwrap(), values);
+
+        // strings read back as strings, whatever they look like
+        let strings: Vec<Value> = ["1.10", "007", "1970-01-01T00:01:00Z", "true", ""]
+            .into_iter()
+            .map(Value::from)
+            .collect();
+        let json = serde_json::to_string(&strings).unwrap();
+        assert_eq!(serde_json::from_str::<Vec<Value>>(&json).unwrap(), strings);
+        assert!(serde_json::from_str::<Value>(r#"{"time":"1970-01-01T00:01:00Z"}"#).is_err());
+
+        // the string form is the one of the underlying types
+        let strings: Vec<String> = values.iter().map(Value::to_string).collect();
+        assert_eq!(
+            strings,
+            [
+                "",
+                "true",
+                "-3",
+                "2.5",
+                "1970-01-01 00:01:00 UTC",
+                "busy",
+                "[1, a]"
+            ]
+        );
+        assert_eq!(Value::Str("12.5".to_string()).as_f64(), Some(12.5));
+        assert_eq!(Value::Bool(true).as_f64(), Some(1.0));
+        assert_eq!(Value::from(u64::MAX), Value::Float(u64::MAX as f64));
+        assert_eq!(Value::from(Some(4u8)), Value::Int(4));
+        assert_eq!(Value::from(0.1f32).to_string(), "0.10000000149011612");
+        assert_eq!(Value::from(12.5f32), Value::Float(12.5));
+        assert!(Value::Bool(false) == "false" && Value::Int(2) != "2.0");
+        assert_eq!(
+            vec![Some(1.5), None].to_value(),
+            Value::List(vec![Value::Float(1.5), Value::Null])
+        );
+    }
+}
@@ -2,6 +2,7 @@ extern crate proc_macro;
 
 use proc_macro::TokenStream;
 use quote::quote;
+use syn::punctuated::Punctuated;
 use syn::{Data, DeriveInput, Error, Fields, parse_macro_input};
 
 #[proc_macro_derive(State)]
@@ -9,9 +10,12 @@ pub fn state_derive(input: TokenStream) -> TokenStream {
     let input = parse_macro_input!(input as DeriveInput);
     let name = &input.ident;
 
+    // a unit struct is a state without data, which never changes
+    let no_fields = Punctuated::new();
     let fields = match &input.data {
         Data::Struct(s) => match &s.fields {
             Fields::Named(fields) => &fields.named,
+            Fields::Unit => &no_fields,
             _ => {
                 return Error::new_spanned(input, "State requires named fields")
                     .to_compile_error()
@@ -33,10 +37,10 @@ pub fn state_derive(input: TokenStream) -> TokenStream {
             if self.#field_name != other.#field_name {
                 changes.push(agsim::state::StateChangeEvent {
                     time,
-                    agent_id: String::new(),
-                    field: #field_name_str.to_string(),
-                    old_value: self.#field_name.to_string(),
-                    new_value: other.#field_name.to_string(),
+                    agent_id: agsim::state::AgentId::default(),
+                    field: #field_name_str,
+                    old_value: (&agsim::value::__private::Wrap(&self.#field_name)).__value(),
+                    new_value: (&agsim::value::__private::Wrap(&other.#field_name)).__value(),
                 });
             }
         }
@@ -45,6 +49,9 @@ pub fn state_derive(input: TokenStream) -> TokenStream {
     let expanded = quote! {
         impl agsim::state::State for #name {
             fn diff(&self, other: &Self, time: chrono::DateTime<chrono::Utc>) -> Vec<agsim::state::StateChangeEvent> {
+                // fields implementing `ToValue` keep their type, the others are recorded in their `Display` form
+                #[allow(unused_imports)]
+                use agsim::value::__private::{ViaDisplay as _, ViaToValue as _};
                 let mut changes = Vec::new();
                 #(#diff_logic)*
                 changes